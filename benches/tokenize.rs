@@ -0,0 +1,90 @@
+// Plain timing benchmark, run with `cargo bench`
+// Compares lexing a file against what reading it one byte per read
+// call costs, which is what the tokenizer used to do.
+
+extern crate cblia;
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use cblia::tokenizer::TokenIterator;
+
+// Lines of source to generate, roughly 40 bytes each
+const LINES: usize = 100000;
+
+fn seconds(start: Instant) -> f64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9
+}
+
+fn main() {
+    let path = env::temp_dir().join("cblia_bench_tokenize.txt");
+
+    {
+        let mut f = File::create(&path).unwrap();
+        for i in 0..LINES {
+            write!(f, "Function func{}#(a, b$ = \"x\", c# = 1.5)\r\n", i).unwrap();
+        }
+    }
+
+    let size = File::open(&path).unwrap().metadata().unwrap().len() as f64;
+    let megabytes = size / (1024.0 * 1024.0);
+
+    // Unbuffered, one read call per byte
+    let start = Instant::now();
+    #[allow(clippy::unbuffered_bytes)]
+    let bytes = File::open(&path).unwrap().bytes().count();
+    let unbuffered = seconds(start);
+    println!("unbuffered byte reads: {} bytes in {:.3}s ({:.1} MB/s)",
+             bytes, unbuffered, megabytes / unbuffered);
+
+    // The tokenizer proper
+    let start = Instant::now();
+    let tokens = TokenIterator::new(File::open(&path).unwrap())
+                 .filter(|t| t.is_ok())
+                 .count();
+    let buffered = seconds(start);
+    println!("tokenizer: {} tokens in {:.3}s ({:.1} MB/s)",
+             tokens, buffered, megabytes / buffered);
+
+    // Memory mapped with the mmap feature, the same as above otherwise
+    let start = Instant::now();
+    let tokens = TokenIterator::open(&path).unwrap()
+                 .filter(|t| t.is_ok())
+                 .count();
+    let mapped = seconds(start);
+    println!("tokenizer over TokenIterator::open: {} tokens in {:.3}s ({:.1} MB/s)",
+             tokens, mapped, megabytes / mapped);
+
+    // Every line as a script of its own, a new tokenizer for each or one
+    // reset for each
+    let mut source = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut source).unwrap();
+    let scripts = source.split(|&b| b == b'\n')
+                  .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                  .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut tokens = 0;
+    for script in &scripts {
+        tokens += TokenIterator::new(*script).filter(|t| t.is_ok()).count();
+    }
+    let fresh = seconds(start);
+    println!("new tokenizer per script: {} tokens in {:.3}s ({:.1} MB/s)",
+             tokens, fresh, megabytes / fresh);
+
+    let start = Instant::now();
+    let mut tokens = 0;
+    let mut it = TokenIterator::new(&b""[..]);
+    for script in &scripts {
+        it.reset(*script);
+        tokens += it.by_ref().filter(|t| t.is_ok()).count();
+    }
+    let reused = seconds(start);
+    println!("reset tokenizer per script: {} tokens in {:.3}s ({:.1} MB/s)",
+             tokens, reused, megabytes / reused);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,853 @@
+// Syntax tree of a whole program, see parser::parse_str and parse_file
+
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+
+use tokenizer::Span;
+
+// A name as written, and as compared, which is lowercase if the lexer was
+// case insensitive
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Name {
+    pub name: String,
+    pub spelling: String
+}
+
+// The sigil after a name, no sigil means an integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Sigil {
+    None,
+    // $, a string
+    Dollar,
+    // #, a float
+    Hash
+}
+
+impl Sigil {
+    // As it's written after the name
+    pub fn suffix(&self) -> &'static str {
+        match *self {
+            Sigil::None => "",
+            Sigil::Dollar => "$",
+            Sigil::Hash => "#"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Variable {
+    pub name: Name,
+    pub sigil: Sigil
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BinaryOp {
+    Add,
+    // + with a string on either side, see Expr::sigil
+    Concat,
+    Subtract,
+    Multiply,
+    Divide,
+    // Mod or %
+    Modulo,
+    Power,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    And,
+    Or,
+    Xor,
+    // And and Or with LexerConfig::short_circuit, the right side is only
+    // worked out if the left side doesn't already decide it
+    AndAlso,
+    OrElse
+}
+
+impl BinaryOp {
+    // How tightly it binds, higher first:
+    //
+    //   ^                right to left
+    //   -                just in front of something, see UnaryOp
+    //   * / Mod %
+    //   + -
+    //   = <> < > <= >=
+    //   Not              see UnaryOp
+    //   And
+    //   Or Xor
+    //
+    // Everything but ^ goes left to right
+    pub fn precedence(&self) -> u8 {
+        match *self {
+            BinaryOp::Or | BinaryOp::OrElse | BinaryOp::Xor => 1,
+            BinaryOp::And | BinaryOp::AndAlso => 2,
+            BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::Less | BinaryOp::Greater |
+            BinaryOp::LessEqual | BinaryOp::GreaterEqual => 4,
+            BinaryOp::Add | BinaryOp::Concat | BinaryOp::Subtract => 5,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => 6,
+            BinaryOp::Power => 8
+        }
+    }
+
+    pub fn right_associative(&self) -> bool {
+        *self == BinaryOp::Power
+    }
+
+    // As it's written, Mod for Modulo
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            BinaryOp::Add | BinaryOp::Concat => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Modulo => "Mod",
+            BinaryOp::Power => "^",
+            BinaryOp::Equal => "=",
+            BinaryOp::NotEqual => "<>",
+            BinaryOp::Less => "<",
+            BinaryOp::Greater => ">",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::And | BinaryOp::AndAlso => "And",
+            BinaryOp::Or | BinaryOp::OrElse => "Or",
+            BinaryOp::Xor => "Xor"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum UnaryOp {
+    // -x. -1 comes out of the parser as a negation too, passes that need
+    // a literal fold it into one, see passes::negative_literal
+    Negate,
+    // 1 for 0 and 0 for anything else, with or without short circuits
+    Not
+}
+
+impl UnaryOp {
+    // Between the binary operators, so -a ^ 2 is -(a ^ 2), Not a = b is
+    // Not (a = b) and Not a And b is (Not a) And b
+    pub fn precedence(&self) -> u8 {
+        match *self {
+            UnaryOp::Negate => 7,
+            UnaryOp::Not => 3
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "Not"
+        }
+    }
+}
+
+// From the start of its first token to the end of its last, see Stmt
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ExprKind {
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Variable(Variable),
+    // p\x, the field of whatever p points to
+    Member { object: ExprId, field: Variable },
+    Binary { op: BinaryOp, left: ExprId, right: ExprId },
+    Unary { op: UnaryOp, operand: ExprId },
+    // f(a, b), a Function. Arrays are indexed with the same parens, the
+    // parser makes the ones with that name Dim'd somewhere an Index
+    Call { name: Variable, arguments: Vec<ExprId> },
+    // grid(x, y), one index for each dimension it was Dim'd with
+    Index { array: Variable, indices: Vec<ExprId> }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Expr {
+        Expr { kind, span }
+    }
+
+    // The type the sigils and literals say it has, Sigil::None being an
+    // integer like it is for variables
+    // None if it takes more than that to work out, like for a Function
+    // without a sigil or + on two of those. Whatever finds out the type
+    // of those later can make a + a Concat too
+    pub fn sigil(&self, arena: &Arena) -> Option<Sigil> {
+        match self.kind {
+            ExprKind::Integer(_) => Some(Sigil::None),
+            ExprKind::Float(_) => Some(Sigil::Hash),
+            ExprKind::String(_) => Some(Sigil::Dollar),
+            ExprKind::Variable(ref variable) => Some(variable.sigil),
+            ExprKind::Member { ref field, .. } => Some(field.sigil),
+            ExprKind::Index { ref array, .. } => Some(array.sigil),
+            ExprKind::Call { ref name, .. } if name.sigil != Sigil::None => Some(name.sigil),
+            ExprKind::Call { .. } => None,
+            ExprKind::Binary { op, left, right } => match op {
+                BinaryOp::Concat => Some(Sigil::Dollar),
+                BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::Less | BinaryOp::Greater |
+                BinaryOp::LessEqual | BinaryOp::GreaterEqual | BinaryOp::And | BinaryOp::Or |
+                BinaryOp::Xor | BinaryOp::AndAlso | BinaryOp::OrElse => Some(Sigil::None),
+                _ => match (arena[left].sigil(arena), arena[right].sigil(arena)) {
+                    (Some(Sigil::Hash), Some(Sigil::None)) | (Some(Sigil::None), Some(Sigil::Hash)) |
+                    (Some(Sigil::Hash), Some(Sigil::Hash)) => Some(Sigil::Hash),
+                    (Some(Sigil::None), Some(Sigil::None)) => Some(Sigil::None),
+                    _ => None
+                }
+            },
+            ExprKind::Unary { op: UnaryOp::Negate, operand } => match arena[operand].sigil(arena) {
+                Some(Sigil::Dollar) => None,
+                sigil => sigil
+            },
+            ExprKind::Unary { op: UnaryOp::Not, .. } => Some(Sigil::None)
+        }
+    }
+}
+
+// What Print is given, in order
+// A comma moves to the next column and a semicolon doesn't move at all, so
+// a line ending in either doesn't get a line break
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum PrintItem {
+    Value(ExprId),
+    Comma,
+    Semicolon
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ElseIf {
+    pub condition: ExprId,
+    pub body: Vec<StmtId>
+}
+
+// One value after Case, or Case 1 To 5 which includes both ends
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CaseValue {
+    Value(ExprId),
+    Range(ExprId, ExprId)
+}
+
+// Case 1, 3 To 5 runs if any of the values match
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Case {
+    pub values: Vec<CaseValue>,
+    pub body: Vec<StmtId>
+}
+
+// An array and how big it is in each dimension, like grid(20, 20)
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ArrayDecl {
+    pub variable: Variable,
+    pub dimensions: Vec<ExprId>
+}
+
+// The sigil of the name is what it returns, and those of the parameters
+// what they take
+// CoolBasic has no Subs, a Function that doesn't return anything returns 0
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Function {
+    pub name: Variable,
+    pub parameters: Vec<Variable>,
+    pub body: Vec<StmtId>
+}
+
+// What an Exit gets out of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Loop {
+    For,
+    While,
+    Repeat
+}
+
+// Type Point with a Field line for each of its fields
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TypeDecl {
+    pub name: Name,
+    pub fields: Vec<Variable>
+}
+
+// Where a declared variable can be seen
+// A Local inside a Function hides a Global of the same name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Scope {
+    Global,
+    Local
+}
+
+// A statement and where it is, from its first token to its last, so up to
+// the end of the block for an If or a loop
+// Statements and expressions that a pass makes up have the span of what
+// they were made from
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Stmt {
+        Stmt { kind, span }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum StmtKind {
+    // The target is a Variable or a Member
+    Assign { target: ExprId, value: ExprId },
+    // A command with its arguments, like DrawScreen or Text 10, 10, "hi"
+    Call { name: Name, arguments: Vec<ExprId> },
+    Print(Vec<PrintItem>),
+    // single_line is If x Then y = 1 Else y = 2, which never has ElseIfs
+    If {
+        condition: ExprId,
+        then_branch: Vec<StmtId>,
+        else_ifs: Vec<ElseIf>,
+        else_branch: Option<Vec<StmtId>>,
+        single_line: bool
+    },
+    // Step is 1 if there isn't one
+    For { variable: Variable, from: ExprId, to: ExprId, step: Option<ExprId>, body: Vec<StmtId> },
+    While { condition: ExprId, body: Vec<StmtId> },
+    // Runs at least once, until is None for Repeat ... Forever
+    Repeat { body: Vec<StmtId>, until: Option<ExprId> },
+    // Default is also Case Else
+    Select { value: ExprId, cases: Vec<Case>, default: Option<Vec<StmtId>> },
+    // Dim a(10), b$(2, 2) declares, ReDim resizes
+    Dim(Vec<ArrayDecl>),
+    ReDim(Vec<ArrayDecl>),
+    // Only at the top level
+    Function(Function),
+    Type(TypeDecl),
+    // The file name as written, see parser::parse_sources
+    Include(String),
+    // Data only has literals, Read takes the next ones in the program and
+    // Restore goes back to the first Data, or the first after a label
+    Data(Vec<ExprId>),
+    Read(Vec<Variable>),
+    Restore(Option<Name>),
+    // Global x, y$ or Local z#, Globals aren't allowed inside a Function
+    Declare { scope: Scope, variables: Vec<Variable> },
+    // The value only has literals and other constants in it
+    Const { name: Variable, value: ExprId },
+    Label(Name),
+    Goto(Name),
+    Gosub(Name),
+    // Back to after the last Gosub, or out of the Function with what it
+    // returns
+    Return(Option<ExprId>),
+    // Out of the innermost loop of the kind, Exit on its own is Exit for
+    // whatever loop it's in
+    Exit(Loop),
+    End
+}
+
+// Where a node is in the Arena of its Program, which stays the same for as
+// long as the program is around
+// Passes can keep what they find out about the nodes in tables of their
+// own by them, a Vec as long as stmt_count or expr_count or a HashMap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExprId(u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StmtId(u32);
+
+impl ExprId {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl StmtId {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+// Either kind of node, for a table that has both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NodeId {
+    Stmt(StmtId),
+    Expr(ExprId)
+}
+
+// Every statement and expression in a program, which have the ids of their
+// children instead of owning them
+// Nodes are only ever added. One a pass replaces is left where it is with
+// nothing pointing to it anymore, so the ids of the rest don't change
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Arena {
+    stmts: Vec<Stmt>,
+    exprs: Vec<Expr>
+}
+
+impl Arena {
+    pub fn new() -> Arena {
+        Arena { stmts: Vec::new(), exprs: Vec::new() }
+    }
+
+    pub fn add_stmt(&mut self, kind: StmtKind, span: Span) -> StmtId {
+        self.stmts.push(Stmt::new(kind, span));
+        StmtId(self.stmts.len() as u32 - 1)
+    }
+
+    pub fn add_expr(&mut self, kind: ExprKind, span: Span) -> ExprId {
+        self.exprs.push(Expr::new(kind, span));
+        ExprId(self.exprs.len() as u32 - 1)
+    }
+
+    // How many there are of each, every id is below it
+    pub fn stmt_count(&self) -> usize {
+        self.stmts.len()
+    }
+
+    pub fn expr_count(&self) -> usize {
+        self.exprs.len()
+    }
+}
+
+impl Index<StmtId> for Arena {
+    type Output = Stmt;
+
+    fn index(&self, id: StmtId) -> &Stmt {
+        &self.stmts[id.index()]
+    }
+}
+
+impl IndexMut<StmtId> for Arena {
+    fn index_mut(&mut self, id: StmtId) -> &mut Stmt {
+        &mut self.stmts[id.index()]
+    }
+}
+
+impl Index<ExprId> for Arena {
+    type Output = Expr;
+
+    fn index(&self, id: ExprId) -> &Expr {
+        &self.exprs[id.index()]
+    }
+}
+
+impl IndexMut<ExprId> for Arena {
+    fn index_mut(&mut self, id: ExprId) -> &mut Expr {
+        &mut self.exprs[id.index()]
+    }
+}
+
+// A comment as it's written, with its ' or REM or /* */
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Comment {
+    pub text: String,
+    pub span: Span
+}
+
+// The comments that go with a statement. Leading are on lines of their own
+// before it, trailing are after it on the line it ends on, inside it
+// outside of its blocks, or at the end of the block it's last in
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Comments {
+    pub leading: Vec<Comment>,
+    pub trailing: Vec<Comment>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Program {
+    pub statements: Vec<StmtId>,
+    pub arena: Arena,
+    // Where every label is, by its compared name
+    pub labels: HashMap<String, Span>,
+    // The value of every Const the same way
+    pub constants: HashMap<String, ExprId>,
+    // Every Include and where it is
+    pub includes: Vec<(String, Span)>,
+    // Those of every statement that has any, only when the tokens were
+    // lexed with LexerConfig::lossless
+    // A program without statements has nothing to put its comments on
+    pub comments: HashMap<StmtId, Comments>
+}
+
+// The tree as a Graphviz graph, one box per node with its children below
+// it in order, for dot -Tsvg and the like
+// Edges are labelled with what the child is to its parent when that isn't
+// obvious, like condition or step
+pub fn to_dot(program: &Program) -> String {
+    let mut dot = Dot { out: String::new(), nodes: 0, arena: &program.arena };
+    dot.out.push_str("digraph program {\n");
+    dot.out.push_str("    graph [ordering=out];\n");
+    dot.out.push_str("    node [shape=box, fontname=monospace];\n");
+    let root = dot.node("Program");
+    dot.block(root, "", &program.statements);
+    dot.out.push_str("}\n");
+    dot.out
+}
+
+fn dot_variable(variable: &Variable) -> String {
+    format!("{}{}", variable.name.spelling, variable.sigil.suffix())
+}
+
+fn dot_variables(variables: &[Variable]) -> String {
+    variables.iter().map(dot_variable).collect::<Vec<_>>().join(", ")
+}
+
+struct Dot<'a> {
+    out: String,
+    nodes: usize,
+    arena: &'a Arena
+}
+
+impl<'a> Dot<'a> {
+    fn node(&mut self, label: &str) -> usize {
+        let mut escaped = String::new();
+        for c in label.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                c => escaped.push(c)
+            }
+        }
+
+        let id = self.nodes;
+        self.nodes += 1;
+        self.out.push_str(&format!("    n{} [label=\"{}\"];\n", id, escaped));
+        id
+    }
+
+    // A new node under parent
+    fn child(&mut self, parent: usize, role: &str, label: &str) -> usize {
+        let id = self.node(label);
+        if role.is_empty() {
+            self.out.push_str(&format!("    n{} -> n{};\n", parent, id));
+        }
+        else {
+            self.out.push_str(&format!("    n{} -> n{} [label=\"{}\"];\n", parent, id, role));
+        }
+        id
+    }
+
+    fn block(&mut self, parent: usize, role: &str, statements: &[StmtId]) {
+        for &stmt in statements {
+            self.stmt(parent, role, stmt);
+        }
+    }
+
+    fn stmt(&mut self, parent: usize, role: &str, stmt: StmtId) {
+        let arena = self.arena;
+        match arena[stmt].kind {
+            StmtKind::Assign { target, value } => {
+                let id = self.child(parent, role, "=");
+                self.expr(id, "target", target);
+                self.expr(id, "value", value);
+            },
+            StmtKind::Call { ref name, ref arguments } => {
+                let id = self.child(parent, role, &name.spelling);
+                for &argument in arguments {
+                    self.expr(id, "", argument);
+                }
+            },
+            StmtKind::Print(ref items) => {
+                let id = self.child(parent, role, "Print");
+                for item in items {
+                    match *item {
+                        PrintItem::Value(value) => self.expr(id, "", value),
+                        PrintItem::Comma => { self.child(id, "", ","); },
+                        PrintItem::Semicolon => { self.child(id, "", ";"); }
+                    }
+                }
+            },
+            StmtKind::If { condition, ref then_branch, ref else_ifs, ref else_branch,
+                           single_line } => {
+                let id = self.child(parent, role, if single_line { "If (one line)" } else { "If" });
+                self.expr(id, "condition", condition);
+                self.block(id, "then", then_branch);
+                for else_if in else_ifs {
+                    let else_if_id = self.child(id, "", "ElseIf");
+                    self.expr(else_if_id, "condition", else_if.condition);
+                    self.block(else_if_id, "then", &else_if.body);
+                }
+                if let Some(ref else_branch) = *else_branch {
+                    self.block(id, "else", else_branch);
+                }
+            },
+            StmtKind::For { ref variable, from, to, step, ref body } => {
+                let id = self.child(parent, role, &format!("For {}", dot_variable(variable)));
+                self.expr(id, "from", from);
+                self.expr(id, "to", to);
+                if let Some(step) = step {
+                    self.expr(id, "step", step);
+                }
+                self.block(id, "", body);
+            },
+            StmtKind::While { condition, ref body } => {
+                let id = self.child(parent, role, "While");
+                self.expr(id, "condition", condition);
+                self.block(id, "", body);
+            },
+            StmtKind::Repeat { ref body, until } => {
+                let id = self.child(parent, role, if until.is_some() { "Repeat" } else { "Repeat Forever" });
+                self.block(id, "", body);
+                if let Some(until) = until {
+                    self.expr(id, "until", until);
+                }
+            },
+            StmtKind::Select { value, ref cases, ref default } => {
+                let id = self.child(parent, role, "Select");
+                self.expr(id, "value", value);
+                for case in cases {
+                    let case_id = self.child(id, "", "Case");
+                    for value in &case.values {
+                        match *value {
+                            CaseValue::Value(value) => self.expr(case_id, "value", value),
+                            CaseValue::Range(from, to) => {
+                                let range = self.child(case_id, "value", "To");
+                                self.expr(range, "", from);
+                                self.expr(range, "", to);
+                            }
+                        }
+                    }
+                    self.block(case_id, "", &case.body);
+                }
+                if let Some(ref default) = *default {
+                    let default_id = self.child(id, "", "Default");
+                    self.block(default_id, "", default);
+                }
+            },
+            StmtKind::Dim(ref arrays) | StmtKind::ReDim(ref arrays) => {
+                let keyword = if let StmtKind::Dim(_) = arena[stmt].kind { "Dim" } else { "ReDim" };
+                let id = self.child(parent, role, keyword);
+                for array in arrays {
+                    let array_id = self.child(id, "", &dot_variable(&array.variable));
+                    for &dimension in &array.dimensions {
+                        self.expr(array_id, "", dimension);
+                    }
+                }
+            },
+            StmtKind::Function(ref function) => {
+                let label = format!("Function {}({})", dot_variable(&function.name),
+                                    dot_variables(&function.parameters));
+                let id = self.child(parent, role, &label);
+                self.block(id, "", &function.body);
+            },
+            StmtKind::Type(ref declared) => {
+                let id = self.child(parent, role, &format!("Type {}", declared.name.spelling));
+                for field in &declared.fields {
+                    self.child(id, "", &format!("Field {}", dot_variable(field)));
+                }
+            },
+            StmtKind::Include(ref path) => {
+                self.child(parent, role, &format!("Include \"{}\"", path));
+            },
+            StmtKind::Data(ref values) => {
+                let id = self.child(parent, role, "Data");
+                for &value in values {
+                    self.expr(id, "", value);
+                }
+            },
+            StmtKind::Read(ref variables) => {
+                self.child(parent, role, &format!("Read {}", dot_variables(variables)));
+            },
+            StmtKind::Restore(ref label) => {
+                let label = match *label {
+                    Some(ref label) => format!("Restore {}", label.spelling),
+                    None => "Restore".to_string()
+                };
+                self.child(parent, role, &label);
+            },
+            StmtKind::Declare { scope, ref variables } => {
+                let keyword = match scope {
+                    Scope::Global => "Global",
+                    Scope::Local => "Local"
+                };
+                self.child(parent, role, &format!("{} {}", keyword, dot_variables(variables)));
+            },
+            StmtKind::Const { ref name, value } => {
+                let id = self.child(parent, role, &format!("Const {}", dot_variable(name)));
+                self.expr(id, "", value);
+            },
+            StmtKind::Label(ref label) => {
+                self.child(parent, role, &format!("{}:", label.spelling));
+            },
+            StmtKind::Goto(ref label) => {
+                self.child(parent, role, &format!("Goto {}", label.spelling));
+            },
+            StmtKind::Gosub(ref label) => {
+                self.child(parent, role, &format!("Gosub {}", label.spelling));
+            },
+            StmtKind::Return(value) => {
+                let id = self.child(parent, role, "Return");
+                if let Some(value) = value {
+                    self.expr(id, "", value);
+                }
+            },
+            StmtKind::Exit(kind) => {
+                self.child(parent, role, match kind {
+                    Loop::For => "Exit For",
+                    Loop::While => "Exit While",
+                    Loop::Repeat => "Exit Repeat"
+                });
+            },
+            StmtKind::End => { self.child(parent, role, "End"); }
+        }
+    }
+
+    fn expr(&mut self, parent: usize, role: &str, expr: ExprId) {
+        let arena = self.arena;
+        match arena[expr].kind {
+            ExprKind::Integer(value) => { self.child(parent, role, &value.to_string()); },
+            ExprKind::Float(value) => { self.child(parent, role, &format!("{:?}", value)); },
+            ExprKind::String(ref value) => { self.child(parent, role, &format!("\"{}\"", value)); },
+            ExprKind::Variable(ref variable) => { self.child(parent, role, &dot_variable(variable)); },
+            ExprKind::Member { object, ref field } => {
+                let id = self.child(parent, role, &format!("\\{}", dot_variable(field)));
+                self.expr(id, "", object);
+            },
+            ExprKind::Binary { op, left, right } => {
+                let id = self.child(parent, role, op.symbol());
+                self.expr(id, "", left);
+                self.expr(id, "", right);
+            },
+            ExprKind::Unary { op, operand } => {
+                let id = self.child(parent, role, op.symbol());
+                self.expr(id, "", operand);
+            },
+            ExprKind::Call { ref name, ref arguments } => {
+                let id = self.child(parent, role, &format!("{}()", dot_variable(name)));
+                for &argument in arguments {
+                    self.expr(id, "", argument);
+                }
+            },
+            ExprKind::Index { ref array, ref indices } => {
+                let id = self.child(parent, role, &dot_variable(array));
+                for &index in indices {
+                    self.expr(id, "index", index);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_str;
+    #[cfg(feature = "serde")]
+    use serde_json;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn programs_go_through_json() {
+        let source = "Type P\nField x\nEndType\nConst c = 2.5\nl:\n\
+                      If a\\x > 1 Then Goto l Else Print \"s\";\n\
+                      Select q\nCase 1 To 2, 3\nEnd\nDefault\nEnd\nEndSelect\n\
+                      For i = 1 To 2 Step 2\nNext\n";
+        let program = parse_str(source).unwrap();
+        let json = serde_json::to_value(&program).unwrap();
+        // Ids are just their numbers
+        assert_eq!(json["statements"].as_array().unwrap().len(), 6);
+        assert!(json["statements"][0].is_u64());
+        assert_eq!(json["labels"]["l"]["line"], 5);
+
+        let back: Program = serde_json::from_value(json).unwrap();
+        assert_eq!(back, program);
+    }
+
+
+    #[test]
+    fn dot_has_a_node_per_ast_node() {
+        let dot = to_dot(&parse_str("If a > 1 Then\nPrint \"x\\\"\nEndIf\n").unwrap());
+        assert!(dot.starts_with("digraph program {\n"));
+        assert!(dot.ends_with("}\n"));
+        let nodes = dot.lines().filter(|l| l.contains("[label=") && !l.contains("->")).count();
+        assert_eq!(nodes, dot.matches(" -> ").count() + 1);
+        assert!(dot.contains("n0 [label=\"Program\"];"));
+        // Edges are named when the parent has more than one kind of child
+        assert!(dot.contains("n1 -> n2 [label=\"condition\"];"));
+        assert!(dot.contains("n1 -> n5 [label=\"then\"];"));
+        assert!(dot.contains("n2 -> n3;"));
+        // Quotes and backslashes are escaped in the labels
+        assert!(dot.contains("n6 [label=\"\\\"x\\\\\\\"\"];"));
+    }
+
+
+    fn sigil(expr: &str) -> Option<Sigil> {
+        let program = parse_str(&format!("x = {}\n", expr)).unwrap();
+        match program.arena[program.statements[0]].kind {
+            StmtKind::Assign { value, .. } => program.arena[value].sigil(&program.arena),
+            ref kind => panic!("{:?}", kind)
+        }
+    }
+
+    #[test]
+    fn sigils_of_expressions() {
+        assert_eq!(sigil("1 + a"), Some(Sigil::None));
+        assert_eq!(sigil("1.5 * -a"), Some(Sigil::Hash));
+        assert_eq!(sigil("-a# / 2"), Some(Sigil::Hash));
+        assert_eq!(sigil("a$ + 1"), Some(Sigil::Dollar));
+        assert_eq!(sigil("a$ < b$"), Some(Sigil::None));
+        assert_eq!(sigil("m\\n$"), Some(Sigil::Dollar));
+        assert_eq!(sigil("f#(1)"), Some(Sigil::Hash));
+        // Nothing to go by for a Function without a sigil
+        assert_eq!(sigil("f(1)"), None);
+        assert_eq!(sigil("f(1) + 1"), None);
+    }
+
+
+    #[test]
+    fn ids_get_back_what_was_added() {
+        let span = Span { line: 1, column: 1, byte_offset: 0, length: 1 };
+        let mut arena = Arena::new();
+        let one = arena.add_expr(ExprKind::Integer(1), span);
+        let two = arena.add_expr(ExprKind::Integer(2), span);
+        let end = arena.add_stmt(StmtKind::End, span);
+        assert_eq!((one.index(), two.index(), end.index()), (0, 1, 0));
+        assert_eq!((arena.expr_count(), arena.stmt_count()), (2, 1));
+        assert_eq!(arena[two].kind, ExprKind::Integer(2));
+
+        arena[one].kind = ExprKind::Float(1.5);
+        assert_eq!(arena[one], Expr::new(ExprKind::Float(1.5), span));
+        assert_eq!(arena[end], Stmt::new(StmtKind::End, span));
+    }
+
+    #[test]
+    fn side_tables_by_id() {
+        let program = parse_str("x = 1 + 2\nPrint x\n").unwrap();
+        let arena = &program.arena;
+        let mut integers = vec![None; arena.expr_count()];
+        for (i, integer) in integers.iter_mut().enumerate() {
+            if let ExprKind::Integer(value) = arena[ExprId(i as u32)].kind {
+                *integer = Some(value);
+            }
+        }
+        assert_eq!(integers.iter().filter_map(|&value| value).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(program.statements.iter().all(|stmt| stmt.index() < arena.stmt_count()));
+    }
+}
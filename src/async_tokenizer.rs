@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::io::AsyncRead;
+use futures::stream::Stream;
+
+use interner::Interner;
+use push::PushTokenizer;
+use stream::LexResult;
+use tokenizer::{LexError, LexerConfig};
+
+// How much to ask the source for at a time
+const CHUNK: usize = 4096;
+
+// Tokens from an AsyncRead as a Stream
+// Lines are lexed as they come in, so a token comes out once its line is
+// complete. The interner isn't Send, so neither is this
+pub struct AsyncTokenIterator<R: AsyncRead + Unpin> {
+    source: R,
+    tokens: PushTokenizer,
+    ready: VecDeque<LexResult>,
+    done: bool
+}
+
+impl<R: AsyncRead + Unpin> AsyncTokenIterator<R> {
+    pub fn new(source: R) -> AsyncTokenIterator<R> {
+        AsyncTokenIterator::new_with_config(source, LexerConfig::default())
+    }
+
+    pub fn new_with_config(source: R, config: LexerConfig) -> AsyncTokenIterator<R> {
+        AsyncTokenIterator {
+            source,
+            tokens: PushTokenizer::new_with_config(config),
+            ready: VecDeque::new(),
+            done: false
+        }
+    }
+
+    pub fn interner(&self) -> Interner {
+        self.tokens.interner()
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for AsyncTokenIterator<R> {
+    type Item = LexResult;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<LexResult>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(token) = this.ready.pop_front() {
+                return Poll::Ready(Some(token));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let mut chunk = [0; CHUNK];
+            match Pin::new(&mut this.source).poll_read(cx, &mut chunk) {
+                Poll::Pending => return Poll::Pending,
+
+                Poll::Ready(Ok(0)) => {
+                    this.done = true;
+                    this.ready.extend(this.tokens.finish());
+                },
+
+                Poll::Ready(Ok(n)) => {
+                    this.ready.extend(this.tokens.feed(&chunk[..n]));
+                },
+
+                Poll::Ready(Err(ref e)) if e.kind() == ErrorKind::Interrupted => {},
+
+                // Everything read so far still comes out, then the error
+                Poll::Ready(Err(e)) => {
+                    let span = this.tokens.end();
+
+                    this.done = true;
+                    this.ready.extend(this.tokens.finish());
+                    this.ready.push_back(Err(LexError::Io {
+                        kind: e.kind(),
+                        span
+                    }));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    use futures::executor::block_on;
+    use futures::io::Cursor;
+    use futures::stream::StreamExt;
+
+    use tokenizer::{Keyword, TokenKind};
+
+    // Gives its bytes in pieces, with a Pending and an Interrupted in between
+    struct Network {
+        pieces: Vec<&'static [u8]>,
+        waited: bool
+    }
+
+    impl AsyncRead for Network {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+                     -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if !this.waited {
+                this.waited = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            this.waited = false;
+
+            match this.pieces.pop() {
+                Some(b"!") => Poll::Ready(Err(ErrorKind::Interrupted.into())),
+                Some(b"?") => Poll::Ready(Err(ErrorKind::ConnectionReset.into())),
+                Some(piece) => {
+                    buf[..piece.len()].copy_from_slice(piece);
+                    Poll::Ready(Ok(piece.len()))
+                },
+                None => Poll::Ready(Ok(0))
+            }
+        }
+    }
+
+    fn kinds(tokens: Vec<LexResult>) -> Vec<Result<TokenKind, LexError>> {
+        tokens.into_iter().map(|token| token.map(|token| token.kind)).collect()
+    }
+
+    #[test]
+    fn same_tokens_as_reading_it_all() {
+        let source = Cursor::new(b"Print x\ny".to_vec());
+        let tokens = block_on(AsyncTokenIterator::new(source).collect());
+        assert_eq!(kinds(tokens), vec![Ok(TokenKind::Keyword(Keyword::Print)), Ok(TokenKind::Text),
+                                       Ok(TokenKind::EOL), Ok(TokenKind::Text)]);
+    }
+
+    #[test]
+    fn lines_split_between_reads() {
+        // Last piece first
+        let source = Network { pieces: vec![b"End", b"0\n", b"nt 1", b"!", b"Pri"], waited: false };
+        let tokens: Vec<_> = block_on(AsyncTokenIterator::new(source).collect());
+        assert_eq!(kinds(tokens), vec![Ok(TokenKind::Keyword(Keyword::Print)), Ok(TokenKind::Integer),
+                                       Ok(TokenKind::EOL), Ok(TokenKind::Keyword(Keyword::End))]);
+    }
+
+    #[test]
+    fn errors_come_after_what_was_read() {
+        let source = Network { pieces: vec![b"?", b"x = 1"], waited: false };
+        let tokens: Vec<_> = block_on(AsyncTokenIterator::new(source).collect());
+        assert_eq!(tokens.len(), 4);
+        match tokens[3] {
+            Err(LexError::Io { kind: ErrorKind::ConnectionReset, span }) => {
+                assert_eq!(span.column, 6)
+            },
+            ref other => panic!("{:?}", other)
+        }
+    }
+}
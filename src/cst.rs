@@ -0,0 +1,218 @@
+// Lossless syntax tree, see parser::parse_str_cst
+// The nodes are the statements and expressions the parser found, and the
+// leaves the tokens with their trivia, so every byte of the source is in
+// it somewhere: spacing and comments in the leading trivia of the token
+// after them, and whatever is after the last token in trailing
+
+use std::io::{self, Write};
+
+use ast::{Stmt, StmtKind};
+use tokenizer::{Span, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+    Program,
+    // A statement, named like its ast::Stmt
+    Assign,
+    Call,
+    Print,
+    If,
+    For,
+    While,
+    Repeat,
+    Select,
+    Dim,
+    ReDim,
+    Function,
+    Type,
+    Include,
+    Data,
+    Read,
+    Restore,
+    Declare,
+    Const,
+    Label,
+    Goto,
+    Gosub,
+    Return,
+    Exit,
+    End,
+    // Expressions, and a variable as the target of an assignment
+    Literal,
+    Variable,
+    Member,
+    Binary,
+    Unary,
+    // f(a), Call is the statement. It's an array being indexed if the ast
+    // has an Index there, except as the target of an assignment
+    CallExpr,
+    // grid(x, y) = 1
+    Index,
+    // (a + b), the ast only has what's inside
+    Paren,
+    // A statement that didn't parse, or what was skipped after an error
+    Error
+}
+
+impl NodeKind {
+    // Not an expression or an Error
+    pub fn is_statement(&self) -> bool {
+        !matches!(*self, NodeKind::Program | NodeKind::Literal | NodeKind::Variable | NodeKind::Member |
+                         NodeKind::Binary | NodeKind::Unary | NodeKind::CallExpr | NodeKind::Index |
+                         NodeKind::Paren | NodeKind::Error)
+    }
+
+    pub fn of(stmt: &Stmt) -> NodeKind {
+        match stmt.kind {
+            StmtKind::Assign { .. } => NodeKind::Assign,
+            StmtKind::Call { .. } => NodeKind::Call,
+            StmtKind::Print(_) => NodeKind::Print,
+            StmtKind::If { .. } => NodeKind::If,
+            StmtKind::For { .. } => NodeKind::For,
+            StmtKind::While { .. } => NodeKind::While,
+            StmtKind::Repeat { .. } => NodeKind::Repeat,
+            StmtKind::Select { .. } => NodeKind::Select,
+            StmtKind::Dim(_) => NodeKind::Dim,
+            StmtKind::ReDim(_) => NodeKind::ReDim,
+            StmtKind::Function(_) => NodeKind::Function,
+            StmtKind::Type(_) => NodeKind::Type,
+            StmtKind::Include(_) => NodeKind::Include,
+            StmtKind::Data(_) => NodeKind::Data,
+            StmtKind::Read(_) => NodeKind::Read,
+            StmtKind::Restore(_) => NodeKind::Restore,
+            StmtKind::Declare { .. } => NodeKind::Declare,
+            StmtKind::Const { .. } => NodeKind::Const,
+            StmtKind::Label(_) => NodeKind::Label,
+            StmtKind::Goto(_) => NodeKind::Goto,
+            StmtKind::Gosub(_) => NodeKind::Gosub,
+            StmtKind::Return(_) => NodeKind::Return,
+            StmtKind::Exit(_) => NodeKind::Exit,
+            StmtKind::End => NodeKind::End
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Element {
+    Node(Node),
+    // Always has its trivia
+    Token(Token<'static>)
+}
+
+// The separators between statements and the EOLs of a block belong to the
+// statement the block is in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub children: Vec<Element>
+}
+
+impl Node {
+    // Every token in it, in order
+    pub fn tokens(&self) -> Vec<&Token<'static>> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a Token>) {
+        for child in &self.children {
+            match *child {
+                Element::Node(ref node) => node.collect_tokens(tokens),
+                Element::Token(ref token) => tokens.push(token)
+            }
+        }
+    }
+
+    // From the start of the first token to the end of the last, without
+    // the trivia before it
+    // None for a node without tokens, like an empty Error
+    pub fn span(&self) -> Option<Span> {
+        let tokens = self.tokens();
+        let (first, last) = match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => (first.span, last.span),
+            _ => return None
+        };
+
+        Some(Span {
+            length: last.byte_offset + last.length - first.byte_offset,
+            .. first
+        })
+    }
+
+    // The leading trivia and the text of every token
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for token in self.tokens() {
+            if let Some(ref trivia) = token.trivia {
+                out.write_all(&trivia.leading)?;
+                out.write_all(&trivia.text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cst {
+    pub root: Node,
+    // After the last token
+    pub trailing: Vec<u8>
+}
+
+impl Cst {
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.root.write(out)?;
+        out.write_all(&self.trailing)
+    }
+
+    // The source exactly as it was
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write(&mut bytes).unwrap();
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_str_cst;
+
+    fn statements(cst: &Cst) -> Vec<&Node> {
+        cst.root.children.iter().filter_map(|child| match *child {
+            Element::Node(ref node) => Some(node),
+            Element::Token(_) => None
+        }).collect()
+    }
+
+    #[test]
+    fn every_byte_is_in_the_tree() {
+        let sources = [
+            "' header\nx = (1 +  2)   ' why\r\n\nIf a Then\n\tPrint \"hi\";\nEndIf\n  ",
+            "Function f#(a)\n  Return a * 2.5 /* twice */\nEndFunction",
+            // Errors too
+            "x = @ 1\ny = )\n",
+        ];
+        for source in sources.iter() {
+            let (_, cst, _) = parse_str_cst(source);
+            assert_eq!(String::from_utf8(cst.to_bytes()).unwrap(), *source);
+        }
+    }
+
+    #[test]
+    fn statements_and_their_spans() {
+        let source = "  x = 1 ' one\nPrint x";
+        let (_, cst, errors) = parse_str_cst(source);
+        assert!(errors.is_empty());
+        let found = statements(&cst);
+        let kinds: Vec<_> = found.iter().map(|node| node.kind).collect();
+        assert_eq!(kinds, vec![NodeKind::Assign, NodeKind::Print]);
+
+        // Without the spacing before it
+        let span = found[0].span().unwrap();
+        assert_eq!((span.byte_offset, span.length), (2, 5));
+        let mut written = Vec::new();
+        found[1].write(&mut written).unwrap();
+        assert_eq!(written, b"Print x");
+    }
+}
@@ -0,0 +1,157 @@
+use std::io;
+use std::io::Write;
+
+use interner::Interner;
+use tokenizer::{IntegerWidth, Payload, Token, TokenKind};
+
+// Bytes that run together into one word
+fn word(byte: u8) -> bool {
+    byte == b'_' || byte == b'.' || byte >= 0x80 || byte.is_ascii_alphanumeric()
+}
+
+// Whether writing these two next to each other would lex as something else
+fn needs_space(last: u8, next: u8, label: bool) -> bool {
+    match (last, next) {
+        (a, b) if word(a) && word(b) => true,
+        // A name and a sigil is one identifier
+        (a, b'$') | (a, b'#') if word(a) => true,
+        (_, b':') => label,
+        (b'<', b'=') | (b'<', b'>') | (b'>', b'=') => true,
+        (b'/', b'*') => true,
+        _ => false
+    }
+}
+
+fn token_text(token: &Token, interner: &Interner, width: IntegerWidth) -> io::Result<Vec<u8>> {
+    let mut text = match token.identifier() {
+        Some(identifier) => interner.resolve(identifier.spelling),
+        None => Vec::new()
+    };
+
+    match (token.kind, &token.payload) {
+        (TokenKind::StringIdent, _) => text.push(b'$'),
+        (TokenKind::FloatIdent, _) => text.push(b'#'),
+        (TokenKind::Label, _) => text.push(b':'),
+        (TokenKind::Text, _) => {},
+        (_, Payload::String(bytes)) => {
+            // There's no escaping in string literals
+            if bytes.iter().any(|&b| b == b'"' || b == b'\r' || b == b'\n') {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "String can't be written as a literal"));
+            }
+            text.push(b'"');
+            text.extend_from_slice(bytes);
+            text.push(b'"');
+        },
+        // Debug always has a point or an exponent, so it lexes as a Number
+        (_, &Payload::Number(value)) => text.extend_from_slice(format!("{:?}", value).as_bytes()),
+        // Only a hex or binary literal comes out negative, with the top bit
+        // of the width it was lexed with set. A minus would be a token of
+        // its own
+        (_, &Payload::Integer(value)) if value < 0 => {
+            let bits = match width {
+                IntegerWidth::Bits32 => format!("&H{:X}", value as u32),
+                IntegerWidth::Bits64 => format!("&H{:X}", value as u64)
+            };
+            text.extend_from_slice(bits.as_bytes())
+        },
+        (_, &Payload::Integer(value)) => text.extend_from_slice(format!("{}", value).as_bytes()),
+        (TokenKind::EOL, _) => text.push(b'\n'),
+        (kind, _) => text.extend_from_slice(format!("{}", kind).as_bytes())
+    }
+
+    Ok(text)
+}
+
+// Writes tokens out as source text that lexes back into the same tokens
+// Spaces only go where they're needed, there are no comments and every
+// line ends in LF. Identifiers are written as they were spelled and
+// keywords the way KEYWORDS has them
+// Whatever encoding was read, this writes UTF-8
+// The width has to be the one the tokens were lexed with, see
+// LexerConfig::integer_width
+pub fn write_source<'a, 'b: 'a, W, I>(out: &mut W, tokens: I, interner: &Interner,
+                                      width: IntegerWidth) -> io::Result<()>
+    where W: Write, I: IntoIterator<Item = &'a Token<'b>>
+{
+    let mut last = None;
+    // A name first on a line and a colon would be a label
+    let mut line_start = true;
+    let mut label = false;
+
+    for token in tokens {
+        let text = token_text(token, interner, width)?;
+
+        if let (Some(last), Some(&next)) = (last, text.first()) {
+            if needs_space(last, next, label) {
+                out.write_all(b" ")?;
+            }
+        }
+        out.write_all(&text)?;
+
+        label = line_start && token.kind == TokenKind::Text;
+        line_start = token.kind == TokenKind::EOL;
+        last = text.last().cloned();
+    }
+
+    Ok(())
+}
+
+pub fn to_source<'a, 'b: 'a, I>(tokens: I, interner: &Interner, width: IntegerWidth)
+                                -> io::Result<Vec<u8>>
+    where I: IntoIterator<Item = &'a Token<'b>>
+{
+    let mut source = Vec::new();
+    write_source(&mut source, tokens, interner, width)?;
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizer::{LexerConfig, TokenIterator};
+
+    fn lex(source: &[u8], width: IntegerWidth) -> (Vec<Token<'static>>, Interner) {
+        let config = LexerConfig::new().integer_width(width);
+        let mut tokens = TokenIterator::new_with_config(source, config);
+        let lexed = tokens.by_ref().map(|token| token.unwrap()).collect();
+        (lexed, tokens.interner())
+    }
+
+    fn written(source: &str) -> (String, bool) {
+        written_with(source, IntegerWidth::Bits64)
+    }
+
+    // The source written back out, and whether it lexes the same
+    fn written_with(source: &str, width: IntegerWidth) -> (String, bool) {
+        let (tokens, interner) = lex(source.as_bytes(), width);
+        let written = to_source(&tokens, &interner, width).unwrap();
+        let (again, _) = lex(&written, width);
+        let same = tokens.iter().zip(&again).all(|(a, b)| a.kind == b.kind && a.payload == b.payload);
+        (String::from_utf8(written).unwrap(), same && tokens.len() == again.len())
+    }
+
+    #[test]
+    fn spaces_only_where_needed() {
+        assert_eq!(written("x  =  a - - 1 <= b$\r\n"), ("x=a--1<=b$\n".to_string(), true));
+        assert_eq!(written("If a Or b Then c"), ("If a Or b Then c".to_string(), true));
+    }
+
+    #[test]
+    fn negative_bit_pattern_is_written_in_hex() {
+        let source = "x = &HFFFFFFFFFFFFFFFF";
+        assert_eq!(written(source), ("x=&HFFFFFFFFFFFFFFFF".to_string(), true));
+        let source = "x = &HFFFFFFFF + &B10000000000000000000000000000000";
+        assert_eq!(written_with(source, IntegerWidth::Bits32),
+                   ("x=&HFFFFFFFF+&H80000000".to_string(), true));
+    }
+
+    #[test]
+    fn every_kind_of_token_comes_back() {
+        let source = "top:\n  name$ = \"a 'string'\" ' gone\n\
+                      Print 1.5e3 Mod 2, c#[1]; x\\y /* gone */ : Goto top\n";
+        let (text, same) = written(source);
+        assert!(same, "{}", text);
+        assert_eq!(text, "top:\nname$=\"a 'string'\"\nPrint 1500.0 Mod 2,c#[1];x\\y:Goto top\n");
+    }
+}
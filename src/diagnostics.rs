@@ -0,0 +1,107 @@
+use std::fmt::Write;
+
+use parser::ParseError;
+use tokenizer::{LexError, Span};
+
+// Where the line with this byte offset starts and ends, line break not
+// included
+fn line_bounds(source: &[u8], offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let start = source[..offset].iter()
+                .rposition(|&b| b == b'\n' || b == b'\r')
+                .map_or(0, |i| i + 1);
+    let end = source[offset..].iter()
+              .position(|&b| b == b'\n' || b == b'\r')
+              .map_or(source.len(), |i| offset + i);
+    (start, end)
+}
+
+// Renders a message with the line the span is on and the span underlined,
+// the way rustc does it:
+//
+//   error: String literal is missing its closing quote
+//    --> decl.txt:6:5
+//     |
+//   6 | b = "x
+//     |     ^^
+//
+// Spans over more than one line are underlined to the end of the first
+// The source is shown as UTF-8, whatever encoding it was lexed as
+pub fn render_span(message: &str, span: Span, source: &[u8], file: &str) -> String {
+    let (start, end) = line_bounds(source, span.byte_offset);
+    let offset = span.byte_offset.min(end);
+    let stop = (span.byte_offset + span.length).min(end);
+
+    let line = String::from_utf8_lossy(&source[start..end]);
+    let before = String::from_utf8_lossy(&source[start..offset]);
+    let underlined = String::from_utf8_lossy(&source[offset..stop]).chars().count();
+
+    // Tabs stay tabs so the carets line up whatever the tab width is
+    let padding = before.chars()
+                  .map(|c| if c == '\t' { '\t' } else { ' ' })
+                  .collect::<String>();
+    let carets = "^".repeat(underlined.max(1));
+    let gutter = " ".repeat(span.line.to_string().len());
+
+    let mut out = String::new();
+    writeln!(out, "error: {}", message).unwrap();
+    writeln!(out, "{}--> {}:{}", gutter, file, span).unwrap();
+    writeln!(out, "{} |", gutter).unwrap();
+    writeln!(out, "{} | {}", span.line, line).unwrap();
+    writeln!(out, "{} | {}{}", gutter, padding, carets).unwrap();
+    out
+}
+
+pub fn render(error: &LexError, source: &[u8], file: &str) -> String {
+    render_span(&error.message(), error.span(), source, file)
+}
+
+// Expected `Then` or end of line, found `,` is underlined at the ,
+pub fn render_parse(error: &ParseError, source: &[u8], file: &str) -> String {
+    render_span(&error.message, error.span, source, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_str;
+    use tokenizer::TokenIterator;
+
+    fn first_error(source: &str) -> LexError {
+        TokenIterator::new(source.as_bytes()).filter_map(Result::err).next().unwrap()
+    }
+
+    #[test]
+    fn error_is_underlined_on_its_line() {
+        let source = "a = 1\n\tb = \"é and more\nc";
+        let rendered = render(&first_error(source), source.as_bytes(), "test.cb");
+        assert_eq!(rendered, "error: String literal is missing its closing quote\n \
+                              --> test.cb:2:6\n  \
+                              |\n\
+                              2 | \tb = \"é and more\n  \
+                              | \t    ^^^^^^^^^^^\n");
+    }
+
+    #[test]
+    fn gutter_fits_the_line_number() {
+        let source = format!("{}x = @", "\n".repeat(99));
+        let rendered = render(&first_error(&source), source.as_bytes(), "long.cb");
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(&lines[1..], &["   --> long.cb:100:5", "    |", "100 | x = @", "    |     ^"]);
+    }
+
+    #[test]
+    fn span_at_the_end_still_gets_a_caret() {
+        let span = Span { line: 1, column: 4, byte_offset: 3, length: 0 };
+        let rendered = render_span("Expected more", span, b"x =", "end.cb");
+        assert!(rendered.ends_with("1 | x =\n  |    ^\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn parse_errors_are_rendered_the_same_way() {
+        let source = "If a, b Then\n";
+        let rendered = render_parse(&parse_str(source).unwrap_err(), source.as_bytes(), "if.cb");
+        assert_eq!(rendered, "error: Expected `Then` or end of line, found `,`\n \
+                              --> if.cb:1:5\n  |\n1 | If a, b Then\n  |     ^\n");
+    }
+}
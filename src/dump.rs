@@ -0,0 +1,619 @@
+use std::io;
+use std::io::Write;
+
+use ast::{Arena, CaseValue, ExprId, ExprKind, Name, PrintItem, Program, StmtId, StmtKind,
+          Variable};
+use cst::{self, Cst, Element, NodeKind};
+use interner::Interner;
+use parser::{Datatype, Node};
+use stream::LexResult;
+use tokenizer::{Payload, Span, TokenKind};
+
+// Escaped the same way for JSON and S-expressions
+fn quoted<W: Write>(out: &mut W, bytes: &[u8]) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?
+        }
+    }
+    out.write_all(b"\"")
+}
+
+fn json_span<W: Write>(out: &mut W, span: Span) -> io::Result<()> {
+    write!(out, "\"line\":{},\"column\":{},\"offset\":{},\"length\":{}",
+           span.line, span.column, span.byte_offset, span.length)
+}
+
+// One JSON object per line for every token, errors included:
+//
+//   {"kind":"Text","line":1,"column":1,"offset":0,"length":3,"name":"foo","spelling":"Foo"}
+//   {"kind":"Keyword","keyword":"Function",...}
+//   {"kind":"String",...,"value":"text"}
+//   {"error":"Invalid UTF-8","line":2,...}
+//
+// Kinds are the TokenKind names and names come out resolved, so the
+// other end doesn't need to know about the interner
+pub fn write_json<W, I>(out: &mut W, tokens: I, interner: &Interner) -> io::Result<()>
+    where W: Write, I: IntoIterator<Item = LexResult>
+{
+    for result in tokens {
+        let token = match result {
+            Ok(token) => token,
+            Err(e) => {
+                out.write_all(b"{\"error\":")?;
+                quoted(out, e.message().as_bytes())?;
+                out.write_all(b",")?;
+                json_span(out, e.span())?;
+                out.write_all(b"}\n")?;
+                continue;
+            }
+        };
+
+        match token.kind {
+            TokenKind::Keyword(keyword) => {
+                write!(out, "{{\"kind\":\"Keyword\",\"keyword\":\"{}\",", keyword)?
+            },
+            kind => write!(out, "{{\"kind\":\"{:?}\",", kind)?
+        }
+        json_span(out, token.span)?;
+
+        match token.payload {
+            Payload::None => {},
+            Payload::Identifier(identifier) => {
+                out.write_all(b",\"name\":")?;
+                quoted(out, &interner.resolve(identifier.name))?;
+                out.write_all(b",\"spelling\":")?;
+                quoted(out, &interner.resolve(identifier.spelling))?;
+            },
+            Payload::String(ref bytes) => {
+                out.write_all(b",\"value\":")?;
+                quoted(out, bytes)?;
+            },
+            // Lexed numbers are never infinite or NaN
+            Payload::Number(value) => write!(out, ",\"value\":{:?}", value)?,
+            Payload::Integer(value) => write!(out, ",\"value\":{}", value)?
+        }
+
+        out.write_all(b"}\n")?;
+    }
+
+    Ok(())
+}
+
+// One S-expression per line for every token, errors included:
+//
+//   (Keyword Function 1:1)
+//   (Text "Foo" 1:10)
+//   (String "text" 1:15)
+//   (error "Invalid UTF-8" 2:1)
+//
+// Identifiers are written as spelled, the sigil or colon is in the kind
+pub fn write_sexp<W, I>(out: &mut W, tokens: I, interner: &Interner) -> io::Result<()>
+    where W: Write, I: IntoIterator<Item = LexResult>
+{
+    for result in tokens {
+        let token = match result {
+            Ok(token) => token,
+            Err(e) => {
+                out.write_all(b"(error ")?;
+                quoted(out, e.message().as_bytes())?;
+                writeln!(out, " {})", e.span())?;
+                continue;
+            }
+        };
+
+        match token.kind {
+            TokenKind::Keyword(keyword) => write!(out, "(Keyword {}", keyword)?,
+            kind => write!(out, "({:?}", kind)?
+        }
+
+        match token.payload {
+            Payload::None => {},
+            Payload::Identifier(identifier) => {
+                out.write_all(b" ")?;
+                quoted(out, &interner.resolve(identifier.spelling))?;
+            },
+            Payload::String(ref bytes) => {
+                out.write_all(b" ")?;
+                quoted(out, bytes)?;
+            },
+            Payload::Number(value) => write!(out, " {:?}", value)?,
+            Payload::Integer(value) => write!(out, " {}", value)?
+        }
+
+        writeln!(out, " {})", token.span)?;
+    }
+
+    Ok(())
+}
+
+// The parse tree the same way, nested and indented like tree-sitter does
+//
+//   (Root
+//     (Function "foo" Float
+//       (Argument "a" Integer)))
+pub fn write_node_sexp<W: Write>(out: &mut W, node: &Node) -> io::Result<()> {
+    write_node(out, node, 0)?;
+    out.write_all(b"\n")
+}
+
+fn write_node<W: Write>(out: &mut W, node: &Node, depth: usize) -> io::Result<()> {
+    match *node {
+        Node::Empty => out.write_all(b"(Empty)"),
+
+        Node::Root { ref functions } => {
+            out.write_all(b"(Root")?;
+            write_children(out, functions, depth)
+        },
+
+        Node::Function { ref name, ref datatype, ref arguments } => {
+            out.write_all(b"(Function ")?;
+            quoted(out, name)?;
+            write!(out, " {}", datatype_name(datatype))?;
+            write_children(out, arguments, depth)
+        },
+
+        Node::Argument { ref name, ref datatype } => {
+            out.write_all(b"(Argument ")?;
+            quoted(out, name)?;
+            write!(out, " {})", datatype_name(datatype))
+        }
+    }
+}
+
+// Closes the parent's paren too
+fn write_children<W: Write>(out: &mut W, children: &[Node], depth: usize) -> io::Result<()> {
+    for child in children {
+        write!(out, "\n{}", "  ".repeat(depth + 1))?;
+        write_node(out, child, depth + 1)?;
+    }
+    out.write_all(b")")
+}
+
+fn datatype_name(datatype: &Datatype) -> &'static str {
+    match *datatype {
+        Datatype::Unknown => "Unknown",
+        Datatype::String => "String",
+        Datatype::Float => "Float",
+        Datatype::Integer => "Integer"
+    }
+}
+
+// A parsed program like tree-sitter prints one, every node with what's in
+// it and its span, then its children named where it has more than one kind
+// of them:
+//
+//   (Program
+//     (Assign 1:1
+//       target: (Variable "x" 1:1)
+//       value: (Binary Add 1:5
+//         left: (Integer 1 1:5)
+//         right: (Integer 2 1:9))))
+//
+// Comments are left out, they're in Program::comments
+pub fn write_program_sexp<W: Write>(out: &mut W, program: &Program) -> io::Result<()> {
+    let mut sexp = Sexp { out, depth: 0 };
+    sexp.open("", "Program", &[], None)?;
+    for &stmt in &program.statements {
+        write_stmt(&mut sexp, &program.arena, "", stmt)?;
+    }
+    sexp.close()?;
+    out.write_all(b"\n")
+}
+
+// A Cst the same way, its tokens written like write_sexp does but with
+// their text, so keywords and names are as they were spelled
+//
+//   (Program 1:1
+//     (Assign 1:1
+//       (Variable 1:1
+//         (Text "X" 1:1))
+//       (Equals "=" 1:3)
+//       (Literal 1:5
+//         (Integer "1" 1:5)))
+//     (EOL "\n" 1:6))
+//
+// Trivia is left out, so this is the same whatever the spacing
+pub fn write_cst_sexp<W: Write>(out: &mut W, cst: &Cst) -> io::Result<()> {
+    let mut sexp = Sexp { out, depth: 0 };
+    write_cst_node(&mut sexp, &cst.root)?;
+    out.write_all(b"\n")
+}
+
+// Quoted as an atom of a node
+fn quote(text: &[u8]) -> String {
+    let mut quoted_text = Vec::new();
+    quoted(&mut quoted_text, text).unwrap();
+    String::from_utf8(quoted_text).unwrap()
+}
+
+fn quote_name(name: &Name) -> String {
+    quote(name.spelling.as_bytes())
+}
+
+fn quote_variable(variable: &Variable) -> String {
+    quote(format!("{}{}", variable.name.spelling, variable.sigil.suffix()).as_bytes())
+}
+
+// Each node goes on a line of its own, indented by how deep it is
+struct Sexp<'a, W: 'a> {
+    out: &'a mut W,
+    depth: usize
+}
+
+impl<'a, W: Write> Sexp<'a, W> {
+    // Everything up to the children, which go one deeper until close
+    fn open(&mut self, field: &str, head: &str, atoms: &[String], span: Option<Span>)
+            -> io::Result<()> {
+        if self.depth > 0 {
+            write!(self.out, "\n{}", "  ".repeat(self.depth))?;
+        }
+        if !field.is_empty() {
+            write!(self.out, "{}: ", field)?;
+        }
+        write!(self.out, "({}", head)?;
+        for atom in atoms {
+            write!(self.out, " {}", atom)?;
+        }
+        if let Some(span) = span {
+            write!(self.out, " {}", span)?;
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.depth -= 1;
+        self.out.write_all(b")")
+    }
+
+    // A node without children
+    fn leaf(&mut self, field: &str, head: &str, atoms: &[String], span: Option<Span>)
+            -> io::Result<()> {
+        self.open(field, head, atoms, span)?;
+        self.close()
+    }
+}
+
+fn write_block<W: Write>(sexp: &mut Sexp<W>, arena: &Arena, field: &str, block: &[StmtId])
+                         -> io::Result<()> {
+    for &stmt in block {
+        write_stmt(sexp, arena, field, stmt)?;
+    }
+    Ok(())
+}
+
+fn write_exprs<W: Write>(sexp: &mut Sexp<W>, arena: &Arena, field: &str, exprs: &[ExprId])
+                         -> io::Result<()> {
+    for &expr in exprs {
+        write_expr(sexp, arena, field, expr)?;
+    }
+    Ok(())
+}
+
+fn write_stmt<W: Write>(sexp: &mut Sexp<W>, arena: &Arena, field: &str, stmt: StmtId)
+                        -> io::Result<()> {
+    // Named like the Cst has them, which is how the ast has them too
+    let head = format!("{:?}", NodeKind::of(&arena[stmt]));
+    let span = Some(arena[stmt].span);
+
+    match arena[stmt].kind {
+        StmtKind::Assign { target, value } => {
+            sexp.open(field, &head, &[], span)?;
+            write_expr(sexp, arena, "target", target)?;
+            write_expr(sexp, arena, "value", value)?;
+        },
+        StmtKind::Call { ref name, ref arguments } => {
+            sexp.open(field, &head, &[quote_name(name)], span)?;
+            write_exprs(sexp, arena, "", arguments)?;
+        },
+        StmtKind::Print(ref items) => {
+            sexp.open(field, &head, &[], span)?;
+            for item in items {
+                match *item {
+                    PrintItem::Value(value) => write_expr(sexp, arena, "", value)?,
+                    PrintItem::Comma => sexp.leaf("", "Comma", &[], None)?,
+                    PrintItem::Semicolon => sexp.leaf("", "Semicolon", &[], None)?
+                }
+            }
+        },
+        StmtKind::If { condition, ref then_branch, ref else_ifs, ref else_branch,
+                       single_line } => {
+            let atoms = if single_line { vec!["single_line".to_string()] } else { vec![] };
+            sexp.open(field, &head, &atoms, span)?;
+            write_expr(sexp, arena, "condition", condition)?;
+            write_block(sexp, arena, "then", then_branch)?;
+            for else_if in else_ifs {
+                sexp.open("", "ElseIf", &[], None)?;
+                write_expr(sexp, arena, "condition", else_if.condition)?;
+                write_block(sexp, arena, "then", &else_if.body)?;
+                sexp.close()?;
+            }
+            if let Some(ref else_branch) = *else_branch {
+                write_block(sexp, arena, "else", else_branch)?;
+            }
+        },
+        StmtKind::For { ref variable, from, to, step, ref body } => {
+            sexp.open(field, &head, &[quote_variable(variable)], span)?;
+            write_expr(sexp, arena, "from", from)?;
+            write_expr(sexp, arena, "to", to)?;
+            if let Some(step) = step {
+                write_expr(sexp, arena, "step", step)?;
+            }
+            write_block(sexp, arena, "body", body)?;
+        },
+        StmtKind::While { condition, ref body } => {
+            sexp.open(field, &head, &[], span)?;
+            write_expr(sexp, arena, "condition", condition)?;
+            write_block(sexp, arena, "body", body)?;
+        },
+        StmtKind::Repeat { ref body, until } => {
+            sexp.open(field, &head, &[], span)?;
+            write_block(sexp, arena, "body", body)?;
+            if let Some(until) = until {
+                write_expr(sexp, arena, "until", until)?;
+            }
+        },
+        StmtKind::Select { value, ref cases, ref default } => {
+            sexp.open(field, &head, &[], span)?;
+            write_expr(sexp, arena, "value", value)?;
+            for case in cases {
+                sexp.open("", "Case", &[], None)?;
+                for value in &case.values {
+                    match *value {
+                        CaseValue::Value(value) => write_expr(sexp, arena, "value", value)?,
+                        CaseValue::Range(from, to) => {
+                            sexp.open("value", "Range", &[], None)?;
+                            write_expr(sexp, arena, "from", from)?;
+                            write_expr(sexp, arena, "to", to)?;
+                            sexp.close()?;
+                        }
+                    }
+                }
+                write_block(sexp, arena, "body", &case.body)?;
+                sexp.close()?;
+            }
+            if let Some(ref default) = *default {
+                sexp.open("", "Default", &[], None)?;
+                write_block(sexp, arena, "body", default)?;
+                sexp.close()?;
+            }
+        },
+        StmtKind::Dim(ref arrays) | StmtKind::ReDim(ref arrays) => {
+            sexp.open(field, &head, &[], span)?;
+            for array in arrays {
+                sexp.open("", "Array", &[quote_variable(&array.variable)], None)?;
+                write_exprs(sexp, arena, "", &array.dimensions)?;
+                sexp.close()?;
+            }
+        },
+        StmtKind::Function(ref function) => {
+            sexp.open(field, &head, &[quote_variable(&function.name)], span)?;
+            for parameter in &function.parameters {
+                sexp.leaf("parameter", "Variable", &[quote_variable(parameter)], None)?;
+            }
+            write_block(sexp, arena, "body", &function.body)?;
+        },
+        StmtKind::Type(ref declaration) => {
+            sexp.open(field, &head, &[quote_name(&declaration.name)], span)?;
+            for type_field in &declaration.fields {
+                sexp.leaf("field", "Variable", &[quote_variable(type_field)], None)?;
+            }
+        },
+        StmtKind::Include(ref file) => sexp.open(field, &head, &[quote(file.as_bytes())], span)?,
+        StmtKind::Data(ref values) => {
+            sexp.open(field, &head, &[], span)?;
+            write_exprs(sexp, arena, "", values)?;
+        },
+        StmtKind::Read(ref variables) => {
+            sexp.open(field, &head, &variables.iter().map(quote_variable).collect::<Vec<_>>(),
+                      span)?;
+        },
+        StmtKind::Restore(ref label) => {
+            sexp.open(field, &head, &label.iter().map(quote_name).collect::<Vec<_>>(), span)?;
+        },
+        StmtKind::Declare { scope, ref variables } => {
+            let mut atoms = vec![format!("{:?}", scope)];
+            atoms.extend(variables.iter().map(quote_variable));
+            sexp.open(field, &head, &atoms, span)?;
+        },
+        StmtKind::Const { ref name, value } => {
+            sexp.open(field, &head, &[quote_variable(name)], span)?;
+            write_expr(sexp, arena, "", value)?;
+        },
+        StmtKind::Label(ref name) | StmtKind::Goto(ref name) | StmtKind::Gosub(ref name) => {
+            sexp.open(field, &head, &[quote_name(name)], span)?;
+        },
+        StmtKind::Return(value) => {
+            sexp.open(field, &head, &[], span)?;
+            if let Some(value) = value {
+                write_expr(sexp, arena, "", value)?;
+            }
+        },
+        StmtKind::Exit(kind) => sexp.open(field, &head, &[format!("{:?}", kind)], span)?,
+        StmtKind::End => sexp.open(field, &head, &[], span)?
+    }
+
+    sexp.close()
+}
+
+fn write_expr<W: Write>(sexp: &mut Sexp<W>, arena: &Arena, field: &str, expr: ExprId)
+                        -> io::Result<()> {
+    let span = Some(arena[expr].span);
+
+    match arena[expr].kind {
+        ExprKind::Integer(value) => sexp.open(field, "Integer", &[value.to_string()], span)?,
+        ExprKind::Float(value) => sexp.open(field, "Float", &[format!("{:?}", value)], span)?,
+        ExprKind::String(ref value) => {
+            sexp.open(field, "String", &[quote(value.as_bytes())], span)?;
+        },
+        ExprKind::Variable(ref variable) => {
+            sexp.open(field, "Variable", &[quote_variable(variable)], span)?;
+        },
+        ExprKind::Member { object, field: ref member } => {
+            sexp.open(field, "Member", &[quote_variable(member)], span)?;
+            write_expr(sexp, arena, "", object)?;
+        },
+        ExprKind::Binary { op, left, right } => {
+            sexp.open(field, "Binary", &[format!("{:?}", op)], span)?;
+            write_expr(sexp, arena, "left", left)?;
+            write_expr(sexp, arena, "right", right)?;
+        },
+        ExprKind::Unary { op, operand } => {
+            sexp.open(field, "Unary", &[format!("{:?}", op)], span)?;
+            write_expr(sexp, arena, "", operand)?;
+        },
+        ExprKind::Call { ref name, ref arguments } => {
+            sexp.open(field, "Call", &[quote_variable(name)], span)?;
+            write_exprs(sexp, arena, "", arguments)?;
+        },
+        ExprKind::Index { ref array, ref indices } => {
+            sexp.open(field, "Index", &[quote_variable(array)], span)?;
+            write_exprs(sexp, arena, "", indices)?;
+        }
+    }
+
+    sexp.close()
+}
+
+fn write_cst_node<W: Write>(sexp: &mut Sexp<W>, node: &cst::Node) -> io::Result<()> {
+    sexp.open("", &format!("{:?}", node.kind), &[], node.span())?;
+    for child in &node.children {
+        match *child {
+            Element::Node(ref child) => write_cst_node(sexp, child)?,
+            Element::Token(ref token) => {
+                let head = match token.kind {
+                    TokenKind::Keyword(_) => "Keyword".to_string(),
+                    kind => format!("{:?}", kind)
+                };
+                let text = token.trivia.as_ref().map(|trivia| quote(&trivia.text));
+                sexp.leaf("", &head, &text.into_iter().collect::<Vec<_>>(), Some(token.span))?;
+            }
+        }
+    }
+    sexp.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizer::TokenIterator;
+    use parser::{parse_str, parse_str_cst};
+
+    fn program_sexp(source: &str) -> String {
+        let mut out = Vec::new();
+        write_program_sexp(&mut out, &parse_str(source).unwrap()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn program_is_nested_with_fields() {
+        assert_eq!(program_sexp("x = 1 + 2\n"), "\
+(Program
+  (Assign 1:1
+    target: (Variable \"x\" 1:1)
+    value: (Binary Add 1:5
+      left: (Integer 1 1:5)
+      right: (Integer 2 1:9))))
+");
+    }
+
+    #[test]
+    fn blocks_are_named_by_what_they_are_to_the_statement() {
+        let source = "top:\nIf a < 1 Then\n    Print \"one\";\nElseIf a Then\n    End\n\
+                      Else\n    Goto top\nEndIf\n";
+        assert_eq!(program_sexp(source), "\
+(Program
+  (Label \"top\" 1:1)
+  (If 2:1
+    condition: (Binary Less 2:4
+      left: (Variable \"a\" 2:4)
+      right: (Integer 1 2:8))
+    then: (Print 3:5
+      (String \"one\" 3:11)
+      (Semicolon))
+    (ElseIf
+      condition: (Variable \"a\" 4:8)
+      then: (End 5:5))
+    else: (Goto \"top\" 7:5)))
+");
+    }
+
+    #[test]
+    fn empty_program() {
+        assert_eq!(program_sexp(""), "(Program)\n");
+    }
+
+    #[test]
+    fn cst_has_the_tokens_as_written() {
+        let (_, cst, errors) = parse_str_cst("X  =  1 ' one\n");
+        assert!(errors.is_empty());
+        let mut out = Vec::new();
+        write_cst_sexp(&mut out, &cst).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "\
+(Program 1:1
+  (Assign 1:1
+    (Variable 1:1
+      (Text \"X\" 1:1))
+    (Equals \"=\" 1:4)
+    (Literal 1:7
+      (Integer \"1\" 1:7)))
+  (EOL \"\\n\" 1:14))
+");
+    }
+
+    fn token_dump<F>(source: &[u8], write: F) -> String
+        where F: Fn(&mut Vec<u8>, Vec<LexResult>, &Interner) -> io::Result<()>
+    {
+        let mut tokens = TokenIterator::new(source);
+        let lexed = tokens.by_ref().collect();
+        let mut out = Vec::new();
+        write(&mut out, lexed, &tokens.interner()).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_has_a_line_per_token() {
+        let json = token_dump(b"Foo# = \"a\tb\\\" @\nPrint 2.5", |out, tokens, interner| {
+            write_json(out, tokens, interner)
+        });
+        let lines: Vec<_> = json.lines().collect();
+        assert_eq!(lines, vec![
+            "{\"kind\":\"FloatIdent\",\"line\":1,\"column\":1,\"offset\":0,\"length\":4,\
+             \"name\":\"foo\",\"spelling\":\"Foo\"}",
+            "{\"kind\":\"Equals\",\"line\":1,\"column\":6,\"offset\":5,\"length\":1}",
+            "{\"kind\":\"String\",\"line\":1,\"column\":8,\"offset\":7,\"length\":6,\
+             \"value\":\"a\\tb\\\\\"}",
+            "{\"error\":\"Invalid or unhandled byte 64 encountered\",\
+             \"line\":1,\"column\":15,\"offset\":14,\"length\":1}",
+            "{\"kind\":\"EOL\",\"line\":1,\"column\":16,\"offset\":15,\"length\":1}",
+            "{\"kind\":\"Keyword\",\"keyword\":\"Print\",\"line\":2,\"column\":1,\"offset\":16,\
+             \"length\":5}",
+            "{\"kind\":\"Number\",\"line\":2,\"column\":7,\"offset\":22,\"length\":3,\"value\":2.5}"
+        ]);
+    }
+
+    #[test]
+    fn token_sexp_has_a_line_per_token() {
+        let sexp = token_dump(b"Function Foo$(\"x\", 1) @", |out, tokens, interner| {
+            write_sexp(out, tokens, interner)
+        });
+        assert_eq!(sexp, "\
+(Keyword Function 1:1)
+(StringIdent \"Foo\" 1:10)
+(LParen 1:14)
+(String \"x\" 1:15)
+(Comma 1:18)
+(Integer 1 1:20)
+(RParen 1:21)
+(error \"Invalid or unhandled byte 64 encountered\" 1:23)
+");
+    }
+}
@@ -0,0 +1,272 @@
+use std::mem;
+
+use ast::{Arena, ArrayDecl, Case, CaseValue, ElseIf, ExprId, ExprKind, Function, Name, PrintItem,
+          Program, StmtId, StmtKind, TypeDecl, Variable};
+
+// Rebuilds a tree in its arena, node by node
+// Each method takes its node and returns what goes in its place, by
+// default the same node with its children folded, which keeps its id and
+// span. A node can also be changed where it is, or replaced with a new one
+// from the arena, which leaves the old one with nothing pointing to it
+// While a node's children are being folded it's an End or a 0 in the
+// arena, the walk_ functions put it back after
+//
+//   struct Double;
+//
+//   impl Fold for Double {
+//       fn fold_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+//           let expr = walk_expr(self, arena, expr);
+//           if let ExprKind::Integer(ref mut value) = arena[expr].kind {
+//               *value *= 2;
+//           }
+//           expr
+//       }
+//   }
+pub trait Fold {
+    fn fold_program(&mut self, program: Program) -> Program {
+        walk_program(self, program)
+    }
+
+    // Statements can be added or taken out here
+    fn fold_block(&mut self, arena: &mut Arena, statements: Vec<StmtId>) -> Vec<StmtId> {
+        walk_block(self, arena, statements)
+    }
+
+    fn fold_stmt(&mut self, arena: &mut Arena, stmt: StmtId) -> StmtId {
+        walk_stmt(self, arena, stmt)
+    }
+
+    fn fold_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+        walk_expr(self, arena, expr)
+    }
+
+    fn fold_function(&mut self, arena: &mut Arena, function: Function) -> Function {
+        walk_function(self, arena, function)
+    }
+
+    fn fold_type(&mut self, declared: TypeDecl) -> TypeDecl {
+        declared
+    }
+
+    // Every variable that's used or declared, not the fields after a \
+    fn fold_variable(&mut self, variable: Variable) -> Variable {
+        variable
+    }
+
+    fn fold_label(&mut self, label: Name) -> Name {
+        label
+    }
+}
+
+pub fn walk_program<F: Fold + ?Sized>(folder: &mut F, mut program: Program) -> Program {
+    let statements = mem::take(&mut program.statements);
+    program.statements = folder.fold_block(&mut program.arena, statements);
+    program
+}
+
+pub fn walk_block<F: Fold + ?Sized>(folder: &mut F, arena: &mut Arena, statements: Vec<StmtId>)
+                                    -> Vec<StmtId> {
+    statements.into_iter().map(|stmt| folder.fold_stmt(arena, stmt)).collect()
+}
+
+fn walk_exprs<F: Fold + ?Sized>(folder: &mut F, arena: &mut Arena, exprs: Vec<ExprId>) -> Vec<ExprId> {
+    exprs.into_iter().map(|expr| folder.fold_expr(arena, expr)).collect()
+}
+
+fn walk_variables<F: Fold + ?Sized>(folder: &mut F, variables: Vec<Variable>) -> Vec<Variable> {
+    variables.into_iter().map(|variable| folder.fold_variable(variable)).collect()
+}
+
+fn walk_arrays<F: Fold + ?Sized>(folder: &mut F, arena: &mut Arena, arrays: Vec<ArrayDecl>)
+                                 -> Vec<ArrayDecl> {
+    arrays.into_iter().map(|array| ArrayDecl {
+        variable: folder.fold_variable(array.variable),
+        dimensions: walk_exprs(folder, arena, array.dimensions)
+    }).collect()
+}
+
+pub fn walk_stmt<F: Fold + ?Sized>(folder: &mut F, arena: &mut Arena, stmt: StmtId) -> StmtId {
+    let kind = match mem::replace(&mut arena[stmt].kind, StmtKind::End) {
+        StmtKind::Assign { target, value } => StmtKind::Assign {
+            target: folder.fold_expr(arena, target),
+            value: folder.fold_expr(arena, value)
+        },
+        StmtKind::Call { name, arguments } => StmtKind::Call {
+            name,
+            arguments: walk_exprs(folder, arena, arguments)
+        },
+        StmtKind::Print(items) => StmtKind::Print(items.into_iter().map(|item| match item {
+            PrintItem::Value(value) => PrintItem::Value(folder.fold_expr(arena, value)),
+            separator => separator
+        }).collect()),
+        StmtKind::If { condition, then_branch, else_ifs, else_branch, single_line } => StmtKind::If {
+            condition: folder.fold_expr(arena, condition),
+            then_branch: folder.fold_block(arena, then_branch),
+            else_ifs: else_ifs.into_iter().map(|else_if| ElseIf {
+                condition: folder.fold_expr(arena, else_if.condition),
+                body: folder.fold_block(arena, else_if.body)
+            }).collect(),
+            else_branch: else_branch.map(|branch| folder.fold_block(arena, branch)),
+            single_line
+        },
+        StmtKind::For { variable, from, to, step, body } => StmtKind::For {
+            variable: folder.fold_variable(variable),
+            from: folder.fold_expr(arena, from),
+            to: folder.fold_expr(arena, to),
+            step: step.map(|step| folder.fold_expr(arena, step)),
+            body: folder.fold_block(arena, body)
+        },
+        StmtKind::While { condition, body } => StmtKind::While {
+            condition: folder.fold_expr(arena, condition),
+            body: folder.fold_block(arena, body)
+        },
+        StmtKind::Repeat { body, until } => StmtKind::Repeat {
+            body: folder.fold_block(arena, body),
+            until: until.map(|until| folder.fold_expr(arena, until))
+        },
+        StmtKind::Select { value, cases, default } => StmtKind::Select {
+            value: folder.fold_expr(arena, value),
+            cases: cases.into_iter().map(|case| Case {
+                values: case.values.into_iter().map(|value| match value {
+                    CaseValue::Value(value) => CaseValue::Value(folder.fold_expr(arena, value)),
+                    CaseValue::Range(from, to) => {
+                        let from = folder.fold_expr(arena, from);
+                        CaseValue::Range(from, folder.fold_expr(arena, to))
+                    }
+                }).collect(),
+                body: folder.fold_block(arena, case.body)
+            }).collect(),
+            default: default.map(|default| folder.fold_block(arena, default))
+        },
+        StmtKind::Dim(arrays) => StmtKind::Dim(walk_arrays(folder, arena, arrays)),
+        StmtKind::ReDim(arrays) => StmtKind::ReDim(walk_arrays(folder, arena, arrays)),
+        StmtKind::Function(function) => StmtKind::Function(folder.fold_function(arena, function)),
+        StmtKind::Type(declared) => StmtKind::Type(folder.fold_type(declared)),
+        StmtKind::Data(values) => StmtKind::Data(walk_exprs(folder, arena, values)),
+        StmtKind::Read(variables) => StmtKind::Read(walk_variables(folder, variables)),
+        StmtKind::Restore(label) => StmtKind::Restore(label.map(|label| folder.fold_label(label))),
+        StmtKind::Declare { scope, variables } => StmtKind::Declare {
+            scope,
+            variables: walk_variables(folder, variables)
+        },
+        StmtKind::Const { name, value } => StmtKind::Const {
+            name: folder.fold_variable(name),
+            value: folder.fold_expr(arena, value)
+        },
+        StmtKind::Label(label) => StmtKind::Label(folder.fold_label(label)),
+        StmtKind::Goto(label) => StmtKind::Goto(folder.fold_label(label)),
+        StmtKind::Gosub(label) => StmtKind::Gosub(folder.fold_label(label)),
+        StmtKind::Return(value) => StmtKind::Return(value.map(|value| folder.fold_expr(arena, value))),
+        kind @ StmtKind::Include(_) | kind @ StmtKind::Exit(_) | kind @ StmtKind::End => kind
+    };
+    arena[stmt].kind = kind;
+    stmt
+}
+
+pub fn walk_expr<F: Fold + ?Sized>(folder: &mut F, arena: &mut Arena, expr: ExprId) -> ExprId {
+    let kind = match mem::replace(&mut arena[expr].kind, ExprKind::Integer(0)) {
+        ExprKind::Variable(variable) => ExprKind::Variable(folder.fold_variable(variable)),
+        ExprKind::Member { object, field } => ExprKind::Member {
+            object: folder.fold_expr(arena, object),
+            field
+        },
+        ExprKind::Binary { op, left, right } => {
+            let left = folder.fold_expr(arena, left);
+            ExprKind::Binary { op, left, right: folder.fold_expr(arena, right) }
+        },
+        ExprKind::Unary { op, operand } => ExprKind::Unary {
+            op,
+            operand: folder.fold_expr(arena, operand)
+        },
+        ExprKind::Call { name, arguments } => ExprKind::Call {
+            name,
+            arguments: walk_exprs(folder, arena, arguments)
+        },
+        ExprKind::Index { array, indices } => ExprKind::Index {
+            array: folder.fold_variable(array),
+            indices: walk_exprs(folder, arena, indices)
+        },
+        literal => literal
+    };
+    arena[expr].kind = kind;
+    expr
+}
+
+pub fn walk_function<F: Fold + ?Sized>(folder: &mut F, arena: &mut Arena, function: Function)
+                                       -> Function {
+    Function {
+        name: function.name,
+        parameters: walk_variables(folder, function.parameters),
+        body: folder.fold_block(arena, function.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::Sigil;
+    use parser::parse_str;
+    use pretty::to_source;
+
+    struct Double;
+
+    impl Fold for Double {
+        fn fold_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+            let expr = walk_expr(self, arena, expr);
+            if let ExprKind::Integer(ref mut value) = arena[expr].kind {
+                *value *= 2;
+            }
+            expr
+        }
+    }
+
+    #[test]
+    fn changed_in_place() {
+        let program = parse_str("x = 1 + a(2)\nFor i = 1 To 3\nNext").unwrap();
+        let statements = program.statements.clone();
+        let program = Double.fold_program(program);
+        assert_eq!(program.statements, statements);
+        assert_eq!(to_source(&program).unwrap(), "x = 2 + a(4)\nFor i = 2 To 6\nNext i\n");
+    }
+
+    // Takes out every Print, and puts a variable for every Rnd call
+    struct Replace;
+
+    impl Fold for Replace {
+        fn fold_block(&mut self, arena: &mut Arena, statements: Vec<StmtId>) -> Vec<StmtId> {
+            let mut kept = Vec::new();
+            for stmt in statements {
+                if let StmtKind::Print(_) = arena[stmt].kind {
+                    continue;
+                }
+                kept.push(stmt);
+            }
+            walk_block(self, arena, kept)
+        }
+
+        fn fold_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+            let expr = walk_expr(self, arena, expr);
+            match arena[expr].kind {
+                ExprKind::Call { ref name, .. } if name.name.name == "rnd" => {},
+                _ => return expr
+            }
+
+            let span = arena[expr].span;
+            let variable = Variable {
+                name: Name { name: "random".to_string(), spelling: "random".to_string() },
+                sigil: Sigil::None
+            };
+            arena.add_expr(ExprKind::Variable(variable), span)
+        }
+    }
+
+    #[test]
+    fn replaced_with_new_nodes() {
+        let program = parse_str("Repeat\nPrint 1\nx = Rnd(10) + 1\nUntil Rnd(2)").unwrap();
+        let count = program.arena.expr_count();
+        let program = Replace.fold_program(program);
+        assert_eq!(to_source(&program).unwrap(), "Repeat\n    x = random + 1\nUntil random\n");
+        // The old calls are still there, just with nothing pointing to them
+        assert_eq!(program.arena.expr_count(), count + 2);
+    }
+}
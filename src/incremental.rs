@@ -0,0 +1,195 @@
+use std::ops::Range;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use interner::Interner;
+use stream::LexResult;
+use tokenizer::{position_after, LexerConfig, Span, TokenIterator, TokenKind};
+
+// A source and its tokens, kept up to date through edits
+// Only the lines around an edit are lexed again, for editors and such
+pub struct Document {
+    source: Vec<u8>,
+    tokens: Vec<LexResult>,
+    config: LexerConfig,
+    interner: Interner
+}
+
+fn span_of(result: &LexResult) -> Span {
+    match *result {
+        Ok(ref token) => token.span,
+        Err(ref e) => e.span()
+    }
+}
+
+fn span_mut(result: &mut LexResult) -> &mut Span {
+    match *result {
+        Ok(ref mut token) => &mut token.span,
+        Err(ref mut e) => e.span_mut()
+    }
+}
+
+fn is_eol(result: &LexResult) -> bool {
+    match *result {
+        Ok(ref token) => token.kind == TokenKind::EOL,
+        Err(_) => false
+    }
+}
+
+impl Document {
+    pub fn new(source: Vec<u8>, config: LexerConfig) -> Document {
+        let interner = Interner::new();
+        let tokens = TokenIterator::new_with_config(&source[..], config.clone())
+                     .with_interner(interner.clone())
+                     .collect();
+
+        Document {
+            source,
+            tokens,
+            config,
+            interner
+        }
+    }
+
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    pub fn tokens(&self) -> &[LexResult] {
+        &self.tokens
+    }
+
+    pub fn interner(&self) -> Interner {
+        self.interner.clone()
+    }
+
+    // Replaces deleted bytes at offset with the inserted ones
+    // Gives back which tokens are new, everything else was there before
+    pub fn edit(&mut self, offset: usize, deleted: usize, inserted: &[u8]) -> Range<usize> {
+        self.source.splice(offset..offset + deleted, inserted.iter().cloned());
+
+        // Right after an EOL the lexer has no state, so start from the last
+        // one before the edit
+        // It has to end strictly before, a CR could turn into a CRLF
+        let mut keep = match self.tokens.binary_search_by(|t| {
+            span_of(t).byte_offset.cmp(&offset)
+        }) {
+            Ok(index) | Err(index) => index
+        };
+        while keep > 0 {
+            let span = span_of(&self.tokens[keep - 1]);
+            if is_eol(&self.tokens[keep - 1]) && span.byte_offset + span.length < offset {
+                break;
+            }
+            keep -= 1;
+        }
+
+        let (start, line, column) = if keep > 0 {
+            let span = span_of(&self.tokens[keep - 1]);
+            let (line, column) = position_after(&self.source, 0, span, &self.config);
+            (span.byte_offset + span.length, line, column)
+        }
+        else {
+            (0, 1, 1)
+        };
+
+        let tokens = TokenIterator::new_with_config(&self.source[start..], self.config.clone())
+                     .with_interner(self.interner.clone())
+                     .continue_from(start, line, column);
+
+        // Once an EOL past the edit lines up with an old one, the rest of the
+        // old tokens are still good, just moved
+        let delta = inserted.len() as isize - deleted as isize;
+        let edited_end = offset + inserted.len();
+        let mut old = keep;
+        let mut fresh = Vec::new();
+        let mut rest = None;
+
+        for result in tokens {
+            let span = span_of(&result);
+            let resync = is_eol(&result) && span.byte_offset >= edited_end;
+            fresh.push(result);
+
+            if !resync {
+                continue;
+            }
+
+            let target = (span.byte_offset as isize - delta) as usize;
+            while old < self.tokens.len() && span_of(&self.tokens[old]).byte_offset < target {
+                old += 1;
+            }
+
+            if old < self.tokens.len() && is_eol(&self.tokens[old]) {
+                let old_span = span_of(&self.tokens[old]);
+                if old_span.byte_offset == target && old_span.length == span.length {
+                    rest = Some((old + 1, span.line as isize - old_span.line as isize));
+                    break;
+                }
+            }
+        }
+
+        let end = match rest {
+            Some((from, line_delta)) => {
+                for token in &mut self.tokens[from..] {
+                    let span = span_mut(token);
+                    span.byte_offset = (span.byte_offset as isize + delta) as usize;
+                    span.line = (span.line as isize + line_delta) as usize;
+                }
+                from
+            },
+            None => self.tokens.len()
+        };
+
+        let count = fresh.len();
+        self.tokens.splice(keep..end, fresh);
+        keep..keep + count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+
+    // What lexing the whole source again gives
+    fn fresh(document: &Document) -> Vec<LexResult> {
+        TokenIterator::new_with_config(document.source(), LexerConfig::new())
+            .with_interner(document.interner())
+            .collect()
+    }
+
+    const SOURCE: &str = "x = 1\ny = \"two\"\n\nIf x Then\n  Print y\nEndIf\n";
+
+    #[test]
+    fn edits_lex_the_same_as_starting_over() {
+        let edits: &[(usize, usize, &[u8])] = &[
+            (4, 1, b"100"),
+            (0, 0, b"first\n"),
+            (12, 0, b"\r"),
+            (13, 0, b"\n"),
+            (20, 5, b""),
+            (0, 3, b"/* x = "),
+            (8, 0, b"*/"),
+        ];
+
+        let mut document = Document::new(SOURCE.as_bytes().to_vec(), LexerConfig::new());
+        for &(offset, deleted, inserted) in edits {
+            document.edit(offset, deleted, inserted);
+            assert_eq!(document.tokens(), &fresh(&document)[..],
+                       "{}", String::from_utf8_lossy(document.source()));
+        }
+    }
+
+    #[test]
+    fn only_the_edited_line_is_new() {
+        let mut document = Document::new(SOURCE.as_bytes().to_vec(), LexerConfig::new());
+        let before = document.tokens().to_vec();
+        let changed = document.edit(11, 3, b"three");
+        // y = "three" and its EOL
+        assert_eq!(changed, 4..8);
+        assert_eq!(&document.tokens()[..4], &before[..4]);
+        assert_eq!(document.tokens()[9].as_ref().unwrap().span.byte_offset, 19);
+        assert_eq!(document.tokens(), &fresh(&document)[..]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::cell::RefCell;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::rc::Rc;
+
+// No hashing without std, a BTreeMap does the same job
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::rc::Rc;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+// Cheap stand-in for a name, resolved through the Interner that made it
+// Serialized as just the number, Token::named has the names instead
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Symbol(u32);
+
+impl Symbol {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+struct Table {
+    names: Vec<Vec<u8>>,
+    symbols: HashMap<Vec<u8>, Symbol>
+}
+
+// Every distinct name is stored once
+// Clones share the same table, so the tokenizer can keep one while the
+// parser resolves names through another
+#[derive(Clone)]
+pub struct Interner {
+    table: Rc<RefCell<Table>>
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        Interner {
+            table: Rc::new(RefCell::new(Table {
+                names: Vec::new(),
+                symbols: HashMap::new()
+            }))
+        }
+    }
+
+    // Only allocates the first time a name is seen
+    pub fn intern(&self, name: &[u8]) -> Symbol {
+        let mut table = self.table.borrow_mut();
+        if let Some(&symbol) = table.symbols.get(name) {
+            return symbol;
+        }
+
+        let symbol = Symbol(table.names.len() as u32);
+        table.names.push(name.to_vec());
+        table.symbols.insert(name.to_vec(), symbol);
+        symbol
+    }
+
+    // Symbols from another interner resolve to the wrong name, or panic
+    pub fn resolve(&self, symbol: Symbol) -> Vec<u8> {
+        self.table.borrow().names[symbol.index()].clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.borrow().names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Interner {
+        Interner::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_name_same_symbol() {
+        let interner = Interner::new();
+        let a = interner.intern(b"player");
+        let b = interner.intern(b"enemy");
+        assert_eq!(interner.intern(b"player"), a);
+        assert!(a != b);
+        assert_eq!(interner.len(), 2);
+        assert_eq!(interner.resolve(b), b"enemy");
+    }
+
+    #[test]
+    fn clones_share_the_table() {
+        let interner = Interner::new();
+        let other = interner.clone();
+        let symbol = other.intern(b"x");
+        assert_eq!(interner.resolve(symbol), b"x");
+        assert_eq!(interner.intern(b"x"), symbol);
+        assert!(!interner.is_empty());
+    }
+}
@@ -0,0 +1,60 @@
+// Without std the tokenizer still works over byte slices
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(not(feature = "std"))]
+#[cfg_attr(test, macro_use)]
+extern crate alloc;
+// So that std:: paths mean the same thing either way
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+
+extern crate memchr;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(all(feature = "futures", feature = "std"))]
+extern crate futures;
+#[cfg(all(feature = "mmap", feature = "std"))]
+extern crate memmap2;
+
+pub mod interner;
+pub mod tokenizer;
+pub mod stream;
+pub mod incremental;
+pub mod push;
+#[cfg(all(feature = "futures", feature = "std"))]
+pub mod async_tokenizer;
+#[cfg(feature = "std")]
+pub mod mapped;
+#[cfg(feature = "std")]
+pub mod detokenizer;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod dump;
+#[cfg(feature = "std")]
+pub mod sources;
+#[cfg(feature = "std")]
+pub mod preprocessor;
+#[cfg(feature = "std")]
+pub mod ast;
+#[cfg(feature = "std")]
+pub mod parser;
+#[cfg(feature = "std")]
+pub mod cst;
+#[cfg(feature = "std")]
+pub mod pretty;
+#[cfg(feature = "std")]
+pub mod visit;
+#[cfg(feature = "std")]
+pub mod fold;
+#[cfg(feature = "std")]
+pub mod passes;
+#[cfg(feature = "std")]
+pub mod parents;
+#[cfg(feature = "std")]
+pub mod semant;
+#[cfg(feature = "std")]
+pub mod generator;
@@ -1,26 +1,38 @@
 extern crate rustc_serialize;
 extern crate docopt;
+extern crate cblia;
 
 
-mod tokenizer;
-mod parser;
-mod generator;
-
-
+use std::fs;
 use std::fs::File;
+use std::io;
 use std::io::Read;
 use docopt::Docopt;
+use cblia::tokenizer;
+use cblia::parser;
+use cblia::generator;
+use cblia::diagnostics;
+use cblia::dump;
 
 
 // Usage string
 static USAGE: &'static str = "
-Usage: cbLIA <source>
+Usage: cbLIA [--encoding=<name>] [--dump=<format>] <source>
+
+Options:
+    --encoding=<name>  utf-8, latin-1 or windows-1252 [default: utf-8]
+    --dump=<format>    Print the tokens instead of generating bindings,
+                       json gives one JSON object per line and sexp one
+                       S-expression per line, ast prints the syntax tree
+                       and cst the tree with every token in it
 ";
 
 // Args struct for usage string
 #[derive(RustcDecodable)]
 struct Args {
-    arg_source: String
+    arg_source: String,
+    flag_encoding: String,
+    flag_dump: Option<String>
 }
 
 
@@ -53,9 +65,58 @@ fn main() {
                             (&input_filename).to_string()
                         };
     
-    let f = File::open(input_filename.to_owned()).unwrap();
+    let encoding = match &args.flag_encoding.to_lowercase()[..] {
+        "utf-8" | "utf8" => tokenizer::Encoding::Utf8,
+        "latin-1" | "latin1" | "iso-8859-1" => tokenizer::Encoding::Latin1,
+        "windows-1252" | "cp1252" => tokenizer::Encoding::Windows1252,
+        other => {
+            println!("Unknown encoding: {}", other);
+            return;
+        }
+    };
+
+    let config = tokenizer::LexerConfig::new().encoding(encoding);
+
+    let source = fs::read(&input_filename).unwrap();
+
+    if let Some(format) = args.flag_dump {
+        let format = format.to_lowercase();
+        let config = if format == "cst" { config.lossless(true) } else { config };
+        let tokens = tokenizer::TokenIterator::new_with_config(&source[..], config);
+        let interner = tokens.interner();
+        let stdout = io::stdout();
+
+        match &format[..] {
+            "json" => dump::write_json(&mut stdout.lock(), tokens, &interner).unwrap(),
+            "sexp" => dump::write_sexp(&mut stdout.lock(), tokens, &interner).unwrap(),
+            "ast" => match parser::parse_program(tokens) {
+                Ok(program) => dump::write_program_sexp(&mut stdout.lock(), &program).unwrap(),
+                Err(e) => eprintln!("{}", diagnostics::render_parse(&e, &source, &input_filename))
+            },
+            "cst" => {
+                let (_, cst, errors) = parser::parse_program_cst(tokens);
+                for e in &errors {
+                    eprintln!("{}", diagnostics::render_parse(e, &source, &input_filename));
+                }
+                dump::write_cst_sexp(&mut stdout.lock(), &cst).unwrap();
+            },
+            other => println!("Unknown dump format: {}", other)
+        }
+        return;
+    }
+
+    // Lexing errors get a proper look at the source before anything else
+    let errors = tokenizer::TokenIterator::new_with_config(&source[..], config.clone())
+                 .filter_map(|t| t.err())
+                 .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        for e in &errors {
+            eprintln!("{}", diagnostics::render(e, &source, &input_filename));
+        }
+        return;
+    }
 
-    let tokens = tokenizer::TokenIterator::new(f);
+    let tokens = tokenizer::TokenIterator::new_with_config(&source[..], config);
 
     let ast = match parser::parse(tokens) {
         Ok(node) => node,
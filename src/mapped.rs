@@ -0,0 +1,142 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+use tokenizer::{LexerConfig, ReadError, Source, TokenIterator};
+
+enum Backing {
+    #[cfg(feature = "mmap")]
+    Mapped(Mmap),
+    Buffered(File)
+}
+
+// A source file, memory mapped if the mmap feature is on and mapping
+// works, read through a buffer otherwise
+// A mapped file is lexed right over the map, nothing is copied out of it
+// Mapping can fail for pipes and such, those are just read
+pub struct MappedFile {
+    backing: Backing,
+    // How far into the map reading has got
+    #[cfg(feature = "mmap")]
+    position: usize
+}
+
+impl MappedFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<MappedFile> {
+        let file = File::open(path)?;
+        Ok(MappedFile {
+            backing: MappedFile::map(file),
+            #[cfg(feature = "mmap")]
+            position: 0
+        })
+    }
+
+    // Whether the bytes come straight from memory
+    pub fn is_mapped(&self) -> bool {
+        match self.backing {
+            #[cfg(feature = "mmap")]
+            Backing::Mapped(_) => true,
+            Backing::Buffered(_) => false
+        }
+    }
+
+    // The map is only good as long as nobody changes the file underneath
+    // it, which is the usual deal with mapping source files
+    #[cfg(feature = "mmap")]
+    fn map(file: File) -> Backing {
+        match unsafe { Mmap::map(&file) } {
+            Ok(map) => Backing::Mapped(map),
+            Err(_) => Backing::Buffered(file)
+        }
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn map(file: File) -> Backing {
+        Backing::Buffered(file)
+    }
+}
+
+impl Source for MappedFile {
+    // Only for reading it some other way than with a TokenIterator
+    fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        match self.backing {
+            #[cfg(feature = "mmap")]
+            Backing::Mapped(ref map) => {
+                let rest = &map[self.position.min(map.len())..];
+                let length = buf.len().min(rest.len());
+                buf[..length].copy_from_slice(&rest[..length]);
+                self.position += length;
+                Ok(length)
+            },
+            Backing::Buffered(ref mut file) => file.read_into(buf)
+        }
+    }
+
+    fn contents(&self) -> Option<&[u8]> {
+        match self.backing {
+            #[cfg(feature = "mmap")]
+            Backing::Mapped(ref map) => Some(&map[..]),
+            Backing::Buffered(_) => None
+        }
+    }
+}
+
+impl TokenIterator<MappedFile> {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<TokenIterator<MappedFile>> {
+        TokenIterator::open_with_config(path, LexerConfig::default())
+    }
+
+    pub fn open_with_config<P: AsRef<Path>>(path: P, config: LexerConfig)
+                                            -> io::Result<TokenIterator<MappedFile>> {
+        Ok(TokenIterator::new_with_config(MappedFile::open(path)?, config))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    use tokenizer::TokenKind;
+
+    fn write_temp(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("cblia-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn files_lex_like_their_bytes() {
+        // Longer than a chunk so the reading goes round more than once
+        let source = "x = \"some text\" + 1\n".repeat(1000);
+        let path = write_temp("mapped.cb", source.as_bytes());
+
+        let from_file: Vec<_> = TokenIterator::open(&path).unwrap().map(Result::unwrap).collect();
+        let from_bytes: Vec<_> = TokenIterator::new(source.as_bytes()).map(Result::unwrap).collect();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(from_file.len(), 6000);
+        assert_eq!(from_file[5999].kind, TokenKind::EOL);
+        assert!(from_file == from_bytes);
+    }
+
+    #[test]
+    fn mapped_only_with_the_feature() {
+        let path = write_temp("feature.cb", b"End");
+        let file = MappedFile::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(file.is_mapped(), cfg!(feature = "mmap"));
+    }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let path = env::temp_dir().join("cblia-there-is-no-such-file.cb");
+        assert!(TokenIterator::open(path).is_err());
+    }
+}
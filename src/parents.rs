@@ -0,0 +1,196 @@
+// Where every node of a program is in its tree, for going up from a node
+// or across to the ones next to it, like an editor growing a selection
+// out from the cursor or a lint that looks at the statement before
+
+use ast::{Arena, ExprId, NodeId, Program, StmtId};
+use visit::{walk_block, walk_expr, walk_stmt, Visitor};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Links {
+    parent: Option<NodeId>,
+    previous: Option<NodeId>,
+    next: Option<NodeId>
+}
+
+// Built once for a program, by the node ids. It doesn't follow the
+// program after, a node added later has no parent or siblings in it
+// The siblings of a statement are the others in its block, and those of
+// an expression the other expressions right in the same parent, in the
+// order they're in the source: the left and right of a Binary, the
+// arguments of a Call, or the from, to and step of a For
+#[derive(Debug, Clone, Default)]
+pub struct Parents {
+    stmts: Vec<Links>,
+    exprs: Vec<Links>
+}
+
+impl Parents {
+    pub fn new(program: &Program) -> Parents {
+        let mut builder = Builder {
+            parents: Parents {
+                stmts: vec![Links::default(); program.arena.stmt_count()],
+                exprs: vec![Links::default(); program.arena.expr_count()]
+            },
+            open: Vec::new()
+        };
+        builder.visit_program(program);
+        builder.parents
+    }
+
+    fn links(&self, node: NodeId) -> Links {
+        let links = match node {
+            NodeId::Stmt(stmt) => self.stmts.get(stmt.index()),
+            NodeId::Expr(expr) => self.exprs.get(expr.index())
+        };
+        links.cloned().unwrap_or_default()
+    }
+
+    // None for a statement at the top of the program
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        self.links(node).parent
+    }
+
+    pub fn previous_sibling(&self, node: NodeId) -> Option<NodeId> {
+        self.links(node).previous
+    }
+
+    pub fn next_sibling(&self, node: NodeId) -> Option<NodeId> {
+        self.links(node).next
+    }
+
+    // Every node above it, its parent first
+    pub fn ancestors(&self, node: NodeId) -> Vec<NodeId> {
+        let mut ancestors = Vec::new();
+        let mut node = node;
+        while let Some(parent) = self.parent(node) {
+            ancestors.push(parent);
+            node = parent;
+        }
+        ancestors
+    }
+
+    // The statement an expression is in, a statement is its own
+    // None for an expression that isn't in any, like one a pass replaced
+    pub fn statement(&self, node: NodeId) -> Option<StmtId> {
+        let mut node = node;
+        loop {
+            match node {
+                NodeId::Stmt(stmt) => return Some(stmt),
+                NodeId::Expr(_) => node = self.parent(node)?
+            }
+        }
+    }
+}
+
+struct Builder {
+    parents: Parents,
+    // The nodes being walked, innermost last, and the last expression
+    // found right inside each
+    open: Vec<(NodeId, Option<ExprId>)>
+}
+
+impl Visitor for Builder {
+    fn visit_block(&mut self, arena: &Arena, statements: &[StmtId]) {
+        for (i, &stmt) in statements.iter().enumerate() {
+            let links = &mut self.parents.stmts[stmt.index()];
+            if i > 0 {
+                links.previous = Some(NodeId::Stmt(statements[i - 1]));
+            }
+            links.next = statements.get(i + 1).map(|&next| NodeId::Stmt(next));
+        }
+        walk_block(self, arena, statements)
+    }
+
+    fn visit_stmt(&mut self, arena: &Arena, stmt: StmtId) {
+        self.parents.stmts[stmt.index()].parent = self.open.last().map(|&(parent, _)| parent);
+        self.open.push((NodeId::Stmt(stmt), None));
+        walk_stmt(self, arena, stmt);
+        self.open.pop();
+    }
+
+    fn visit_expr(&mut self, arena: &Arena, expr: ExprId) {
+        if let Some(&mut (parent, ref mut last)) = self.open.last_mut() {
+            self.parents.exprs[expr.index()].parent = Some(parent);
+            if let Some(previous) = last.take() {
+                self.parents.exprs[previous.index()].next = Some(NodeId::Expr(expr));
+                self.parents.exprs[expr.index()].previous = Some(NodeId::Expr(previous));
+            }
+            *last = Some(expr);
+        }
+        self.open.push((NodeId::Expr(expr), None));
+        walk_expr(self, arena, expr);
+        self.open.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::{ExprKind, StmtKind};
+    use parser::parse_str;
+
+    fn branches(program: &Program, stmt: StmtId) -> (Vec<StmtId>, Vec<StmtId>) {
+        match program.arena[stmt].kind {
+            StmtKind::If { ref then_branch, ref else_branch, .. } => {
+                (then_branch.clone(), else_branch.clone().unwrap_or_default())
+            },
+            ref other => panic!("{:?} isn't an If", other)
+        }
+    }
+
+    #[test]
+    fn nested_if() {
+        let program = parse_str("If a Then\n    x = 1\n    If b Then\n        y = 2 + 3\n    EndIf\n\
+                                 Else\n    z = 4\nEndIf\n").unwrap();
+        let parents = Parents::new(&program);
+        let outer = program.statements[0];
+        let (then_branch, else_branch) = branches(&program, outer);
+        let (assign_x, inner, assign_z) = (then_branch[0], then_branch[1], else_branch[0]);
+        let assign_y = branches(&program, inner).0[0];
+
+        assert_eq!(parents.parent(NodeId::Stmt(outer)), None);
+        assert_eq!(parents.parent(NodeId::Stmt(assign_x)), Some(NodeId::Stmt(outer)));
+        assert_eq!(parents.parent(NodeId::Stmt(assign_z)), Some(NodeId::Stmt(outer)));
+        assert_eq!(parents.parent(NodeId::Stmt(assign_y)), Some(NodeId::Stmt(inner)));
+        assert_eq!(parents.ancestors(NodeId::Stmt(assign_y)),
+                   vec![NodeId::Stmt(inner), NodeId::Stmt(outer)]);
+
+        // Then and Else are blocks of their own
+        assert_eq!(parents.next_sibling(NodeId::Stmt(assign_x)), Some(NodeId::Stmt(inner)));
+        assert_eq!(parents.previous_sibling(NodeId::Stmt(inner)), Some(NodeId::Stmt(assign_x)));
+        assert_eq!(parents.next_sibling(NodeId::Stmt(inner)), None);
+        assert_eq!(parents.previous_sibling(NodeId::Stmt(assign_z)), None);
+    }
+
+    #[test]
+    fn expressions_next_to_each_other() {
+        let program = parse_str("y = 2 + 3\n").unwrap();
+        let parents = Parents::new(&program);
+        let assign = program.statements[0];
+        let (target, value) = match program.arena[assign].kind {
+            StmtKind::Assign { target, value } => (target, value),
+            ref other => panic!("{:?} isn't an Assign", other)
+        };
+        let (left, right) = match program.arena[value].kind {
+            ExprKind::Binary { left, right, .. } => (left, right),
+            ref other => panic!("{:?} isn't a Binary", other)
+        };
+
+        assert_eq!(parents.parent(NodeId::Expr(left)), Some(NodeId::Expr(value)));
+        assert_eq!(parents.next_sibling(NodeId::Expr(left)), Some(NodeId::Expr(right)));
+        assert_eq!(parents.previous_sibling(NodeId::Expr(right)), Some(NodeId::Expr(left)));
+        assert_eq!(parents.next_sibling(NodeId::Expr(target)), Some(NodeId::Expr(value)));
+        assert_eq!(parents.statement(NodeId::Expr(right)), Some(assign));
+    }
+
+    #[test]
+    fn nodes_added_later_have_no_links() {
+        let mut program = parse_str("x = 1\n").unwrap();
+        let parents = Parents::new(&program);
+        let span = program.arena[program.statements[0]].span;
+        let added = program.arena.add_expr(ExprKind::Integer(2), span);
+
+        assert_eq!(parents.parent(NodeId::Expr(added)), None);
+        assert_eq!(parents.statement(NodeId::Expr(added)), None);
+    }
+}
@@ -1,7 +1,21 @@
-use std::iter::Peekable;
-use std::ascii::AsciiExt;
-
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+use std::path::Path;
+
+use ast::{Arena, ArrayDecl, BinaryOp, Case, CaseValue, Comment, Comments, ElseIf, Expr, ExprId,
+          ExprKind, Function, Loop, Name, PrintItem, Program, Scope, Sigil, StmtId, StmtKind, TypeDecl,
+          UnaryOp, Variable};
+use cst;
+use cst::{Cst, Element, NodeKind};
+use fold::{walk_expr, Fold};
+use interner::Interner;
+use passes::negative_literal;
+use sources::{FileId, SourceManager};
+use stream::TokenStream;
 use tokenizer;
+use tokenizer::{position_after, Identifier, Keyword, LexError, LexerConfig, Payload, Source, Span,
+                Token, TokenIterator, TokenKind};
 
 // Disclaimer: I'm not entirely sure why I need to borrow the iterator
 // as mutable everywhere. Don't judge me. I'll figure out.
@@ -29,20 +43,20 @@ pub enum Node {
     Argument { name: Vec<u8>, datatype: Datatype }
 }
 
-type PeekableTokenIterator = Peekable<tokenizer::TokenIterator>;
+type PeekableTokenIterator<R> = TokenStream<R>;
 pub type WrappedNode = Option<Result<Node, String>>;
 pub type WrappedNodeVec = Option<Result<Vec<Node>, String>>;
 
-fn datatype_sigil(it: &mut PeekableTokenIterator) -> Datatype {
+fn datatype_sigil<R: Source>(it: &mut PeekableTokenIterator<R>) -> Datatype {
     // Never errors, only mutates state if valid
     match it.peek() {
-        Some(&Ok(tokenizer::Token::Dollar)) => {
+        Some(&Ok(Token { kind: tokenizer::TokenKind::Dollar, .. })) => {
             // Consume
             it.next();
             Datatype::String
         },
 
-        Some(&Ok(tokenizer::Token::Hash)) => {
+        Some(&Ok(Token { kind: tokenizer::TokenKind::Hash, .. })) => {
             it.next();
             Datatype::Float
         },
@@ -57,19 +71,21 @@ fn datatype_sigil(it: &mut PeekableTokenIterator) -> Datatype {
     }
 }
 
-fn single_argument(it: &mut PeekableTokenIterator) -> WrappedNode {
+fn single_argument<R: Source>(it: &mut PeekableTokenIterator<R>) -> WrappedNode {
     // We want one Text, 0-1 sigils
     // If peek gives RParen, we're fine
     // See further below for what the true/false do
     if match it.peek() {
         // No more arguments
-        Some(&Ok(tokenizer::Token::RParen)) => {
+        Some(&Ok(Token { kind: tokenizer::TokenKind::RParen, .. })) => {
             // Consume and return
             true
         }
 
         // An argument!
-        Some(&Ok(tokenizer::Token::Text(_))) => {
+        Some(&Ok(Token { kind: tokenizer::TokenKind::Text, .. })) |
+        Some(&Ok(Token { kind: tokenizer::TokenKind::StringIdent, .. })) |
+        Some(&Ok(Token { kind: tokenizer::TokenKind::FloatIdent, .. })) => {
             // Do not consume, do not return
             false
         },
@@ -84,21 +100,29 @@ fn single_argument(it: &mut PeekableTokenIterator) -> WrappedNode {
         return None;
     }
 
-    // We have a Text at this point
-    let name = match it.next() {
-        Some(Ok(tokenizer::Token::Text(n))) => n,
-    
+    // We have a Text at this point, maybe with the sigil glued on
+    let (name, datatype) = match it.next() {
+        Some(Ok(Token { kind: tokenizer::TokenKind::Text, payload: tokenizer::Payload::Identifier(n), .. })) => {
+            // I really like this lil' function
+            (it.interner().resolve(n.spelling), datatype_sigil(it))
+        },
+
+        Some(Ok(Token { kind: tokenizer::TokenKind::StringIdent, payload: tokenizer::Payload::Identifier(n), .. })) => {
+            (it.interner().resolve(n.spelling), Datatype::String)
+        },
+
+        Some(Ok(Token { kind: tokenizer::TokenKind::FloatIdent, payload: tokenizer::Payload::Identifier(n), .. })) => {
+            (it.interner().resolve(n.spelling), Datatype::Float)
+        },
+
         Some(Err(msg)) => error_expected!("Text", msg),
 
         _ => unreachable!()
     };
 
-    // I really like this lil' function
-    let datatype = datatype_sigil(it);
-
     // Now check if there's defaults
     match &it.peek() {
-        &Some(&Ok(tokenizer::Token::Equals)) => {
+        &Some(&Ok(Token { kind: tokenizer::TokenKind::Equals, .. })) => {
             // Consume that
             {
                 it.next();
@@ -106,19 +130,34 @@ fn single_argument(it: &mut PeekableTokenIterator) -> WrappedNode {
             
             // Now we match the NEXT peek
             if match it.peek() {
-                Some(&Ok(tokenizer::Token::String(_))) => {
+                Some(&Ok(Token { kind: tokenizer::TokenKind::String, .. })) => {
                     // Consume that, too
                     {
                         true
                     }
                 },
 
-                Some(&Ok(tokenizer::Token::Number(_))) => {
+                Some(&Ok(Token { kind: tokenizer::TokenKind::Number, .. })) => {
                     true
                 },
 
-                Some(ref what) => error_expected!("Number or String", what),
-                none @ None => error_expected!("Number or String", none)
+                // A minus and a number is a negative default
+                Some(&Ok(Token { kind: tokenizer::TokenKind::Minus, .. })) => {
+                    it.next();
+                    match it.peek() {
+                        Some(&Ok(Token { kind: tokenizer::TokenKind::Number, .. })) |
+                        Some(&Ok(Token { kind: tokenizer::TokenKind::Integer, .. })) => true,
+                        Some(ref what) => error_expected!("Number or Integer", what),
+                        none @ None => error_expected!("Number or Integer", none)
+                    }
+                },
+
+                Some(&Ok(Token { kind: tokenizer::TokenKind::Integer, .. })) => {
+                    true
+                },
+
+                Some(ref what) => error_expected!("Number, Integer or String", what),
+                none @ None => error_expected!("Number, Integer or String", none)
             } {
                 it.next();
             }
@@ -136,12 +175,12 @@ fn single_argument(it: &mut PeekableTokenIterator) -> WrappedNode {
     // if it returns true. We do it this way because it is still
     // borrowed to match before it ends.
     if match it.peek() {
-        Some(&Ok(tokenizer::Token::RParen)) => {
+        Some(&Ok(Token { kind: tokenizer::TokenKind::RParen, .. })) => {
             // Don't consume the RParen, yet
             false
         },
 
-        Some(&Ok(tokenizer::Token::Comma)) => {
+        Some(&Ok(Token { kind: tokenizer::TokenKind::Comma, .. })) => {
             // Consume comma though
             true
         },
@@ -159,12 +198,12 @@ fn single_argument(it: &mut PeekableTokenIterator) -> WrappedNode {
     Some(Ok(Node::Argument { name: name, datatype: datatype }))
 }
 
-fn function_arguments(it: &mut PeekableTokenIterator) -> WrappedNodeVec {
+fn function_arguments<R: Source>(it: &mut PeekableTokenIterator<R>) -> WrappedNodeVec {
     // Find the LParen
     {
         match it.peek() {
             // On LParen, carry on
-            Some(&Ok(tokenizer::Token::LParen)) => {},
+            Some(&Ok(Token { kind: tokenizer::TokenKind::LParen, .. })) => {},
 
             Some(ref what) => error_expected!("LParen", what),
 
@@ -196,46 +235,37 @@ fn function_arguments(it: &mut PeekableTokenIterator) -> WrappedNodeVec {
     Some(Ok(values))
 }
 
-fn function(it: &mut PeekableTokenIterator) -> WrappedNode {
-    // So that we can reborrow it later
-    let text = {
-        // Check we're getting text
-        // This'll return with error if it's not a Text
-        match it.peek() {
-            // If it's a Text, carry on
-            Some(&Ok(tokenizer::Token::Text(_))) => {},
-
-            // If not...
-            Some(ref what) => error_expected!("Text", what),
-
-            // Absolutely nothing found
-            None => {
-                // Only in this case we can return None
-                // A line began with no token at all => EOF
-                return None;
-            }
-        }
-
-        // Now we know it's a token::Text
-        match it.next() {
-            Some(Ok(tokenizer::Token::Text(val @ _))) => val,
-
-            // So the code must not be broken
-            _ => unreachable!()
+fn function<R: Source>(it: &mut PeekableTokenIterator<R>) -> WrappedNode {
+    // Check we're getting the Function keyword
+    // This'll return with error if it's something else
+    match it.peek() {
+        // If it's Function, carry on
+        Some(&Ok(Token {
+            kind: tokenizer::TokenKind::Keyword(tokenizer::Keyword::Function),
+            ..
+        })) => {},
+
+        // If not...
+        Some(ref what) => error_expected!("Keyword(Function)", what),
+
+        // Absolutely nothing found
+        None => {
+            // Only in this case we can return None
+            // A line began with no token at all => EOF
+            return None;
         }
-    };
+    }
 
-    // Slice all of text, check that it's equal to "Function"
-    if !(&text[..]).eq_ignore_ascii_case(b"Function") {
-        error_expected!(format!("Text({:?})", b"function"),
-                        format!("Text({:?})", text));
-    };
+    // Consume it
+    it.next();
 
     // Keyword Function was found, carry on
     // Now we'll match for the function name
-    let function_name = {
+    let (function_name, datatype) = {
         match it.peek() {
-            Some(&Ok(tokenizer::Token::Text(_))) => {},
+            Some(&Ok(Token { kind: tokenizer::TokenKind::Text, .. })) |
+            Some(&Ok(Token { kind: tokenizer::TokenKind::StringIdent, .. })) |
+            Some(&Ok(Token { kind: tokenizer::TokenKind::FloatIdent, .. })) => {},
 
             Some(ref what) => error_expected!("Text", what),
 
@@ -244,17 +274,25 @@ fn function(it: &mut PeekableTokenIterator) -> WrappedNode {
         }
 
         // Return the actual value for function_name
+        // Check for datatype if it wasn't glued on
+        // Never errors, so we can just do it like this
         match it.next() {
-            Some(Ok(tokenizer::Token::Text(val @ _))) => val,
-            
+            Some(Ok(Token { kind: tokenizer::TokenKind::Text, payload: tokenizer::Payload::Identifier(val), .. })) => {
+                (it.interner().resolve(val.spelling), datatype_sigil(it))
+            },
+
+            Some(Ok(Token { kind: tokenizer::TokenKind::StringIdent, payload: tokenizer::Payload::Identifier(val), .. })) => {
+                (it.interner().resolve(val.spelling), Datatype::String)
+            },
+
+            Some(Ok(Token { kind: tokenizer::TokenKind::FloatIdent, payload: tokenizer::Payload::Identifier(val), .. })) => {
+                (it.interner().resolve(val.spelling), Datatype::Float)
+            },
+
             _ => unreachable!()
         }
     };
 
-    // Check for datatype
-    // Never errors, so we can just do it like this
-    let datatype = datatype_sigil(it);
-
     // Find arguments
     // This returns a vec!
     let arguments = match function_arguments(it) {
@@ -271,7 +309,7 @@ fn function(it: &mut PeekableTokenIterator) -> WrappedNode {
     // This actually matches a line so grab the EOL too
     match it.peek() {
         // What we want
-        Some(&Ok(tokenizer::Token::EOL)) => {},
+        Some(&Ok(Token { kind: tokenizer::TokenKind::EOL, .. })) => {},
 
         Some(ref what) => error_expected!("EOL", what),
 
@@ -292,7 +330,7 @@ fn function(it: &mut PeekableTokenIterator) -> WrappedNode {
     }))
 }
 
-fn root(it: &mut PeekableTokenIterator) -> WrappedNode {
+fn root<R: Source>(it: &mut PeekableTokenIterator<R>) -> WrappedNode {
     let mut functions = Vec::<Node>::new();
 
     loop {
@@ -316,8 +354,8 @@ fn root(it: &mut PeekableTokenIterator) -> WrappedNode {
     }
 }
 
-pub fn parse(it: tokenizer::TokenIterator) -> Result<Node, String> {
-    let mut peekable = it.peekable();
+pub fn parse<R: Source>(it: tokenizer::TokenIterator<R>) -> Result<Node, String> {
+    let mut peekable = TokenStream::new(it);
 
     let result = root(&mut peekable);
 
@@ -332,3 +370,2700 @@ pub fn parse(it: tokenizer::TokenIterator) -> Result<Node, String> {
         None => Ok(Node::Empty)
     }
 }
+
+
+// Everything from here on parses whole programs into an ast::Program,
+// the above is just for cbLIA's declaration files
+
+// Something that would have been fine where a parse error is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Token(TokenKind),
+    Expression,
+    Statement,
+    Variable,
+    Label,
+    // A number or a string
+    Literal,
+    TypeName
+}
+
+// Kinds that are spelled one way are quoted
+fn describe(kind: Option<TokenKind>) -> String {
+    match kind {
+        None => "end of file".to_string(),
+        Some(TokenKind::Text) | Some(TokenKind::StringIdent) | Some(TokenKind::FloatIdent) |
+        Some(TokenKind::Label) | Some(TokenKind::String) | Some(TokenKind::Number) |
+        Some(TokenKind::Integer) | Some(TokenKind::EOL) => format!("{}", kind.unwrap()),
+        Some(kind) => format!("`{}`", kind)
+    }
+}
+
+fn keyword(keyword: Keyword) -> Expected {
+    Expected::Token(TokenKind::Keyword(keyword))
+}
+
+// After the opening line of a block and between statements
+static LINE_END: &[Expected] = &[Expected::Token(TokenKind::EOL),
+                                         Expected::Token(TokenKind::Colon)];
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expected::Token(kind) => f.write_str(&describe(Some(kind))),
+            Expected::Expression => f.write_str("an expression"),
+            Expected::Statement => f.write_str("a statement"),
+            Expected::Variable => f.write_str("a variable"),
+            Expected::Label => f.write_str("a label"),
+            Expected::Literal => f.write_str("a number or a string"),
+            Expected::TypeName => f.write_str("a type name")
+        }
+    }
+}
+
+// expected is empty for errors that aren't about the next token, found is
+// what was there instead and None at the end of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+    pub expected: Vec<Expected>,
+    pub found: Option<TokenKind>
+}
+
+impl ParseError {
+    pub fn new(message: String, span: Span) -> ParseError {
+        ParseError {
+            message,
+            span,
+            expected: Vec::new(),
+            found: None
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+// The token stream plus where the last token ended, for errors at the end
+// Lexing errors come out of here as parse errors
+struct Tokens<R: Source> {
+    stream: TokenStream<R>,
+    interner: Interner,
+    // Every node so far
+    arena: Arena,
+    end: Span,
+    // What the statement being parsed is inside of, innermost last, and
+    // every keyword that ends one of them
+    blocks: Vec<&'static str>,
+    closers: Vec<Keyword>,
+    // Labels so far, and every Goto and Gosub to check once they're all in
+    labels: HashMap<String, Span>,
+    jumps: Vec<(Name, Span)>,
+    constants: HashMap<String, ExprId>,
+    includes: Vec<(String, Span)>,
+    // How many dimensions every Dim'd array has, and every f(a) and what
+    // it has in its parens, which is indexing an array if it's one of them
+    arrays: HashMap<String, usize>,
+    indexes: Vec<(Name, usize, Span)>,
+    // And and Or are AndAlso and OrElse, see LexerConfig::short_circuit
+    short_circuit: bool,
+    // Every error so far, the parse keeps going after them
+    errors: Vec<ParseError>,
+    // With LexerConfig::lossless, every comment so far and whether it was
+    // on a line of its own, and every block with where it starts and ends
+    // Then where the trivia of the next token starts, and whether that's
+    // at the start of a line
+    lossless: bool,
+    comments: Vec<(Comment, bool)>,
+    bodies: Vec<(usize, usize, Vec<StmtId>)>,
+    cursor: Span,
+    line_start: bool,
+    // After the last token, once it's all been read
+    trailing: Vec<u8>,
+    // Whether the tokens ran out, or a block comment did, which for a
+    // StatementParser means more could still be coming
+    ran_out: bool,
+    // Every token and node so far that isn't inside another one yet, when
+    // there's a Cst being built
+    elements: Option<Vec<Element>>
+}
+
+impl<R: Source> Tokens<R> {
+    fn new(tokens: TokenIterator<R>) -> Tokens<R> {
+        let interner = tokens.interner();
+        let short_circuit = tokens.config().short_circuit;
+        let lossless = tokens.config().lossless;
+        Tokens {
+            stream: TokenStream::new(tokens),
+            interner,
+            arena: Arena::new(),
+            end: Span { line: 1, column: 1, byte_offset: 0, length: 0 },
+            blocks: Vec::new(),
+            closers: Vec::new(),
+            labels: HashMap::new(),
+            jumps: Vec::new(),
+            constants: HashMap::new(),
+            includes: Vec::new(),
+            arrays: HashMap::new(),
+            indexes: Vec::new(),
+            short_circuit,
+            errors: Vec::new(),
+            lossless,
+            comments: Vec::new(),
+            bodies: Vec::new(),
+            cursor: Span { line: 1, column: 1, byte_offset: 0, length: 0 },
+            line_start: true,
+            trailing: Vec::new(),
+            ran_out: false,
+            elements: None
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<&Token<'static>>, ParseError> {
+        match self.stream.peek() {
+            Some(Ok(token)) => Ok(Some(token)),
+            Some(Err(e)) => {
+                if let LexError::UnterminatedComment { .. } = *e {
+                    self.ran_out = true;
+                }
+                Err(ParseError::new(e.message(), e.span()))
+            },
+            None => {
+                self.ran_out = true;
+                Ok(None)
+            }
+        }
+    }
+
+    // None at the end
+    fn peek_kind(&mut self) -> Result<Option<TokenKind>, ParseError> {
+        Ok(self.peek()?.map(|token| token.kind))
+    }
+
+    // Kind of the token k after the next one, errors are just None here
+    fn peek_kind_after(&mut self, k: usize) -> Option<TokenKind> {
+        match self.stream.peek_n(k + 1) {
+            Some(Ok(token)) => Some(token.kind),
+            Some(&Err(_)) => None,
+            None => {
+                self.ran_out = true;
+                None
+            }
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Token<'static>>, ParseError> {
+        match self.stream.next() {
+            Some(Ok(token)) => {
+                self.end = Span {
+                    column: token.span.column + token.span.length,
+                    byte_offset: token.span.byte_offset + token.span.length,
+                    length: 0,
+                    .. token.span
+                };
+                if self.lossless {
+                    self.take_comments(&token);
+                }
+                if let Some(ref mut elements) = self.elements {
+                    elements.push(Element::Token(token.clone()));
+                }
+                Ok(Some(token))
+            },
+            Some(Err(e)) => Err(ParseError::new(e.message(), e.span())),
+            None => Ok(None)
+        }
+    }
+
+    // The comments in the trivia of a token that was just taken
+    // A block comment over more than one line is the text of an EOL
+    fn take_comments(&mut self, token: &Token) {
+        let trivia = match token.trivia {
+            Some(ref trivia) => trivia,
+            None => return
+        };
+        let mut comments = Vec::new();
+        scan_comments(&trivia.leading, self.cursor, &mut comments);
+        self.cursor = match token.kind {
+            TokenKind::EOL => {
+                let start = Span { length: 0, .. token.span };
+                scan_comments(&trivia.text, start, &mut comments)
+            },
+            _ => self.end
+        };
+
+        let own_line = self.line_start;
+        self.comments.extend(comments.into_iter().map(|comment| (comment, own_line)));
+        self.line_start = token.kind == TokenKind::EOL;
+    }
+
+    // What's after the last token and the comments in it, once there's
+    // nothing more to read
+    fn take_trailing(&mut self) {
+        self.trailing = self.stream.trailing_trivia();
+        let mut comments = Vec::new();
+        scan_comments(&self.trailing, self.cursor, &mut comments);
+        let own_line = self.line_start;
+        self.comments.extend(comments.into_iter().map(|comment| (comment, own_line)));
+    }
+
+    // Where the next token is, or the end
+    fn span(&mut self) -> Span {
+        let end = self.end;
+        match self.peek() {
+            Ok(Some(token)) => token.span,
+            _ => end
+        }
+    }
+
+    // From the start of start to the end of the last token so far
+    fn since(&self, start: Span) -> Span {
+        join(start, self.end)
+    }
+
+    // Expected `Then` or end of line, found `,`
+    // A lexing error where the token would be is returned as it is
+    fn unexpected(&mut self, expected: &[Expected]) -> ParseError {
+        let found = match self.peek_kind() {
+            Ok(found) => found,
+            Err(e) => return e
+        };
+        let span = self.span();
+
+        let mut message = "Expected ".to_string();
+        for (i, item) in expected.iter().enumerate() {
+            if i > 0 {
+                message.push_str(if i + 1 == expected.len() { " or " } else { ", " });
+            }
+            message.push_str(&item.to_string());
+        }
+        message.push_str(", found ");
+        message.push_str(&describe(found));
+
+        ParseError {
+            message,
+            span,
+            expected: expected.to_vec(),
+            found
+        }
+    }
+
+    fn error<T>(&mut self, expected: &[Expected]) -> Result<T, ParseError> {
+        Err(self.unexpected(expected))
+    }
+
+    // Where a node starts, for wrap
+    fn mark(&self) -> usize {
+        self.elements.as_ref().map_or(0, Vec::len)
+    }
+
+    // Everything since the mark goes into a node
+    fn wrap(&mut self, mark: usize, kind: NodeKind) {
+        if let Some(ref mut elements) = self.elements {
+            let children = elements.split_off(mark);
+            elements.push(Element::Node(cst::Node { kind, children }));
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<Token<'static>, ParseError> {
+        if self.peek_kind()? == Some(kind) {
+            Ok(self.next()?.unwrap())
+        }
+        else {
+            self.error(&[Expected::Token(kind)])
+        }
+    }
+
+    fn name(&self, identifier: Identifier) -> Name {
+        Name {
+            name: String::from_utf8_lossy(&self.interner.resolve(identifier.name)).into_owned(),
+            spelling: String::from_utf8_lossy(&self.interner.resolve(identifier.spelling))
+                      .into_owned()
+        }
+    }
+}
+
+// Where the bytes take a position that's at the start of them
+fn advance(mut at: Span, bytes: &[u8]) -> Span {
+    for (i, &byte) in bytes.iter().enumerate() {
+        at.byte_offset += 1;
+        if byte == b'\n' || (byte == b'\r' && bytes.get(i + 1) != Some(&b'\n')) {
+            at.line += 1;
+            at.column = 1;
+        }
+        else {
+            at.column += 1;
+        }
+    }
+    at
+}
+
+// How long the comment at the start of the bytes is, to the end of the
+// line or its closing */
+fn comment_length(bytes: &[u8]) -> usize {
+    if !bytes.starts_with(b"/*") {
+        return bytes.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(bytes.len());
+    }
+
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        }
+        else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        }
+        else {
+            i += 1;
+        }
+    }
+    i
+}
+
+// The comments in trivia that starts at at, and where it ends
+// Words and the like are tokens, so the only things there that look like
+// the start of a comment are ones
+fn scan_comments(bytes: &[u8], mut at: Span, comments: &mut Vec<Comment>) -> Span {
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        let comment = match rest[0] {
+            b'\'' | b'#' => true,
+            b'/' => rest.starts_with(b"/*"),
+            b'R' | b'r' => rest.len() >= 3 && rest[..3].eq_ignore_ascii_case(b"REM"),
+            _ => false
+        };
+        let length = if comment { comment_length(rest) } else { 1 };
+
+        if comment {
+            comments.push(Comment {
+                text: String::from_utf8_lossy(&rest[..length]).into_owned(),
+                span: Span { length, .. at }
+            });
+        }
+        at = advance(at, &rest[..length]);
+        i += length;
+    }
+    at
+}
+
+fn sigil(kind: TokenKind) -> Sigil {
+    match kind {
+        TokenKind::StringIdent => Sigil::Dollar,
+        TokenKind::FloatIdent => Sigil::Hash,
+        _ => Sigil::None
+    }
+}
+
+fn is_variable(kind: Option<TokenKind>) -> bool {
+    matches!(kind, Some(TokenKind::Text) | Some(TokenKind::StringIdent) | Some(TokenKind::FloatIdent))
+}
+
+// At least one, separated by commas
+fn variables<R: Source>(it: &mut Tokens<R>) -> Result<Vec<Variable>, ParseError> {
+    let mut variables = vec![variable(it)?];
+    while it.peek_kind()? == Some(TokenKind::Comma) {
+        it.next()?;
+        variables.push(variable(it)?);
+    }
+    Ok(variables)
+}
+
+fn variable<R: Source>(it: &mut Tokens<R>) -> Result<Variable, ParseError> {
+    if !is_variable(it.peek_kind()?) {
+        return it.error(&[Expected::Variable]);
+    }
+
+    let token = it.next()?.unwrap();
+    Ok(Variable {
+        name: it.name(token.identifier().unwrap()),
+        sigil: sigil(token.kind)
+    })
+}
+
+// See BinaryOp::precedence for how tightly they bind
+fn binary_op(kind: TokenKind) -> Option<BinaryOp> {
+    Some(match kind {
+        TokenKind::Keyword(Keyword::Or) => BinaryOp::Or,
+        TokenKind::Keyword(Keyword::Xor) => BinaryOp::Xor,
+        TokenKind::Keyword(Keyword::And) => BinaryOp::And,
+        TokenKind::Equals => BinaryOp::Equal,
+        TokenKind::NotEquals => BinaryOp::NotEqual,
+        TokenKind::Less => BinaryOp::Less,
+        TokenKind::Greater => BinaryOp::Greater,
+        TokenKind::LessEquals => BinaryOp::LessEqual,
+        TokenKind::GreaterEquals => BinaryOp::GreaterEqual,
+        TokenKind::Plus => BinaryOp::Add,
+        TokenKind::Minus => BinaryOp::Subtract,
+        TokenKind::Star => BinaryOp::Multiply,
+        TokenKind::Slash => BinaryOp::Divide,
+        TokenKind::Percent | TokenKind::Keyword(Keyword::Mod) => BinaryOp::Modulo,
+        TokenKind::Caret => BinaryOp::Power,
+        _ => return None
+    })
+}
+
+// From the start of first to the end of last
+fn join(first: Span, last: Span) -> Span {
+    Span {
+        length: last.byte_offset + last.length - first.byte_offset,
+        .. first
+    }
+}
+
+fn binary(arena: &mut Arena, op: BinaryOp, left: ExprId, right: ExprId) -> ExprId {
+    let op = {
+        let string = |side: ExprId| arena[side].sigil(arena) == Some(Sigil::Dollar);
+        match op {
+            BinaryOp::Add if string(left) || string(right) => BinaryOp::Concat,
+            op => op
+        }
+    };
+    let span = join(arena[left].span, arena[right].span);
+    arena.add_expr(ExprKind::Binary { op, left, right }, span)
+}
+
+// Not in the arena yet
+fn literal(token: Token) -> Expr {
+    let kind = match token.payload {
+        Payload::Integer(value) => ExprKind::Integer(value),
+        Payload::Number(value) => ExprKind::Float(value),
+        Payload::String(bytes) => ExprKind::String(String::from_utf8_lossy(&bytes).into_owned()),
+        _ => unreachable!()
+    };
+    Expr::new(kind, token.span)
+}
+
+fn operand<R: Source>(it: &mut Tokens<R>) -> Result<ExprId, ParseError> {
+    let mark = it.mark();
+    match it.peek_kind()? {
+        Some(TokenKind::Integer) | Some(TokenKind::Number) | Some(TokenKind::String) => {
+            let value = literal(it.next()?.unwrap());
+            it.wrap(mark, NodeKind::Literal);
+            Ok(it.arena.add_expr(value.kind, value.span))
+        },
+
+        kind if is_variable(kind) => {
+            let span = it.peek()?.unwrap().span;
+            let name = variable(it)?;
+            if it.peek_kind()? == Some(TokenKind::LParen) {
+                let arguments = parenthesized(it)?;
+                it.indexes.push((name.name.clone(), arguments.len(), span));
+                it.wrap(mark, NodeKind::CallExpr);
+                let call = ExprKind::Call { name, arguments };
+                let span = it.since(span);
+                let call = it.arena.add_expr(call, span);
+                return members(it, call, mark);
+            }
+
+            it.wrap(mark, NodeKind::Variable);
+            let span = it.since(span);
+            let variable = it.arena.add_expr(ExprKind::Variable(name), span);
+            members(it, variable, mark)
+        },
+
+        // The parens are in the span of what's inside them
+        Some(TokenKind::LParen) => {
+            let span = it.next()?.unwrap().span;
+            let inner = expression(it)?;
+            it.expect(TokenKind::RParen)?;
+            it.wrap(mark, NodeKind::Paren);
+            it.arena[inner].span = it.since(span);
+            Ok(inner)
+        },
+
+        // Everything after them that binds tighter, so -2 ^ 2 is -(2 ^ 2)
+        Some(TokenKind::Minus) | Some(TokenKind::Keyword(Keyword::Not)) => {
+            let token = it.next()?.unwrap();
+            let op = match token.kind {
+                TokenKind::Minus => UnaryOp::Negate,
+                _ => UnaryOp::Not
+            };
+            let inner_mark = it.mark();
+            let inner = operand(it)?;
+            let inner = operators(it, inner, op.precedence() + 1, inner_mark)?;
+            it.wrap(mark, NodeKind::Unary);
+            let span = it.since(token.span);
+            Ok(it.arena.add_expr(ExprKind::Unary { op, operand: inner }, span))
+        },
+
+        _ => it.error(&[Expected::Expression])
+    }
+}
+
+// Any number of \field after the object, which starts at the mark
+fn members<R: Source>(it: &mut Tokens<R>, mut object: ExprId, mark: usize)
+                      -> Result<ExprId, ParseError> {
+    while it.peek_kind()? == Some(TokenKind::Backslash) {
+        it.next()?;
+        let field = variable(it)?;
+        let span = it.since(it.arena[object].span);
+        object = it.arena.add_expr(ExprKind::Member { object, field }, span);
+        it.wrap(mark, NodeKind::Member);
+    }
+    Ok(object)
+}
+
+// Operators binding at least as tightly as precedence, after left which
+// starts at the mark
+fn operators<R: Source>(it: &mut Tokens<R>, mut left: ExprId, precedence: u8, mark: usize)
+                        -> Result<ExprId, ParseError> {
+    loop {
+        let op = match it.peek_kind()?.and_then(binary_op) {
+            Some(BinaryOp::And) if it.short_circuit => Some(BinaryOp::AndAlso),
+            Some(BinaryOp::Or) if it.short_circuit => Some(BinaryOp::OrElse),
+            op => op
+        };
+        let op = match op {
+            Some(op) if op.precedence() >= precedence => op,
+            _ => return Ok(left)
+        };
+        it.next()?;
+
+        let next = if op.right_associative() { op.precedence() } else { op.precedence() + 1 };
+        let right_mark = it.mark();
+        let right = operand(it)?;
+        let right = operators(it, right, next, right_mark)?;
+        left = binary(&mut it.arena, op, left, right);
+        it.wrap(mark, NodeKind::Binary);
+    }
+}
+
+fn expression<R: Source>(it: &mut Tokens<R>) -> Result<ExprId, ParseError> {
+    let mark = it.mark();
+    let left = operand(it)?;
+    operators(it, left, 0, mark)
+}
+
+// Whether a statement can end here
+// Else too, for If x Then y = 1 Else y = 2, it can't start an expression
+fn at_statement_end(kind: Option<TokenKind>) -> bool {
+    matches!(kind, None | Some(TokenKind::EOL) | Some(TokenKind::Colon) |
+                   Some(TokenKind::Keyword(Keyword::Else)))
+}
+
+// The arguments in f(a, b), there can be none
+fn parenthesized<R: Source>(it: &mut Tokens<R>) -> Result<Vec<ExprId>, ParseError> {
+    it.expect(TokenKind::LParen)?;
+    let mut arguments = Vec::new();
+    if it.peek_kind()? == Some(TokenKind::RParen) {
+        it.next()?;
+        return Ok(arguments);
+    }
+
+    loop {
+        arguments.push(expression(it)?);
+        match it.peek_kind()? {
+            Some(TokenKind::Comma) => { it.next()?; },
+            Some(TokenKind::RParen) => {
+                it.next()?;
+                return Ok(arguments);
+            },
+            _ => {
+                return it.error(&[Expected::Token(TokenKind::Comma), Expected::Token(TokenKind::RParen)]);
+            }
+        }
+    }
+}
+
+// The arguments of a command, which can be in parens like a call or not
+fn arguments<R: Source>(it: &mut Tokens<R>) -> Result<Vec<ExprId>, ParseError> {
+    let arguments = Vec::new();
+    if at_statement_end(it.peek_kind()?) {
+        return Ok(arguments);
+    }
+    more_arguments(it, arguments)
+}
+
+// After the parens right after a command, which started at the mark and
+// at start
+// f (a) + 1, b has a first argument that starts with parens instead
+fn parenthesized_arguments<R: Source>(it: &mut Tokens<R>, mut arguments: Vec<ExprId>, mark: usize,
+                                      start: Span) -> Result<Vec<ExprId>, ParseError> {
+    if at_statement_end(it.peek_kind()?) || arguments.len() != 1 {
+        return Ok(arguments);
+    }
+
+    it.wrap(mark, NodeKind::Paren);
+    let first = arguments.pop().unwrap();
+    it.arena[first].span = it.since(start);
+    arguments.push(operators(it, first, 0, mark)?);
+    if it.peek_kind()? != Some(TokenKind::Comma) {
+        return Ok(arguments);
+    }
+    it.next()?;
+    more_arguments(it, arguments)
+}
+
+fn more_arguments<R: Source>(it: &mut Tokens<R>, mut arguments: Vec<ExprId>)
+                             -> Result<Vec<ExprId>, ParseError> {
+    loop {
+        arguments.push(expression(it)?);
+        if it.peek_kind()? != Some(TokenKind::Comma) {
+            return Ok(arguments);
+        }
+        it.next()?;
+    }
+}
+
+// Values need a separator between them, separators can go anywhere
+fn print<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let mut items = Vec::new();
+    let mut after_value = false;
+
+    loop {
+        let kind = it.peek_kind()?;
+        if at_statement_end(kind) {
+            return Ok(StmtKind::Print(items));
+        }
+
+        match kind {
+            Some(TokenKind::Comma) => {
+                it.next()?;
+                items.push(PrintItem::Comma);
+                after_value = false;
+            },
+            Some(TokenKind::Semicolon) => {
+                it.next()?;
+                items.push(PrintItem::Semicolon);
+                after_value = false;
+            },
+            _ if after_value => {
+                return it.error(&[Expected::Token(TokenKind::Comma), Expected::Token(TokenKind::Semicolon),
+                                  Expected::Token(TokenKind::EOL), Expected::Token(TokenKind::Colon)]);
+            },
+            _ => {
+                items.push(PrintItem::Value(expression(it)?));
+                after_value = true;
+            }
+        }
+    }
+}
+
+// In a node of its own, an Error one if it doesn't parse
+fn statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtId, ParseError> {
+    let mark = it.mark();
+    let start = it.span();
+    let stmt = statement_node(it).map(|kind| {
+        let span = it.since(start);
+        it.arena.add_stmt(kind, span)
+    });
+    let kind = match stmt {
+        Ok(stmt) => NodeKind::of(&it.arena[stmt]),
+        Err(_) => NodeKind::Error
+    };
+    it.wrap(mark, kind);
+    stmt
+}
+
+fn statement_node<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    match it.peek_kind()? {
+        Some(TokenKind::Label) => {
+            let token = it.next()?.unwrap();
+            let name = it.name(token.identifier().unwrap());
+            if it.labels.insert(name.name.clone(), token.span).is_some() {
+                return Err(ParseError::new(format!("Label {} is already defined", name.spelling),
+                                           token.span));
+            }
+            Ok(StmtKind::Label(name))
+        },
+
+        Some(TokenKind::Keyword(Keyword::Dim)) => {
+            it.next()?;
+            Ok(StmtKind::Dim(arrays(it)?))
+        },
+
+        Some(TokenKind::Keyword(Keyword::ReDim)) => {
+            it.next()?;
+            Ok(StmtKind::ReDim(arrays(it)?))
+        },
+
+        Some(TokenKind::Keyword(Keyword::Data)) => {
+            it.next()?;
+            let mut values = Vec::new();
+            loop {
+                // Only literals, a minus before a number makes it negative
+                let start = it.span();
+                let negative = it.peek_kind()? == Some(TokenKind::Minus);
+                if negative {
+                    it.next()?;
+                }
+
+                match it.peek_kind()? {
+                    Some(TokenKind::Integer) | Some(TokenKind::Number) => {
+                        let value = literal(it.next()?.unwrap());
+                        let kind = match value.kind {
+                            ExprKind::Integer(n) if negative => ExprKind::Integer(n.wrapping_neg()),
+                            ExprKind::Float(n) if negative => ExprKind::Float(-n),
+                            kind => kind
+                        };
+                        let span = it.since(start);
+                        values.push(it.arena.add_expr(kind, span));
+                    },
+                    Some(TokenKind::String) if !negative => {
+                        let value = literal(it.next()?.unwrap());
+                        values.push(it.arena.add_expr(value.kind, value.span));
+                    },
+                    _ if negative => {
+                        let numbers = [Expected::Token(TokenKind::Integer), Expected::Token(TokenKind::Number)];
+                        return it.error(&numbers);
+                    },
+                    _ => return it.error(&[Expected::Literal])
+                }
+
+                if it.peek_kind()? != Some(TokenKind::Comma) {
+                    return Ok(StmtKind::Data(values));
+                }
+                it.next()?;
+            }
+        },
+
+        Some(TokenKind::Keyword(Keyword::Read)) => {
+            it.next()?;
+            Ok(StmtKind::Read(variables(it)?))
+        },
+
+        Some(TokenKind::Keyword(Keyword::Restore)) => {
+            it.next()?;
+            if at_statement_end(it.peek_kind()?) {
+                return Ok(StmtKind::Restore(None));
+            }
+            Ok(StmtKind::Restore(Some(jump(it)?)))
+        },
+
+        Some(TokenKind::Keyword(Keyword::Global)) => {
+            let span = it.next()?.unwrap().span;
+            if it.blocks.contains(&"Function") {
+                return Err(ParseError::new("Global can't be inside Function".to_string(), span));
+            }
+            Ok(StmtKind::Declare { scope: Scope::Global, variables: variables(it)? })
+        },
+
+        Some(TokenKind::Keyword(Keyword::Local)) => {
+            it.next()?;
+            Ok(StmtKind::Declare { scope: Scope::Local, variables: variables(it)? })
+        },
+
+        Some(TokenKind::Keyword(Keyword::Const)) => {
+            it.next()?;
+            let span = it.span();
+            let name = variable(it)?;
+            it.expect(TokenKind::Equals)?;
+            let value_span = it.span();
+            let value = expression(it)?;
+
+            if !is_constant(&it.arena, value, &it.constants) {
+                let message = format!("Const {} can only have literals and constants in it",
+                                      name.name.spelling);
+                return Err(ParseError::new(message, value_span));
+            }
+            negative_literal(&mut it.arena, value);
+            if it.constants.insert(name.name.name.clone(), value).is_some() {
+                return Err(ParseError::new(format!("Const {} is already defined", name.name.spelling), span));
+            }
+            Ok(StmtKind::Const { name, value })
+        },
+
+        Some(TokenKind::Keyword(Keyword::Goto)) => {
+            it.next()?;
+            Ok(StmtKind::Goto(jump(it)?))
+        },
+
+        Some(TokenKind::Keyword(Keyword::Gosub)) => {
+            it.next()?;
+            Ok(StmtKind::Gosub(jump(it)?))
+        },
+
+        // Only a Function returns a value
+        Some(TokenKind::Keyword(Keyword::Return)) => {
+            it.next()?;
+            if at_statement_end(it.peek_kind()?) {
+                return Ok(StmtKind::Return(None));
+            }
+            if !it.blocks.contains(&"Function") {
+                return Err(ParseError::new("Return with a value outside of a Function".to_string(),
+                                           it.span()));
+            }
+            Ok(StmtKind::Return(Some(expression(it)?)))
+        },
+
+        Some(TokenKind::Keyword(Keyword::Exit)) => exit(it),
+
+        Some(TokenKind::Keyword(Keyword::End)) => {
+            it.next()?;
+            Ok(StmtKind::End)
+        },
+
+        Some(TokenKind::Keyword(Keyword::If)) => if_statement(it),
+        Some(TokenKind::Keyword(Keyword::For)) => for_statement(it),
+        Some(TokenKind::Keyword(Keyword::While)) => while_statement(it),
+        Some(TokenKind::Keyword(Keyword::Repeat)) => repeat_statement(it),
+        Some(TokenKind::Keyword(Keyword::Select)) => select_statement(it),
+        Some(TokenKind::Keyword(Keyword::Function)) => function_statement(it),
+        Some(TokenKind::Keyword(Keyword::Type)) => type_statement(it),
+
+        Some(TokenKind::Keyword(Keyword::Include)) => {
+            let span = top_level(it, "Include")?;
+            let path = match it.next()? {
+                Some(Token { payload: Payload::String(path), .. }) => {
+                    String::from_utf8_lossy(&path).into_owned()
+                },
+                _ => return Err(ParseError::new("Include needs a file name string".to_string(),
+                                                it.end))
+            };
+
+            it.includes.push((path.clone(), span));
+            Ok(StmtKind::Include(path))
+        },
+
+        // ? is Print too
+        Some(TokenKind::Keyword(Keyword::Print)) => {
+            it.next()?;
+            print(it)
+        },
+
+        kind if is_variable(kind) => {
+            let mark = it.mark();
+            let span = it.peek()?.unwrap().span;
+            let name = variable(it)?;
+
+            // Parens before the = make it an element of an array, a command
+            // can have them around its arguments too
+            let mut indices = None;
+            if it.peek_kind()? == Some(TokenKind::LParen) {
+                let parens = it.mark();
+                let start = it.span();
+                let values = parenthesized(it)?;
+                match it.peek_kind()? {
+                    Some(TokenKind::Equals) | Some(TokenKind::Backslash) => indices = Some(values),
+                    _ if name.sigil != Sigil::None => {
+                        return it.error(&[Expected::Token(TokenKind::Equals)]);
+                    },
+                    _ => {
+                        let arguments = parenthesized_arguments(it, values, parens, start)?;
+                        return Ok(StmtKind::Call { name: name.name, arguments });
+                    }
+                }
+            }
+
+            // A name on its own or with arguments is a command
+            match it.peek_kind()? {
+                Some(TokenKind::Equals) | Some(TokenKind::Backslash) => {},
+                _ if name.sigil != Sigil::None => return it.error(&[Expected::Token(TokenKind::Equals)]),
+                _ => return Ok(StmtKind::Call { name: name.name, arguments: arguments(it)? })
+            }
+
+            let target = match indices {
+                Some(indices) => {
+                    it.indexes.push((name.name.clone(), indices.len(), span));
+                    it.wrap(mark, NodeKind::Index);
+                    ExprKind::Index { array: name, indices }
+                },
+                None => {
+                    it.wrap(mark, NodeKind::Variable);
+                    ExprKind::Variable(name)
+                }
+            };
+            let span = it.since(span);
+            let target = it.arena.add_expr(target, span);
+            let target = members(it, target, mark)?;
+            it.expect(TokenKind::Equals)?;
+            Ok(StmtKind::Assign { target, value: expression(it)? })
+        },
+
+        _ => it.error(&[Expected::Statement])
+    }
+}
+
+// Two words that can be written for one keyword
+fn joined(first: Keyword, second: Option<TokenKind>) -> Option<Keyword> {
+    Some(match (first, second) {
+        (Keyword::End, Some(TokenKind::Keyword(Keyword::If))) => Keyword::EndIf,
+        (Keyword::Else, Some(TokenKind::Keyword(Keyword::If))) => Keyword::ElseIf,
+        (Keyword::End, Some(TokenKind::Keyword(Keyword::Select))) => Keyword::EndSelect,
+        (Keyword::End, Some(TokenKind::Keyword(Keyword::Function))) => Keyword::EndFunction,
+        (Keyword::End, Some(TokenKind::Keyword(Keyword::Type))) => Keyword::EndType,
+        // Default is what CoolBasic calls it
+        (Keyword::Case, Some(TokenKind::Keyword(Keyword::Else))) => Keyword::Default,
+        _ => return None
+    })
+}
+
+// The keyword that starts the next statement, with End If and the like
+// as the one word they stand for
+fn block_keyword<R: Source>(it: &mut Tokens<R>) -> Result<Option<Keyword>, ParseError> {
+    let keyword = match it.peek_kind()? {
+        Some(TokenKind::Keyword(keyword)) => keyword,
+        _ => return Ok(None)
+    };
+
+    Ok(Some(joined(keyword, it.peek_kind_after(0)).unwrap_or(keyword)))
+}
+
+// Consumes what block_keyword found
+fn take_block_keyword<R: Source>(it: &mut Tokens<R>) -> Result<Keyword, ParseError> {
+    let keyword = match it.next()?.map(|token| token.kind) {
+        Some(TokenKind::Keyword(keyword)) => keyword,
+        _ => unreachable!()
+    };
+
+    match joined(keyword, it.peek_kind()?) {
+        Some(joined) => {
+            it.next()?;
+            Ok(joined)
+        },
+        None => Ok(keyword)
+    }
+}
+
+// Statements on lines of their own until one of the keywords, which is
+// left for the caller
+// Without an opening the block is the whole program and ends at the end
+// An error in one of the statements is kept and the block goes on after
+// it, one that's missing its end is returned
+fn block<R: Source>(it: &mut Tokens<R>, opening: Option<(&'static str, Span)>, ends: &[Keyword])
+                    -> Result<Vec<StmtId>, ParseError> {
+    if let Some((opening, _)) = opening {
+        it.blocks.push(opening);
+    }
+    let closers = it.closers.len();
+    it.closers.extend_from_slice(ends);
+
+    let start = it.span().byte_offset;
+    let statements = block_statements(it, opening, ends);
+    if let Ok(ref statements) = statements {
+        if it.lossless && opening.is_some() {
+            let end = it.span().byte_offset;
+            it.bodies.push((start, end, statements.clone()));
+        }
+    }
+
+    it.closers.truncate(closers);
+    if opening.is_some() {
+        it.blocks.pop();
+    }
+    statements
+}
+
+// Skips to the end of the line, or to a keyword that ends one of the
+// blocks the error is in
+// A lexing error on the way is kept too, unless it's the one already kept
+fn synchronize<R: Source>(it: &mut Tokens<R>) {
+    skip_line(it);
+    if let Ok(Some(TokenKind::EOL)) = it.peek_kind() {
+        let _ = it.next();
+    }
+}
+
+// The same but leaving the end of the line for whatever comes next
+// True if it went past a Then with more after it or stopped at one of the
+// keywords, either way there's no block on the lines after it
+fn skip_line<R: Source>(it: &mut Tokens<R>) -> bool {
+    let mut then = false;
+    let mut after_then = false;
+    loop {
+        match it.peek_kind() {
+            Ok(None) | Ok(Some(TokenKind::EOL)) => return after_then,
+            Ok(Some(TokenKind::Keyword(_))) => {
+                if let Ok(Some(keyword)) = block_keyword(it) {
+                    if it.closers.contains(&keyword) {
+                        return true;
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        after_then = then;
+        match it.next() {
+            Ok(token) => {
+                then = then || token.map(|token| token.kind) == Some(TokenKind::Keyword(Keyword::Then));
+            },
+            Err(e) => {
+                if it.errors.last() != Some(&e) {
+                    it.errors.push(e);
+                }
+            }
+        }
+    }
+}
+
+// An error in the line that opens a block is kept and the rest of the line
+// is skipped, the whole line an Error node from the mark on. The block's
+// still parsed after it so its end isn't an error of its own, then what
+// opened it returns the error again, which recover knows it has
+// True like skip_line if there's no block to parse
+fn broken_header<R: Source>(it: &mut Tokens<R>, mark: usize, error: &ParseError) -> bool {
+    it.errors.push(error.clone());
+    let no_block = skip_line(it);
+    it.wrap(mark, NodeKind::Error);
+    no_block
+}
+
+// What gets skipped is an Error node
+fn recover<R: Source>(it: &mut Tokens<R>, error: ParseError) {
+    // From broken_header, the block is parsed to its end already
+    if it.errors.contains(&error) {
+        return;
+    }
+    it.errors.push(error);
+    let mark = it.mark();
+    synchronize(it);
+    if it.mark() > mark {
+        it.wrap(mark, NodeKind::Error);
+    }
+}
+
+// The same but stopping at the keywords too, for the lines of a Select or
+// Type between the ones that are blocks
+fn recover_at<R: Source>(it: &mut Tokens<R>, error: ParseError, keywords: &[Keyword]) {
+    let closers = it.closers.len();
+    it.closers.extend_from_slice(keywords);
+    recover(it, error);
+    it.closers.truncate(closers);
+}
+
+// A statement and the separator after it, if there needs to be one
+fn separated_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtId, ParseError> {
+    // Labels don't need a separator after them
+    let label = it.peek_kind()? == Some(TokenKind::Label);
+    let stmt = statement(it)?;
+    match it.peek_kind()? {
+        None | Some(TokenKind::EOL) | Some(TokenKind::Colon) => Ok(stmt),
+        _ if label => Ok(stmt),
+        _ => it.error(LINE_END)
+    }
+}
+
+fn block_statements<R: Source>(it: &mut Tokens<R>, opening: Option<(&str, Span)>, ends: &[Keyword])
+                               -> Result<Vec<StmtId>, ParseError> {
+    let mut statements = Vec::new();
+
+    // Nothing else on the line that opened it
+    if opening.is_some() {
+        match it.peek_kind() {
+            Ok(None) | Ok(Some(TokenKind::EOL)) | Ok(Some(TokenKind::Colon)) => {},
+            Ok(_) => {
+                let error = it.unexpected(LINE_END);
+                recover(it, error);
+            },
+            Err(e) => recover(it, e)
+        }
+    }
+
+    loop {
+        // Blank lines and stray colons are fine
+        match it.peek_kind() {
+            Ok(Some(TokenKind::EOL)) | Ok(Some(TokenKind::Colon)) => {
+                it.next()?;
+                continue;
+            },
+            Ok(_) => {},
+            Err(e) => {
+                recover(it, e);
+                continue;
+            }
+        }
+
+        // The end of the file or of a block it's inside of means this one
+        // never got its end
+        let keyword = block_keyword(it)?;
+        let missing = match keyword {
+            Some(keyword) if ends.contains(&keyword) => return Ok(statements),
+            Some(keyword) => it.closers.contains(&keyword),
+            None => it.peek_kind()?.is_none()
+        };
+        if missing {
+            return match opening {
+                None => Ok(statements),
+                Some((opening, span)) => {
+                    let message = format!("{} is missing its {}", opening, ends[ends.len() - 1].name());
+                    Err(ParseError::new(message, span))
+                }
+            };
+        }
+
+        match separated_statement(it) {
+            Ok(stmt) => statements.push(stmt),
+            Err(e) => recover(it, e)
+        }
+    }
+}
+
+// Statements up to the end of the line, or an Else
+fn line_block<R: Source>(it: &mut Tokens<R>) -> Result<Vec<StmtId>, ParseError> {
+    let mut statements = Vec::new();
+
+    loop {
+        statements.push(statement(it)?);
+        match it.peek_kind()? {
+            Some(TokenKind::Colon) => {
+                it.next()?;
+            },
+            None | Some(TokenKind::EOL) | Some(TokenKind::Keyword(Keyword::Else)) => {
+                return Ok(statements);
+            },
+            _ => return it.error(&[Expected::Token(TokenKind::EOL), Expected::Token(TokenKind::Colon),
+                                   keyword(Keyword::Else)])
+        }
+    }
+}
+
+// Then can be left out, as long as what comes after the condition can't be
+// more of it
+fn then<R: Source>(it: &mut Tokens<R>) -> Result<(), ParseError> {
+    match it.peek_kind()? {
+        Some(TokenKind::Keyword(Keyword::Then)) => {
+            it.next()?;
+            Ok(())
+        },
+        None | Some(TokenKind::EOL) | Some(TokenKind::Colon) | Some(TokenKind::Label) |
+        Some(TokenKind::Keyword(_)) => Ok(()),
+        kind if is_variable(kind) => Ok(()),
+        _ => it.error(&[keyword(Keyword::Then), Expected::Token(TokenKind::EOL)])
+    }
+}
+
+// Then is optional, nothing after it on the line makes it a block
+fn if_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let mark = it.mark();
+    let span = it.next()?.unwrap().span;
+    let condition = expression(it).and_then(|condition| then(it).map(|_| condition));
+    if let Err(ref e) = condition {
+        // A one line If is just skipped
+        if broken_header(it, mark, e) {
+            return Err(e.clone());
+        }
+    }
+
+    match it.peek_kind()? {
+        None | Some(TokenKind::EOL) => {},
+        _ if condition.is_err() => {},
+        _ => {
+            let then_branch = line_block(it)?;
+            let else_branch = if it.peek_kind()? == Some(TokenKind::Keyword(Keyword::Else)) {
+                it.next()?;
+                Some(line_block(it)?)
+            }
+            else {
+                None
+            };
+
+            return Ok(StmtKind::If {
+                condition: condition?,
+                then_branch,
+                else_ifs: Vec::new(),
+                else_branch,
+                single_line: true
+            });
+        }
+    }
+
+    let opening = Some(("If", span));
+    let ends = [Keyword::ElseIf, Keyword::Else, Keyword::EndIf];
+    let then_branch = block(it, opening, &ends)?;
+    let mut else_ifs = Vec::new();
+    let mut else_branch = None;
+
+    loop {
+        let mark = it.mark();
+        match take_block_keyword(it)? {
+            // One that doesn't parse is left out
+            Keyword::ElseIf => {
+                let condition = expression(it).and_then(|condition| then(it).map(|_| condition));
+                if let Err(ref e) = condition {
+                    broken_header(it, mark, e);
+                }
+                let body = block(it, opening, &ends)?;
+                if let Ok(condition) = condition {
+                    else_ifs.push(ElseIf { condition, body });
+                }
+            },
+            Keyword::Else => {
+                else_branch = Some(block(it, opening, &[Keyword::EndIf])?);
+            },
+            _ => break
+        }
+    }
+
+    Ok(StmtKind::If {
+        condition: condition?,
+        then_branch,
+        else_ifs,
+        else_branch,
+        single_line: false
+    })
+}
+
+// The variable after Next is optional, but has to be the loop's if it's
+// there. The wrong one is kept as an error but the loop isn't
+fn for_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let mark = it.mark();
+    let span = it.next()?.unwrap().span;
+    let header = for_header(it);
+    if let Err(ref e) = header {
+        if broken_header(it, mark, e) {
+            return Err(e.clone());
+        }
+    }
+
+    let body = block(it, Some(("For", span)), &[Keyword::Next])?;
+    it.next()?;
+
+    let next = if is_variable(it.peek_kind()?) {
+        Some((it.span(), variable(it)?))
+    }
+    else {
+        None
+    };
+    let (counter, from, to, step) = header?;
+    if let Some((next_span, next)) = next {
+        if next.name.name != counter.name.name || next.sigil != counter.sigil {
+            let message = format!("Next {}{} doesn't go with For {}{}",
+                                  next.name.spelling, next.sigil.suffix(),
+                                  counter.name.spelling, counter.sigil.suffix());
+            it.errors.push(ParseError::new(message, next_span));
+        }
+    }
+
+    Ok(StmtKind::For {
+        variable: counter,
+        from,
+        to,
+        step,
+        body
+    })
+}
+
+// The counter, where it goes from and to, and the Step
+fn for_header<R: Source>(it: &mut Tokens<R>)
+                         -> Result<(Variable, ExprId, ExprId, Option<ExprId>), ParseError> {
+    let counter = variable(it)?;
+    it.expect(TokenKind::Equals)?;
+    let from = expression(it)?;
+    it.expect(TokenKind::Keyword(Keyword::To))?;
+    let to = expression(it)?;
+    let step = if it.peek_kind()? == Some(TokenKind::Keyword(Keyword::Step)) {
+        it.next()?;
+        Some(expression(it)?)
+    }
+    else {
+        None
+    };
+    Ok((counter, from, to, step))
+}
+
+fn while_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let mark = it.mark();
+    let span = it.next()?.unwrap().span;
+    let condition = expression(it);
+    if let Err(ref e) = condition {
+        if broken_header(it, mark, e) {
+            return Err(e.clone());
+        }
+    }
+    let body = block(it, Some(("While", span)), &[Keyword::Wend])?;
+    it.next()?;
+
+    Ok(StmtKind::While { condition: condition?, body })
+}
+
+fn repeat_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let span = it.next()?.unwrap().span;
+    // Until last, that is what a missing end asks for
+    let body = block(it, Some(("Repeat", span)), &[Keyword::Forever, Keyword::Until])?;
+
+    let until = match it.next()?.unwrap().kind {
+        TokenKind::Keyword(Keyword::Until) => Some(expression(it)?),
+        _ => None
+    };
+
+    Ok(StmtKind::Repeat { body, until })
+}
+
+// Only blank lines between Select and the first Case, and Default has to
+// be the last one
+fn select_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let mark = it.mark();
+    let span = it.next()?.unwrap().span;
+    let value = expression(it);
+    let mut cases = Vec::new();
+    let mut default = None;
+
+    let opening = Some(("Select", span));
+    let ends = [Keyword::Case, Keyword::Default, Keyword::EndSelect];
+    match value {
+        Err(ref e) => {
+            if broken_header(it, mark, e) {
+                return Err(e.clone());
+            }
+        },
+        Ok(_) => match it.peek_kind()? {
+            None | Some(TokenKind::EOL) | Some(TokenKind::Colon) => {},
+            _ => {
+                let error = it.unexpected(LINE_END);
+                recover_at(it, error, &ends);
+            }
+        }
+    }
+
+    loop {
+        while let Some(TokenKind::EOL) | Some(TokenKind::Colon) = it.peek_kind()? {
+            it.next()?;
+        }
+
+        match block_keyword(it)? {
+            // One that doesn't parse is left out
+            Some(Keyword::Case) if default.is_none() => {
+                let mark = it.mark();
+                it.next()?;
+                let values = case_values(it);
+                if let Err(ref e) = values {
+                    broken_header(it, mark, e);
+                }
+                let body = block(it, opening, &ends)?;
+                if let Ok(values) = values {
+                    cases.push(Case { values, body });
+                }
+            },
+            Some(Keyword::Default) if default.is_none() => {
+                take_block_keyword(it)?;
+                default = Some(block(it, opening, &ends)?);
+            },
+            Some(Keyword::EndSelect) => {
+                take_block_keyword(it)?;
+                break;
+            },
+            // Or whatever ends a block it's inside of
+            Some(keyword) if it.closers.contains(&keyword) => {
+                return Err(ParseError::new("Select is missing its EndSelect".to_string(), span));
+            },
+            _ if it.peek_kind()?.is_none() => {
+                return Err(ParseError::new("Select is missing its EndSelect".to_string(), span));
+            },
+            _ if default.is_some() => {
+                let error = it.unexpected(&[keyword(Keyword::EndSelect)]);
+                recover_at(it, error, &[Keyword::EndSelect]);
+            },
+            _ => {
+                let error = it.unexpected(&[keyword(Keyword::Case), keyword(Keyword::Default),
+                                            keyword(Keyword::EndSelect)]);
+                recover_at(it, error, &ends);
+            }
+        }
+    }
+
+    Ok(StmtKind::Select { value: value?, cases, default })
+}
+
+// What comes after a Case, values and ranges of them
+fn case_values<R: Source>(it: &mut Tokens<R>) -> Result<Vec<CaseValue>, ParseError> {
+    let mut values = Vec::new();
+    loop {
+        let first = expression(it)?;
+        if it.peek_kind()? == Some(TokenKind::Keyword(Keyword::To)) {
+            it.next()?;
+            values.push(CaseValue::Range(first, expression(it)?));
+        }
+        else {
+            values.push(CaseValue::Value(first));
+        }
+
+        if it.peek_kind()? != Some(TokenKind::Comma) {
+            return Ok(values);
+        }
+        it.next()?;
+    }
+}
+
+// Exit For, Exit While or Exit Repeat, or just Exit
+fn exit<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let span = it.next()?.unwrap().span;
+    let named = match it.peek_kind()? {
+        Some(TokenKind::Keyword(Keyword::For)) => Some("For"),
+        Some(TokenKind::Keyword(Keyword::While)) => Some("While"),
+        Some(TokenKind::Keyword(Keyword::Repeat)) => Some("Repeat"),
+        kind if at_statement_end(kind) => None,
+        _ => {
+            return it.error(&[keyword(Keyword::For), keyword(Keyword::While), keyword(Keyword::Repeat),
+                              Expected::Token(TokenKind::EOL)]);
+        }
+    };
+    if named.is_some() {
+        it.next()?;
+    }
+
+    // Past a Function is outside of the loops too
+    let target = it.blocks.iter()
+                   .rev()
+                   .take_while(|&&inside| inside != "Function")
+                   .find(|&&inside| match named {
+                       Some(named) => inside == named,
+                       None => inside == "For" || inside == "While" || inside == "Repeat"
+                   });
+
+    match target {
+        Some(&"For") => Ok(StmtKind::Exit(Loop::For)),
+        Some(&"While") => Ok(StmtKind::Exit(Loop::While)),
+        Some(_) => Ok(StmtKind::Exit(Loop::Repeat)),
+        None => Err(ParseError::new(format!("Exit outside of {}", named.unwrap_or("a loop")), span))
+    }
+}
+
+// Where a Function or Type starts, if it's not inside anything
+fn top_level<R: Source>(it: &mut Tokens<R>, what: &str) -> Result<Span, ParseError> {
+    let span = it.span();
+    if let Some(&inside) = it.blocks.last() {
+        return Err(ParseError::new(format!("{} can't be inside {}", what, inside), span));
+    }
+
+    it.next()?;
+    Ok(span)
+}
+
+fn function_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let mark = it.mark();
+    let span = top_level(it, "Function")?;
+    let header = function_header(it);
+    if let Err(ref e) = header {
+        if broken_header(it, mark, e) {
+            return Err(e.clone());
+        }
+    }
+
+    let body = block(it, Some(("Function", span)), &[Keyword::EndFunction])?;
+    take_block_keyword(it)?;
+
+    let (name, parameters) = header?;
+    Ok(StmtKind::Function(Function {
+        name,
+        parameters,
+        body
+    }))
+}
+
+// The name and the parameters in parentheses
+fn function_header<R: Source>(it: &mut Tokens<R>) -> Result<(Variable, Vec<Variable>), ParseError> {
+    let name = variable(it)?;
+    it.expect(TokenKind::LParen)?;
+    let mut parameters = Vec::new();
+    if it.peek_kind()? != Some(TokenKind::RParen) {
+        loop {
+            parameters.push(variable(it)?);
+            if it.peek_kind()? != Some(TokenKind::Comma) {
+                break;
+            }
+            it.next()?;
+        }
+    }
+    it.expect(TokenKind::RParen)?;
+    Ok((name, parameters))
+}
+
+// Whether it can be worked out without running anything
+fn is_constant(arena: &Arena, expr: ExprId, constants: &HashMap<String, ExprId>) -> bool {
+    match arena[expr].kind {
+        ExprKind::Integer(_) | ExprKind::Float(_) | ExprKind::String(_) => true,
+        ExprKind::Variable(ref variable) => constants.contains_key(&variable.name.name),
+        ExprKind::Member { .. } | ExprKind::Call { .. } | ExprKind::Index { .. } => false,
+        ExprKind::Binary { left, right, .. } => {
+            is_constant(arena, left, constants) && is_constant(arena, right, constants)
+        },
+        ExprKind::Unary { operand, .. } => is_constant(arena, operand, constants)
+    }
+}
+
+// Only Field lines inside, each with one field or more
+fn type_statement<R: Source>(it: &mut Tokens<R>) -> Result<StmtKind, ParseError> {
+    let span = top_level(it, "Type")?;
+    if it.peek_kind()? != Some(TokenKind::Text) {
+        return it.error(&[Expected::TypeName]);
+    }
+    let token = it.next()?.unwrap();
+    let name = it.name(token.identifier().unwrap());
+    let mut fields = Vec::new();
+    let ends = [Keyword::Field, Keyword::EndType];
+
+    // After the name and after every Field line
+    let mut line_end = true;
+    loop {
+        if line_end {
+            match it.peek_kind()? {
+                None | Some(TokenKind::EOL) | Some(TokenKind::Colon) => {},
+                _ => {
+                    let error = it.unexpected(LINE_END);
+                    recover_at(it, error, &ends);
+                }
+            }
+        }
+        while let Some(TokenKind::EOL) | Some(TokenKind::Colon) = it.peek_kind()? {
+            it.next()?;
+        }
+
+        line_end = false;
+        match block_keyword(it)? {
+            Some(Keyword::Field) => {
+                it.next()?;
+                match variables(it) {
+                    Ok(variables) => {
+                        fields.extend(variables);
+                        line_end = true;
+                    },
+                    Err(e) => recover_at(it, e, &ends)
+                }
+            },
+            Some(Keyword::EndType) => {
+                take_block_keyword(it)?;
+                return Ok(StmtKind::Type(TypeDecl { name, fields }));
+            },
+            _ if it.peek_kind()?.is_none() => {
+                return Err(ParseError::new("Type is missing its EndType".to_string(), span));
+            },
+            _ => {
+                let error = it.unexpected(&[keyword(Keyword::Field), keyword(Keyword::EndType)]);
+                recover_at(it, error, &ends);
+            }
+        }
+    }
+}
+
+// What comes after Dim or ReDim, at least one array
+fn arrays<R: Source>(it: &mut Tokens<R>) -> Result<Vec<ArrayDecl>, ParseError> {
+    let mut arrays = Vec::new();
+
+    loop {
+        let variable = variable(it)?;
+        it.expect(TokenKind::LParen)?;
+        let mut dimensions = vec![expression(it)?];
+        while it.peek_kind()? == Some(TokenKind::Comma) {
+            it.next()?;
+            dimensions.push(expression(it)?);
+        }
+        it.expect(TokenKind::RParen)?;
+
+        it.arrays.entry(variable.name.name.clone()).or_insert(dimensions.len());
+        arrays.push(ArrayDecl { variable, dimensions });
+        if it.peek_kind()? != Some(TokenKind::Comma) {
+            return Ok(arrays);
+        }
+        it.next()?;
+    }
+}
+
+// The label after Goto, Gosub or Restore, which can come later in the program
+fn jump<R: Source>(it: &mut Tokens<R>) -> Result<Name, ParseError> {
+    if it.peek_kind()? != Some(TokenKind::Text) {
+        return it.error(&[Expected::Label]);
+    }
+
+    let token = it.next()?.unwrap();
+    let name = it.name(token.identifier().unwrap());
+    let span = token.span;
+    it.jumps.push((name.clone(), span));
+    Ok(name)
+}
+
+// Whatever could be parsed, the errors are left in it
+// Makes f(a) an Index if f is an array
+struct Indexes<'a>(&'a HashMap<String, usize>);
+
+impl<'a> Fold for Indexes<'a> {
+    fn fold_expr(&mut self, arena: &mut Arena, expr: ExprId) -> ExprId {
+        let expr = walk_expr(self, arena, expr);
+        let kind = match arena[expr].kind {
+            ExprKind::Call { ref name, ref arguments } if self.0.contains_key(&name.name.name) => {
+                ExprKind::Index { array: name.clone(), indices: arguments.clone() }
+            },
+            _ => return expr
+        };
+        arena[expr].kind = kind;
+        expr
+    }
+}
+
+// Each comment goes to a statement of the innermost block it's in that has
+// any, or the program: the one it's after on the same line or inside of,
+// or else the next one, or else the last one
+fn attach_comments(arena: &Arena, comments: Vec<(Comment, bool)>,
+                   bodies: &[(usize, usize, Vec<StmtId>)], statements: &[StmtId])
+                   -> HashMap<StmtId, Comments> {
+    let mut attached = HashMap::new();
+    for (comment, own_line) in comments {
+        let offset = comment.span.byte_offset;
+        let block = bodies.iter()
+            .filter(|&&(start, end, ref block)| start <= offset && offset < end && !block.is_empty())
+            .min_by_key(|&&(start, end, _)| end - start)
+            .map_or(statements, |(_, _, block)| &block[..]);
+
+        let before = block.iter().rev().cloned().find(|&stmt| arena[stmt].span.byte_offset <= offset);
+        let after = block.iter().cloned().find(|&stmt| arena[stmt].span.byte_offset > offset);
+        let inside = before.is_some_and(|stmt| {
+            let span = arena[stmt].span;
+            offset < span.byte_offset + span.length
+        });
+        let (stmt, leading) = match (before, after) {
+            (Some(before), _) if inside || !own_line => (before, false),
+            (_, Some(after)) => (after, true),
+            (Some(before), None) => (before, false),
+            (None, None) => continue
+        };
+
+        let comments = attached.entry(stmt).or_insert_with(Comments::default);
+        if leading {
+            comments.leading.push(comment);
+        }
+        else {
+            comments.trailing.push(comment);
+        }
+    }
+    attached
+}
+
+fn count(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+// What can only be checked once the whole program is there, then f(a)
+// where f is an array becomes an Index
+fn resolve<R: Source>(it: &mut Tokens<R>, statements: Vec<StmtId>) -> Vec<StmtId> {
+    for &(ref name, span) in &it.jumps {
+        if !it.labels.contains_key(&name.name) {
+            it.errors.push(ParseError::new(format!("Label {} isn't defined", name.spelling), span));
+        }
+    }
+
+    // An array can be used before its Dim, in a Function above it
+    for &(ref name, indices, span) in &it.indexes {
+        match it.arrays.get(&name.name) {
+            Some(&dimensions) if dimensions != indices => {
+                let message = format!("{} for {}, it has {}", count(indices, "index", "indices"),
+                                      name.spelling, count(dimensions, "dimension", "dimensions"));
+                it.errors.push(ParseError::new(message, span));
+            },
+            _ => {}
+        }
+    }
+    Indexes(&it.arrays).fold_block(&mut it.arena, statements)
+}
+
+fn program<R: Source>(it: &mut Tokens<R>) -> Program {
+    // Only a block that has an opening can be missing its end
+    let statements = match block(it, None, &[]) {
+        Ok(statements) => statements,
+        Err(e) => {
+            it.errors.push(e);
+            Vec::new()
+        }
+    };
+    let statements = resolve(it, statements);
+
+    if it.lossless {
+        it.take_trailing();
+    }
+    let comments = mem::take(&mut it.comments);
+    let comments = attach_comments(&it.arena, comments, &it.bodies, &statements);
+
+    Program {
+        statements,
+        arena: mem::take(&mut it.arena),
+        labels: it.labels.clone(),
+        constants: it.constants.clone(),
+        includes: it.includes.clone(),
+        comments
+    }
+}
+
+// The first error, see parse_program_recovering for all of them
+pub fn parse_program<R: Source>(tokens: TokenIterator<R>) -> Result<Program, ParseError> {
+    let (program, errors) = parse_program_recovering(tokens);
+    match errors.into_iter().next() {
+        Some(error) => Err(error),
+        None => Ok(program)
+    }
+}
+
+// Every error instead of just the first, for an editor that shows them
+// all at once
+// A statement with an error in it is skipped to the end of its line or to
+// whatever ends the block it's in, or to its own end if the error's in the
+// line that opens a block, and the program has everything else.
+// Errors are in the order they're in the source, except for undefined
+// labels which come last
+pub fn parse_program_recovering<R: Source>(tokens: TokenIterator<R>) -> (Program, Vec<ParseError>) {
+    let mut it = Tokens::new(tokens);
+    let program = program(&mut it);
+    (program, it.errors)
+}
+
+// The Cst too, the tokens have to be lexed with LexerConfig::lossless for
+// it to have all of the source
+pub fn parse_program_cst<R: Source>(tokens: TokenIterator<R>) -> (Program, Cst, Vec<ParseError>) {
+    let mut it = Tokens::new(tokens);
+    it.elements = Some(Vec::new());
+    let program = program(&mut it);
+
+    it.wrap(0, NodeKind::Program);
+    let root = match it.elements.take().unwrap().pop() {
+        Some(Element::Node(root)) => root,
+        _ => unreachable!()
+    };
+    let cst = Cst {
+        root,
+        trailing: it.trailing
+    };
+    (program, cst, it.errors)
+}
+
+pub fn parse_str(source: &str) -> Result<Program, ParseError> {
+    parse_program(TokenIterator::from_str(source))
+}
+
+pub fn parse_str_recovering(source: &str) -> (Program, Vec<ParseError>) {
+    parse_program_recovering(TokenIterator::from_str(source))
+}
+
+pub fn parse_str_cst(source: &str) -> (Program, Cst, Vec<ParseError>) {
+    let config = LexerConfig::default().lossless(true);
+    parse_program_cst(TokenIterator::new_with_config(source.as_bytes(), config))
+}
+
+// The next top-level statement for a StatementParser and the separator
+// after it, the errors are left in it
+// None if there's no statement yet, or if more could still be coming
+// that's part of it, like the rest of a block or of a block comment. Blank
+// lines are left for the statement after them, unless they're the last
+// ones and have errors on them, which is Some(None), like a statement that
+// doesn't parse
+fn next_statement<R: Source>(it: &mut Tokens<R>, last: bool) -> Option<Option<StmtId>> {
+    loop {
+        match it.peek_kind() {
+            Ok(Some(TokenKind::EOL)) | Ok(Some(TokenKind::Colon)) => {
+                it.next().ok();
+            },
+            Ok(None) if !last || it.errors.is_empty() => return None,
+            Ok(None) => return Some(None),
+            Ok(Some(_)) => break,
+            Err(e) => recover(it, e)
+        }
+    }
+
+    let stmt = match separated_statement(it) {
+        Ok(stmt) => Some(stmt),
+        Err(e) => {
+            recover(it, e);
+            None
+        }
+    };
+    if it.ran_out && !last {
+        return None;
+    }
+    match it.peek_kind() {
+        Ok(Some(TokenKind::EOL)) | Ok(Some(TokenKind::Colon)) => {
+            it.next().ok();
+        },
+        _ => {}
+    }
+    Some(stmt)
+}
+
+// Parses a program as it comes in, for a REPL or live coding
+// Each top-level statement comes out of feed as soon as the lines it's on
+// are complete, after the errors in it, and goes in the program. One that
+// opens a block waits for the line that ends it
+// Labels, constants and arrays carry on from one statement to the next,
+// but f(a) is only an Index for arrays Dim'd before it until finish, which
+// also checks Gotos and the indices of arrays against the whole program
+pub struct StatementParser {
+    config: LexerConfig,
+    interner: Interner,
+    program: Program,
+    jumps: Vec<(Name, Span)>,
+    arrays: HashMap<String, usize>,
+    indexes: Vec<(Name, usize, Span)>,
+    // What's been fed but isn't in a statement yet, where it starts, and
+    // whether that's at the start of a line
+    pending: Vec<u8>,
+    start: Span,
+    line_start: bool
+}
+
+impl StatementParser {
+    pub fn new() -> StatementParser {
+        StatementParser::new_with_config(LexerConfig::default())
+    }
+
+    pub fn new_with_config(config: LexerConfig) -> StatementParser {
+        StatementParser {
+            config,
+            interner: Interner::new(),
+            program: Program {
+                statements: Vec::new(),
+                arena: Arena::new(),
+                labels: HashMap::new(),
+                constants: HashMap::new(),
+                includes: Vec::new(),
+                comments: HashMap::new()
+            },
+            jumps: Vec::new(),
+            arrays: HashMap::new(),
+            indexes: Vec::new(),
+            pending: Vec::new(),
+            start: Span { line: 1, column: 1, byte_offset: 0, length: 0 },
+            line_start: true
+        }
+    }
+
+    // Every statement so far
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn into_program(self) -> Program {
+        self.program
+    }
+
+    // The statements the bytes complete
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<Result<StmtId, ParseError>> {
+        self.pending.extend_from_slice(bytes);
+
+        // Up to the last line break, a CR at the very end could still get
+        // its LF
+        let lone_cr = self.config.lone_cr_is_newline;
+        let length = self.pending.len();
+        let end = (0..length).rev().find(|&i| match self.pending[i] {
+            b'\n' => true,
+            b'\r' => lone_cr && i + 1 < length,
+            _ => false
+        });
+        match end {
+            Some(end) => self.parse(end + 1, false),
+            None => Vec::new()
+        }
+    }
+
+    // Whatever is left, there's nothing more coming. A block that's still
+    // open is missing its end
+    pub fn finish(&mut self) -> Vec<Result<StmtId, ParseError>> {
+        let end = self.pending.len();
+        let mut results = self.parse(end, true);
+
+        let mut it = self.tokens(&[]);
+        self.swap(&mut it);
+        let statements = mem::take(&mut self.program.statements);
+        self.program.statements = resolve(&mut it, statements);
+        self.swap(&mut it);
+        results.extend(it.errors.into_iter().map(Err));
+        results
+    }
+
+    fn tokens<'a>(&self, source: &'a [u8]) -> Tokens<&'a [u8]> {
+        let tokens = TokenIterator::new_with_config(source, self.config.clone())
+                     .with_interner(self.interner.clone())
+                     .continue_from(self.start.byte_offset, self.start.line, self.start.column);
+        let mut it = Tokens::new(tokens);
+        it.end = self.start;
+        it.cursor = self.start;
+        it.line_start = self.line_start;
+        it
+    }
+
+    // What's kept from one statement to the next goes into the Tokens for
+    // one, and back out after
+    fn swap<R: Source>(&mut self, it: &mut Tokens<R>) {
+        mem::swap(&mut it.arena, &mut self.program.arena);
+        mem::swap(&mut it.labels, &mut self.program.labels);
+        mem::swap(&mut it.constants, &mut self.program.constants);
+        mem::swap(&mut it.includes, &mut self.program.includes);
+        mem::swap(&mut it.jumps, &mut self.jumps);
+        mem::swap(&mut it.arrays, &mut self.arrays);
+        mem::swap(&mut it.indexes, &mut self.indexes);
+    }
+
+    // The statements in the first end bytes of what's pending
+    fn parse(&mut self, end: usize, last: bool) -> Vec<Result<StmtId, ParseError>> {
+        let mut results = Vec::new();
+        let mut pending = mem::take(&mut self.pending);
+        let mut taken = 0;
+
+        loop {
+            let source = &pending[taken..end];
+
+            // Whether it's complete, without changing anything that's kept
+            if !last && next_statement(&mut self.tokens(source), false).is_none() {
+                break;
+            }
+
+            let mut it = self.tokens(source);
+            self.swap(&mut it);
+            let stmt = next_statement(&mut it, last);
+            self.swap(&mut it);
+            let stmt = match stmt {
+                Some(stmt) => stmt,
+                None => {
+                    if last {
+                        self.attach_trailing(&mut it);
+                    }
+                    break;
+                }
+            };
+
+            results.extend(it.errors.drain(..).map(Err));
+            if let Some(stmt) = stmt {
+                let stmt = Indexes(&self.arrays).fold_stmt(&mut self.program.arena, stmt);
+                self.program.statements.push(stmt);
+                results.push(Ok(stmt));
+            }
+            if it.lossless {
+                self.attach_comments(&mut it);
+            }
+
+            // An error at the very end, like a comment that's still open,
+            // takes nothing with it
+            let length = it.end.byte_offset - self.start.byte_offset;
+            if length == 0 {
+                if last {
+                    self.attach_trailing(&mut it);
+                }
+                break;
+            }
+            let span = Span { length, .. self.start };
+            let (line, column) = position_after(source, self.start.byte_offset, span, &self.config);
+            self.start = Span {
+                line,
+                column,
+                byte_offset: it.end.byte_offset,
+                length: 0
+            };
+            self.line_start = it.line_start;
+            taken += length;
+        }
+
+        pending.drain(..taken);
+        self.pending = pending;
+        results
+    }
+
+    // The comments after the last statement, once nothing more is coming
+    fn attach_trailing<R: Source>(&mut self, it: &mut Tokens<R>) {
+        if it.lossless {
+            it.take_trailing();
+            self.attach_comments(it);
+        }
+    }
+
+    // Those of the last statement go with it or the one before, the ones
+    // before the first statement have nothing to go with
+    fn attach_comments<R: Source>(&mut self, it: &mut Tokens<R>) {
+        let comments = mem::take(&mut it.comments);
+        let statements = &self.program.statements;
+        let recent = &statements[statements.len().saturating_sub(2)..];
+        for (stmt, found) in attach_comments(&self.program.arena, comments, &it.bodies, recent) {
+            let comments = self.program.comments.entry(stmt).or_default();
+            comments.leading.extend(found.leading);
+            comments.trailing.extend(found.trailing);
+        }
+    }
+}
+
+impl Default for StatementParser {
+    fn default() -> StatementParser {
+        StatementParser::new()
+    }
+}
+
+// The file and every file it includes, each parsed once into a program of
+// its own, root first
+// Included files are looked up next to the file including them. Labels
+// and constants are only seen in the file they're in
+pub fn parse_sources(sources: &mut SourceManager, root: FileId)
+                     -> Result<Vec<(FileId, Program)>, (FileId, ParseError)> {
+    let mut programs = Vec::new();
+    let mut queue = vec![root];
+
+    while let Some(file) = queue.pop() {
+        if programs.iter().any(|&(parsed, _)| parsed == file) {
+            continue;
+        }
+
+        let program = parse_program(sources.tokenizer(file)).map_err(|e| (file, e))?;
+        let directory = sources.path(file).parent().map(Path::to_path_buf).unwrap_or_default();
+
+        // Backwards so they get parsed in order
+        for &(ref name, span) in program.includes.iter().rev() {
+            let path = directory.join(name);
+            match sources.load(&path) {
+                Ok(included) => queue.push(included),
+                Err(e) => {
+                    let message = format!("Couldn't include {}: {}", path.display(), e);
+                    return Err((file, ParseError::new(message, span)));
+                }
+            }
+        }
+
+        programs.push((file, program));
+    }
+
+    Ok(programs)
+}
+
+// Read errors are parse errors at the very start
+// For another LexerConfig, use parse_program with TokenIterator::open_with_config
+pub fn parse_file<P: AsRef<Path>>(path: P) -> Result<Program, ParseError> {
+    match TokenIterator::open(path.as_ref()) {
+        Ok(tokens) => parse_program(tokens),
+        Err(e) => Err(ParseError::new(format!("Couldn't read {}: {}", path.as_ref().display(), e),
+                                      Span { line: 1, column: 1, byte_offset: 0, length: 0 }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use visit::{walk_expr, walk_stmt, Visitor};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use dump::write_program_sexp;
+
+    // The program and the value of its first statement, an assignment
+    fn assigned(source: &str) -> (Program, ExprId) {
+        let program = parse_str(source).unwrap();
+        match program.arena[program.statements[0]].kind {
+            StmtKind::Assign { value, .. } => (program, value),
+            ref kind => panic!("not an assignment: {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn declaration_default_can_be_negative() {
+        match parse(TokenIterator::from_str("Function f(a = -1, b$ = \"x\")\n")).unwrap() {
+            Node::Root { ref functions } => assert_eq!(functions.len(), 1),
+            node => panic!("not a root: {:?}", node)
+        }
+    }
+
+    #[test]
+    fn minus_one_is_a_negation() {
+        let (program, value) = assigned("x = -1");
+        match program.arena[value].kind {
+            ExprKind::Unary { op: UnaryOp::Negate, operand } => {
+                assert_eq!(program.arena[operand].kind, ExprKind::Integer(1));
+                assert_eq!(program.arena[operand].span.column, 6);
+            },
+            ref kind => panic!("not a negation: {:?}", kind)
+        }
+        assert_eq!(program.arena[value].span.column, 5);
+    }
+
+    #[test]
+    fn negation_binds_looser_than_power() {
+        let (program, value) = assigned("x = -2 ^ 2");
+        match program.arena[value].kind {
+            ExprKind::Unary { op: UnaryOp::Negate, operand } => match program.arena[operand].kind {
+                ExprKind::Binary { op: BinaryOp::Power, .. } => {},
+                ref kind => panic!("not a power: {:?}", kind)
+            },
+            ref kind => panic!("not a negation: {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn minus_without_spaces_is_a_subtraction() {
+        let (program, value) = assigned("x = a-1");
+        match program.arena[value].kind {
+            ExprKind::Binary { op: BinaryOp::Subtract, right, .. } => {
+                assert_eq!(program.arena[right].kind, ExprKind::Integer(1));
+            },
+            ref kind => panic!("not a subtraction: {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn const_and_data_get_negative_literals() {
+        let program = parse_str("Const LOW = -3\nData -1, - 2.5, \"a\"").unwrap();
+        assert_eq!(program.arena[program.constants["low"]].kind, ExprKind::Integer(-3));
+        match program.arena[program.statements[1]].kind {
+            StmtKind::Data(ref values) => {
+                let kinds: Vec<_> = values.iter().map(|&value| &program.arena[value].kind).collect();
+                assert_eq!(kinds, vec![&ExprKind::Integer(-1), &ExprKind::Float(-2.5),
+                                       &ExprKind::String("a".to_string())]);
+            },
+            ref kind => panic!("not Data: {:?}", kind)
+        }
+    }
+
+    // The program as one line of S-expression, without the spans
+    fn tree(source: &str) -> String {
+        match parse_str(source) {
+            Ok(program) => sexp(&program),
+            Err(e) => panic!("{}: {}", e.span, e.message)
+        }
+    }
+
+    // Without the spans
+    fn sexp(program: &Program) -> String {
+        let mut out = Vec::new();
+        write_program_sexp(&mut out, program).unwrap();
+
+        let is_span = |word: &str| {
+            let parts: Vec<_> = word.split(':').collect();
+            parts.len() == 2 && parts.iter().all(|part| {
+                !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit())
+            })
+        };
+        let mut words: Vec<String> = Vec::new();
+        for word in String::from_utf8(out).unwrap().split_whitespace() {
+            let bare = word.trim_end_matches(')');
+            match words.last_mut() {
+                Some(last) if is_span(bare) => last.push_str(&word[bare.len()..]),
+                _ => words.push(word.to_string())
+            }
+        }
+        words.join(" ")
+    }
+
+    // Where the error is and what it says
+    fn error(source: &str) -> String {
+        match parse_str(source) {
+            Ok(program) => panic!("parsed: {:?}", program.statements),
+            Err(e) => format!("{}: {}", e.span, e.message)
+        }
+    }
+
+    #[test]
+    fn statements_in_order() {
+        assert_eq!(tree("x = 1\n\n  End : y = x\n"),
+                   "(Program (Assign target: (Variable \"x\") value: (Integer 1)) (End) \
+                    (Assign target: (Variable \"y\") value: (Variable \"x\")))");
+        assert_eq!(tree(""), "(Program)");
+        assert_eq!(error("x = 1 @"), "1:7: Invalid or unhandled byte 64 encountered");
+    }
+
+    #[test]
+    fn files_are_read_and_parsed() {
+        let path = env::temp_dir().join(format!("cblia-parser-{}.cb", process::id()));
+        fs::write(&path, "Print 1\n").unwrap();
+        let program = parse_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(program.unwrap().statements.len(), 1);
+
+        let missing = parse_file("there/is/no/such.cb").unwrap_err();
+        assert!(missing.message.starts_with("Couldn't read there/is/no/such.cb"),
+                "{}", missing.message);
+    }
+
+    // Just the expression, as the value of an assignment
+    fn value(expr: &str) -> String {
+        let tree = tree(&format!("x = {}", expr));
+        let head = "(Program (Assign target: (Variable \"x\") value: ";
+        assert!(tree.starts_with(head) && tree.ends_with("))"), "{}", tree);
+        tree[head.len()..tree.len() - 2].to_string()
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(value("1 + 2 * 3"),
+                   "(Binary Add left: (Integer 1) \
+                    right: (Binary Multiply left: (Integer 2) right: (Integer 3)))");
+        assert_eq!(value("10 - 4 - 3"),
+                   "(Binary Subtract left: (Binary Subtract left: (Integer 10) right: (Integer 4)) \
+                    right: (Integer 3))");
+        // Power goes the other way
+        assert_eq!(value("2 ^ 3 ^ 2"),
+                   "(Binary Power left: (Integer 2) \
+                    right: (Binary Power left: (Integer 3) right: (Integer 2)))");
+        assert_eq!(value("4 / 2 Mod 3"),
+                   "(Binary Modulo left: (Binary Divide left: (Integer 4) right: (Integer 2)) \
+                    right: (Integer 3))");
+        assert_eq!(value("a + 1 < b * 2"),
+                   "(Binary Less left: (Binary Add left: (Variable \"a\") right: (Integer 1)) \
+                    right: (Binary Multiply left: (Variable \"b\") right: (Integer 2)))");
+        assert_eq!(value("(1 + 2) * 3"),
+                   "(Binary Multiply left: (Binary Add left: (Integer 1) right: (Integer 2)) \
+                    right: (Integer 3))");
+    }
+
+    #[test]
+    fn unfinished_expressions() {
+        assert_eq!(error("x = 1 +\n"), "1:8: Expected an expression, found end of line");
+        assert_eq!(error("x = (1\n"), "1:7: Expected `)`, found end of line");
+    }
+
+    #[test]
+    fn print_keeps_its_separators() {
+        assert_eq!(tree("Print\nPrint 1, \"a\"; x;\n? a"),
+                   "(Program (Print) (Print (Integer 1) (Comma) (String \"a\") (Semicolon) \
+                    (Variable \"x\") (Semicolon)) (Print (Variable \"a\")))");
+    }
+
+    #[test]
+    fn single_line_if() {
+        assert_eq!(tree("If a Then b = 1 Else b = 2"),
+                   "(Program (If single_line condition: (Variable \"a\") \
+                    then: (Assign target: (Variable \"b\") value: (Integer 1)) \
+                    else: (Assign target: (Variable \"b\") value: (Integer 2))))");
+        assert_eq!(tree("If a Then b : c Else d"),
+                   "(Program (If single_line condition: (Variable \"a\") \
+                    then: (Call \"b\") then: (Call \"c\") else: (Call \"d\")))");
+    }
+
+    #[test]
+    fn block_if_with_elseifs() {
+        let source = "If a Then\nb\nElseIf c Then\nd\nElse If e Then\nf\nElse\ng\nEndIf\n";
+        assert_eq!(tree(source),
+                   "(Program (If condition: (Variable \"a\") then: (Call \"b\") \
+                    (ElseIf condition: (Variable \"c\") then: (Call \"d\")) \
+                    (ElseIf condition: (Variable \"e\") then: (Call \"f\")) else: (Call \"g\")))");
+        // Then can be left out and EndIf split in two
+        assert_eq!(tree("If a\nb\nEnd If"),
+                   "(Program (If condition: (Variable \"a\") then: (Call \"b\")))");
+        assert_eq!(error("If a Then\nb\n"), "1:1: If is missing its EndIf");
+        assert_eq!(error("If a Then\nElse\nElse\nEndIf"), "3:1: Expected a statement, found `Else`");
+    }
+
+    #[test]
+    fn for_with_and_without_step() {
+        assert_eq!(tree("For i = 1 To 10\nNext"),
+                   "(Program (For \"i\" from: (Integer 1) to: (Integer 10)))");
+        assert_eq!(tree("For i# = 10 To 1 Step -0.5\n    x\nNext i#"),
+                   "(Program (For \"i#\" from: (Integer 10) to: (Integer 1) \
+                    step: (Unary Negate (Float 0.5)) body: (Call \"x\")))");
+    }
+
+    #[test]
+    fn next_has_to_match() {
+        assert_eq!(error("For i = 1 To 2\nNext j"), "2:6: Next j doesn't go with For i");
+        assert_eq!(error("For i = 1 To 2\nx\n"), "1:1: For is missing its Next");
+    }
+
+    #[test]
+    fn while_loops() {
+        assert_eq!(tree("While a\nb\nWend"),
+                   "(Program (While condition: (Variable \"a\") body: (Call \"b\")))");
+        assert_eq!(error("While a\nb\n"), "1:1: While is missing its Wend");
+    }
+
+    #[test]
+    fn repeat_loops() {
+        assert_eq!(tree("Repeat\na\nUntil b"),
+                   "(Program (Repeat body: (Call \"a\") until: (Variable \"b\")))");
+        assert_eq!(tree("Repeat\na\nForever"), "(Program (Repeat body: (Call \"a\")))");
+        assert_eq!(error("Repeat\na\n"), "1:1: Repeat is missing its Until");
+    }
+
+    #[test]
+    fn select_with_values_ranges_and_default() {
+        let source = "Select a\nCase 1, 3 To 5\nb\nCase \"x\"\nDefault\nc\nEndSelect";
+        assert_eq!(tree(source),
+                   "(Program (Select value: (Variable \"a\") \
+                    (Case value: (Integer 1) value: (Range from: (Integer 3) to: (Integer 5)) \
+                    body: (Call \"b\")) (Case value: (String \"x\")) (Default body: (Call \"c\"))))");
+        assert_eq!(tree("Select a\nCase Else\nc\nEnd Select"),
+                   "(Program (Select value: (Variable \"a\") (Default body: (Call \"c\"))))");
+        assert_eq!(error("Select a\nCase 1\n"), "1:1: Select is missing its EndSelect");
+    }
+
+    #[test]
+    fn jumps_go_to_labels() {
+        let source = "top:\nGosub sub\nGoto top\nsub:\nReturn";
+        assert_eq!(tree(source),
+                   "(Program (Label \"top\") (Gosub \"sub\") (Goto \"top\") (Label \"sub\") (Return))");
+        let program = parse_str(source).unwrap();
+        assert_eq!(program.labels.len(), 2);
+        assert_eq!(program.labels["sub"].line, 4);
+    }
+
+    #[test]
+    fn labels_have_to_be_there_once() {
+        assert_eq!(error("Goto nowhere"), "1:6: Label nowhere isn't defined");
+        assert_eq!(error("a:\na:"), "2:1: Label a is already defined");
+    }
+
+    #[test]
+    fn arrays_are_declared_with_their_sizes() {
+        assert_eq!(tree("Dim a(10), b$(2, 3)\nReDim a(20)"),
+                   "(Program (Dim (Array \"a\" (Integer 10)) (Array \"b$\" (Integer 2) (Integer 3))) \
+                    (ReDim (Array \"a\" (Integer 20))))");
+        assert_eq!(error("Dim a\n"), "1:6: Expected `(`, found end of line");
+    }
+
+    #[test]
+    fn functions_have_typed_parameters() {
+        assert_eq!(tree("Function f#(a, b$)\nReturn a\nEndFunction\nFunction g()\nEndFunction"),
+                   "(Program (Function \"f#\" parameter: (Variable \"a\") parameter: (Variable \"b$\") \
+                    body: (Return (Variable \"a\"))) (Function \"g\"))");
+    }
+
+    #[test]
+    fn functions_only_at_the_top() {
+        assert_eq!(error("Function f()\nFunction g()\nEndFunction\nEndFunction"),
+                   "2:1: Function can't be inside Function");
+        assert_eq!(error("Function f()\n"), "1:1: Function is missing its EndFunction");
+    }
+
+    #[test]
+    fn exit_knows_its_loop() {
+        // On its own it's whatever loop it's in
+        assert_eq!(tree("While a\nRepeat\nExit While\nExit\nForever\nWend"),
+                   "(Program (While condition: (Variable \"a\") \
+                    body: (Repeat body: (Exit While) body: (Exit Repeat))))");
+        assert_eq!(error("Exit\n"), "1:1: Exit outside of a loop");
+        assert_eq!(error("For i = 1 To 2\nExit While\nNext"), "2:1: Exit outside of While");
+    }
+
+    #[test]
+    fn return_with_and_without_a_value() {
+        assert_eq!(tree("Function f()\nReturn 1 + 2\nEndFunction\nReturn"),
+                   "(Program (Function \"f\" \
+                    body: (Return (Binary Add left: (Integer 1) right: (Integer 2)))) (Return))");
+    }
+
+    #[test]
+    fn data_read_and_restore() {
+        assert_eq!(tree("Data 1, \"a\", 2.5\nRead a, b$, c#\nRestore\nl:\nRestore l"),
+                   "(Program (Data (Integer 1) (String \"a\") (Float 2.5)) (Read \"a\" \"b$\" \"c#\") \
+                    (Restore) (Label \"l\") (Restore \"l\"))");
+        assert_eq!(error("Restore nowhere"), "1:9: Label nowhere isn't defined");
+        assert_eq!(error("Data x"), "1:6: Expected a number or a string, found identifier");
+        assert_eq!(error("Read 1"), "1:6: Expected a variable, found integer");
+    }
+
+    #[test]
+    fn constants_are_in_the_table() {
+        let source = "Const MAX = 10\nConst HALF# = MAX / 2\nConst NAME$ = \"x\" + \"y\"";
+        assert_eq!(tree(source),
+                   "(Program (Const \"MAX\" (Integer 10)) \
+                    (Const \"HALF#\" (Binary Divide left: (Variable \"MAX\") right: (Integer 2))) \
+                    (Const \"NAME$\" (Binary Concat left: (String \"x\") right: (String \"y\"))))");
+        let program = parse_str(source).unwrap();
+        let mut names: Vec<_> = program.constants.keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["half", "max", "name"]);
+    }
+
+    #[test]
+    fn constants_only_have_constants_in_them() {
+        let message = "1:11: Const A can only have literals and constants in it";
+        assert_eq!(error("Const A = b"), message);
+        assert_eq!(error("Const A = Rnd(1)"), message);
+    }
+
+    #[test]
+    fn types_and_their_fields() {
+        assert_eq!(tree("Type Point\nField x\nField name$\nEndType\np\\x = 1\ny = p\\name$"),
+                   "(Program (Type \"Point\" field: (Variable \"x\") field: (Variable \"name$\")) \
+                    (Assign target: (Member \"x\" (Variable \"p\")) value: (Integer 1)) \
+                    (Assign target: (Variable \"y\") value: (Member \"name$\" (Variable \"p\"))))");
+        assert_eq!(tree("Type P\nEndType"), "(Program (Type \"P\"))");
+    }
+
+    #[test]
+    fn declarations_have_their_scope() {
+        assert_eq!(tree("Global a, b$\nFunction f()\nLocal c#\nEndFunction"),
+                   "(Program (Declare Global \"a\" \"b$\") \
+                    (Function \"f\" body: (Declare Local \"c#\")))");
+        assert_eq!(error("Function f()\nGlobal a\nEndFunction"),
+                   "2:1: Global can't be inside Function");
+        }
+
+    #[test]
+    fn includes_are_recorded() {
+        assert_eq!(tree("Include \"lib.cb\"\nx = 1"),
+                   "(Program (Include \"lib.cb\") \
+                    (Assign target: (Variable \"x\") value: (Integer 1)))");
+        let program = parse_str("\nInclude \"lib.cb\"").unwrap();
+        assert_eq!(program.includes[0].0, "lib.cb");
+        assert_eq!(program.includes[0].1.line, 2);
+        assert_eq!(error("Include x\n"), "1:10: Include needs a file name string");
+    }
+
+    #[test]
+    fn every_included_file_is_parsed_once() {
+        let mut sources = SourceManager::new(LexerConfig::new());
+        let main = sources.add("project/main.cb", b"Include \"a.cb\"\nInclude \"b.cb\"".to_vec());
+        let a = sources.add("project/a.cb", b"Include \"b.cb\"\nPrint 1".to_vec());
+        let b = sources.add("project/b.cb", b"x = 2".to_vec());
+
+        let programs = parse_sources(&mut sources, main).unwrap();
+        let files: Vec<_> = programs.iter().map(|&(file, _)| file).collect();
+        assert_eq!(files, vec![main, a, b]);
+        assert_eq!(programs[2].1.statements.len(), 1);
+
+        let broken = sources.add("project/broken.cb", b"Include \"missing.cb\"".to_vec());
+        let (file, e) = parse_sources(&mut sources, broken).unwrap_err();
+        assert_eq!(file, broken);
+        assert!(e.message.starts_with("Couldn't include project/missing.cb"), "{}", e.message);
+    }
+
+    #[test]
+    fn errors_say_what_was_expected() {
+        let e = parse_str("If a, b Then").unwrap_err();
+        assert_eq!(e.message, "Expected `Then` or end of line, found `,`");
+        assert_eq!(e.expected, vec![Expected::Token(TokenKind::Keyword(Keyword::Then)),
+                                    Expected::Token(TokenKind::EOL)]);
+        assert_eq!((e.found, e.span.column, e.span.length), (Some(TokenKind::Comma), 5, 1));
+
+        let e = parse_str("x = 1 +").unwrap_err();
+        assert_eq!(e.message, "Expected an expression, found end of file");
+        assert_eq!((e.expected, e.found), (vec![Expected::Expression], None));
+
+        // Not about the next token at all
+        let e = parse_str("Goto x").unwrap_err();
+        assert!(e.expected.is_empty());
+    }
+
+    fn recovered(source: &str) -> (usize, Vec<String>) {
+        let (program, errors) = parse_str_recovering(source);
+        (program.statements.len(), errors.iter().map(|e| e.to_string()).collect())
+    }
+
+    #[test]
+    fn every_bad_statement_is_an_error() {
+        assert_eq!(recovered("x = \ny = 1\nIf a,\nPrint )\nEndIf\nz = 2"),
+                   (2, vec!["1:5: Expected an expression, found end of line".to_string(),
+                            "3:5: Expected `Then` or end of line, found `,`".to_string(),
+                            "4:7: Expected an expression, found `)`".to_string()]));
+        // The rest of the line goes with it
+        assert_eq!(recovered("x = 1 : ) : y = 2"),
+                   (1, vec!["1:9: Expected a statement, found `)`".to_string()]));
+    }
+
+    #[test]
+    fn blocks_go_on_after_an_error_inside() {
+        let (program, errors) = parse_str_recovering("While a\nx = (\nWend\ny = 1");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(program.statements.len(), 2);
+        match program.arena[program.statements[0]].kind {
+            StmtKind::While { ref body, .. } => assert!(body.is_empty()),
+            ref kind => panic!("not a While: {:?}", kind)
+        }
+    }
+
+    #[test]
+    fn blocks_go_on_after_an_error_in_their_header() {
+        // Their ends aren't errors and neither is what's inside
+        assert_eq!(recovered("If a Or Then\nEndIf"),
+                   (0, vec!["1:9: Expected an expression, found `Then`".to_string()]));
+        assert_eq!(recovered("If x, y\nExit\nEndIf\nz = 1"),
+                   (1, vec!["1:5: Expected `Then` or end of line, found `,`".to_string(),
+                            "2:1: Exit outside of a loop".to_string()]));
+        assert_eq!(recovered("While 1\nFor i = \nExit For\nNext\nWend"),
+                   (1, vec!["2:9: Expected an expression, found end of line".to_string()]));
+        assert_eq!(recovered("Select (\nCase 1, )\nx = 1\nCase 2\nEndSelect"),
+                   (0, vec!["1:9: Expected an expression, found end of line".to_string(),
+                            "2:9: Expected an expression, found `)`".to_string()]));
+        assert_eq!(recovered("Function f(a\nReturn a\nEndFunction"),
+                   (0, vec!["1:13: Expected `)`, found end of line".to_string()]));
+        // A one line If is just skipped
+        assert_eq!(recovered("If a Or Then x = 1\ny = 2"),
+                   (1, vec!["1:9: Expected an expression, found `Then`".to_string()]));
+
+        // The rest of an If is still there without the ElseIf, and a For
+        // with the wrong Next is still the loop
+        assert_eq!(sexp(&parse_str_recovering("If a\nElseIf )\nx = 1\nElse\ny = 1\nEndIf").0),
+                   "(Program (If condition: (Variable \"a\") else: (Assign target: (Variable \"y\") \
+                    value: (Integer 1))))");
+        assert_eq!(sexp(&parse_str_recovering("For i = 1 To 2\nPrint i\nNext j").0),
+                   "(Program (For \"i\" from: (Integer 1) to: (Integer 2) body: (Print \
+                    (Variable \"i\"))))");
+        assert_eq!(recovered("For i = 1 To 2\nPrint i\nNext j").1,
+                   vec!["3:6: Next j doesn't go with For i".to_string()]);
+
+        // The header's an Error node of its own
+        let (_, cst, _) = parse_str_cst("For i = \nPrint i\nNext");
+        let kinds = |node: &cst::Node| node.children.iter().filter_map(|child| match *child {
+            Element::Node(ref node) => Some(node.kind),
+            Element::Token(_) => None
+        }).collect::<Vec<_>>();
+        let statement = match cst.root.children[0] {
+            Element::Node(ref node) => node,
+            _ => panic!("not a node")
+        };
+        assert_eq!(statement.kind, NodeKind::Error);
+        assert_eq!(kinds(statement), vec![NodeKind::Error, NodeKind::Print]);
+    }
+
+
+    #[test]
+    fn calls_and_parentheses() {
+        assert_eq!(value("f(a, b$, 3) + (1 + 2) * g()"),
+                   "(Binary Add left: (Call \"f\" (Variable \"a\") (Variable \"b$\") (Integer 3)) \
+                    right: (Binary Multiply left: (Binary Add left: (Integer 1) \
+                    right: (Integer 2)) right: (Call \"g\")))");
+        // A command's arguments can start with a parenthesis that isn't around all of them
+        assert_eq!(tree("f(1)\nf (1) + 2, 3\n"),
+                   "(Program (Call \"f\" (Integer 1)) (Call \"f\" (Binary Add left: (Integer 1) \
+                    right: (Integer 2)) (Integer 3)))");
+        assert_eq!(error("x = f(1,\n"), "1:9: Expected an expression, found end of line");
+        assert_eq!(error("x = (1 + 2\n"), "1:11: Expected `)`, found end of line");
+    }
+
+
+    #[test]
+    fn arrays_are_indexed_on_both_sides() {
+        assert_eq!(tree("Dim m(2, 3)\nm(1, 2) = m(0, 1) + 1\n"),
+                   "(Program (Dim (Array \"m\" (Integer 2) (Integer 3))) \
+                    (Assign target: (Index \"m\" (Integer 1) (Integer 2)) \
+                    value: (Binary Add left: (Index \"m\" (Integer 0) (Integer 1)) \
+                    right: (Integer 1))))");
+        // It's an array if it's Dim'd anywhere, before or after
+        assert_eq!(tree("y = m(1)\nDim m(2)\n"),
+                   "(Program (Assign target: (Variable \"y\") value: (Index \"m\" (Integer 1))) \
+                    (Dim (Array \"m\" (Integer 2))))");
+        assert_eq!(error("Dim m(2)\nm(1, 2) = 1\n"), "2:1: 2 indices for m, it has 1 dimension");
+        assert_eq!(error("Dim m(2)\ny = m(1, 2)\n"), "2:5: 2 indices for m, it has 1 dimension");
+    }
+
+
+    #[test]
+    fn adding_a_string_is_concatenating() {
+        assert_eq!(value("\"a\" + b$"),
+                   "(Binary Concat left: (String \"a\") right: (Variable \"b$\"))");
+        assert_eq!(value("1 + 2 + a$"),
+                   "(Binary Concat left: (Binary Add left: (Integer 1) right: (Integer 2)) \
+                    right: (Variable \"a$\"))");
+        assert_eq!(value("f$(1) + 1"),
+                   "(Binary Concat left: (Call \"f$\" (Integer 1)) right: (Integer 1))");
+        assert_eq!(value("a# + 1"), "(Binary Add left: (Variable \"a#\") right: (Integer 1))");
+    }
+
+
+    #[test]
+    fn logical_operators_are_below_comparisons() {
+        assert_eq!(value("Not a = 1 And b Or c Xor d"),
+                   "(Binary Xor left: (Binary Or left: (Binary And left: (Unary Not \
+                    (Binary Equal left: (Variable \"a\") right: (Integer 1))) \
+                    right: (Variable \"b\")) right: (Variable \"c\")) right: (Variable \"d\"))");
+        assert_eq!(value("a Or b And c"),
+                   "(Binary Or left: (Variable \"a\") right: (Binary And left: (Variable \"b\") \
+                    right: (Variable \"c\")))");
+        assert_eq!(value("Not Not a"), "(Unary Not (Unary Not (Variable \"a\")))");
+    }
+
+    #[test]
+    fn and_and_or_can_short_circuit() {
+        let ops = |short_circuit| {
+            let config = LexerConfig::default().short_circuit(short_circuit);
+            let tokens = TokenIterator::new_with_config("x = a And b Or c Xor d\n".as_bytes(), config);
+            let program = parse_program(tokens).unwrap();
+            let mut expr = match program.arena[program.statements[0]].kind {
+                StmtKind::Assign { value, .. } => value,
+                ref kind => panic!("{:?}", kind)
+            };
+            // They're all on the left
+            let mut ops = Vec::new();
+            while let ExprKind::Binary { op, left, .. } = program.arena[expr].kind {
+                ops.insert(0, op);
+                expr = left;
+            }
+            ops
+        };
+        assert_eq!(ops(false), vec![BinaryOp::And, BinaryOp::Or, BinaryOp::Xor]);
+        assert_eq!(ops(true), vec![BinaryOp::AndAlso, BinaryOp::OrElse, BinaryOp::Xor]);
+    }
+
+
+    // What every statement and expression covers, from the outside in
+    struct Spanned<'a> {
+        source: &'a str,
+        covered: Vec<&'a str>
+    }
+
+    impl<'a> Spanned<'a> {
+        fn push(&mut self, span: Span) {
+            let source = self.source;
+            self.covered.push(&source[span.byte_offset..span.byte_offset + span.length]);
+        }
+    }
+
+    impl<'a> Visitor for Spanned<'a> {
+        fn visit_stmt(&mut self, arena: &Arena, stmt: StmtId) {
+            self.push(arena[stmt].span);
+            walk_stmt(self, arena, stmt)
+        }
+
+        fn visit_expr(&mut self, arena: &Arena, expr: ExprId) {
+            self.push(arena[expr].span);
+            walk_expr(self, arena, expr)
+        }
+    }
+
+    #[test]
+    fn nodes_cover_their_tokens() {
+        let source = "x = (1 + 2) * f(a)\nWhile -x\n  Print  x$ ; \nWend\n";
+        let mut spanned = Spanned { source, covered: Vec::new() };
+        spanned.visit_program(&parse_str(source).unwrap());
+        assert_eq!(spanned.covered,
+                   vec!["x = (1 + 2) * f(a)", "x", "(1 + 2) * f(a)", "(1 + 2)", "1", "2", "f(a)", "a",
+                        "While -x\n  Print  x$ ; \nWend", "-x", "x", "Print  x$ ;", "x$"]);
+    }
+
+    // Each statement that has comments, as its first word, its leading
+    // comments and its trailing ones
+    fn comments(source: &str) -> Vec<String> {
+        let config = LexerConfig::default().lossless(true);
+        let program = parse_program(TokenIterator::new_with_config(source.as_bytes(), config)).unwrap();
+        let texts = |comments: &[Comment]| {
+            comments.iter().map(|comment| comment.text.as_str()).collect::<Vec<_>>().join(", ")
+        };
+        let mut attached: Vec<_> = program.comments.iter().map(|(&stmt, comments)| {
+            let at = program.arena[stmt].span.byte_offset;
+            let first = source[at..].split_whitespace().next().unwrap();
+            (at, format!("{}: {} | {}", first, texts(&comments.leading), texts(&comments.trailing)))
+        }).collect();
+        attached.sort();
+        attached.into_iter().map(|(_, attached)| attached).collect()
+    }
+
+    #[test]
+    fn comments_go_with_their_statements() {
+        assert_eq!(comments("' About x\nREM and more\nx = 1 ' one\nWhile x\n  ' Inside\n  \
+                             Print x /* it */ ; \n  ' Last\nWend ' done\n"),
+                   vec!["x: ' About x, REM and more | ' one", "While:  | ' done",
+                        "Print: ' Inside | /* it */, ' Last"]);
+        // Without lossless nothing keeps them
+        assert!(parse_str("x = 1 ' one\n").unwrap().comments.is_empty());
+    }
+
+
+    // How many statements came out, and the errors
+    fn fed(results: Vec<Result<StmtId, ParseError>>) -> (usize, Vec<String>) {
+        let statements = results.iter().filter(|result| result.is_ok()).count();
+        let errors = results.into_iter().filter_map(Result::err)
+            .map(|e| format!("{}: {}", e.span, e.message)).collect();
+        (statements, errors)
+    }
+
+    #[test]
+    fn statements_come_out_when_their_lines_are_done() {
+        let mut parser = StatementParser::new();
+        assert_eq!(fed(parser.feed(b"x = 1\nIf a")), (1, vec![]));
+        assert_eq!(fed(parser.feed(b" Then\nPrint 1\n")), (0, vec![]));
+        assert_eq!(fed(parser.feed(b"EndIf\nGoto nowhere\ny = ")), (2, vec![]));
+        assert_eq!(fed(parser.feed(b"2\r")), (0, vec![]));
+        assert_eq!(fed(parser.feed(b"\nz = +\n")), (1, vec!["7:5: Expected an expression, \
+                                                              found `+`".to_string()]));
+        // Gotos can go forward, so they're only checked at the end
+        assert_eq!(fed(parser.finish()), (0, vec!["5:6: Label nowhere isn't defined".to_string()]));
+
+        let program = parser.into_program();
+        let whole = parse_str_recovering("x = 1\nIf a Then\nPrint 1\nEndIf\nGoto nowhere\ny = 2\r\n").0;
+        assert_eq!(program.statements.len(), whole.statements.len());
+    }
+
+    #[test]
+    fn open_blocks_are_unfinished_at_the_end() {
+        let mut parser = StatementParser::new();
+        assert_eq!(fed(parser.feed(b"While a\nPrint 1\n")), (0, vec![]));
+        assert_eq!(fed(parser.finish()), (0, vec!["1:1: While is missing its Wend".to_string()]));
+    }
+}
@@ -0,0 +1,587 @@
+// Passes over a parsed program, before it's run or compiled
+
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+
+use ast::{Arena, BinaryOp, ElseIf, Expr, ExprId, ExprKind, Function, Loop, Name, Program, Sigil, StmtId,
+          StmtKind, UnaryOp, Variable};
+use cst::{Cst, Element, Node, NodeKind};
+use fold::{walk_function, walk_stmt, Fold};
+use interner::Symbol;
+use tokenizer::{Keyword, Span, Token, TokenKind};
+use visit::{walk_expr_mut, VisitorMut};
+
+// Works out everything that only has literals in it, so 2 * 30 + 4 is 64
+// and "a" + "b" is "ab"
+// Comparisons and Not are 1 or 0 the way CoolBasic has them. What would overflow,
+// divide by zero or come out as infinity is left for the runtime to fail
+// on, and so are And, Or, Xor and ^ whose results depend on how the
+// runtime does them
+pub fn fold_constants(program: &mut Program) {
+    let mut folder = ConstantFolder;
+    folder.visit_program_mut(program);
+    for &value in program.constants.values() {
+        folder.visit_expr_mut(&mut program.arena, value);
+    }
+}
+
+struct ConstantFolder;
+
+impl VisitorMut for ConstantFolder {
+    // The operands first, so whole subexpressions fold from the bottom up
+    // The folded node takes the value in, its operands are left in the
+    // arena with nothing pointing to them
+    fn visit_expr_mut(&mut self, arena: &mut Arena, expr: ExprId) {
+        walk_expr_mut(self, arena, expr);
+        if negative_literal(arena, expr) {
+            return;
+        }
+
+        let folded = match arena[expr].kind {
+            ExprKind::Binary { op, left, right } => evaluate(op, &arena[left], &arena[right]),
+            ExprKind::Unary { op: UnaryOp::Not, operand } => match arena[operand].kind {
+                ExprKind::Integer(value) => Some(truth(value == 0)),
+                ExprKind::Float(value) => Some(truth(value == 0.0)),
+                _ => None
+            },
+            _ => None
+        };
+        if let Some(folded) = folded {
+            arena[expr].kind = folded;
+        }
+    }
+}
+
+// Makes a negation of a number the negative number, where it is. -1 is
+// Minus and 1 to the lexer, the parser does this for Const and Data and
+// desugar for a Step, which need their literals
+// False if it isn't one, or it would overflow
+pub fn negative_literal(arena: &mut Arena, expr: ExprId) -> bool {
+    let negative = match arena[expr].kind {
+        ExprKind::Unary { op: UnaryOp::Negate, operand } => match arena[operand].kind {
+            ExprKind::Integer(value) => value.checked_neg().map(ExprKind::Integer),
+            ExprKind::Float(value) => Some(ExprKind::Float(-value)),
+            _ => None
+        },
+        _ => None
+    };
+    match negative {
+        Some(negative) => {
+            arena[expr].kind = negative;
+            true
+        },
+        None => false
+    }
+}
+
+// None if it can't be done here
+fn evaluate(op: BinaryOp, left: &Expr, right: &Expr) -> Option<ExprKind> {
+    match (&left.kind, &right.kind) {
+        (&ExprKind::Integer(a), &ExprKind::Integer(b)) => integer(op, a, b),
+        (&ExprKind::Float(a), &ExprKind::Float(b)) => float(op, a, b),
+        // An integer with a float is a float
+        (&ExprKind::Integer(a), &ExprKind::Float(b)) => float(op, a as f64, b),
+        (&ExprKind::Float(a), &ExprKind::Integer(b)) => float(op, a, b as f64),
+        (ExprKind::String(a), ExprKind::String(b)) if op == BinaryOp::Concat => {
+            Some(ExprKind::String(format!("{}{}", a, b)))
+        },
+        _ => None
+    }
+}
+
+fn truth(value: bool) -> ExprKind {
+    ExprKind::Integer(i64::from(value))
+}
+
+// Division rounds towards zero
+fn integer(op: BinaryOp, a: i64, b: i64) -> Option<ExprKind> {
+    Some(match op {
+        BinaryOp::Add => ExprKind::Integer(a.checked_add(b)?),
+        BinaryOp::Subtract => ExprKind::Integer(a.checked_sub(b)?),
+        BinaryOp::Multiply => ExprKind::Integer(a.checked_mul(b)?),
+        BinaryOp::Divide => ExprKind::Integer(a.checked_div(b)?),
+        BinaryOp::Modulo => ExprKind::Integer(a.checked_rem(b)?),
+        BinaryOp::Equal => truth(a == b),
+        BinaryOp::NotEqual => truth(a != b),
+        BinaryOp::Less => truth(a < b),
+        BinaryOp::Greater => truth(a > b),
+        BinaryOp::LessEqual => truth(a <= b),
+        BinaryOp::GreaterEqual => truth(a >= b),
+        BinaryOp::Concat | BinaryOp::Power | BinaryOp::And | BinaryOp::Or | BinaryOp::Xor |
+        BinaryOp::AndAlso | BinaryOp::OrElse => return None
+    })
+}
+
+fn float(op: BinaryOp, a: f64, b: f64) -> Option<ExprKind> {
+    let value = match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide if b != 0.0 => a / b,
+        BinaryOp::Modulo if b != 0.0 => a % b,
+        BinaryOp::Equal => return Some(truth(a == b)),
+        BinaryOp::NotEqual => return Some(truth(a != b)),
+        BinaryOp::Less => return Some(truth(a < b)),
+        BinaryOp::Greater => return Some(truth(a > b)),
+        BinaryOp::LessEqual => return Some(truth(a <= b)),
+        BinaryOp::GreaterEqual => return Some(truth(a >= b)),
+        _ => return None
+    };
+
+    if value.is_finite() {
+        Some(ExprKind::Float(value))
+    }
+    else {
+        None
+    }
+}
+
+// Something that's probably a mistake but still runs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub message: String,
+    pub span: Span
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.message)
+    }
+}
+
+// Statements that can never run because they come after a Goto, Return or
+// End in the same block with no label in between, and Functions that
+// nothing calls, as a command or in an expression
+// It goes through the Cst for the spans, see parser::parse_str_cst
+// Functions, Types, Data and Consts aren't run in order, so they're never
+// unreachable themselves. Warnings are in the order they're in the source
+pub fn dead_code(cst: &Cst) -> Vec<Warning> {
+    let mut dead = DeadCode {
+        warnings: Vec::new(),
+        functions: Vec::new(),
+        calls: HashSet::new()
+    };
+    dead.node(&cst.root);
+
+    for &(name, ref spelling, span) in &dead.functions {
+        if !dead.calls.contains(&name) {
+            dead.warnings.push(Warning {
+                message: format!("Function {} is never used", spelling),
+                span
+            });
+        }
+    }
+
+    dead.warnings.sort_by_key(|warning| warning.span.byte_offset);
+    dead.warnings
+}
+
+struct DeadCode {
+    warnings: Vec<Warning>,
+    // Every Function by its compared name, and the names that are called
+    functions: Vec<(Symbol, String, Span)>,
+    calls: HashSet<Symbol>
+}
+
+// The name a Function or a command is given, which is the first
+// identifier in it
+fn named(node: &Node) -> Option<&Token<'static>> {
+    node.children.iter().filter_map(|child| match *child {
+        Element::Token(ref token) => Some(token),
+        Element::Node(_) => None
+    }).find(|token| token.identifier().is_some())
+}
+
+fn has_label(node: &Node) -> bool {
+    node.children.iter().any(|child| match *child {
+        Element::Node(ref node) => node.kind == NodeKind::Label || has_label(node),
+        Element::Token(_) => false
+    })
+}
+
+impl DeadCode {
+    fn node(&mut self, node: &Node) {
+        match node.kind {
+            NodeKind::Function => if let Some(token) = named(node) {
+                let spelling = match token.trivia {
+                    Some(ref trivia) => String::from_utf8_lossy(&trivia.text).into_owned(),
+                    None => String::new()
+                };
+                self.functions.push((token.identifier().unwrap().name, spelling, token.span));
+            },
+            NodeKind::Call | NodeKind::CallExpr => if let Some(token) = named(node) {
+                self.calls.insert(token.identifier().unwrap().name);
+            },
+            _ => {}
+        }
+
+        // The statements in it are one block until the next Else, Case or
+        // the like starts another
+        let mut after = None;
+        let mut unreachable: Option<(Span, Span)> = None;
+        for child in &node.children {
+            let child = match *child {
+                Element::Node(ref child) => child,
+                Element::Token(ref token) => {
+                    match token.kind {
+                        TokenKind::Keyword(Keyword::Else) | TokenKind::Keyword(Keyword::ElseIf) |
+                        TokenKind::Keyword(Keyword::Case) | TokenKind::Keyword(Keyword::Default) => {
+                            self.unreachable(after, unreachable.take());
+                            after = None;
+                        },
+                        _ => {}
+                    }
+                    continue;
+                }
+            };
+            self.node(child);
+
+            match child.kind {
+                NodeKind::Function | NodeKind::Type | NodeKind::Data | NodeKind::Const => {
+                    self.unreachable(after, unreachable.take());
+                },
+                _ if !child.kind.is_statement() => {},
+                // Whatever jumps to the label gets there
+                _ if child.kind == NodeKind::Label || has_label(child) => {
+                    self.unreachable(after, unreachable.take());
+                    after = None;
+                },
+                _ if after.is_some() => if let Some(span) = child.span() {
+                    unreachable = match unreachable {
+                        Some((first, _)) => Some((first, span)),
+                        None => Some((span, span))
+                    };
+                },
+                NodeKind::Goto => after = Some("Goto"),
+                NodeKind::Return => after = Some("Return"),
+                NodeKind::End => after = Some("End"),
+                _ => {}
+            }
+        }
+        self.unreachable(after, unreachable);
+    }
+
+    fn unreachable(&mut self, after: Option<&str>, statements: Option<(Span, Span)>) {
+        if let (Some(after), Some((first, last))) = (after, statements) {
+            self.warnings.push(Warning {
+                message: format!("Unreachable code after {}", after),
+                span: Span {
+                    length: last.byte_offset + last.length - first.byte_offset,
+                    .. first
+                }
+            });
+        }
+    }
+}
+
+// Rewrites the program to use fewer kinds of statements, so whatever runs
+// it only has to know those:
+//
+//   If x Then y        is a block If
+//   ElseIf             is an If inside the Else
+//   For i = a To b     is i = a and a While i <= b with i = i + 1 at the
+//                      end of it, or i >= b for a negative Step
+//
+// The To and Step values are worked out once before the loop like For
+// does, in variables with names that can't be written in a program so
+// they can't clash with one. Where an Exit would now get out of the wrong
+// loop it's a Goto to a label like that after the right one
+// Those labels aren't in the program's labels, which are for the source
+pub fn desugar(program: &mut Program) {
+    let mut desugar = Desugar { loops: Vec::new(), names: 0 };
+    let statements = mem::take(&mut program.statements);
+    program.statements = desugar.fold_block(&mut program.arena, statements);
+}
+
+// A loop being desugared, what it was and what it is now, and the label
+// after it if something needs it
+struct LoopFrame {
+    kind: Loop,
+    lowered: Loop,
+    exit: Option<Name>
+}
+
+struct Desugar {
+    // Innermost last
+    loops: Vec<LoopFrame>,
+    // How many names have been made up
+    names: usize
+}
+
+fn variable_expr(arena: &mut Arena, variable: &Variable, span: Span) -> ExprId {
+    arena.add_expr(ExprKind::Variable(variable.clone()), span)
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::Integer(_) | ExprKind::Float(_) | ExprKind::String(_))
+}
+
+fn binary(arena: &mut Arena, op: BinaryOp, left: ExprId, right: ExprId, span: Span) -> ExprId {
+    arena.add_expr(ExprKind::Binary { op, left, right }, span)
+}
+
+// What once gave back, again as a node of its own. That's a literal or a
+// variable, which don't have anything in them
+fn copy(arena: &mut Arena, expr: ExprId) -> ExprId {
+    let expr = arena[expr].clone();
+    arena.add_expr(expr.kind, expr.span)
+}
+
+impl Desugar {
+    fn name(&mut self, what: &str) -> Name {
+        self.names += 1;
+        let name = format!("{}.{}", what, self.names);
+        Name { name: name.clone(), spelling: name }
+    }
+
+    // A literal as it is, anything else goes in a variable first
+    fn once(&mut self, arena: &mut Arena, value: ExprId, what: &str, sigil: Sigil,
+            out: &mut Vec<StmtId>) -> ExprId {
+        negative_literal(arena, value);
+        if is_literal(&arena[value]) {
+            return value;
+        }
+
+        let span = arena[value].span;
+        let variable = Variable { name: self.name(what), sigil };
+        let target = variable_expr(arena, &variable, span);
+        out.push(arena.add_stmt(StmtKind::Assign { target, value }, span));
+        variable_expr(arena, &variable, span)
+    }
+
+    // The body of a loop, and the label to put after it if it needs one
+    fn loop_body(&mut self, arena: &mut Arena, kind: Loop, lowered: Loop, body: Vec<StmtId>)
+                 -> (Vec<StmtId>, Option<Name>) {
+        self.loops.push(LoopFrame { kind, lowered, exit: None });
+        let body = self.fold_block(arena, body);
+        (body, self.loops.pop().unwrap().exit)
+    }
+
+    fn exit(&mut self, kind: Loop) -> StmtKind {
+        // The parser only makes these inside the loop
+        let target = match self.loops.iter().rposition(|frame| frame.kind == kind) {
+            Some(target) => target,
+            None => return StmtKind::Exit(kind)
+        };
+        let lowered = self.loops[target].lowered;
+        if self.loops.iter().rposition(|frame| frame.lowered == lowered) == Some(target) {
+            return StmtKind::Exit(lowered);
+        }
+
+        if self.loops[target].exit.is_none() {
+            self.loops[target].exit = Some(self.name("exit"));
+        }
+        StmtKind::Goto(self.loops[target].exit.clone().unwrap())
+    }
+
+    // The If's own span goes to the ones made from its ElseIfs too
+    fn if_chain(&mut self, arena: &mut Arena, condition: ExprId, then_branch: Vec<StmtId>,
+                mut else_ifs: Vec<ElseIf>, else_branch: Option<Vec<StmtId>>, span: Span) -> StmtKind {
+        let else_branch = if else_ifs.is_empty() {
+            else_branch.map(|branch| self.fold_block(arena, branch))
+        }
+        else {
+            let first = else_ifs.remove(0);
+            let kind = self.if_chain(arena, first.condition, first.body, else_ifs, else_branch, span);
+            Some(vec![arena.add_stmt(kind, span)])
+        };
+
+        StmtKind::If {
+            condition: self.fold_expr(arena, condition),
+            then_branch: self.fold_block(arena, then_branch),
+            else_ifs: Vec::new(),
+            else_branch,
+            single_line: false
+        }
+    }
+
+    // The For becomes the While, with what it needs before it and the label
+    // after it in out too
+    fn for_loop(&mut self, arena: &mut Arena, stmt: StmtId, out: &mut Vec<StmtId>) {
+        let span = arena[stmt].span;
+        let (counter, from, to, step, body) = match mem::replace(&mut arena[stmt].kind, StmtKind::End) {
+            StmtKind::For { variable, from, to, step, body } => (variable, from, to, step, body),
+            _ => unreachable!()
+        };
+
+        let from = self.fold_expr(arena, from);
+        let start = StmtKind::Assign { target: variable_expr(arena, &counter, span), value: from };
+        out.push(arena.add_stmt(start, span));
+        let to = self.fold_expr(arena, to);
+        let to = self.once(arena, to, "to", counter.sigil, out);
+        let step = match step {
+            Some(step) => {
+                let step = self.fold_expr(arena, step);
+                self.once(arena, step, "step", counter.sigil, out)
+            },
+            None => arena.add_expr(ExprKind::Integer(1), span)
+        };
+
+        let (up_counter, down_counter) = (variable_expr(arena, &counter, span),
+                                          variable_expr(arena, &counter, span));
+        let down_to = copy(arena, to);
+        let up = binary(arena, BinaryOp::LessEqual, up_counter, to, span);
+        let down = binary(arena, BinaryOp::GreaterEqual, down_counter, down_to, span);
+        let condition = match arena[step].kind {
+            ExprKind::Integer(step) if step < 0 => down,
+            ExprKind::Float(step) if step < 0.0 => down,
+            ExprKind::Integer(_) | ExprKind::Float(_) => up,
+            // Which way it goes is only known when it runs
+            _ => {
+                let (forwards, backwards) = (copy(arena, step), copy(arena, step));
+                let (zero, other_zero) = (arena.add_expr(ExprKind::Integer(0), span),
+                                          arena.add_expr(ExprKind::Integer(0), span));
+                let forwards = binary(arena, BinaryOp::GreaterEqual, forwards, zero, span);
+                let backwards = binary(arena, BinaryOp::Less, backwards, other_zero, span);
+                let forwards = binary(arena, BinaryOp::And, forwards, up, span);
+                let backwards = binary(arena, BinaryOp::And, backwards, down, span);
+                binary(arena, BinaryOp::Or, forwards, backwards, span)
+            }
+        };
+
+        let (mut body, exit) = self.loop_body(arena, Loop::For, Loop::While, body);
+        let target = variable_expr(arena, &counter, span);
+        let current = variable_expr(arena, &counter, span);
+        let next = binary(arena, BinaryOp::Add, current, step, span);
+        body.push(arena.add_stmt(StmtKind::Assign { target, value: next }, span));
+        arena[stmt].kind = StmtKind::While { condition, body };
+        out.push(stmt);
+        out.extend(exit.map(|exit| arena.add_stmt(StmtKind::Label(exit), span)));
+    }
+
+    // What a statement becomes keeps its id
+    fn lower(&mut self, arena: &mut Arena, stmt: StmtId, out: &mut Vec<StmtId>) {
+        if let StmtKind::For { .. } = arena[stmt].kind {
+            self.for_loop(arena, stmt, out);
+            return;
+        }
+
+        let span = arena[stmt].span;
+        let mut exit = None;
+        let kind = match mem::replace(&mut arena[stmt].kind, StmtKind::End) {
+            StmtKind::If { condition, then_branch, else_ifs, else_branch, .. } => {
+                self.if_chain(arena, condition, then_branch, else_ifs, else_branch, span)
+            },
+            StmtKind::While { condition, body } => {
+                let condition = self.fold_expr(arena, condition);
+                let (body, label) = self.loop_body(arena, Loop::While, Loop::While, body);
+                exit = label;
+                StmtKind::While { condition, body }
+            },
+            StmtKind::Repeat { body, until } => {
+                let (body, label) = self.loop_body(arena, Loop::Repeat, Loop::Repeat, body);
+                exit = label;
+                StmtKind::Repeat { body, until: until.map(|until| self.fold_expr(arena, until)) }
+            },
+            StmtKind::Exit(kind) => self.exit(kind),
+            kind => {
+                arena[stmt].kind = kind;
+                out.push(walk_stmt(self, arena, stmt));
+                return;
+            }
+        };
+        arena[stmt].kind = kind;
+        out.push(stmt);
+        out.extend(exit.map(|exit| arena.add_stmt(StmtKind::Label(exit), span)));
+    }
+}
+
+impl Fold for Desugar {
+    fn fold_block(&mut self, arena: &mut Arena, statements: Vec<StmtId>) -> Vec<StmtId> {
+        let mut out = Vec::new();
+        for stmt in statements {
+            self.lower(arena, stmt, &mut out);
+        }
+        out
+    }
+
+    // An Exit can't get out of a Function
+    fn fold_function(&mut self, arena: &mut Arena, function: Function) -> Function {
+        let loops = mem::take(&mut self.loops);
+        let function = walk_function(self, arena, function);
+        self.loops = loops;
+        function
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::{parse_str, parse_str_cst};
+    use pretty::to_source;
+
+    fn folded(source: &str) -> String {
+        let mut program = parse_str(source).unwrap();
+        fold_constants(&mut program);
+        to_source(&program).unwrap()
+    }
+
+
+    #[test]
+    fn literals_are_worked_out() {
+        assert_eq!(folded("a = 2 * 30 + 4\nb$ = \"a\" + \"b\"\nc = 1 < 2\ng = Not 0\n\
+                           f = -7 / 2\ni = 1.5 + 1\nConst k = 2 * 3\n"),
+                   "a = 64\nb$ = \"ab\"\nc = 1\ng = 1\nf = -3\ni = 2.5\nConst k = 6\n");
+        // Inside of what can't be folded too
+        assert_eq!(folded("h = x + 2 * 3\nPrint (1 + 2) * a\n"), "h = x + 6\nPrint 3 * a\n");
+    }
+
+    #[test]
+    fn whatever_would_fail_is_left_alone() {
+        let source = "d = 7 / 0\nj = 9223372036854775807 + 1\nk = 1e308 * 10\ne = 3 And 1\n";
+        assert_eq!(folded(source), source);
+    }
+
+
+    fn dead(source: &str) -> Vec<String> {
+        let (_, cst, errors) = parse_str_cst(source);
+        assert_eq!(errors, vec![]);
+        dead_code(&cst).iter().map(|warning| warning.to_string()).collect()
+    }
+
+    #[test]
+    fn nothing_runs_after_a_jump_until_a_label() {
+        assert_eq!(dead("Goto l\nPrint 1\nPrint 2\nl:\nPrint 3\nEnd\nx = 1\n\
+                         If a Then\nReturn\nb = 2\nEndIf\n"),
+                   vec!["2:1: Unreachable code after Goto", "7:1: Unreachable code after End",
+                        "10:1: Unreachable code after Return"]);
+    }
+
+    #[test]
+    fn functions_nobody_calls() {
+        assert_eq!(dead("y = k(1)\nh\nFunction k(a)\nEndFunction\nFunction h()\nEndFunction\n\
+                         Function g()\nReturn 1\nPrint 3\nEndFunction\n"),
+                   vec!["7:10: Function g is never used", "9:1: Unreachable code after Return"]);
+    }
+
+
+    fn desugared(source: &str) -> String {
+        let mut program = parse_str(source).unwrap();
+        desugar(&mut program);
+        to_source(&program).unwrap()
+    }
+
+    #[test]
+    fn ifs_are_all_blocks() {
+        assert_eq!(desugared("If a Then b = 1 Else b = 2\nIf x\nPrint 1\nElseIf y\nPrint 2\n\
+                              Else\nPrint 3\nEndIf\n"),
+                   "If a Then\n    b = 1\nElse\n    b = 2\nEndIf\n\
+                    If x Then\n    Print 1\nElse\n    If y Then\n        Print 2\n    \
+                    Else\n        Print 3\n    EndIf\nEndIf\n");
+    }
+
+    #[test]
+    fn fors_are_whiles() {
+        assert_eq!(desugared("For i = 1 To n Step -2\nFor j = 1 To 3\nExit\nNext j\n\
+                              If q Then Exit\nNext i\n"),
+                   "i = 1\nto.1 = n\nWhile i >= to.1\n    j = 1\n    While j <= 3\n        \
+                    Exit While\n        j = j + 1\n    Wend\n    If q Then\n        \
+                    Exit While\n    EndIf\n    i = i + -2\nWend\n");
+        // Which way it goes isn't known until it runs
+        assert_eq!(desugared("For i = 1 To 5 Step s\nWhile a\nExit For\nWend\nNext\n"),
+                   "i = 1\nstep.1 = s\n\
+                    While step.1 >= 0 And i <= 5 Or step.1 < 0 And i >= 5\n    \
+                    While a\n        Goto exit.2\n    Wend\n    i = i + step.1\nWend\nexit.2:\n");
+    }
+}
@@ -0,0 +1,483 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::iter::Peekable;
+
+use interner::{Interner, Symbol};
+use stream::LexResult;
+use tokenizer::{Identifier, LexError, Payload, Span, Token, TokenKind};
+
+// A token after preprocessing
+// Tokens a constant expanded to keep the spans they have in the #define,
+// where the constant was used is in expanded_from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Preprocessed {
+    pub token: Token<'static>,
+    pub expanded_from: Option<Span>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PreprocessError {
+    Lex(LexError),
+    // #define, #ifdef and such without a name after them
+    MissingName { directive: &'static str, span: Span },
+    // A constant that expands to itself, maybe through others
+    Recursive { name: Vec<u8>, span: Span },
+    // #else or #endif that doesn't go with any #if
+    Unmatched { directive: &'static str, span: Span },
+    // Another #else after the #else
+    DuplicateElse { span: Span },
+    // #if, #ifdef or #ifndef that the file ended inside of
+    MissingEndIf { span: Span }
+}
+
+impl PreprocessError {
+    pub fn span(&self) -> Span {
+        match *self {
+            PreprocessError::Lex(ref e) => e.span(),
+            PreprocessError::MissingName { span, .. } |
+            PreprocessError::Recursive { span, .. } |
+            PreprocessError::Unmatched { span, .. } |
+            PreprocessError::DuplicateElse { span } |
+            PreprocessError::MissingEndIf { span } => span
+        }
+    }
+}
+
+impl fmt::Display for PreprocessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PreprocessError::Lex(ref e) => write!(f, "{}", e),
+            PreprocessError::MissingName { directive, span } => {
+                write!(f, "{}: #{} needs a name", span, directive)
+            },
+            PreprocessError::Recursive { ref name, span } => {
+                write!(f, "{}: {} expands to itself", span, String::from_utf8_lossy(name))
+            },
+            PreprocessError::Unmatched { directive, span } => {
+                write!(f, "{}: #{} without an #if", span, directive)
+            },
+            PreprocessError::DuplicateElse { span } => {
+                write!(f, "{}: Only one #else allowed per #if", span)
+            },
+            PreprocessError::MissingEndIf { span } => {
+                write!(f, "{}: #if is missing its #endif", span)
+            }
+        }
+    }
+}
+
+pub type PreprocessResult = Result<Preprocessed, PreprocessError>;
+
+static DIRECTIVES: &[&str] = &[
+    "define", "ifdef", "ifndef", "if", "else", "endif"
+];
+
+// The directive a token after a # at the start of a line names
+// If, Else and EndIf are keywords, the rest are just identifiers
+fn directive(token: &Token, interner: &Interner) -> Option<&'static str> {
+    let word = match (token.kind, token.identifier()) {
+        (TokenKind::Text, Some(identifier)) => interner.resolve(identifier.spelling),
+        (TokenKind::Keyword(keyword), _) => keyword.name().as_bytes().to_vec(),
+        _ => return None
+    };
+
+    DIRECTIVES.iter()
+              .find(|directive| word.eq_ignore_ascii_case(directive.as_bytes()))
+              .cloned()
+}
+
+// An #if, #ifdef or #ifndef that hasn't had its #endif yet
+struct Condition {
+    // Whether the lines go through right now
+    active: bool,
+    // Whether the lines would go through without this
+    outer: bool,
+    // #else only goes through if nothing before it did
+    taken: bool,
+    seen_else: bool,
+    span: Span
+}
+
+// Textual constants, defined on a line of their own
+//
+//   #define WIDTH 640
+//   #define TITLE "Game " + VERSION
+//
+// Every identifier with the name of a constant after its #define is
+// replaced by the constant's tokens, which can use other constants
+// Defining a constant again replaces it from there on
+//
+// Conditional sections go through if the name is defined, with #define
+// or Preprocessor::define
+//
+//   #ifdef DEBUG
+//   #ifndef DEBUG
+//   #if DEBUG
+//   #else
+//   #endif
+//
+// #if is like #ifdef, except that a constant defined as 0 counts as not
+// being there. The lines that don't go through are left out apart from
+// their EOLs, errors in them too
+pub struct Preprocessor {
+    interner: Interner,
+    case_insensitive: bool,
+    constants: HashMap<Symbol, Vec<Token<'static>>>,
+    defines: HashSet<Symbol>
+}
+
+impl Preprocessor {
+    // The interner and case_insensitive have to be what the tokens were
+    // lexed with
+    pub fn new(interner: Interner, case_insensitive: bool) -> Preprocessor {
+        Preprocessor {
+            interner,
+            case_insensitive,
+            constants: HashMap::new(),
+            defines: HashSet::new()
+        }
+    }
+
+    // Defined for #ifdef and #if without being a constant, like -D for a
+    // C compiler
+    pub fn define(&mut self, name: &[u8]) {
+        let name = Identifier::new(name, self.case_insensitive, &self.interner).name;
+        self.defines.insert(name);
+    }
+
+    fn is_defined(&self, name: Symbol) -> bool {
+        self.defines.contains(&name) || self.constants.contains_key(&name)
+    }
+
+    // True unless it's a constant that's just 0
+    fn is_true(&self, name: Symbol) -> bool {
+        match self.constants.get(&name) {
+            Some(value) => match value.first() {
+                Some(&Token { payload: Payload::Integer(0), .. }) => value.len() != 1,
+                _ => true
+            },
+            None => self.defines.contains(&name)
+        }
+    }
+
+    // The directive lines are left out, apart from their EOL
+    pub fn run<I>(&mut self, tokens: I) -> Vec<PreprocessResult>
+        where I: IntoIterator<Item = LexResult>
+    {
+        let mut tokens = tokens.into_iter().peekable();
+        let mut out = Vec::new();
+        let mut conditions: Vec<Condition> = Vec::new();
+        let mut line_start = true;
+
+        while let Some(result) = tokens.next() {
+            let active = conditions.last().is_none_or(|c| c.active);
+
+            let token = match result {
+                Ok(token) => token,
+                Err(e) => {
+                    if active {
+                        out.push(Err(PreprocessError::Lex(e)));
+                    }
+                    line_start = false;
+                    continue;
+                }
+            };
+
+            let directive = if line_start && token.kind == TokenKind::Hash {
+                match tokens.peek() {
+                    Some(Ok(next)) => directive(next, &self.interner),
+                    _ => None
+                }
+            }
+            else {
+                None
+            };
+            line_start = token.kind == TokenKind::EOL;
+
+            if let Some(directive) = directive {
+                tokens.next();
+                match directive {
+                    "define" if active => self.define_constant(token.span, &mut tokens, &mut out),
+                    "define" => {},
+
+                    "else" => match conditions.last_mut() {
+                        Some(condition) if condition.seen_else => {
+                            out.push(Err(PreprocessError::DuplicateElse { span: token.span }));
+                            condition.active = false;
+                        },
+                        Some(condition) => {
+                            condition.active = condition.outer && !condition.taken;
+                            condition.taken = true;
+                            condition.seen_else = true;
+                        },
+                        None => out.push(Err(PreprocessError::Unmatched {
+                            directive,
+                            span: token.span
+                        }))
+                    },
+
+                    "endif" => if conditions.pop().is_none() {
+                        out.push(Err(PreprocessError::Unmatched {
+                            directive,
+                            span: token.span
+                        }));
+                    },
+
+                    _ => {
+                        let name = match tokens.peek() {
+                            Some(Ok(next)) if next.kind == TokenKind::Text => next.identifier(),
+                            _ => None
+                        };
+
+                        let holds = match name {
+                            Some(name) => {
+                                tokens.next();
+                                match directive {
+                                    "ifdef" => self.is_defined(name.name),
+                                    "ifndef" => !self.is_defined(name.name),
+                                    _ => self.is_true(name.name)
+                                }
+                            },
+                            None => {
+                                if active {
+                                    out.push(Err(PreprocessError::MissingName {
+                                        directive,
+                                        span: token.span
+                                    }));
+                                }
+                                false
+                            }
+                        };
+
+                        conditions.push(Condition {
+                            active: active && holds,
+                            outer: active,
+                            taken: holds,
+                            seen_else: false,
+                            span: token.span
+                        });
+                    }
+                }
+
+                // Anything else on the line goes too
+                loop {
+                    match tokens.peek() {
+                        None | Some(&Ok(Token { kind: TokenKind::EOL, .. })) => break,
+                        _ => tokens.next()
+                    };
+                }
+                continue;
+            }
+
+            if !active && token.kind != TokenKind::EOL {
+                continue;
+            }
+
+            match (token.kind, token.identifier()) {
+                (TokenKind::Text, Some(identifier)) if self.constants.contains_key(&identifier.name) => {
+                    self.expand(identifier.name, token.span, token.span, &mut Vec::new(), &mut out);
+                },
+                _ => out.push(Ok(Preprocessed {
+                    token,
+                    expanded_from: None
+                }))
+            }
+        }
+
+        for condition in conditions {
+            out.push(Err(PreprocessError::MissingEndIf { span: condition.span }));
+        }
+
+        out
+    }
+
+    // Reads the rest of a #define line
+    fn define_constant<I>(&mut self, span: Span, tokens: &mut Peekable<I>,
+                          out: &mut Vec<PreprocessResult>)
+        where I: Iterator<Item = LexResult>
+    {
+        let name = match tokens.peek() {
+            Some(Ok(token)) if token.kind == TokenKind::Text => token.identifier(),
+            _ => None
+        };
+
+        let name = match name {
+            Some(name) => {
+                tokens.next();
+                name.name
+            },
+            None => {
+                out.push(Err(PreprocessError::MissingName {
+                    directive: "define",
+                    span
+                }));
+                return;
+            }
+        };
+
+        let mut value = Vec::new();
+        loop {
+            match tokens.peek() {
+                None | Some(&Ok(Token { kind: TokenKind::EOL, .. })) => break,
+                _ => {}
+            }
+
+            match tokens.next() {
+                Some(Ok(token)) => value.push(token),
+                Some(Err(e)) => out.push(Err(PreprocessError::Lex(e))),
+                None => unreachable!()
+            }
+        }
+
+        self.constants.insert(name, value);
+    }
+
+    // The name is at span, which is inside a #define unless it's the one
+    // that was used
+    // The constants being expanded are kept around to catch cycles
+    fn expand(&self, name: Symbol, span: Span, used: Span, expanding: &mut Vec<Symbol>,
+              out: &mut Vec<PreprocessResult>) {
+        if expanding.contains(&name) {
+            out.push(Err(PreprocessError::Recursive {
+                name: self.interner.resolve(name),
+                span
+            }));
+            return;
+        }
+
+        expanding.push(name);
+        for token in &self.constants[&name] {
+            match (token.kind, token.identifier()) {
+                (TokenKind::Text, Some(identifier)) if self.constants.contains_key(&identifier.name) => {
+                    self.expand(identifier.name, token.span, used, expanding, out);
+                },
+                _ => out.push(Ok(Preprocessed {
+                    token: token.clone(),
+                    expanded_from: Some(used)
+                }))
+            }
+        }
+        expanding.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizer::TokenIterator;
+
+    // Lines of what got through, identifiers by their names
+    fn run(source: &str, defined: &[&str]) -> (Vec<String>, Vec<PreprocessError>) {
+        let mut tokens = TokenIterator::new(source.as_bytes());
+        let interner = tokens.interner();
+        let mut preprocessor = Preprocessor::new(interner.clone(), true);
+        for name in defined {
+            preprocessor.define(name.as_bytes());
+        }
+
+        let mut lines = vec![String::new()];
+        let mut errors = Vec::new();
+        for result in preprocessor.run(tokens.by_ref()) {
+            let token = match result {
+                Ok(preprocessed) => preprocessed.token,
+                Err(e) => {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            let line = lines.last_mut().unwrap();
+            match token.payload {
+                _ if token.kind == TokenKind::EOL => {},
+                Payload::Identifier(identifier) => {
+                    line.push_str(&String::from_utf8_lossy(&interner.resolve(identifier.name)));
+                },
+                _ => line.push_str(&token.to_string())
+            }
+            if token.kind == TokenKind::EOL {
+                lines.push(String::new());
+            }
+            else {
+                line.push(' ');
+            }
+        }
+        (lines.into_iter().map(|line| line.trim_end().to_string()).collect(), errors)
+    }
+
+    #[test]
+    fn constants_are_expanded() {
+        let source = "#define WIDTH 640\n#define HALF WIDTH / 2\nx = half\n\
+                      #define WIDTH 800\ny = HALF + width";
+        let (lines, errors) = run(source, &[]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(lines, vec!["", "", "x = 640 / 2", "", "y = 800 / 2 + 800"]);
+    }
+
+    #[test]
+    fn expanded_tokens_know_where_they_were_used() {
+        let mut tokens = TokenIterator::new(&b"#define ONE 1\nx = one"[..]);
+        let mut preprocessor = Preprocessor::new(tokens.interner(), true);
+        let out: Vec<_> = preprocessor.run(tokens.by_ref()).into_iter().map(Result::unwrap).collect();
+        let one = &out[3];
+        assert_eq!(one.token.payload, Payload::Integer(1));
+        assert_eq!(one.token.span.line, 1);
+        assert_eq!(one.expanded_from.unwrap().column, 5);
+        assert_eq!(out[1].expanded_from, None);
+    }
+
+    #[test]
+    fn bad_constants() {
+        let (lines, errors) = run("#define A B + 1\n#define B A\nx = A\n#define\n", &[]);
+        // What was around the A inside B still goes through
+        assert_eq!(lines[2], "x = + 1");
+        assert_eq!(errors.len(), 2);
+        match errors[0] {
+            PreprocessError::Recursive { ref name, span } => {
+                assert_eq!((&name[..], span.line), (&b"a"[..], 2));
+            },
+            ref other => panic!("{:?}", other)
+        }
+        match errors[1] {
+            PreprocessError::MissingName { directive, span } => {
+                assert_eq!((directive, span.line), ("define", 4));
+            },
+            ref other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn sections_go_through_if_defined() {
+        let source = "#ifdef DEBUG\na\n#else\nb\n#endif\n#ifndef debug\nc\n#endif\n\
+                      #define OFF 0\n#if OFF\nd\n#else\ne\n#endif";
+        let (lines, errors) = run(source, &[]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        // Every line keeps its EOL
+        assert_eq!(lines.len(), 14);
+        let through: Vec<_> = lines.iter().filter(|line| !line.is_empty()).collect();
+        assert_eq!(through, vec!["b", "c", "e"]);
+
+        let (lines, _) = run(source, &["Debug"]);
+        let through: Vec<_> = lines.iter().filter(|line| !line.is_empty()).collect();
+        assert_eq!(through, vec!["a", "e"]);
+    }
+
+    #[test]
+    fn nested_sections_and_errors_left_out() {
+        let source = "#ifdef A\n#ifdef B\nab @\n#else\na\n#endif\n#else\nnot_a @\n#endif";
+        let (lines, errors) = run(source, &["a"]);
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(lines[4], "a");
+        assert_eq!(lines.iter().filter(|line| !line.is_empty()).count(), 1);
+    }
+
+    #[test]
+    fn directives_have_to_match_up() {
+        let (_, errors) = run("#endif\n#ifdef\n#if A\n#else\n#else\n", &[]);
+        let messages: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+        // The #ifdef without a name still needs its #endif
+        assert_eq!(messages, vec!["1:1: #endif without an #if",
+                                  "2:1: #ifdef needs a name",
+                                  "5:1: Only one #else allowed per #if",
+                                  "2:1: #if is missing its #endif",
+                                  "3:1: #if is missing its #endif"]);
+    }
+}
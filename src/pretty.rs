@@ -0,0 +1,503 @@
+use std::io;
+use std::io::Write;
+
+use ast::{Arena, ArrayDecl, CaseValue, ExprId, ExprKind, Loop, Name, PrintItem, Program, Scope, StmtId,
+          StmtKind, UnaryOp, Variable};
+
+// Four spaces a level
+const INDENT: &str = "    ";
+
+fn name<W: Write>(out: &mut W, name: &Name) -> io::Result<()> {
+    out.write_all(name.spelling.as_bytes())
+}
+
+fn variable<W: Write>(out: &mut W, variable: &Variable) -> io::Result<()> {
+    write!(out, "{}{}", variable.name.spelling, variable.sigil.suffix())
+}
+
+// Comma separated
+fn list<W, T, F>(out: &mut W, items: &[T], mut write: F) -> io::Result<()>
+    where W: Write, F: FnMut(&mut W, &T) -> io::Result<()>
+{
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b", ")?;
+        }
+        write(out, item)?;
+    }
+    Ok(())
+}
+
+fn arrays<W: Write>(out: &mut W, arena: &Arena, arrays: &[ArrayDecl]) -> io::Result<()> {
+    list(out, arrays, |out, array| {
+        variable(out, &array.variable)?;
+        out.write_all(b"(")?;
+        list(out, &array.dimensions, |out, &dimension| expression(out, arena, dimension))?;
+        out.write_all(b")")
+    })
+}
+
+// Parens only where the precedence needs them
+fn operand<W: Write>(out: &mut W, arena: &Arena, expr: ExprId, parens: bool) -> io::Result<()> {
+    if parens {
+        out.write_all(b"(")?;
+        expression(out, arena, expr)?;
+        out.write_all(b")")
+    }
+    else {
+        expression(out, arena, expr)
+    }
+}
+
+// Anything that isn't an operator binds tighter than all of them
+fn precedence(arena: &Arena, expr: ExprId) -> u8 {
+    match arena[expr].kind {
+        ExprKind::Binary { op, .. } => op.precedence(),
+        ExprKind::Unary { op, .. } => op.precedence(),
+        // A negative number is written as a negation of the positive one
+        ExprKind::Integer(i64::MIN) => u8::MAX,
+        ExprKind::Integer(value) if value < 0 => UnaryOp::Negate.precedence(),
+        ExprKind::Float(value) if value.is_sign_negative() => UnaryOp::Negate.precedence(),
+        _ => u8::MAX
+    }
+}
+
+pub fn expression<W: Write>(out: &mut W, arena: &Arena, expr: ExprId) -> io::Result<()> {
+    match arena[expr].kind {
+        // Which has no positive one, it's the bit pattern instead
+        ExprKind::Integer(i64::MIN) => out.write_all(b"&H8000000000000000"),
+        ExprKind::Integer(value) => write!(out, "{}", value),
+        // Debug always has a point or an exponent, so it parses as a Float
+        ExprKind::Float(value) => write!(out, "{:?}", value),
+        ExprKind::String(ref value) => {
+            // There's no escaping in string literals
+            if value.contains(&['"', '\r', '\n'][..]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "String can't be written as a literal"));
+            }
+            write!(out, "\"{}\"", value)
+        },
+        ExprKind::Variable(ref v) => variable(out, v),
+        ExprKind::Member { object, ref field } => {
+            operand(out, arena, object, precedence(arena, object) != u8::MAX)?;
+            out.write_all(b"\\")?;
+            variable(out, field)
+        },
+        ExprKind::Binary { op, left, right } => {
+            let binds = op.precedence();
+            let (left_binds, right_binds) = (precedence(arena, left), precedence(arena, right));
+
+            // The side it doesn't group towards needs parens for the same
+            // precedence too
+            let (left_parens, right_parens) = if op.right_associative() {
+                (left_binds <= binds, right_binds < binds)
+            }
+            else {
+                (left_binds < binds, right_binds <= binds)
+            };
+
+            operand(out, arena, left, left_parens)?;
+            write!(out, " {} ", op.symbol())?;
+            operand(out, arena, right, right_parens)
+        },
+        ExprKind::Unary { op: UnaryOp::Negate, operand: inner } => {
+            out.write_all(b"-")?;
+            operand(out, arena, inner, precedence(arena, inner) < UnaryOp::Negate.precedence())
+        },
+        ExprKind::Unary { op, operand: inner } => {
+            write!(out, "{} ", op.symbol())?;
+            operand(out, arena, inner, precedence(arena, inner) < op.precedence())
+        },
+        ExprKind::Call { name: ref called, arguments: ref values } |
+        ExprKind::Index { array: ref called, indices: ref values } => {
+            variable(out, called)?;
+            out.write_all(b"(")?;
+            list(out, values, |out, &value| expression(out, arena, value))?;
+            out.write_all(b")")
+        }
+    }
+}
+
+fn indent<W: Write>(out: &mut W, depth: usize) -> io::Result<()> {
+    for _ in 0..depth {
+        out.write_all(INDENT.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn block<W: Write>(out: &mut W, arena: &Arena, statements: &[StmtId], depth: usize) -> io::Result<()> {
+    for &statement in statements {
+        indent(out, depth)?;
+        write_statement(out, arena, statement, depth)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// Statements joined with colons, for single line Ifs
+fn line<W: Write>(out: &mut W, arena: &Arena, statements: &[StmtId], depth: usize) -> io::Result<()> {
+    for (i, &statement) in statements.iter().enumerate() {
+        if i > 0 {
+            out.write_all(b" : ")?;
+        }
+        write_statement(out, arena, statement, depth)?;
+    }
+    Ok(())
+}
+
+// Whether an If can go on one line again, a transformed one might have
+// nothing or a loop to put there
+// An Else after the line would go with the last If on it, so that one
+// needs an Else of its own if there's one coming
+fn fits_line(arena: &Arena, then_branch: &[StmtId], else_branch: &Option<Vec<StmtId>>, else_after: bool)
+             -> bool {
+    match *else_branch {
+        Some(ref else_branch) => fits(arena, then_branch, true) && fits(arena, else_branch, else_after),
+        None => !else_after && fits(arena, then_branch, false)
+    }
+}
+
+fn fits(arena: &Arena, statements: &[StmtId], else_after: bool) -> bool {
+    let last = statements.len().wrapping_sub(1);
+    !statements.is_empty() && statements.iter().enumerate().all(|(i, &stmt)| match arena[stmt].kind {
+        StmtKind::If { ref then_branch, ref else_ifs, ref else_branch, single_line, .. } => {
+            single_line && i == last && else_ifs.is_empty() &&
+            fits_line(arena, then_branch, else_branch, else_after)
+        },
+        StmtKind::For { .. } | StmtKind::While { .. } | StmtKind::Repeat { .. } |
+        StmtKind::Select { .. } | StmtKind::Function(_) | StmtKind::Type(_) |
+        StmtKind::Label(_) => false,
+        _ => true
+    })
+}
+
+// One statement without indentation or a line break, apart from the ones
+// inside blocks. depth is how far the statement itself is indented
+pub fn write_statement<W: Write>(out: &mut W, arena: &Arena, statement: StmtId, depth: usize)
+                                -> io::Result<()> {
+    match arena[statement].kind {
+        StmtKind::Assign { target, value } => {
+            expression(out, arena, target)?;
+            out.write_all(b" = ")?;
+            expression(out, arena, value)
+        },
+
+        StmtKind::Call { name: ref command, ref arguments } => {
+            name(out, command)?;
+            if !arguments.is_empty() {
+                out.write_all(b" ")?;
+                list(out, arguments, |out, &argument| expression(out, arena, argument))?;
+            }
+            Ok(())
+        },
+
+        // Separators right after what's before them
+        StmtKind::Print(ref items) => {
+            out.write_all(b"Print")?;
+            for item in items {
+                match *item {
+                    PrintItem::Value(value) => {
+                        out.write_all(b" ")?;
+                        expression(out, arena, value)?;
+                    },
+                    PrintItem::Comma => out.write_all(b",")?,
+                    PrintItem::Semicolon => out.write_all(b";")?
+                }
+            }
+            Ok(())
+        },
+
+        StmtKind::If { condition, ref then_branch, ref else_ifs, ref else_branch, single_line }
+            if single_line && else_ifs.is_empty() &&
+               fits_line(arena, then_branch, else_branch, false) =>
+        {
+            out.write_all(b"If ")?;
+            expression(out, arena, condition)?;
+            out.write_all(b" Then ")?;
+            line(out, arena, then_branch, depth)?;
+            if let Some(ref else_branch) = *else_branch {
+                out.write_all(b" Else ")?;
+                line(out, arena, else_branch, depth)?;
+            }
+            Ok(())
+        },
+
+        StmtKind::If { condition, ref then_branch, ref else_ifs, ref else_branch, .. } => {
+            out.write_all(b"If ")?;
+            expression(out, arena, condition)?;
+            out.write_all(b" Then\n")?;
+            block(out, arena, then_branch, depth + 1)?;
+            for else_if in else_ifs {
+                indent(out, depth)?;
+                out.write_all(b"ElseIf ")?;
+                expression(out, arena, else_if.condition)?;
+                out.write_all(b" Then\n")?;
+                block(out, arena, &else_if.body, depth + 1)?;
+            }
+            if let Some(ref else_branch) = *else_branch {
+                indent(out, depth)?;
+                out.write_all(b"Else\n")?;
+                block(out, arena, else_branch, depth + 1)?;
+            }
+            indent(out, depth)?;
+            out.write_all(b"EndIf")
+        },
+
+        StmtKind::For { variable: ref counter, from, to, step, ref body } => {
+            out.write_all(b"For ")?;
+            variable(out, counter)?;
+            out.write_all(b" = ")?;
+            expression(out, arena, from)?;
+            out.write_all(b" To ")?;
+            expression(out, arena, to)?;
+            if let Some(step) = step {
+                out.write_all(b" Step ")?;
+                expression(out, arena, step)?;
+            }
+            out.write_all(b"\n")?;
+            block(out, arena, body, depth + 1)?;
+            indent(out, depth)?;
+            out.write_all(b"Next ")?;
+            variable(out, counter)
+        },
+
+        StmtKind::While { condition, ref body } => {
+            out.write_all(b"While ")?;
+            expression(out, arena, condition)?;
+            out.write_all(b"\n")?;
+            block(out, arena, body, depth + 1)?;
+            indent(out, depth)?;
+            out.write_all(b"Wend")
+        },
+
+        StmtKind::Repeat { ref body, until } => {
+            out.write_all(b"Repeat\n")?;
+            block(out, arena, body, depth + 1)?;
+            indent(out, depth)?;
+            match until {
+                Some(until) => {
+                    out.write_all(b"Until ")?;
+                    expression(out, arena, until)
+                },
+                None => out.write_all(b"Forever")
+            }
+        },
+
+        // Cases one level in, their bodies another
+        StmtKind::Select { value, ref cases, ref default } => {
+            out.write_all(b"Select ")?;
+            expression(out, arena, value)?;
+            out.write_all(b"\n")?;
+            for case in cases {
+                indent(out, depth + 1)?;
+                out.write_all(b"Case ")?;
+                list(out, &case.values, |out, value| match *value {
+                    CaseValue::Value(value) => expression(out, arena, value),
+                    CaseValue::Range(from, to) => {
+                        expression(out, arena, from)?;
+                        out.write_all(b" To ")?;
+                        expression(out, arena, to)
+                    }
+                })?;
+                out.write_all(b"\n")?;
+                block(out, arena, &case.body, depth + 2)?;
+            }
+            if let Some(ref default) = *default {
+                indent(out, depth + 1)?;
+                out.write_all(b"Default\n")?;
+                block(out, arena, default, depth + 2)?;
+            }
+            indent(out, depth)?;
+            out.write_all(b"EndSelect")
+        },
+
+        StmtKind::Dim(ref declared) => {
+            out.write_all(b"Dim ")?;
+            arrays(out, arena, declared)
+        },
+
+        StmtKind::ReDim(ref declared) => {
+            out.write_all(b"ReDim ")?;
+            arrays(out, arena, declared)
+        },
+
+        StmtKind::Declare { scope, ref variables } => {
+            out.write_all(match scope {
+                Scope::Global => b"Global ",
+                Scope::Local => b"Local "
+            })?;
+            list(out, variables, |out, v| variable(out, v))
+        },
+
+        StmtKind::Const { name: ref constant, value } => {
+            out.write_all(b"Const ")?;
+            variable(out, constant)?;
+            out.write_all(b" = ")?;
+            expression(out, arena, value)
+        },
+
+        StmtKind::Data(ref values) => {
+            out.write_all(b"Data ")?;
+            list(out, values, |out, &value| expression(out, arena, value))
+        },
+
+        StmtKind::Read(ref variables) => {
+            out.write_all(b"Read ")?;
+            list(out, variables, |out, v| variable(out, v))
+        },
+
+        StmtKind::Restore(ref label) => {
+            out.write_all(b"Restore")?;
+            if let Some(ref label) = *label {
+                out.write_all(b" ")?;
+                name(out, label)?;
+            }
+            Ok(())
+        },
+
+        StmtKind::Function(ref function) => {
+            out.write_all(b"Function ")?;
+            variable(out, &function.name)?;
+            out.write_all(b"(")?;
+            list(out, &function.parameters, |out, v| variable(out, v))?;
+            out.write_all(b")\n")?;
+            block(out, arena, &function.body, depth + 1)?;
+            indent(out, depth)?;
+            out.write_all(b"EndFunction")
+        },
+
+        StmtKind::Type(ref declared) => {
+            out.write_all(b"Type ")?;
+            name(out, &declared.name)?;
+            out.write_all(b"\n")?;
+            for field in &declared.fields {
+                indent(out, depth + 1)?;
+                out.write_all(b"Field ")?;
+                variable(out, field)?;
+                out.write_all(b"\n")?;
+            }
+            indent(out, depth)?;
+            out.write_all(b"EndType")
+        },
+
+        StmtKind::Include(ref path) => {
+            if path.contains(&['"', '\r', '\n'][..]) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "String can't be written as a literal"));
+            }
+            write!(out, "Include \"{}\"", path)
+        },
+
+        StmtKind::Label(ref label) => {
+            name(out, label)?;
+            out.write_all(b":")
+        },
+
+        StmtKind::Goto(ref label) => {
+            out.write_all(b"Goto ")?;
+            name(out, label)
+        },
+
+        StmtKind::Gosub(ref label) => {
+            out.write_all(b"Gosub ")?;
+            name(out, label)
+        },
+
+        StmtKind::Return(value) => {
+            out.write_all(b"Return")?;
+            if let Some(value) = value {
+                out.write_all(b" ")?;
+                expression(out, arena, value)?;
+            }
+            Ok(())
+        },
+
+        // Always with the loop, which is what a bare Exit meant anyway
+        StmtKind::Exit(target) => out.write_all(match target {
+            Loop::For => b"Exit For",
+            Loop::While => b"Exit While",
+            Loop::Repeat => b"Exit Repeat"
+        }),
+
+        StmtKind::End => out.write_all(b"End")
+    }
+}
+
+// Writes the program out as source that parses back into the same thing
+// Four spaces a level, keywords the way KEYWORDS has them and names as
+// they were spelled. There are no comments and every line ends in LF
+pub fn write_program<W: Write>(out: &mut W, program: &Program) -> io::Result<()> {
+    block(out, &program.arena, &program.statements, 0)
+}
+
+pub fn to_source(program: &Program) -> io::Result<String> {
+    let mut source = Vec::new();
+    write_program(&mut source, program)?;
+    Ok(String::from_utf8(source).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_str;
+    use passes::fold_constants;
+
+    fn printed(source: &str) -> String {
+        to_source(&parse_str(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn negations_print_as_written() {
+        assert_eq!(printed("x = -1 - -a\ny = -(-2) ^ 2\n"), "x = -1 - -a\ny = -(-2) ^ 2\n");
+        assert_eq!(printed("z = (-2) ^ 2\n"), "z = (-2) ^ 2\n");
+    }
+
+    #[test]
+    fn negative_literal_keeps_its_parens() {
+        let mut program = parse_str("x = (-2) ^ 2 + 2 * 3\n").unwrap();
+        fold_constants(&mut program);
+        let source = to_source(&program).unwrap();
+        assert_eq!(source, "x = (-2) ^ 2 + 6\n");
+        assert_eq!(to_source(&parse_str(&source).unwrap()).unwrap(), source);
+    }
+
+    #[test]
+    fn blocks_are_indented() {
+        let source = "If a Then\n    For i = 1 To 10 Step -1\n        Print i\n    Next i\nEndIf\n";
+        assert_eq!(printed(source), source);
+    }
+
+    #[test]
+    fn printed_programs_print_the_same_again() {
+        let source = "Const W = 640\nGlobal a, b$\nType Point\n    Field x\n    Field y#\nEndType\n\
+                      Function f#(a, b$)\n    Local i\n    For i = 1 To 10 Step 2\n\
+                      \x20       If a Then b = 1 : c = 2 Else Print a; b, : Exit For\n\
+                      \x20       If x = 1 Then\n            Print\n        ElseIf x = 2 Then\n\
+                      \x20           Return 1.5\n        EndIf\n    Next i\nEndFunction\n\
+                      start:\nWhile p\\x < 2 ^ 3 ^ 2 And (a Or b)\n    p\\x = p\\x + 1\nWend\n\
+                      Repeat\n    Select a\n        Case 1, 2 To 3\n            Print \"x\"\n\
+                      \x20       Default\n            Gosub start\n    EndSelect\nUntil (2 ^ 3) ^ 2\n\
+                      Repeat\nForever\nDim arr(10), g#(2, 2)\nReDim arr(2)\nData 1, -2, 3.5, \"s\"\n\
+                      Read a, b$\nRestore start\nInclude \"x.cb\"\nGoto start\nfoo 1, 2\nEnd\n";
+        assert_eq!(printed(source), source);
+    }
+
+    #[test]
+    fn spelling_is_kept_and_layout_isnt() {
+        assert_eq!(printed("IF Score>10\nPRINT  \"win\" ' comment\nEnd If\n  x=( 1+2 )*3"),
+                   "If Score > 10 Then\n    Print \"win\"\nEndIf\nx = (1 + 2) * 3\n");
+    }
+
+
+    #[test]
+    fn smallest_integer_parses_back() {
+        let mut program = parse_str("x = -9223372036854775807 - 1\ny = -(-9223372036854775807 - 1)\n")
+            .unwrap();
+        fold_constants(&mut program);
+        let source = to_source(&program).unwrap();
+        assert_eq!(source, "x = &H8000000000000000\ny = -&H8000000000000000\n");
+        let mut again = parse_str(&source).unwrap();
+        fold_constants(&mut again);
+        assert_eq!(to_source(&again).unwrap(), source);
+    }
+}
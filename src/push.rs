@@ -0,0 +1,198 @@
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use memchr::memchr2;
+
+use interner::Interner;
+use stream::LexResult;
+use tokenizer::{position_after, LexerConfig, Span, TokenIterator, TokenKind};
+
+// A tokenizer that's given its bytes instead of reading them, for when
+// they come in bit by bit
+// Tokens come out once their line is complete, the rest with finish()
+pub struct PushTokenizer {
+    config: LexerConfig,
+    interner: Interner,
+    // Unlexed bytes, starting right after an EOL where the lexer has no
+    // state, and where in the source they start
+    pending: Vec<u8>,
+    offset: usize,
+    line: usize,
+    column: usize
+}
+
+impl PushTokenizer {
+    pub fn new() -> PushTokenizer {
+        PushTokenizer::new_with_config(LexerConfig::default())
+    }
+
+    pub fn new_with_config(config: LexerConfig) -> PushTokenizer {
+        PushTokenizer {
+            config,
+            interner: Interner::new(),
+            pending: Vec::new(),
+            offset: 0,
+            line: 1,
+            column: 1
+        }
+    }
+
+    pub fn interner(&self) -> Interner {
+        self.interner.clone()
+    }
+
+    // Zero-length span right after everything fed so far
+    pub fn end(&self) -> Span {
+        let whole = Span {
+            line: self.line,
+            column: self.column,
+            byte_offset: self.offset,
+            length: self.pending.len()
+        };
+        let (line, column) = position_after(&self.pending, self.offset, whole, &self.config);
+
+        Span {
+            line,
+            column,
+            byte_offset: self.offset + self.pending.len(),
+            length: 0
+        }
+    }
+
+    // The tokens of every line the bytes complete
+    // Whatever is after the last EOL is lexed again once there's more that
+    // could end a line, a line break or the */ of a block comment, or
+    // anything after a CR that might have been waiting for its LF. A block
+    // comment over many lines is still lexed again at each of them
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<LexResult> {
+        let before = self.pending.last().cloned();
+        self.pending.extend_from_slice(bytes);
+        let ends = before == Some(b'\r') && !bytes.is_empty() ||
+                   before == Some(b'*') && bytes.first() == Some(&b'/') ||
+                   memchr2(b'\n', b'\r', bytes).is_some() ||
+                   bytes.windows(2).any(|pair| pair == b"*/");
+        if !ends {
+            return Vec::new();
+        }
+        let mut tokens = self.lex();
+
+        // One that ends on a CR that's the last byte could still get its LF
+        let end = self.offset + self.pending.len();
+        let cr = self.pending.last() == Some(&b'\r');
+        let last = tokens.iter().rposition(|t| match *t {
+            Ok(ref token) => {
+                token.kind == TokenKind::EOL &&
+                (token.span.byte_offset + token.span.length < end || !cr)
+            },
+            Err(_) => false
+        });
+
+        match last {
+            Some(index) => {
+                let span = tokens[index].as_ref().map(|token| token.span).unwrap();
+                let (line, column) = position_after(&self.pending, self.offset, span, &self.config);
+                let length = span.byte_offset + span.length - self.offset;
+
+                tokens.truncate(index + 1);
+                self.pending.drain(..length);
+                self.offset += length;
+                self.line = line;
+                self.column = column;
+                tokens
+            },
+            None => Vec::new()
+        }
+    }
+
+    // The tokens of whatever is left, there's nothing more coming
+    pub fn finish(&mut self) -> Vec<LexResult> {
+        let tokens = self.lex();
+        let end = self.end();
+
+        self.offset = end.byte_offset;
+        self.line = end.line;
+        self.column = end.column;
+        self.pending.clear();
+        tokens
+    }
+
+    fn lex(&self) -> Vec<LexResult> {
+        TokenIterator::new_with_config(&self.pending[..], self.config.clone())
+        .with_interner(self.interner.clone())
+        .continue_from(self.offset, self.line, self.column)
+        .collect()
+    }
+}
+
+impl Default for PushTokenizer {
+    fn default() -> PushTokenizer {
+        PushTokenizer::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokenizer::Keyword;
+
+    fn kinds(tokens: Vec<LexResult>) -> Vec<TokenKind> {
+        tokens.into_iter().map(|token| token.unwrap().kind).collect()
+    }
+
+    #[test]
+    fn complete_line_comes_out_right_away() {
+        let mut tokens = PushTokenizer::new();
+        assert_eq!(kinds(tokens.feed(b"Print 1\n")),
+                   vec![TokenKind::Keyword(Keyword::Print), TokenKind::Integer, TokenKind::EOL]);
+        assert_eq!(kinds(tokens.finish()), vec![]);
+    }
+
+    #[test]
+    fn unfinished_line_waits() {
+        let mut tokens = PushTokenizer::new();
+        assert_eq!(kinds(tokens.feed(b"x = ")), vec![]);
+        assert_eq!(kinds(tokens.feed(b"1\ny")),
+                   vec![TokenKind::Text, TokenKind::Equals, TokenKind::Integer, TokenKind::EOL]);
+        assert_eq!(kinds(tokens.finish()), vec![TokenKind::Text]);
+    }
+
+    #[test]
+    fn cr_waits_for_its_lf() {
+        let mut tokens = PushTokenizer::new();
+        assert_eq!(kinds(tokens.feed(b"End\r")), vec![]);
+        assert_eq!(kinds(tokens.feed(b"\n")), vec![TokenKind::Keyword(Keyword::End), TokenKind::EOL]);
+        assert_eq!(kinds(tokens.finish()), vec![]);
+    }
+
+    #[test]
+    fn lines_can_end_between_feeds() {
+        let config = LexerConfig::default().lone_cr_is_newline(true);
+        let mut tokens = PushTokenizer::new_with_config(config.clone());
+        assert_eq!(kinds(tokens.feed(b"End\r")), vec![]);
+        assert_eq!(kinds(tokens.feed(b"x")), vec![TokenKind::Keyword(Keyword::End), TokenKind::EOL]);
+        assert_eq!(kinds(tokens.feed(b" /* a\n b *")), vec![]);
+        // A block comment over lines is an EOL
+        assert_eq!(kinds(tokens.feed(b"/")), vec![TokenKind::Text, TokenKind::EOL]);
+        assert_eq!(kinds(tokens.feed(b" y")), vec![]);
+        assert_eq!(kinds(tokens.finish()), vec![TokenKind::Text]);
+
+        // A byte at a time comes out the same as all at once
+        let source = b"x = 1 /* one\ntwo */ y$ = \"a\" ' z\r\n\rq";
+        let mut tokens = PushTokenizer::new_with_config(config.clone());
+        let mut fed = Vec::new();
+        for &byte in source.iter() {
+            fed.extend(tokens.feed(&[byte]));
+        }
+        fed.extend(tokens.finish());
+        let lexed: Vec<_> = TokenIterator::new_with_config(&source[..], config).collect();
+        assert_eq!(fed, lexed);
+    }
+
+    #[test]
+    fn spans_carry_on_between_feeds() {
+        let mut tokens = PushTokenizer::new();
+        tokens.feed(b"a\n");
+        let b = tokens.feed(b"  b\n").remove(0).unwrap();
+        assert_eq!(b.span, Span { line: 2, column: 3, byte_offset: 4, length: 1 });
+    }
+}
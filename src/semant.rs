@@ -0,0 +1,595 @@
+// What a program declares, each thing by its name and where it can be seen
+// from, for the passes that need to know what a name is and for whatever
+// runs the program
+
+use std::collections::HashMap;
+use std::mem;
+use std::ops::Index;
+
+use ast::{Arena, ExprId, ExprKind, Name, NodeId, Program, Scope, Sigil, StmtId, StmtKind, Variable};
+use passes::Warning;
+use tokenizer::Span;
+use visit::{walk_expr, walk_stmt, Visitor};
+
+// Where a symbol is in its SymbolTable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SymbolId(u32);
+
+impl SymbolId {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    // A variable that's never declared is a local of wherever it's first
+    // used, which is where its declaration is then
+    Variable { declared: bool },
+    Parameter,
+    // How many dimensions it was first Dim'd with
+    Array { dimensions: usize },
+    Function { parameters: usize },
+    Constant,
+    Label
+}
+
+// Where a symbol can be seen from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolScope {
+    // Everywhere, which is where arrays, Functions, constants and labels
+    // all are, and the variables declared Global
+    Global,
+    // The program outside of its Functions
+    Main,
+    // Inside the Function statement, its parameters and locals
+    Function(StmtId)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: Name,
+    // Labels don't have one, theirs is Sigil::None
+    pub sigil: Sigil,
+    pub kind: SymbolKind,
+    pub scope: SymbolScope,
+    // The statement declaring it, or the expression for a variable first
+    // used in one
+    pub declaration: NodeId,
+    pub span: Span
+}
+
+// Built once for a program, like parents::Parents
+// A name declared again the same way in the same scope is the same symbol,
+// the first declaration is the one that's kept. One that's declared as
+// different kinds of things there, like an array and a Function, is a
+// symbol for each
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    // By scope and compared name, in the order they were declared
+    names: HashMap<SymbolScope, HashMap<String, Vec<SymbolId>>>
+}
+
+impl SymbolTable {
+    pub fn new(program: &Program) -> SymbolTable {
+        let mut builder = Builder {
+            table: SymbolTable::default(),
+            scope: SymbolScope::Main,
+            uses: false
+        };
+        // Everything declared first, so a use can be of something declared
+        // after it
+        builder.visit_program(program);
+        builder.uses = true;
+        builder.visit_program(program);
+        builder.table
+    }
+
+    // Every symbol, by id
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // The ones with the compared name declared right in the scope, not the
+    // Global ones it also sees
+    pub fn declared(&self, scope: SymbolScope, name: &str) -> &[SymbolId] {
+        match self.names.get(&scope).and_then(|names| names.get(name)) {
+            Some(symbols) => symbols,
+            None => &[]
+        }
+    }
+
+    // Everything the name could be in the scope, its own first
+    pub fn visible(&self, scope: SymbolScope, name: &str) -> Vec<SymbolId> {
+        let mut symbols = self.declared(scope, name).to_vec();
+        if scope != SymbolScope::Global {
+            symbols.extend_from_slice(self.declared(SymbolScope::Global, name));
+        }
+        symbols
+    }
+
+    fn add(&mut self, symbol: Symbol) -> SymbolId {
+        let kind = mem::discriminant(&symbol.kind);
+        let id = SymbolId(self.symbols.len() as u32);
+        let symbols = &mut self.symbols;
+        let declared = self.names.entry(symbol.scope).or_default()
+                       .entry(symbol.name.name.clone()).or_default();
+        match declared.iter().find(|&&id| mem::discriminant(&symbols[id.index()].kind) == kind) {
+            Some(&existing) => existing,
+            None => {
+                declared.push(id);
+                symbols.push(symbol);
+                id
+            }
+        }
+    }
+}
+
+impl Index<SymbolId> for SymbolTable {
+    type Output = Symbol;
+
+    fn index(&self, id: SymbolId) -> &Symbol {
+        &self.symbols[id.index()]
+    }
+}
+
+struct Builder {
+    table: SymbolTable,
+    // Main or the Function being walked
+    scope: SymbolScope,
+    // The declarations are found on the first walk and the variables that
+    // are only used on the second
+    uses: bool
+}
+
+impl Builder {
+    fn declare(&mut self, name: &Name, sigil: Sigil, kind: SymbolKind, scope: SymbolScope,
+               declaration: NodeId, span: Span) {
+        self.table.add(Symbol {
+            name: name.clone(),
+            sigil,
+            kind,
+            scope,
+            declaration,
+            span
+        });
+    }
+
+    fn declare_stmt(&mut self, arena: &Arena, stmt: StmtId) {
+        let span = arena[stmt].span;
+        let at = NodeId::Stmt(stmt);
+        match arena[stmt].kind {
+            StmtKind::Declare { scope, ref variables } => {
+                let scope = match scope {
+                    Scope::Global => SymbolScope::Global,
+                    Scope::Local => self.scope
+                };
+                for variable in variables {
+                    let kind = SymbolKind::Variable { declared: true };
+                    self.declare(&variable.name, variable.sigil, kind, scope, at, span);
+                }
+            },
+            StmtKind::Dim(ref arrays) => for array in arrays {
+                let kind = SymbolKind::Array { dimensions: array.dimensions.len() };
+                let variable = &array.variable;
+                self.declare(&variable.name, variable.sigil, kind, SymbolScope::Global, at, span);
+            },
+            StmtKind::Const { ref name, .. } => {
+                let kind = SymbolKind::Constant;
+                self.declare(&name.name, name.sigil, kind, SymbolScope::Global, at, span);
+            },
+            StmtKind::Label(ref label) => {
+                self.declare(label, Sigil::None, SymbolKind::Label, SymbolScope::Global, at, span);
+            },
+            StmtKind::Function(ref function) => {
+                let kind = SymbolKind::Function { parameters: function.parameters.len() };
+                let name = &function.name;
+                self.declare(&name.name, name.sigil, kind, SymbolScope::Global, at, span);
+                let scope = SymbolScope::Function(stmt);
+                for parameter in &function.parameters {
+                    let kind = SymbolKind::Parameter;
+                    self.declare(&parameter.name, parameter.sigil, kind, scope, at, span);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // A variable that nothing declares gets declared where it's used
+    fn used(&mut self, variable: &Variable, at: NodeId, span: Span) {
+        let table = &self.table;
+        let found = table.visible(self.scope, &variable.name.name).into_iter()
+                    .any(|id| rank(&table[id].kind, Usage::Variable).is_some());
+        if !found {
+            let kind = SymbolKind::Variable { declared: false };
+            let scope = self.scope;
+            self.declare(&variable.name, variable.sigil, kind, scope, at, span);
+        }
+    }
+}
+
+impl Visitor for Builder {
+    fn visit_stmt(&mut self, arena: &Arena, stmt: StmtId) {
+        if !self.uses {
+            self.declare_stmt(arena, stmt);
+        }
+        else {
+            let span = arena[stmt].span;
+            match arena[stmt].kind {
+                StmtKind::For { ref variable, .. } => self.used(variable, NodeId::Stmt(stmt), span),
+                StmtKind::Read(ref variables) => for variable in variables {
+                    self.used(variable, NodeId::Stmt(stmt), span);
+                },
+                _ => {}
+            }
+        }
+
+        if let StmtKind::Function(_) = arena[stmt].kind {
+            self.scope = SymbolScope::Function(stmt);
+            walk_stmt(self, arena, stmt);
+            self.scope = SymbolScope::Main;
+        }
+        else {
+            walk_stmt(self, arena, stmt);
+        }
+    }
+
+    fn visit_expr(&mut self, arena: &Arena, expr: ExprId) {
+        if self.uses {
+            if let ExprKind::Variable(ref variable) = arena[expr].kind {
+                self.used(variable, NodeId::Expr(expr), arena[expr].span);
+            }
+        }
+        walk_expr(self, arena, expr)
+    }
+}
+
+// How a name is used
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Usage {
+    Variable,
+    // grid(x, y)
+    Index,
+    // f(a) in an expression, or f a as a command
+    Call,
+    Label
+}
+
+// Whether a symbol of the kind can be what a name used that way is, and
+// how much less likely it is than the others that can
+fn rank(kind: &SymbolKind, usage: Usage) -> Option<usize> {
+    match (usage, *kind) {
+        (Usage::Variable, SymbolKind::Variable { .. }) | (Usage::Variable, SymbolKind::Parameter) |
+        (Usage::Variable, SymbolKind::Constant) => Some(0),
+        (Usage::Index, SymbolKind::Array { .. }) | (Usage::Call, SymbolKind::Function { .. }) |
+        (Usage::Label, SymbolKind::Label) => Some(0),
+        (Usage::Index, SymbolKind::Function { .. }) |
+        (Usage::Call, SymbolKind::Array { .. }) => Some(1),
+        _ => None
+    }
+}
+
+fn describe(symbol: &Symbol) -> &'static str {
+    match symbol.kind {
+        SymbolKind::Variable { .. } if symbol.scope == SymbolScope::Global => "a Global variable",
+        SymbolKind::Variable { .. } => "a Local variable",
+        SymbolKind::Parameter => "a parameter",
+        SymbolKind::Array { .. } => "an array",
+        SymbolKind::Function { .. } => "a Function",
+        SymbolKind::Constant => "a constant",
+        SymbolKind::Label => "a label"
+    }
+}
+
+// The symbol of every name in a program, by the node it's in, see resolve
+#[derive(Debug, Clone, Default)]
+pub struct Resolved {
+    exprs: HashMap<ExprId, SymbolId>,
+    stmts: HashMap<StmtId, Vec<SymbolId>>
+}
+
+impl Resolved {
+    // That of a Variable, Index or Call
+    pub fn expr(&self, expr: ExprId) -> Option<SymbolId> {
+        self.exprs.get(&expr).cloned()
+    }
+
+    // Those of the names right in the statement, in the order they're
+    // written, like the variables of a Read or the name of a Function then
+    // its parameters
+    pub fn stmt(&self, stmt: StmtId) -> &[SymbolId] {
+        match self.stmts.get(&stmt) {
+            Some(symbols) => symbols,
+            None => &[]
+        }
+    }
+}
+
+// Which symbol of the table every name used or declared in the program is
+// A name is looked up where it's used first, so a Local or a parameter
+// hides a Global of the same name, then in the Global scope. One that could
+// still be more than one symbol there, like a parameter that's declared
+// Local too or a constant that's also a Global, gets a warning and is the
+// one declared first, or the array for an Index and the Function for a
+// Call when there's both
+// Commands and Functions that come with CoolBasic aren't declared anywhere
+// and don't resolve to anything, and neither do the fields after a \
+pub fn resolve(program: &Program, table: &SymbolTable) -> (Resolved, Vec<Warning>) {
+    let mut resolver = Resolver {
+        table,
+        scope: SymbolScope::Main,
+        resolved: Resolved::default(),
+        warnings: Vec::new()
+    };
+    resolver.visit_program(program);
+    resolver.warnings.sort_by_key(|warning| warning.span.byte_offset);
+    (resolver.resolved, resolver.warnings)
+}
+
+struct Resolver<'a> {
+    table: &'a SymbolTable,
+    scope: SymbolScope,
+    resolved: Resolved,
+    warnings: Vec<Warning>
+}
+
+impl<'a> Resolver<'a> {
+    fn lookup(&mut self, name: &Name, usage: Usage, span: Span) -> Option<SymbolId> {
+        let table = self.table;
+        let fits = |symbols: &[SymbolId]| -> Vec<SymbolId> {
+            let mut fits: Vec<_> = symbols.iter().cloned()
+                                   .filter(|&id| rank(&table[id].kind, usage).is_some()).collect();
+            fits.sort_by_key(|&id| rank(&table[id].kind, usage));
+            fits
+        };
+        let mut found = Vec::new();
+        if self.scope != SymbolScope::Global {
+            found = fits(table.declared(self.scope, &name.name));
+        }
+        if found.is_empty() {
+            found = fits(table.declared(SymbolScope::Global, &name.name));
+        }
+
+        if found.len() > 1 {
+            let all: Vec<_> = found.iter().map(|&id| describe(&table[id])).collect();
+            self.warnings.push(Warning {
+                message: format!("{} could be {}, it's {} here", name.spelling, all.join(" or "),
+                                 all[0]),
+                span
+            });
+        }
+        found.first().cloned()
+    }
+
+    // The one the statement declares, which is of the same kind as like,
+    // whatever's in that
+    fn declared(&self, scope: SymbolScope, name: &Name, like: SymbolKind) -> Option<SymbolId> {
+        let table = self.table;
+        table.declared(scope, &name.name).iter().cloned()
+        .find(|&id| mem::discriminant(&table[id].kind) == mem::discriminant(&like))
+    }
+
+    fn names(&mut self, arena: &Arena, stmt: StmtId) -> Vec<Option<SymbolId>> {
+        let span = arena[stmt].span;
+        match arena[stmt].kind {
+            StmtKind::For { ref variable, .. } => {
+                vec![self.lookup(&variable.name, Usage::Variable, span)]
+            },
+            StmtKind::Read(ref variables) => variables.iter().map(|variable| {
+                self.lookup(&variable.name, Usage::Variable, span)
+            }).collect(),
+            StmtKind::Declare { scope, ref variables } => {
+                let scope = match scope {
+                    Scope::Global => SymbolScope::Global,
+                    Scope::Local => self.scope
+                };
+                let like = SymbolKind::Variable { declared: true };
+                variables.iter().map(|variable| self.declared(scope, &variable.name, like)).collect()
+            },
+            StmtKind::Dim(ref arrays) | StmtKind::ReDim(ref arrays) => arrays.iter().map(|array| {
+                let like = SymbolKind::Array { dimensions: 0 };
+                self.declared(SymbolScope::Global, &array.variable.name, like)
+            }).collect(),
+            StmtKind::Const { ref name, .. } => {
+                vec![self.declared(SymbolScope::Global, &name.name, SymbolKind::Constant)]
+            },
+            StmtKind::Function(ref function) => {
+                let like = SymbolKind::Function { parameters: 0 };
+                let mut names = vec![self.declared(SymbolScope::Global, &function.name.name, like)];
+                for parameter in &function.parameters {
+                    let scope = SymbolScope::Function(stmt);
+                    names.push(self.declared(scope, &parameter.name, SymbolKind::Parameter));
+                }
+                names
+            },
+            StmtKind::Call { ref name, .. } => vec![self.lookup(name, Usage::Call, span)],
+            StmtKind::Restore(Some(ref label)) | StmtKind::Label(ref label) |
+            StmtKind::Goto(ref label) | StmtKind::Gosub(ref label) => {
+                vec![self.lookup(label, Usage::Label, span)]
+            },
+            _ => Vec::new()
+        }
+    }
+}
+
+impl<'a> Visitor for Resolver<'a> {
+    fn visit_stmt(&mut self, arena: &Arena, stmt: StmtId) {
+        let names: Vec<_> = self.names(arena, stmt).into_iter().flatten().collect();
+        if !names.is_empty() {
+            self.resolved.stmts.insert(stmt, names);
+        }
+
+        if let StmtKind::Function(_) = arena[stmt].kind {
+            self.scope = SymbolScope::Function(stmt);
+            walk_stmt(self, arena, stmt);
+            self.scope = SymbolScope::Main;
+        }
+        else {
+            walk_stmt(self, arena, stmt);
+        }
+    }
+
+    fn visit_expr(&mut self, arena: &Arena, expr: ExprId) {
+        let span = arena[expr].span;
+        let symbol = match arena[expr].kind {
+            ExprKind::Variable(ref variable) => self.lookup(&variable.name, Usage::Variable, span),
+            ExprKind::Index { ref array, .. } => self.lookup(&array.name, Usage::Index, span),
+            ExprKind::Call { ref name, .. } => self.lookup(&name.name, Usage::Call, span),
+            _ => None
+        };
+        if let Some(symbol) = symbol {
+            self.resolved.exprs.insert(expr, symbol);
+        }
+        walk_expr(self, arena, expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_str;
+
+    const SOURCE: &str = "Dim grid(2, 3)\nConst MAX = 10\nGlobal score\n\
+                          Function add(a, b)\n    Local total\n    total = a + b\n    Return total\n\
+                          EndFunction\ntop:\ny = MAX\nGoto top\n";
+
+    // The only symbol with the name in the scope
+    fn only(table: &SymbolTable, scope: SymbolScope, name: &str) -> Symbol {
+        match *table.declared(scope, name) {
+            [id] => table[id].clone(),
+            ref ids => panic!("{} has {} symbols", name, ids.len())
+        }
+    }
+
+    #[test]
+    fn every_kind_of_declaration() {
+        let program = parse_str(SOURCE).unwrap();
+        let table = SymbolTable::new(&program);
+        let function = program.statements[3];
+        let local = SymbolScope::Function(function);
+
+        let grid = only(&table, SymbolScope::Global, "grid");
+        assert_eq!(grid.kind, SymbolKind::Array { dimensions: 2 });
+        assert_eq!(grid.declaration, NodeId::Stmt(program.statements[0]));
+
+        let max = only(&table, SymbolScope::Global, "max");
+        assert_eq!(max.kind, SymbolKind::Constant);
+        assert_eq!(max.name.spelling, "MAX");
+
+        let score = only(&table, SymbolScope::Global, "score");
+        assert_eq!(score.kind, SymbolKind::Variable { declared: true });
+
+        let add = only(&table, SymbolScope::Global, "add");
+        assert_eq!(add.kind, SymbolKind::Function { parameters: 2 });
+        assert_eq!(only(&table, local, "a").kind, SymbolKind::Parameter);
+        assert_eq!(only(&table, local, "b").kind, SymbolKind::Parameter);
+        assert_eq!(only(&table, local, "total").kind, SymbolKind::Variable { declared: true });
+
+        let top = only(&table, SymbolScope::Global, "top");
+        assert_eq!(top.kind, SymbolKind::Label);
+        assert_eq!(top.declaration, NodeId::Stmt(program.statements[4]));
+
+        assert_eq!(table.symbols().len(), 9);
+    }
+
+    #[test]
+    fn undeclared_variables_are_local_where_first_used() {
+        let program = parse_str(SOURCE).unwrap();
+        let table = SymbolTable::new(&program);
+
+        let y = only(&table, SymbolScope::Main, "y");
+        assert_eq!(y.kind, SymbolKind::Variable { declared: false });
+        match y.declaration {
+            NodeId::Expr(expr) => assert_eq!(program.arena[expr].span, y.span),
+            NodeId::Stmt(_) => panic!("y is declared by the expression it's in")
+        }
+        assert!(table.declared(SymbolScope::Main, "total").is_empty());
+    }
+
+    #[test]
+    fn functions_see_globals_after_their_own() {
+        let program = parse_str("Global x\nFunction f(x)\nEndFunction\n").unwrap();
+        let table = SymbolTable::new(&program);
+        let local = SymbolScope::Function(program.statements[1]);
+
+        let visible: Vec<_> = table.visible(local, "x").into_iter().map(|id| table[id].kind).collect();
+        assert_eq!(visible, vec![SymbolKind::Parameter, SymbolKind::Variable { declared: true }]);
+    }
+
+    #[test]
+    fn declaring_again_is_the_same_symbol() {
+        let program = parse_str("Dim grid(2)\nDim grid(4)\nx = 1\nx = 2\n").unwrap();
+        let table = SymbolTable::new(&program);
+
+        assert_eq!(only(&table, SymbolScope::Global, "grid").kind, SymbolKind::Array { dimensions: 1 });
+        assert_eq!(table.declared(SymbolScope::Main, "x").len(), 1);
+    }
+
+    fn resolved(source: &str) -> (Program, SymbolTable, Resolved, Vec<Warning>) {
+        let program = parse_str(source).unwrap();
+        let table = SymbolTable::new(&program);
+        let (resolved, warnings) = resolve(&program, &table);
+        (program, table, resolved, warnings)
+    }
+
+    // The expression a statement assigns
+    fn value(program: &Program, stmt: StmtId) -> ExprId {
+        match program.arena[stmt].kind {
+            StmtKind::Assign { value, .. } => value,
+            ref other => panic!("{:?} isn't an Assign", other)
+        }
+    }
+
+    #[test]
+    fn array_and_function_with_the_same_name() {
+        let (program, table, resolved, warnings) =
+            resolved("Dim f(10)\nFunction f(a)\n    Return a\nEndFunction\nx = f(1)\n");
+        let index = value(&program, program.statements[2]);
+
+        let symbol = resolved.expr(index).unwrap();
+        assert_eq!(table[symbol].kind, SymbolKind::Array { dimensions: 1 });
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "f could be an array or a Function, it's an array here");
+        assert_eq!(warnings[0].span, program.arena[index].span);
+    }
+
+    #[test]
+    fn locals_hide_globals() {
+        let (program, table, resolved, warnings) =
+            resolved("Global x\nFunction f(x)\n    y = x\nEndFunction\ny = x\n");
+        let function = program.statements[1];
+        let inside = match program.arena[function].kind {
+            StmtKind::Function(ref function) => value(&program, function.body[0]),
+            _ => unreachable!()
+        };
+        let outside = value(&program, program.statements[2]);
+
+        assert!(warnings.is_empty());
+        assert_eq!(table[resolved.expr(inside).unwrap()].kind, SymbolKind::Parameter);
+        assert_eq!(table[resolved.expr(outside).unwrap()].scope, SymbolScope::Global);
+    }
+
+    #[test]
+    fn statements_have_their_names_in_order() {
+        let (program, table, resolved, _) =
+            resolved("Function add(a, b)\nEndFunction\nlabel:\nGoto label\nRead p, q\n");
+        let names = |stmt: StmtId| -> Vec<String> {
+            resolved.stmt(stmt).iter().map(|&id| table[id].name.name.clone()).collect()
+        };
+
+        assert_eq!(names(program.statements[0]), vec!["add", "a", "b"]);
+        assert_eq!(names(program.statements[2]), vec!["label"]);
+        assert_eq!(resolved.stmt(program.statements[1]), resolved.stmt(program.statements[2]));
+        assert_eq!(names(program.statements[3]), vec!["p", "q"]);
+    }
+
+    #[test]
+    fn commands_that_come_with_coolbasic_are_nothing() {
+        let (program, _, resolved, warnings) = resolved("DrawScreen\nx = Rnd(10)\n");
+
+        assert!(resolved.stmt(program.statements[0]).is_empty());
+        assert_eq!(resolved.expr(value(&program, program.statements[1])), None);
+        assert!(warnings.is_empty());
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use interner::Interner;
+use tokenizer::{Keyword, LexError, LexerConfig, Payload, Span, Token, TokenIterator, TokenKind};
+
+// Which file something came from, spans only say where in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FileId(u32);
+
+impl FileId {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+struct SourceFile {
+    path: PathBuf,
+    source: Vec<u8>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceError {
+    Lex(LexError),
+    // The included file couldn't be read
+    Io { path: PathBuf, kind: io::ErrorKind, span: Span },
+    // Include without a string after it
+    MissingPath { span: Span },
+    // A file that ends up including itself
+    Cycle { path: PathBuf, span: Span }
+}
+
+impl SourceError {
+    pub fn span(&self) -> Span {
+        match *self {
+            SourceError::Lex(ref e) => e.span(),
+            SourceError::Io { span, .. } |
+            SourceError::MissingPath { span } |
+            SourceError::Cycle { span, .. } => span
+        }
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceError::Lex(ref e) => write!(f, "{}", e),
+            SourceError::Io { ref path, kind, span } => {
+                write!(f, "{}: Couldn't include {}: {:?}", span, path.display(), kind)
+            },
+            SourceError::MissingPath { span } => {
+                write!(f, "{}: Include needs a file name string", span)
+            },
+            SourceError::Cycle { ref path, span } => {
+                write!(f, "{}: {} ends up including itself", span, path.display())
+            }
+        }
+    }
+}
+
+// A token or an error, and the file it's from
+pub type FileToken = (FileId, Result<Token<'static>, SourceError>);
+
+// Owns every file of a project, each one read only once
+// Tokens from all of them share one interner
+pub struct SourceManager {
+    files: Vec<SourceFile>,
+    paths: HashMap<PathBuf, FileId>,
+    config: LexerConfig,
+    interner: Interner
+}
+
+impl SourceManager {
+    pub fn new(config: LexerConfig) -> SourceManager {
+        SourceManager {
+            files: Vec::new(),
+            paths: HashMap::new(),
+            config,
+            interner: Interner::new()
+        }
+    }
+
+    // A file that's already in memory, includes in it are looked up next
+    // to the path
+    pub fn add<P: AsRef<Path>>(&mut self, path: P, source: Vec<u8>) -> FileId {
+        let id = FileId(self.files.len() as u32);
+        self.files.push(SourceFile {
+            path: path.as_ref().to_path_buf(),
+            source
+        });
+        self.paths.insert(SourceManager::key(path.as_ref()), id);
+        id
+    }
+
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FileId> {
+        if let Some(&id) = self.paths.get(&SourceManager::key(path.as_ref())) {
+            return Ok(id);
+        }
+
+        let source = fs::read(path.as_ref())?;
+        Ok(self.add(path, source))
+    }
+
+    pub fn path(&self, file: FileId) -> &Path {
+        &self.files[file.index()].path
+    }
+
+    pub fn source(&self, file: FileId) -> &[u8] {
+        &self.files[file.index()].source
+    }
+
+    pub fn interner(&self) -> Interner {
+        self.interner.clone()
+    }
+
+    // Tokens of just the file, with the interner the others use
+    pub fn tokenizer(&self, file: FileId) -> TokenIterator<&[u8]> {
+        TokenIterator::new_with_config(self.source(file), self.config.clone())
+        .with_interner(self.interner.clone())
+    }
+
+    // The same file through different paths is still the same file
+    fn key(path: &Path) -> PathBuf {
+        fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+    }
+
+    // Tokens of the file with every Include line replaced by the tokens of
+    // the file it names, relative to the including file
+    // The spans are always in the file the token is from
+    pub fn tokens(&mut self, root: FileId) -> Vec<FileToken> {
+        let mut tokens = Vec::new();
+        self.splice(root, &mut vec![root], &mut tokens);
+        tokens
+    }
+
+    fn splice(&mut self, file: FileId, including: &mut Vec<FileId>, out: &mut Vec<FileToken>) {
+        let lexed = self.tokenizer(file).collect::<Vec<_>>();
+        let directory = self.path(file).parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let mut lexed = lexed.into_iter().peekable();
+        let mut line_start = true;
+
+        while let Some(result) = lexed.next() {
+            let include = match result {
+                Ok(ref token) => {
+                    line_start && token.kind == TokenKind::Keyword(Keyword::Include)
+                },
+                Err(_) => false
+            };
+
+            line_start = match result {
+                Ok(ref token) => token.kind == TokenKind::EOL,
+                Err(_) => false
+            };
+
+            let span = match result {
+                Ok(ref token) if include => token.span,
+                Ok(token) => {
+                    out.push((file, Ok(token)));
+                    continue;
+                },
+                Err(e) => {
+                    out.push((file, Err(SourceError::Lex(e))));
+                    continue;
+                }
+            };
+
+            let name = match lexed.peek() {
+                Some(&Ok(Token { payload: Payload::String(ref name), .. })) => {
+                    String::from_utf8_lossy(name).into_owned()
+                },
+                _ => {
+                    out.push((file, Err(SourceError::MissingPath { span })));
+                    continue;
+                }
+            };
+            lexed.next();
+
+            let path = directory.join(name);
+            match self.load(&path) {
+                Ok(included) if including.contains(&included) => {
+                    out.push((file, Err(SourceError::Cycle { path, span })));
+                },
+                Ok(included) => {
+                    including.push(included);
+                    self.splice(included, including, out);
+                    including.pop();
+                },
+                Err(e) => {
+                    out.push((file, Err(SourceError::Io {
+                        path,
+                        kind: e.kind(),
+                        span
+                    })));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Paths that aren't on disk are found by what they were added as
+    fn project(files: &[(&str, &str)]) -> (SourceManager, Vec<FileId>) {
+        let mut sources = SourceManager::new(LexerConfig::new());
+        let ids = files.iter()
+                  .map(|&(path, source)| sources.add(Path::new("project").join(path), source.into()))
+                  .collect();
+        (sources, ids)
+    }
+
+    fn kinds(tokens: &[FileToken]) -> Vec<(usize, Option<TokenKind>)> {
+        tokens.iter()
+        .map(|&(file, ref token)| (file.index(), token.as_ref().ok().map(|token| token.kind)))
+        .collect()
+    }
+
+    #[test]
+    fn includes_are_spliced_in() {
+        let (mut sources, ids) = project(&[
+            ("main.cb", "a\nInclude \"lib/b.cb\"\nc"),
+            ("lib/b.cb", "b\nInclude \"d.cb\""),
+            ("lib/d.cb", "d"),
+        ]);
+        let tokens = sources.tokens(ids[0]);
+        assert_eq!(kinds(&tokens), vec![(0, Some(TokenKind::Text)), (0, Some(TokenKind::EOL)),
+                                        (1, Some(TokenKind::Text)), (1, Some(TokenKind::EOL)),
+                                        (2, Some(TokenKind::Text)),
+                                        (0, Some(TokenKind::EOL)), (0, Some(TokenKind::Text))]);
+        // Spans are in the file the token is from
+        assert_eq!(tokens[4].1.as_ref().unwrap().span.line, 1);
+        assert_eq!(tokens[6].1.as_ref().unwrap().span.line, 3);
+        assert_eq!(sources.path(ids[2]), Path::new("project/lib/d.cb"));
+    }
+
+    #[test]
+    fn including_itself_is_an_error() {
+        let (mut sources, ids) = project(&[
+            ("a.cb", "Include \"b.cb\""),
+            ("b.cb", "x\nInclude \"a.cb\""),
+        ]);
+        let tokens = sources.tokens(ids[0]);
+        assert_eq!(tokens.len(), 3);
+        match tokens[2] {
+            (file, Err(SourceError::Cycle { ref path, span })) => {
+                assert_eq!(file, ids[1]);
+                assert_eq!(path, Path::new("project/a.cb"));
+                assert_eq!(span.line, 2);
+            },
+            ref other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn include_needs_a_file_that_is_there() {
+        let (mut sources, ids) = project(&[("main.cb", "Include x\nInclude \"not/there.cb\"")]);
+        let tokens = sources.tokens(ids[0]);
+        match tokens[0].1 {
+            Err(SourceError::MissingPath { span }) => assert_eq!(span.column, 1),
+            ref other => panic!("{:?}", other)
+        }
+        match tokens[3].1 {
+            Err(SourceError::Io { kind, span, .. }) => {
+                assert_eq!(kind, io::ErrorKind::NotFound);
+                assert_eq!(span.line, 2);
+            },
+            ref other => panic!("{:?}", other)
+        }
+    }
+}
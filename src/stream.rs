@@ -0,0 +1,259 @@
+#[cfg(feature = "std")]
+use std::collections::VecDeque;
+#[cfg(not(feature = "std"))]
+use alloc::collections::VecDeque;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+use interner::Interner;
+use tokenizer::{LexError, Source, Token, TokenIterator, TokenKind};
+
+pub type LexResult = Result<Token<'static>, LexError>;
+
+// Where to come back to with TokenStream::rewind
+// Every checkpoint has to be rewound to or committed, otherwise the
+// stream keeps every token after it around
+#[derive(Debug)]
+pub struct Checkpoint(usize);
+
+// Tokens with as much lookahead as you want and backtracking
+// Positions here count tokens, not bytes
+pub struct TokenStream<R: Source> {
+    tokens: TokenIterator<R>,
+    // Read but not yet thrown away, starting from token number base
+    buffer: VecDeque<LexResult>,
+    base: usize,
+    // Number of the next token next() gives
+    cursor: usize,
+    // Checkpoints not rewound to or committed yet
+    pinned: usize
+}
+
+impl<R: Source> TokenStream<R> {
+    pub fn new(tokens: TokenIterator<R>) -> TokenStream<R> {
+        TokenStream {
+            tokens,
+            buffer: VecDeque::new(),
+            base: 0,
+            cursor: 0,
+            pinned: 0
+        }
+    }
+
+    pub fn interner(&self) -> Interner {
+        self.tokens.interner()
+    }
+
+    // See TokenIterator::trailing_trivia
+    pub fn trailing_trivia(&mut self) -> Vec<u8> {
+        self.tokens.trailing_trivia()
+    }
+
+    pub fn peek(&mut self) -> Option<&LexResult> {
+        self.peek_n(0)
+    }
+
+    // peek_n(0) is the token next() would give, peek_n(1) the one after
+    pub fn peek_n(&mut self, k: usize) -> Option<&LexResult> {
+        let index = self.cursor + k;
+        if self.fill(index) {
+            self.buffer.get(index - self.base)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        self.pinned += 1;
+        Checkpoint(self.cursor)
+    }
+
+    // Go back to the checkpoint, the tokens since come out again
+    pub fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.cursor = checkpoint.0;
+        self.release();
+    }
+
+    // Keep going from here, the checkpoint isn't needed anymore
+    pub fn commit(&mut self, _checkpoint: Checkpoint) {
+        self.release();
+    }
+
+    fn release(&mut self) {
+        self.pinned -= 1;
+
+        // Nobody can come back to anything before the cursor now
+        if self.pinned == 0 {
+            while self.base < self.cursor {
+                self.buffer.pop_front();
+                self.base += 1;
+            }
+        }
+    }
+
+    // Reads until token number index is in the buffer, false if it never is
+    fn fill(&mut self, index: usize) -> bool {
+        while self.base + self.buffer.len() <= index {
+            match self.tokens.next() {
+                Some(token) => self.buffer.push_back(token),
+                None => return false
+            }
+        }
+        true
+    }
+}
+
+impl<R: Source> Iterator for TokenStream<R> {
+    type Item = LexResult;
+
+    fn next(&mut self) -> Option<LexResult> {
+        let index = self.cursor;
+        if !self.fill(index) {
+            return None;
+        }
+        self.cursor += 1;
+
+        // Consumed tokens are only kept for rewinding
+        if self.pinned > 0 {
+            self.buffer.get(index - self.base).cloned()
+        }
+        else {
+            self.base += 1;
+            self.buffer.pop_front()
+        }
+    }
+}
+
+// Tokens a logical line at a time, without the EOL
+// Continued lines are already one line by the time they get here, and
+// blank lines don't come out at all
+// A line with an error in it comes out as its first error, the rest of it
+// is skipped
+pub struct Lines<I: Iterator<Item = LexResult>> {
+    tokens: I
+}
+
+impl<I: Iterator<Item = LexResult>> Lines<I> {
+    pub fn new(tokens: I) -> Lines<I> {
+        Lines { tokens }
+    }
+}
+
+impl<R: Source> TokenIterator<R> {
+    pub fn lines(self) -> Lines<TokenIterator<R>> {
+        Lines::new(self)
+    }
+}
+
+impl<I: Iterator<Item = LexResult>> Iterator for Lines<I> {
+    type Item = Result<Vec<Token<'static>>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        let mut error = None;
+
+        loop {
+            match self.tokens.next() {
+                Some(Ok(ref token)) if token.kind == TokenKind::EOL => {
+                    if error.is_some() || !line.is_empty() {
+                        break;
+                    }
+                },
+                Some(Ok(token)) => line.push(token),
+                Some(Err(e)) => if error.is_none() {
+                    error = Some(e);
+                },
+                None if error.is_some() || !line.is_empty() => break,
+                None => return None
+            }
+        }
+
+        match error {
+            Some(e) => Some(Err(e)),
+            None => Some(Ok(line))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(source: &str) -> TokenStream<&[u8]> {
+        TokenStream::new(TokenIterator::new(source.as_bytes()))
+    }
+
+    fn kind(token: Option<&LexResult>) -> Option<TokenKind> {
+        token.map(|token| token.as_ref().unwrap().kind)
+    }
+
+    #[test]
+    fn peeking_doesnt_move() {
+        let mut tokens = stream("x = 1");
+        assert_eq!(kind(tokens.peek_n(2)), Some(TokenKind::Integer));
+        assert_eq!(kind(tokens.peek()), Some(TokenKind::Text));
+        assert_eq!(kind(tokens.peek_n(3)), None);
+
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenKind::Text);
+        assert_eq!(kind(tokens.peek_n(1)), Some(TokenKind::Integer));
+        assert_eq!(tokens.count(), 2);
+    }
+
+    #[test]
+    fn rewinding_gives_the_same_tokens_again() {
+        let mut tokens = stream("a b c d");
+        tokens.next();
+        let checkpoint = tokens.checkpoint();
+        let first: Vec<_> = tokens.by_ref().take(2).map(Result::unwrap).collect();
+        tokens.rewind(checkpoint);
+        let again: Vec<_> = tokens.by_ref().take(2).map(Result::unwrap).collect();
+        assert_eq!(first, again);
+        assert_eq!(tokens.next().unwrap().unwrap().span.column, 7);
+    }
+
+    #[test]
+    fn committing_keeps_going() {
+        let mut tokens = stream("a b c");
+        let outer = tokens.checkpoint();
+        tokens.next();
+        let inner = tokens.checkpoint();
+        tokens.next();
+        tokens.commit(inner);
+        // The outer one still goes all the way back
+        tokens.rewind(outer);
+        assert_eq!(tokens.next().unwrap().unwrap().span.column, 1);
+
+        let checkpoint = tokens.checkpoint();
+        tokens.next();
+        tokens.commit(checkpoint);
+        assert_eq!(tokens.next().unwrap().unwrap().span.column, 5);
+        assert!(tokens.next().is_none());
+    }
+
+    fn lines(source: &str) -> Vec<Result<Vec<TokenKind>, LexError>> {
+        TokenIterator::new(source.as_bytes()).lines()
+            .map(|line| line.map(|line| line.into_iter().map(|token| token.kind).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn a_line_at_a_time() {
+        assert_eq!(lines("a = b\n\n\nc _\n  d\ne"),
+                   vec![Ok(vec![TokenKind::Text, TokenKind::Equals, TokenKind::Text]),
+                        Ok(vec![TokenKind::Text, TokenKind::Text]),
+                        Ok(vec![TokenKind::Text])]);
+        assert_eq!(lines("\n\n"), vec![]);
+    }
+
+    #[test]
+    fn line_with_errors_is_its_first_error() {
+        let lexed = lines("a @ b @\nc");
+        assert_eq!(lexed.len(), 2);
+        match lexed[0] {
+            Err(LexError::UnexpectedByte { span, .. }) => assert_eq!(span.column, 3),
+            ref other => panic!("{:?}", other)
+        }
+        assert_eq!(lexed[1], Ok(vec![TokenKind::Text]));
+    }
+}
@@ -1,382 +1,3219 @@
-use std::io::Bytes;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
 use std::io::Read;
-use std::iter::Peekable;
-use std::fs::File;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 use std::result::Result;
-use std::io::Error;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+use std::str;
+
+#[cfg(not(feature = "std"))]
+use alloc::string::String;
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
+use alloc::borrow::Cow;
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap as HashMap;
+
+use memchr::{memchr2, memchr3};
+
+use interner::{Interner, Symbol};
 
 macro_rules! emit_token_number {
-    ($buf:ident) => {{
-        // The following involves some magic
-        // First convert the buffer - a string - into a slice
-        return match String::from_utf8_lossy(&$buf[..])
-                          .parse() {
-            Ok(value) => Some(Ok(Token::Number(value))),
-            Err(_) => Some(Err("String was not a valid number".to_owned()))
-        };
+    ($buf:ident, $width:expr, $span:expr) => {{
+        return Some(number_token(&$buf, $width, $span));
+    }}
+}
+
+// Same as above, for &H/0x and &B/0b style literals
+// The prefix is never in the buffer, just the digits
+macro_rules! emit_token_radix {
+    ($buf:ident, $radix:expr, $width:expr, $span:expr) => {{
+        return Some(radix_token(&$buf, $radix, $width, $span));
+    }}
+}
+
+// Skips a _ between digits, anywhere else it's an error
+// A bad one takes the rest of the digits and separators with it, so they
+// don't start a token of their own
+macro_rules! skip_digit_separator {
+    ($byte:ident, $peeked:ident, $radix:expr, $lexer:expr) => {{
+        if $byte == b'_' {
+            match $peeked {
+                Some(&peek_byte) if radix_digit(peek_byte, $radix) => continue,
+                _ => {
+                    let run = $lexer.run_length(|b| b == b'_' || radix_digit(b, $radix));
+                    $lexer.take(run, None);
+                    return Some(Err(LexError::InvalidNumber {
+                        span: $lexer.token_span()
+                    }));
+                }
+            }
+        }
     }}
 }
+
+// Reserved words come out as keywords instead
+// A $ or # right after an identifier is glued on in Mode::Sigil, and so
+// is the : of a label in Mode::Label
 macro_rules! emit_token_text {
-    ($buf:ident) => {{
-        return Some(Ok(Token::Text($buf.clone())));
+    ($buf:ident, $peeked:ident, $mode:ident, $lexer:expr) => {{
+        let case_insensitive = $lexer.config.case_insensitive;
+        match Keyword::from_word(&$buf, case_insensitive) {
+            Some(keyword) if $lexer.config.keywords.contains(keyword) => {
+                return Some(Ok(bare(TokenKind::Keyword(keyword))));
+            },
+            _ => match $peeked {
+                Some(&b'$') | Some(&b'#') => {
+                    $mode = Mode::Sigil;
+                    continue;
+                },
+                Some(&b':') if $lexer.line_start => {
+                    $mode = Mode::Label;
+                    continue;
+                },
+                _ => return Some(Ok((TokenKind::Text, Payload::Identifier(
+                    Identifier::new(&$buf, case_insensitive, &$lexer.interner)))))
+            }
+        }
     }}
 }
 
+// The contents are always UTF-8 once decoded
 macro_rules! emit_token_string {
-    ($buf:ident) => {{
-        return Some(Ok(Token::String($buf.clone())));
+    ($buf:ident, $encoding:expr, $start:expr, $borrow:expr) => {{
+        return Some(decode_string(&$buf, $encoding, $start, $borrow));
     }}
 }
 
+// Where in the source something is
+// Lines and columns start from 1, columns count bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub byte_offset: usize,
+    pub length: usize
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// The source bytes around a token in lossless mode
+// Writing out leading and text of every token, and then the trailing
+// trivia of the iterator, gives back the input byte for byte
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Trivia {
+    // Whitespace, comments and line continuations before the token,
+    // plus whatever bytes an error before it was about
+    pub leading: Vec<u8>,
+    // The token exactly as written
+    // An EOL ending a multi-line block comment is written as the comment
+    pub text: Vec<u8>
+}
+
+// Every error carries the span of whatever caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexError {
+    // A byte that can't start or continue any token
+    UnexpectedByte { byte: u8, span: Span },
+    // Same, for a character that isn't ASCII
+    UnexpectedChar { c: char, span: Span },
+    // Bytes that aren't valid UTF-8
+    InvalidUtf8 { span: Span },
+    // A byte that doesn't stand for anything in a single byte encoding
+    UnmappedByte { byte: u8, encoding: Encoding, span: Span },
+    // \r followed by something other than \n
+    CarriageReturnWithoutLineFeed { byte: Option<u8>, span: Span },
+    MultipleDecimalPoints { span: Span },
+    // The number buffer couldn't be parsed after all
+    InvalidNumber { span: Span },
+    // A literal too big for the configured integer width, or for f64
+    // Lexing carries on after it like after any other error
+    NumberOverflow { digits: Vec<u8>, span: Span },
+    // A string that the line or file ended in the middle of
+    UnterminatedString { span: Span },
+    // A /* comment that the file ended in the middle of
+    UnterminatedComment { span: Span },
+    // The underlying reader failed
+    Io { kind: ReadError, span: Span }
+}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match *self {
+            LexError::UnexpectedByte { span, .. } |
+            LexError::UnexpectedChar { span, .. } |
+            LexError::InvalidUtf8 { span } |
+            LexError::UnmappedByte { span, .. } |
+            LexError::CarriageReturnWithoutLineFeed { span, .. } |
+            LexError::MultipleDecimalPoints { span } |
+            LexError::InvalidNumber { span } |
+            LexError::NumberOverflow { span, .. } |
+            LexError::UnterminatedString { span } |
+            LexError::UnterminatedComment { span } |
+            LexError::Io { span, .. } => span
+        }
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        match *self {
+            LexError::UnexpectedByte { ref mut span, .. } |
+            LexError::UnexpectedChar { ref mut span, .. } |
+            LexError::InvalidUtf8 { ref mut span } |
+            LexError::UnmappedByte { ref mut span, .. } |
+            LexError::CarriageReturnWithoutLineFeed { ref mut span, .. } |
+            LexError::MultipleDecimalPoints { ref mut span } |
+            LexError::InvalidNumber { ref mut span } |
+            LexError::NumberOverflow { ref mut span, .. } |
+            LexError::UnterminatedString { ref mut span } |
+            LexError::UnterminatedComment { ref mut span } |
+            LexError::Io { ref mut span, .. } => span
+        }
+    }
+
+    // The Display text without the position in front
+    pub fn message(&self) -> String {
+        let mut message = String::new();
+        fmt::Write::write_fmt(&mut message, format_args!("{}", Message(self))).unwrap();
+        message
+    }
+}
+
+// What went wrong, without where
+struct Message<'a>(&'a LexError);
+
+impl<'a> fmt::Display for Message<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            LexError::UnexpectedByte { byte, .. } => {
+                write!(f, "Invalid or unhandled byte {:?} encountered", byte)
+            },
+            LexError::UnexpectedChar { c, .. } => {
+                write!(f, "Invalid or unhandled character {:?} encountered", c)
+            },
+            LexError::InvalidUtf8 { .. } => {
+                write!(f, "Invalid UTF-8")
+            },
+            LexError::UnmappedByte { byte, encoding, .. } => {
+                write!(f, "Byte 0x{:02X} is not a character in {}", byte, encoding)
+            },
+            LexError::CarriageReturnWithoutLineFeed { .. } => {
+                write!(f, "CR without corresponding LF")
+            },
+            LexError::MultipleDecimalPoints { .. } => {
+                write!(f, "Only one decimal point allowed in a number")
+            },
+            LexError::InvalidNumber { .. } => {
+                write!(f, "String was not a valid number")
+            },
+            LexError::NumberOverflow { ref digits, .. } => {
+                write!(f, "Number {} is too large", String::from_utf8_lossy(digits))
+            },
+            LexError::UnterminatedString { .. } => {
+                write!(f, "String literal is missing its closing quote")
+            },
+            LexError::UnterminatedComment { .. } => {
+                write!(f, "Block comment is missing its closing */")
+            },
+            LexError::Io { kind, .. } => {
+                write!(f, "Read error {:?}", kind)
+            }
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span(), Message(self))
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for LexError {}
+
 #[derive(Debug)]
 enum Mode {
     None,
     Newline,
     Text,
+    // The current byte is the sigil of a typed identifier
+    Sigil,
+    // The current byte is the colon after a label
+    Label,
     Number,
     Decimal,
-    String
+    ExponentMark,
+    ExponentSign,
+    Exponent,
+    // The current byte is the letter of a radix prefix
+    RadixPrefix(u32),
+    Radix(u32),
+    String,
+    // Second byte of an operator starting with this
+    Operator(u8),
+    // This many continuation bytes left of a character this long
+    Utf8(usize, usize),
+    // Skipping the line break after a line continuation
+    Continuation,
+    // Skipping to the end of the line
+    Comment,
+    // Inside a /* */ comment, nested this deep
+    BlockComment(usize),
+    // The current byte ends a /* or */, leaving the nesting this deep
+    BlockCommentDelimiter(usize)
 }
 
-#[derive(Debug)]
-pub enum Token {
-    Text(Vec<u8>),
-    String(Vec<u8>),
-    Number(f64),
-    LParen,
-    RParen,
-    Dollar,
-    Hash,
-    Equals,
-    Comma,
-    EOL
+fn radix_digit(byte: u8, radix: u32) -> bool {
+    (byte as char).is_digit(radix)
+}
+
+// How many bytes a UTF-8 sequence starting with this is
+fn utf8_length(lead: u8) -> Option<usize> {
+    match lead {
+        0xC2..=0xDF => Some(2),
+        0xE0..=0xEF => Some(3),
+        0xF0..=0xF4 => Some(4),
+        _ => None
+    }
 }
 
-struct ByteResultIterator {
-    iterator: Box<Iterator<Item = u8>>
+fn utf8_continuation(byte: u8) -> bool {
+    byte & 0xC0 == 0x80
 }
 
-impl ByteResultIterator {
-    pub fn new<T: Iterator<Item = u8> + 'static>(iter: T) -> Self {
-        ByteResultIterator{ iterator: Box::new(iter) }
+// What a byte means in a single byte encoding
+// Latin-1 maps every byte straight to the same code point, Windows-1252
+// has punctuation in 0x80-0x9F with a few holes
+fn decode_byte(byte: u8, encoding: Encoding) -> Option<char> {
+    match (encoding, byte) {
+        (Encoding::Windows1252, 0x80..=0x9F) => {
+            let c = WINDOWS_1252[byte as usize - 0x80];
+            if c == '\0' { None } else { Some(c) }
+        },
+        _ => Some(byte as char)
     }
 }
 
-impl Iterator for ByteResultIterator {
-    type Item = Result<u8, Error>;
+static WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\0', '\u{201A}', '\u{0192}',
+    '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}',
+    '\u{0152}', '\0', '\u{017D}', '\0',
+    '\0', '\u{2018}', '\u{2019}', '\u{201C}',
+    '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}',
+    '\u{0153}', '\0', '\u{017E}', '\u{0178}'
+];
 
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.iterator.next() {
-            Some(x) => Some(Ok(x)),
-            None => None
+fn push_char(buf: &mut Vec<u8>, c: char) {
+    let mut bytes = [0; 4];
+    buf.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+}
+
+// Turns the bytes between the quotes into UTF-8
+// The string can't span lines, so the nth byte is n columns after the "
+// A borrowed one is left empty for BorrowedTokens to point at the source
+fn decode_string(buf: &[u8], encoding: Encoding, start: Span, borrow: bool)
+                 -> Result<Lexeme, LexError> {
+    let span_at = |index: usize| Span {
+        line: start.line,
+        column: start.column + 1 + index,
+        byte_offset: start.byte_offset + 1 + index,
+        length: 1
+    };
+
+    match encoding {
+        Encoding::Utf8 => match str::from_utf8(buf) {
+            Ok(_) if borrow => Ok((TokenKind::String, Payload::String(Cow::Borrowed(&[])))),
+            Ok(_) => Ok((TokenKind::String, Payload::String(Cow::Owned(buf.to_vec())))),
+            Err(e) => Err(LexError::InvalidUtf8 { span: span_at(e.valid_up_to()) })
+        },
+        _ => {
+            let mut decoded = Vec::with_capacity(buf.len());
+            for (index, &byte) in buf.iter().enumerate() {
+                match decode_byte(byte, encoding) {
+                    Some(c) => push_char(&mut decoded, c),
+                    None => return Err(LexError::UnmappedByte {
+                        byte,
+                        encoding,
+                        span: span_at(index)
+                    })
+                }
+            }
+            Ok((TokenKind::String, Payload::String(Cow::Owned(decoded))))
         }
     }
 }
 
-type UnderlyingType = Box<Iterator<Item = Result<u8, Error>>>;
-
-pub struct TokenIterator {
-    iterator: Peekable<UnderlyingType>
+// Mode to go to after the lead byte of a multi-byte letter
+// None if the sequence is already broken
+fn utf8_mode(lead: u8, peeked: Option<&u8>) -> Option<Mode> {
+    match (utf8_length(lead), peeked) {
+        (Some(length), Some(&next)) if utf8_continuation(next) => {
+            Some(Mode::Utf8(length - 1, length))
+        },
+        _ => None
+    }
 }
 
-impl TokenIterator {
-    pub fn new(file: File) -> TokenIterator {
-        TokenIterator{ iterator: (Box::new(file.bytes()) as UnderlyingType).peekable() }
+// Decimal literals are integers unless they have a point or an exponent
+fn number_token(buf: &[u8], width: IntegerWidth, span: Span) -> Result<Lexeme, LexError> {
+    // The buffer only ever has ASCII in it
+    let text = String::from_utf8_lossy(buf);
+
+    if buf.iter().any(|&b| b == b'.' || b == b'e' || b == b'E') {
+        // Too big floats parse to infinity
+        return match text.parse::<f64>() {
+            Ok(value) if value.is_infinite() => Err(LexError::NumberOverflow {
+                digits: buf.to_vec(),
+                span
+            }),
+            Ok(value) => Ok((TokenKind::Number, Payload::Number(value))),
+            Err(_) => Err(LexError::InvalidNumber { span })
+        };
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> TokenIterator {
-        TokenIterator{ iterator: (Box::new(ByteResultIterator::new(bytes.to_owned().into_iter())) as UnderlyingType).peekable() }
+    // The digits were checked while lexing, so failing means too many
+    match text.parse::<i64>() {
+        Ok(value) if width.fits(value) => Ok((TokenKind::Integer, Payload::Integer(value))),
+        _ => Err(LexError::NumberOverflow {
+            digits: buf.to_vec(),
+            span
+        })
     }
 }
 
-impl Iterator for TokenIterator {
-    type Item = Result<Token, String>;
+// Hex and binary literals are bit patterns, so &HFFFFFFFF is -1 in 32 bits
+fn radix_token(buf: &[u8], radix: u32, width: IntegerWidth, span: Span)
+               -> Result<Lexeme, LexError> {
+    if let Ok(value) = u64::from_str_radix(&String::from_utf8_lossy(buf), radix) {
+        match width {
+            IntegerWidth::Bits32 if value <= u32::MAX as u64 => {
+                return Ok((TokenKind::Integer, Payload::Integer(value as u32 as i32 as i64)))
+            },
+            IntegerWidth::Bits64 => return Ok((TokenKind::Integer, Payload::Integer(value as i64))),
+            _ => {}
+        }
+    }
 
-    // The actual tokenisation happens here
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut mode = Mode::None;
-        let mut buf = Vec::<u8>::new();
+    Err(LexError::NumberOverflow {
+        digits: buf.to_vec(),
+        span
+    })
+}
 
-        loop {
-            let next = self.iterator.next();
-            let peek = self.iterator.peek();
+// A sigil or a colon right after makes it a name, rem$ or a label rem:,
+// and so does a \ before it, which makes it a field
+fn is_rem(word: &[u8], peeked: Option<&u8>, after_backslash: bool, config: &LexerConfig) -> bool {
+    if !config.comments.rem || after_backslash {
+        false
+    }
+    else if let Some(&b'$') | Some(&b'#') | Some(&b':') = peeked {
+        false
+    }
+    else if config.case_insensitive {
+        word.eq_ignore_ascii_case(b"REM")
+    }
+    else {
+        word == b"REM"
+    }
+}
 
-            println!("{:?} {:?} {:?}", next, peek, mode);
+// Mode to go to after the byte that starts a comment
+// If the line ends right there, there's nothing to skip
+fn comment_mode(peeked: Option<&u8>) -> Mode {
+    match peeked {
+        None | Some(&b'\r') | Some(&b'\n') => Mode::None,
+        _ => Mode::Comment
+    }
+}
 
-            // Returning None stops iteration
-            let byte = match next {
-                None => return None,
-                Some(Err(_)) => return None,
-                Some(Ok(value)) => value
-            };
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Keyword {
+    If,
+    Then,
+    Else,
+    ElseIf,
+    EndIf,
+    For,
+    To,
+    Step,
+    Next,
+    While,
+    Wend,
+    Repeat,
+    Until,
+    Forever,
+    Select,
+    Case,
+    Default,
+    EndSelect,
+    Function,
+    EndFunction,
+    Return,
+    End,
+    Exit,
+    Goto,
+    Gosub,
+    Dim,
+    ReDim,
+    Const,
+    Global,
+    Local,
+    Type,
+    Field,
+    EndType,
+    Include,
+    Data,
+    Read,
+    Restore,
+    Print,
+    And,
+    Or,
+    Xor,
+    Not,
+    Mod
+}
 
-            let peeked = match peek {
-                None => None,
-                Some(&Err(_)) => None,
-                Some(&Ok(ref value)) => Some(value)
-            };
+// The canonical spelling of every keyword
+static KEYWORDS: &[(&str, Keyword)] = &[
+    ("If", Keyword::If),
+    ("Then", Keyword::Then),
+    ("Else", Keyword::Else),
+    ("ElseIf", Keyword::ElseIf),
+    ("EndIf", Keyword::EndIf),
+    ("For", Keyword::For),
+    ("To", Keyword::To),
+    ("Step", Keyword::Step),
+    ("Next", Keyword::Next),
+    ("While", Keyword::While),
+    ("Wend", Keyword::Wend),
+    ("Repeat", Keyword::Repeat),
+    ("Until", Keyword::Until),
+    ("Forever", Keyword::Forever),
+    ("Select", Keyword::Select),
+    ("Case", Keyword::Case),
+    ("Default", Keyword::Default),
+    ("EndSelect", Keyword::EndSelect),
+    ("Function", Keyword::Function),
+    ("EndFunction", Keyword::EndFunction),
+    ("Return", Keyword::Return),
+    ("End", Keyword::End),
+    ("Exit", Keyword::Exit),
+    ("Goto", Keyword::Goto),
+    ("Gosub", Keyword::Gosub),
+    ("Dim", Keyword::Dim),
+    ("ReDim", Keyword::ReDim),
+    ("Const", Keyword::Const),
+    ("Global", Keyword::Global),
+    ("Local", Keyword::Local),
+    ("Type", Keyword::Type),
+    ("Field", Keyword::Field),
+    ("EndType", Keyword::EndType),
+    ("Include", Keyword::Include),
+    ("Data", Keyword::Data),
+    ("Read", Keyword::Read),
+    ("Restore", Keyword::Restore),
+    ("Print", Keyword::Print),
+    ("And", Keyword::And),
+    ("Or", Keyword::Or),
+    ("Xor", Keyword::Xor),
+    ("Not", Keyword::Not),
+    ("Mod", Keyword::Mod)
+];
 
+impl Keyword {
+    // Without case_insensitive only the canonical spelling is a keyword
+    pub fn from_word(word: &[u8], case_insensitive: bool) -> Option<Keyword> {
+        KEYWORDS.iter()
+                .find(|&&(name, _)| if case_insensitive {
+                    word.eq_ignore_ascii_case(name.as_bytes())
+                }
+                else {
+                    word == name.as_bytes()
+                })
+                .map(|&(_, keyword)| keyword)
+    }
 
-            match mode {
-                Mode::None => match byte {
-                    b'\r' => {
-                        mode = Mode::Newline;
-                    },
-                    
-                    // Letters
-                    // These can't be a macro, either? :c
-                    c @ b'_' |
-                    c @ b'A' ... b'Z' |
-                    c @ b'a' ... b'z' |
-                    c @ 0xC0 ... 0xD6 |
-                    c @ 0xD8 ... 0xF6 |
-                    c @ 0xF8 ... 0xFF => {
-                        // Clear token buffer
-                        buf.clear();
-                        // Insert current char
-                        buf.push(c);
-                        // Go to text mode if next also letter
-                        match peeked {
-                            Some(&peek_byte) => match peek_byte {
-                                b'_' |
-                                b'0' ... b'9' |
-                                b'A' ... b'Z' |
-                                b'a' ... b'z' |
-                                0xC0 ... 0xD6 |
-                                0xD8 ... 0xF6 |
-                                0xF8 ... 0xFF => {
-                                    mode = Mode::Text;
-                                },
-                                _ => {
-                                    // Emit text token
-                                    emit_token_text!(buf);
-                                }
-                            },
-                            
-                            None => {
-                                // Also emit text token
-                                emit_token_text!(buf);
-                            }
-                        }
-                    },
+    pub fn name(&self) -> &'static str {
+        KEYWORDS.iter()
+                .find(|&&(_, keyword)| keyword == *self)
+                .map(|&(name, _)| name)
+                .unwrap()
+    }
+}
 
-                    // String
-                    b'"' => {
-                        // Clear token buffer
-                        buf.clear();
-                        // Don't insert current char - it's irrelevant, it's always "
-                        // We don't even match peeked - we just go to Mode::String
-                        mode = Mode::String;
-                    },
+// Which keywords are reserved, one bit per keyword
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeywordSet(u64);
 
-                    // Number
-                    c @ b'0' ... b'9' => {
-                        buf.clear();
-                        buf.push(c);
+impl KeywordSet {
+    pub fn all() -> KeywordSet {
+        KEYWORDS.iter().fold(KeywordSet::empty(), |set, &(_, keyword)| set.with(keyword))
+    }
 
-                        match peeked {
-                            Some(&peek_byte) => match peek_byte {
-                                b'0' ... b'9' => {
-                                    // Goto number mode
-                                    mode = Mode::Number;
-                                }
+    pub fn empty() -> KeywordSet {
+        KeywordSet(0)
+    }
 
-                                b'.' => {
-                                    // Goto decimal mode
-                                    mode = Mode::Decimal;
-                                },
+    pub fn with(self, keyword: Keyword) -> KeywordSet {
+        KeywordSet(self.0 | 1 << keyword as u32)
+    }
 
-                                _ => {
-                                    // Emit number token
-                                    emit_token_number!(buf);
-                                }
-                            },
-                            None => {
-                                // Also emit number token
-                                emit_token_number!(buf);
-                            }
-                        }
-                    },
+    pub fn without(self, keyword: Keyword) -> KeywordSet {
+        KeywordSet(self.0 & !(1 << keyword as u32))
+    }
 
-                    // Negative number
-                    c @ b'-' => {
-                        buf.clear();
-                        buf.push(c);
+    pub fn contains(&self, keyword: Keyword) -> bool {
+        self.0 & 1 << keyword as u32 != 0
+    }
+}
 
-                        match peeked {
-                            Some(&peek_byte) => match peek_byte {
-                                b'0' ... b'9' => {
-                                    // Goto number mode
-                                    mode = Mode::Number;
-                                }
-                                _ => {
-                                    // Invalid!
-                                    return Some(Err("Minus encountered without number".to_owned()));
-                                }
-                            },
-                            None => {
-                                // Invalid!
-                                return Some(Err("Minus encountered without number".to_owned()));
-                            }
-                        }
-                    },
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
-                    // Opening paren
-                    b'(' => {
-                        // Just emit it
-                        return Some(Ok(Token::LParen));
-                    },
+// Name is what identifiers are compared by, spelling is what was written
+// Both are interned, see TokenIterator::interner for getting them back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Identifier {
+    pub name: Symbol,
+    pub spelling: Symbol
+}
 
-                    // Closing paren
-                    b')' => {
-                        // Just emit it
-                        return Some(Ok(Token::RParen));
-                    },
+impl Identifier {
+    // Case insensitive names are all lowercase
+    // The spelling is always valid UTF-8 so the lossy part never kicks in
+    pub fn new(spelling: &[u8], case_insensitive: bool, interner: &Interner) -> Identifier {
+        let written = interner.intern(spelling);
+        let lowercase = !spelling.iter().any(|&b| b >= 0x80 || b.is_ascii_uppercase());
 
-                    b'$' => {
-                        return Some(Ok(Token::Dollar));
-                    },
+        Identifier {
+            name: if case_insensitive && !lowercase {
+                let name = String::from_utf8_lossy(spelling).to_lowercase();
+                interner.intern(name.as_bytes())
+            }
+            else {
+                written
+            },
+            spelling: written
+        }
+    }
+}
 
-                    b'#' => {
-                        return Some(Ok(Token::Hash));
-                    },
+// What a token is, without any of its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TokenKind {
+    Text,
+    // Identifiers with a $ or # right after them
+    StringIdent,
+    FloatIdent,
+    // An identifier and a colon starting a line, the span covers both
+    Label,
+    Keyword(Keyword),
+    String,
+    // Literals with a decimal point or an exponent
+    Number,
+    Integer,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Dollar,
+    Hash,
+    Equals,
+    Comma,
+    Colon,
+    Semicolon,
+    // Member access, p\x
+    Backslash,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Caret,
+    Percent,
+    Less,
+    Greater,
+    LessEquals,
+    GreaterEquals,
+    NotEquals,
+    EOL
+}
 
-                    b'=' => {
-                        return Some(Ok(Token::Equals));
-                    },
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            TokenKind::Text => "identifier",
+            TokenKind::StringIdent => "string identifier",
+            TokenKind::FloatIdent => "float identifier",
+            TokenKind::Label => "label",
+            TokenKind::Keyword(keyword) => keyword.name(),
+            TokenKind::String => "string",
+            TokenKind::Number => "number",
+            TokenKind::Integer => "integer",
+            TokenKind::LParen => "(",
+            TokenKind::RParen => ")",
+            TokenKind::LBracket => "[",
+            TokenKind::RBracket => "]",
+            TokenKind::Dollar => "$",
+            TokenKind::Hash => "#",
+            TokenKind::Equals => "=",
+            TokenKind::Comma => ",",
+            TokenKind::Colon => ":",
+            TokenKind::Semicolon => ";",
+            TokenKind::Backslash => "\\",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Caret => "^",
+            TokenKind::Percent => "%",
+            TokenKind::Less => "<",
+            TokenKind::Greater => ">",
+            TokenKind::LessEquals => "<=",
+            TokenKind::GreaterEquals => ">=",
+            TokenKind::NotEquals => "<>",
+            TokenKind::EOL => "end of line"
+        })
+    }
+}
 
-                    b',' => {
-                        return Some(Ok(Token::Comma));
-                    },
+// The data that goes with some kinds of token
+// Text, StringIdent, FloatIdent and Label have an Identifier, the literal
+// kinds have their value and everything else has None
+// Strings lexed by BorrowedTokens point into the source, the rest own theirs
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Payload<'a> {
+    None,
+    Identifier(Identifier),
+    String(Cow<'a, [u8]>),
+    Number(f64),
+    Integer(i64)
+}
 
-                    // Skip spaces
-                    b' ' => {},
+// Numbers compare by their bits so that tokens can be Eq and Hash
+// The lexer never makes a NaN anyway
+impl<'a> PartialEq for Payload<'a> {
+    fn eq(&self, other: &Payload<'a>) -> bool {
+        match (self, other) {
+            (Payload::None, Payload::None) => true,
+            (Payload::Identifier(a), Payload::Identifier(b)) => a == b,
+            (Payload::String(a), Payload::String(b)) => a == b,
+            (Payload::Number(a), Payload::Number(b)) => a.to_bits() == b.to_bits(),
+            (Payload::Integer(a), Payload::Integer(b)) => a == b,
+            _ => false
+        }
+    }
+}
 
-                    b'\n' => {
-                        return Some(Err("UNIX newline encountered".to_owned()));
-                    }
+impl<'a> Eq for Payload<'a> {}
 
-                    b @ _ => {
-                        return Some(Err(format!("Invalid or unhandled byte {:?} encountered",
-                               b)));
-                    }
+impl<'a> Hash for Payload<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            Payload::None => 0.hash(state),
+            Payload::Identifier(ref identifier) => {
+                1.hash(state);
+                identifier.hash(state);
+            },
+            Payload::String(ref bytes) => {
+                2.hash(state);
+                bytes.hash(state);
+            },
+            Payload::Number(value) => {
+                3.hash(state);
+                value.to_bits().hash(state);
+            },
+            Payload::Integer(value) => {
+                4.hash(state);
+                value.hash(state);
+            }
+        }
+    }
+}
 
-                },
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub span: Span,
+    pub payload: Payload<'a>,
+    // Only there in lossless mode
+    pub trivia: Option<Trivia>
+}
 
-                Mode::Newline => match byte {
-                    b'\n' => {
-                        // Emit EOL, we're good
-                        return Some(Ok(Token::EOL));
-                    }
-                    _ => return Some(Err("CR without corresponding LF in input file".to_owned()))
-                },
+impl<'a> Token<'a> {
+    // Copies a borrowed string so the token can outlive the source
+    pub fn into_owned(self) -> Token<'static> {
+        Token {
+            kind: self.kind,
+            span: self.span,
+            payload: match self.payload {
+                Payload::None => Payload::None,
+                Payload::Identifier(identifier) => Payload::Identifier(identifier),
+                Payload::String(bytes) => Payload::String(Cow::Owned(bytes.into_owned())),
+                Payload::Number(value) => Payload::Number(value),
+                Payload::Integer(value) => Payload::Integer(value)
+            },
+            trivia: self.trivia
+        }
+    }
 
-                // We've already peeked at byte if we're here
-                // That means we know we have something!
-                // Push *current* (not peeked!) byte
-                Mode::Text => {
-                    buf.push(byte);
-                    match peeked {
-                        Some(&peek_byte) => match peek_byte {
-                                // NOTE: THIS IS NOT THE SAME PATTERN AS
-                                // THE PREVIOUS LETTER PATTERN!!
-                                b'_' |
-                                b'0' ... b'9' |
-                                b'A' ... b'Z' |
-                                b'a' ... b'z' |
-                                0xC0 ... 0xD6 |
-                                0xD8 ... 0xF6 |
-                                0xF8 ... 0xFF => {
-                                    // Carry on
-                                },
+    pub fn identifier(&self) -> Option<Identifier> {
+        match self.payload {
+            Payload::Identifier(identifier) => Some(identifier),
+            _ => None
+        }
+    }
 
-                                // Valid varname ends
-                                _ => {
-                                    // Emit text token
-                                    emit_token_text!(buf);
-                                }
-                        },
+    // For serializing it with the names of an identifier instead of its
+    // symbols, which don't mean anything without the interner
+    #[cfg(feature = "serde")]
+    pub fn named<'t>(&'t self, interner: &'t Interner) -> Named<'t, 'a> {
+        Named { token: self, interner }
+    }
+}
 
-                        None => {
-                            // Well, let's go back to normal mode?
-                            // Also emit text token
-                            emit_token_text!(buf);
-                        }
-                    }
-                },
+// Serialized like the token, except that an identifier's payload is
+// {"Identifier": {"name": ..., "spelling": ...}} with the names as strings
+// The one to hand to anything outside, Token's own Serialize is only good
+// for reading back in with the same interner
+#[cfg(feature = "serde")]
+pub struct Named<'t, 'a: 't> {
+    token: &'t Token<'a>,
+    interner: &'t Interner
+}
 
-                Mode::Number => {
-                    // Push current byte
-                    buf.push(byte);
-                    match peeked {
-                        Some(&peek_byte) => match peek_byte {
-                                b'0' ... b'9' => {
-                                    // Carry on...
-                                },
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct Names {
+    name: String,
+    spelling: String
+}
 
-                                // Decimal point found
-                                b'.' => {
-                                    // Change modes
-                                    mode = Mode::Decimal;
-                                },
+#[cfg(feature = "serde")]
+impl<'t, 'a> ::serde::Serialize for Named<'t, 'a> {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
 
-                                // Valid number ends
-                                _ => {
-                                    // Emit number token
-                                    emit_token_number!(buf);
-                                }
-                        },
+        let mut token = serializer.serialize_struct("Token", 4)?;
+        token.serialize_field("kind", &self.token.kind)?;
+        token.serialize_field("span", &self.token.span)?;
+        match self.token.payload {
+            Payload::Identifier(identifier) => {
+                let resolve = |symbol| {
+                    String::from_utf8_lossy(&self.interner.resolve(symbol)).into_owned()
+                };
+                let names = Names {
+                    name: resolve(identifier.name),
+                    spelling: resolve(identifier.spelling)
+                };
+                token.serialize_field("payload", &NamedPayload(names))?;
+            },
+            ref payload => token.serialize_field("payload", payload)?
+        }
+        token.serialize_field("trivia", &self.token.trivia)?;
+        token.end()
+    }
+}
 
-                        None => {
-                            // Also emit number token
-                            emit_token_number!(buf);
-                        }
-                    }
+// The Identifier variant of Payload, with the same index for formats that
+// go by that
+#[cfg(feature = "serde")]
+struct NamedPayload(Names);
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for NamedPayload {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_variant("Payload", 1, "Identifier", &self.0)
+    }
+}
+
+// Identifiers only have their symbols, for names use the interner
+impl<'a> fmt::Display for Token<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.payload {
+            Payload::String(ref bytes) => {
+                write!(f, "\"{}\"", String::from_utf8_lossy(bytes))
+            },
+            Payload::Number(value) => write!(f, "{}", value),
+            Payload::Integer(value) => write!(f, "{}", value),
+            _ => write!(f, "{}", self.kind)
+        }
+    }
+}
+
+// What next_token finds, the iterator adds the span and trivia
+type Lexeme = (TokenKind, Payload<'static>);
+
+fn bare(kind: TokenKind) -> Lexeme {
+    (kind, Payload::None)
+}
+
+// How wide Integer tokens may be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegerWidth {
+    Bits32,
+    Bits64
+}
+
+impl IntegerWidth {
+    fn fits(&self, value: i64) -> bool {
+        match *self {
+            IntegerWidth::Bits32 => {
+                value >= i32::MIN as i64 && value <= i32::MAX as i64
+            },
+            IntegerWidth::Bits64 => true
+        }
+    }
+}
+
+// What the source bytes are
+// Identifiers and strings come out as UTF-8 whatever they were read as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Windows1252
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Latin1 => "Latin-1",
+            Encoding::Windows1252 => "Windows-1252"
+        })
+    }
+}
+
+// Which kinds of comment are recognised
+// Turned off, the bytes that would start them are just unexpected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommentStyles {
+    // ' to the end of the line
+    pub apostrophe: bool,
+    // REM to the end of the line
+    pub rem: bool,
+    // Nesting /* */
+    pub block: bool,
+    // #! on the first line
+    pub shebang: bool
+}
+
+impl Default for CommentStyles {
+    fn default() -> CommentStyles {
+        CommentStyles {
+            apostrophe: true,
+            rem: true,
+            block: true,
+            shebang: true
+        }
+    }
+}
+
+// Options for how the tokenizer treats its input
+// Either set the fields or chain the methods of the same name, starting
+// from LexerConfig::new()
+#[derive(Debug, Clone)]
+pub struct LexerConfig {
+    // Match keywords and compare identifiers ignoring case
+    pub case_insensitive: bool,
+    // Old Mac style line endings, LF and CRLF are always accepted
+    pub lone_cr_is_newline: bool,
+    // Skip vertical tabs and form feeds too, not just spaces and tabs
+    pub skip_all_whitespace: bool,
+    // Integer literals that don't fit are errors
+    pub integer_width: IntegerWidth,
+    pub encoding: Encoding,
+    // Attach Trivia to every token
+    pub lossless: bool,
+    // Words left out of here are just identifiers
+    pub keywords: KeywordSet,
+    pub comments: CommentStyles,
+    // Count every kind of token and time the lexing, see Statistics
+    pub statistics: bool,
+    // And and Or only work out their right side when the left side isn't
+    // enough, see BinaryOp::AndAlso. Otherwise they do both sides bit by
+    // bit like CoolBasic does
+    pub short_circuit: bool
+}
+
+impl Default for LexerConfig {
+    fn default() -> LexerConfig {
+        LexerConfig {
+            case_insensitive: true,
+            lone_cr_is_newline: false,
+            skip_all_whitespace: false,
+            integer_width: IntegerWidth::Bits64,
+            encoding: Encoding::Utf8,
+            lossless: false,
+            keywords: KeywordSet::all(),
+            comments: CommentStyles::default(),
+            statistics: false,
+            short_circuit: false
+        }
+    }
+}
+
+impl LexerConfig {
+    pub fn new() -> LexerConfig {
+        LexerConfig::default()
+    }
+
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> LexerConfig {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    pub fn lone_cr_is_newline(mut self, lone_cr_is_newline: bool) -> LexerConfig {
+        self.lone_cr_is_newline = lone_cr_is_newline;
+        self
+    }
+
+    pub fn skip_all_whitespace(mut self, skip_all_whitespace: bool) -> LexerConfig {
+        self.skip_all_whitespace = skip_all_whitespace;
+        self
+    }
+
+    pub fn integer_width(mut self, integer_width: IntegerWidth) -> LexerConfig {
+        self.integer_width = integer_width;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: Encoding) -> LexerConfig {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn lossless(mut self, lossless: bool) -> LexerConfig {
+        self.lossless = lossless;
+        self
+    }
+
+    pub fn keywords(mut self, keywords: KeywordSet) -> LexerConfig {
+        self.keywords = keywords;
+        self
+    }
+
+    pub fn comments(mut self, comments: CommentStyles) -> LexerConfig {
+        self.comments = comments;
+        self
+    }
+
+    pub fn statistics(mut self, statistics: bool) -> LexerConfig {
+        self.statistics = statistics;
+        self
+    }
+
+    pub fn short_circuit(mut self, short_circuit: bool) -> LexerConfig {
+        self.short_circuit = short_circuit;
+        self
+    }
+}
+
+// What a TokenIterator has done so far, across resets too
+// Bytes, tokens and errors are always counted, the rest only with
+// LexerConfig::statistics on since timing every token isn't free
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    pub bytes: usize,
+    pub tokens: usize,
+    pub errors: usize,
+    pub kinds: HashMap<TokenKind, usize>,
+    #[cfg(feature = "std")]
+    pub time: Duration
+}
+
+// Line and column of the byte right after a span, source starting base
+// bytes in
+// After most EOLs that's the start of the next line, but an EOL ending a
+// block comment ends where the comment does
+pub fn position_after(source: &[u8], base: usize, span: Span, config: &LexerConfig)
+                      -> (usize, usize) {
+    let mut line = span.line;
+    let mut column = span.column;
+
+    for i in span.byte_offset - base..span.byte_offset - base + span.length {
+        let lone_cr = source[i] == b'\r' && config.lone_cr_is_newline &&
+                      source.get(i + 1) != Some(&b'\n');
+        if source[i] == b'\n' || lone_cr {
+            line += 1;
+            column = 1;
+        }
+        else {
+            column += 1;
+        }
+    }
+
+    (line, column)
+}
+
+#[cfg(feature = "std")]
+pub type ReadError = io::ErrorKind;
+
+// Slices are all there is without std, and those can't fail
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {}
+
+// Where the tokenizer gets its bytes from
+// Anything Read with std, only byte slices without it
+pub trait Source {
+    // Like Read::read, 0 means there's nothing more
+    fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, ReadError>;
+
+    // All of it, if it's in memory already. The tokenizer lexes straight
+    // over that and never calls read_into
+    fn contents(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read> Source for R {
+    fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        loop {
+            match self.read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                result => return result.map_err(|e| e.kind())
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Source for &[u8] {
+    fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let length = buf.len().min(self.len());
+        buf[..length].copy_from_slice(&self[..length]);
+        *self = &self[length..];
+        Ok(length)
+    }
+}
+
+// Reading a byte at a time from an unbuffered source means a read call
+// per byte, so the tokenizer reads in chunks this big
+const CHUNK: usize = 8192;
+
+// What head..tail are in, the source itself if it has its contents
+fn window<'s, R: Source>(buffer: &'s [u8], source: &'s R) -> &'s [u8] {
+    source.contents().unwrap_or(buffer)
+}
+
+fn ascii_identifier(byte: u8) -> bool {
+    byte == b'_' || byte.is_ascii_alphanumeric()
+}
+
+pub struct TokenIterator<R: Source> {
+    config: LexerConfig,
+    source: R,
+    // Bytes head..tail are read from the source but not lexed yet
+    buffer: Vec<u8>,
+    head: usize,
+    tail: usize,
+    // Once the source runs out or fails it isn't read again
+    // A failure is reported once
+    exhausted: bool,
+    error: Option<ReadError>,
+    // Offset, line and column of the next byte to be read
+    position: usize,
+    line: usize,
+    column: usize,
+    // Where the token being lexed began
+    start: Span,
+    // Nothing but whitespace and comments so far on this line
+    line_start: bool,
+    // The last token was a \, so a name is a field
+    after_backslash: bool,
+    interner: Interner,
+    // Lossless mode keeps every byte read since the last token here
+    raw: Vec<u8>,
+    raw_offset: usize,
+    // Every token is put together in here so lexing one doesn't allocate
+    // Names are interned so a name only costs an allocation the first time
+    // it's seen, strings are copied unless BorrowedTokens is lexing
+    scratch: Vec<u8>,
+    // Strings are left for BorrowedTokens to fill in
+    borrow_strings: bool,
+    statistics: Statistics
+}
+
+impl<R: Source> TokenIterator<R> {
+    pub fn new(source: R) -> TokenIterator<R> {
+        TokenIterator::new_with_config(source, LexerConfig::default())
+    }
+
+    pub fn new_with_config(source: R, config: LexerConfig) -> TokenIterator<R> {
+        TokenIterator{
+            config,
+            source,
+            buffer: Vec::new(),
+            head: 0,
+            tail: 0,
+            exhausted: false,
+            error: None,
+            position: 0,
+            line: 1,
+            column: 1,
+            start: Span { line: 1, column: 1, byte_offset: 0, length: 0 },
+            line_start: true,
+            after_backslash: false,
+            interner: Interner::new(),
+            raw: Vec::new(),
+            raw_offset: 0,
+            scratch: Vec::new(),
+            borrow_strings: false,
+            statistics: Statistics::default()
+        }
+    }
+
+    pub fn config(&self) -> &LexerConfig {
+        &self.config
+    }
+
+    // Resolves the symbols in the identifiers this gives out
+    pub fn interner(&self) -> Interner {
+        self.interner.clone()
+    }
+
+    // Share names with another tokenizer, before any tokens are read
+    pub fn with_interner(mut self, interner: Interner) -> TokenIterator<R> {
+        self.interner = interner;
+        self
+    }
+
+    // Lex a piece of a bigger source as if it was read from this far in
+    // The piece has to start right after an EOL, where nothing carries over
+    pub fn continue_from(mut self, byte_offset: usize, line: usize, column: usize)
+                         -> TokenIterator<R> {
+        self.position = byte_offset;
+        self.line = line;
+        self.column = column;
+        self.start = self.cursor();
+        self.raw_offset = byte_offset;
+        self
+    }
+
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+
+    // Start over on another source, keeping the buffers, the config and
+    // the statistics
+    // Names are still shared with everything lexed before
+    pub fn reset(&mut self, source: R) {
+        self.source = source;
+        self.head = 0;
+        self.tail = 0;
+        self.exhausted = false;
+        self.error = None;
+        self.position = 0;
+        self.line = 1;
+        self.column = 1;
+        self.start = self.cursor();
+        self.line_start = true;
+        self.after_backslash = false;
+        self.raw.clear();
+        self.raw_offset = 0;
+    }
+
+    // What was left after the last token in lossless mode
+    // Only complete once the iterator has run out
+    pub fn trailing_trivia(&mut self) -> Vec<u8> {
+        self.raw_offset = self.position;
+        mem::take(&mut self.raw)
+    }
+
+    // Splits what was read since the last token into its trivia
+    fn take_trivia(&mut self) -> Option<Trivia> {
+        if !self.config.lossless {
+            return None;
+        }
+
+        let text = self.raw.split_off(self.start.byte_offset - self.raw_offset);
+        Some(Trivia {
+            leading: self.trailing_trivia(),
+            text
+        })
+    }
+
+    // Makes sure n bytes are buffered, false if the source doesn't have them
+    fn fill(&mut self, n: usize) -> bool {
+        if !self.exhausted {
+            if let Some(contents) = self.source.contents() {
+                self.tail = contents.len();
+                self.exhausted = true;
+            }
+        }
+
+        while self.tail - self.head < n && !self.exhausted {
+            if self.buffer.is_empty() {
+                self.buffer.resize(CHUNK, 0);
+            }
+
+            // Whatever is left goes to the front to make room
+            if self.head > 0 {
+                self.buffer.copy_within(self.head..self.tail, 0);
+                self.tail -= self.head;
+                self.head = 0;
+            }
+
+            match self.source.read_into(&mut self.buffer[self.tail..]) {
+                Ok(0) => self.exhausted = true,
+                Ok(read) => self.tail += read,
+                Err(e) => {
+                    self.exhausted = true;
+                    self.error = Some(e);
+                }
+            }
+        }
+
+        self.tail - self.head >= n
+    }
+
+    fn next_byte(&mut self) -> Option<Result<u8, ReadError>> {
+        if !self.fill(1) {
+            return self.error.take().map(Err);
+        }
+
+        let byte = window(&self.buffer, &self.source)[self.head];
+        self.head += 1;
+        self.statistics.bytes += 1;
+        Some(Ok(byte))
+    }
+
+    // How many buffered bytes in a row pass the test
+    fn run_length<F: Fn(u8) -> bool>(&self, test: F) -> usize {
+        let rest = &window(&self.buffer, &self.source)[self.head..self.tail];
+        rest.iter().take_while(|&&b| test(b)).count()
+    }
+
+    // Takes n buffered bytes in one go, onto buf if there is one
+    // Only for bytes the state machine would take one by one without doing
+    // anything else, and never line breaks
+    fn take(&mut self, n: usize, buf: Option<&mut Vec<u8>>) {
+        let bytes = &window(&self.buffer, &self.source)[self.head..self.head + n];
+        if let Some(buf) = buf {
+            buf.extend_from_slice(bytes);
+        }
+        if self.config.lossless {
+            self.raw.extend_from_slice(bytes);
+        }
+
+        self.head += n;
+        self.position += n;
+        self.column += n;
+        self.statistics.bytes += n;
+    }
+
+    // Takes a run of bytes that each have another one like them after them
+    // The last of the run is left for the state machine to see what's
+    // after it
+    fn take_run<F: Fn(u8) -> bool>(&mut self, buf: &mut Vec<u8>, test: F) {
+        let run = self.run_length(test);
+        if run > 1 {
+            self.take(run - 1, Some(buf));
+        }
+    }
+
+    // Zero-length span at the next byte to be read
+    fn cursor(&self) -> Span {
+        Span {
+            line: self.line,
+            column: self.column,
+            byte_offset: self.position,
+            length: 0
+        }
+    }
+
+    // Span of the last length bytes read, all on the current line
+    fn char_span(&self, length: usize) -> Span {
+        Span {
+            line: self.line,
+            column: self.column - length,
+            byte_offset: self.position - length,
+            length
+        }
+    }
+
+    // Span from the start of the current token up to the cursor
+    fn token_span(&self) -> Span {
+        Span {
+            length: self.position - self.start.byte_offset,
+            .. self.start
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Result<Lexeme, LexError>> {
+        let mut buf = mem::take(&mut self.scratch);
+        buf.clear();
+        let token = self.lex(&mut buf);
+        self.scratch = buf;
+        token
+    }
+
+    // The actual tokenisation happens here
+    fn lex(&mut self, buf: &mut Vec<u8>) -> Option<Result<Lexeme, LexError>> {
+        let mut mode = Mode::None;
+        // Block comments spanning lines still end the line they began on
+        let mut comment_newline = false;
+        let encoding = self.config.encoding;
+
+        loop {
+            let next = self.next_byte();
+            self.fill(1);
+            let peeked = window(&self.buffer, &self.source)[self.head..self.tail].first();
+
+            // Returning None stops iteration
+            let byte = match next {
+                None => return match mode {
+                    Mode::BlockComment(_) => {
+                        Some(Err(LexError::UnterminatedComment {
+                            span: self.token_span()
+                        }))
+                    },
+                    _ => None
                 },
+                Some(Err(e)) => return Some(Err(LexError::Io {
+                    kind: e,
+                    span: self.cursor()
+                })),
+                Some(Ok(value)) => value
+            };
 
-                Mode::Decimal => {
-                    buf.push(byte);
-                    match peeked {
-                        Some(&peek_byte) => match peek_byte {
-                            b'0' ... b'9' => {
-                                // Yes, good
-                            },
+            if self.config.lossless {
+                self.raw.push(byte);
+            }
 
-                            b'.' => {
-                                return Some(Err("Only one decimal point allowed in a number!".to_owned()));
-                            },
+            // Span of byte, the peeked byte is at the cursor after this
+            let here = Span {
+                line: self.line,
+                column: self.column,
+                byte_offset: self.position,
+                length: 1
+            };
 
+            // Anything read in Mode::None may begin a token
+            if let Mode::None = mode {
+                self.start = here;
+            }
+
+            // A CR followed by LF leaves the line break to the LF
+            self.position += 1;
+            if byte == b'\n' ||
+               (byte == b'\r' && self.config.lone_cr_is_newline &&
+                peeked != Some(&b'\n')) {
+                self.line += 1;
+                self.column = 1;
+            }
+            else {
+                self.column += 1;
+            }
+
+
+            match mode {
+                Mode::None => match byte {
+                    // CRLF, or CR alone if the config allows it
+                    b'\r' => {
+                        match peeked {
+                            Some(&b'\n') => {
+                                mode = Mode::Newline;
+                            },
+                            _ if self.config.lone_cr_is_newline => {
+                                return Some(Ok(bare(TokenKind::EOL)));
+                            },
                             _ => {
-                                // Emit number token
-                                emit_token_number!(buf);
+                                return Some(Err(LexError::CarriageReturnWithoutLineFeed {
+                                    byte: peeked.cloned(),
+                                    span: here
+                                }));
                             }
-                        },
-                        
-                        None => {
-                            // Also emit number token
-                            emit_token_number!(buf);
                         }
-                    }
-                },
-
-                Mode::String => {
-                    // The current byte might be the closing "
-                    match byte {
-                        b'"' => {
-                            emit_token_string!(buf);
-                        },
+                    },
 
-                        c @ _ => {
-                            // Nope! Push it.
-                            buf.push(c);
+                    // Plain LF
+                    b'\n' => {
+                        return Some(Ok(bare(TokenKind::EOL)));
+                    },
+                    
+                    // A letter in a single byte encoding
+                    c @ 0x80..=0xFF if encoding != Encoding::Utf8 => {
+                        match decode_byte(c, encoding) {
+                            Some(letter) if letter.is_alphabetic() => {
+                                buf.clear();
+                                push_char(buf, letter);
+                                match peeked {
+                                    Some(&b'_') |
+                                    Some(&(b'0'..=b'9')) |
+                                    Some(&(b'A'..=b'Z')) |
+                                    Some(&(b'a'..=b'z')) |
+                                    Some(&(0x80..=0xFF)) => {
+                                        mode = Mode::Text;
+                                    },
+                                    _ => {
+                                        emit_token_text!(buf, peeked, mode, self);
+                                    }
+                                }
+                            },
+                            Some(other) => return Some(Err(LexError::UnexpectedChar {
+                                c: other,
+                                span: here
+                            })),
+                            None => return Some(Err(LexError::UnmappedByte {
+                                byte: c,
+                                encoding,
+                                span: here
+                            }))
                         }
-                    }
+                    },
 
-                    // Kinda strange how there's no peeky logic here
-                    // Guess it's what happens when ~~delimiters~~
-                }
-            }
+                    // Start of a UTF-8 encoded letter
+                    c @ 0xC2..=0xF4 => {
+                        buf.clear();
+                        buf.push(c);
+                        mode = match utf8_mode(c, peeked) {
+                            Some(utf8) => utf8,
+                            None => return Some(Err(LexError::InvalidUtf8 {
+                                span: here
+                            }))
+                        };
+                    },
+
+                    // Line continuation, the line break is skipped
+                    b'_' | b'\\' if peeked == Some(&b'\r') ||
+                                    peeked == Some(&b'\n') => {
+                        mode = Mode::Continuation;
+                    },
+
+                    // Letters
+                    // These can't be a macro, either? :c
+                    c @ b'_' |
+                    c @ b'A'..=b'Z' |
+                    c @ b'a'..=b'z' => {
+                        // Clear token buffer
+                        buf.clear();
+                        // Insert current char
+                        buf.push(c);
+                        // Go to text mode if next also letter
+                        match peeked {
+                            Some(&peek_byte) => match peek_byte {
+                                b'_' |
+                                b'0'..=b'9' |
+                                b'A'..=b'Z' |
+                                b'a'..=b'z' |
+                                0xC2..=0xF4 => {
+                                    mode = Mode::Text;
+                                },
+                                _ => {
+                                    // Emit text token
+                                    emit_token_text!(buf, peeked, mode, self);
+                                }
+                            },
                             
-        }
+                            None => {
+                                // Also emit text token
+                                emit_token_text!(buf, peeked, mode, self);
+                            }
+                        }
+                    },
+
+                    // String
+                    b'"' => {
+                        // Clear token buffer
+                        buf.clear();
+                        // Don't insert current char - it's irrelevant, it's always "
+                        // Strings can't continue past the end of the line
+                        match peeked {
+                            None |
+                            Some(&b'\r') |
+                            Some(&b'\n') => {
+                                return Some(Err(LexError::UnterminatedString {
+                                    span: self.token_span()
+                                }));
+                            },
+                            _ => {
+                                mode = Mode::String;
+                            }
+                        }
+                    },
+
+                    // Number
+                    c @ b'0'..=b'9' => {
+                        buf.clear();
+                        buf.push(c);
+
+                        match peeked {
+                            Some(&peek_byte) => match peek_byte {
+                                b'0'..=b'9' | b'_' => {
+                                    // Goto number mode
+                                    mode = Mode::Number;
+                                }
+
+                                b'.' => {
+                                    // Goto decimal mode
+                                    mode = Mode::Decimal;
+                                },
+
+                                b'e' | b'E' => {
+                                    mode = Mode::ExponentMark;
+                                },
+
+                                // 0x and 0b prefixes, the 0 isn't part of the digits
+                                b'x' | b'X' if c == b'0' => {
+                                    buf.clear();
+                                    mode = Mode::RadixPrefix(16);
+                                },
+
+                                b'b' | b'B' if c == b'0' => {
+                                    buf.clear();
+                                    mode = Mode::RadixPrefix(2);
+                                },
+
+                                _ => {
+                                    // Emit number token
+                                    emit_token_number!(buf, self.config.integer_width, self.token_span());
+                                }
+                            },
+                            None => {
+                                // Also emit number token
+                                emit_token_number!(buf, self.config.integer_width, self.token_span());
+                            }
+                        }
+                    },
+
+                    // &H and &B prefixes
+                    b'&' => {
+                        buf.clear();
+
+                        match peeked {
+                            Some(&b'H') | Some(&b'h') => {
+                                mode = Mode::RadixPrefix(16);
+                            },
+                            Some(&b'B') | Some(&b'b') => {
+                                mode = Mode::RadixPrefix(2);
+                            },
+                            _ => {
+                                return Some(Err(LexError::UnexpectedByte {
+                                    byte: b'&',
+                                    span: here
+                                }));
+                            }
+                        }
+                    },
+
+                    // Always just a minus, -1 is a negation for the parser
+                    b'-' => {
+                        return Some(Ok(bare(TokenKind::Minus)));
+                    },
+
+                    b'+' => {
+                        return Some(Ok(bare(TokenKind::Plus)));
+                    },
+
+                    b'*' => {
+                        return Some(Ok(bare(TokenKind::Star)));
+                    },
+
+                    b'^' => {
+                        return Some(Ok(bare(TokenKind::Caret)));
+                    },
+
+                    // Modulo
+                    b'%' => {
+                        return Some(Ok(bare(TokenKind::Percent)));
+                    },
+
+                    // Comparisons, might be two bytes long
+                    c @ b'<' |
+                    c @ b'>' => {
+                        match peeked {
+                            Some(&b'=') => {
+                                mode = Mode::Operator(c);
+                            },
+                            Some(&b'>') if c == b'<' => {
+                                mode = Mode::Operator(c);
+                            },
+                            _ => {
+                                return Some(Ok(bare(if c == b'<' {
+                                    TokenKind::Less
+                                }
+                                else {
+                                    TokenKind::Greater
+                                })));
+                            }
+                        }
+                    },
+
+                    // Opening paren
+                    b'(' => {
+                        // Just emit it
+                        return Some(Ok(bare(TokenKind::LParen)));
+                    },
+
+                    // Closing paren
+                    b')' => {
+                        // Just emit it
+                        return Some(Ok(bare(TokenKind::RParen)));
+                    },
+
+                    b'[' => {
+                        return Some(Ok(bare(TokenKind::LBracket)));
+                    },
+
+                    b']' => {
+                        return Some(Ok(bare(TokenKind::RBracket)));
+                    },
+
+                    b'$' => {
+                        return Some(Ok(bare(TokenKind::Dollar)));
+                    },
+
+                    // #! on the very first line is a shebang, skip it
+                    b'#' if here.line == 1 && here.column == 1 &&
+                            peeked == Some(&b'!') && self.config.comments.shebang => {
+                        mode = Mode::Comment;
+                    },
+
+                    b'#' => {
+                        return Some(Ok(bare(TokenKind::Hash)));
+                    },
+
+                    // Shorthand for Print
+                    b'?' if self.config.keywords.contains(Keyword::Print) => {
+                        return Some(Ok(bare(TokenKind::Keyword(Keyword::Print))));
+                    },
+
+                    b'=' => {
+                        return Some(Ok(bare(TokenKind::Equals)));
+                    },
+
+                    b',' => {
+                        return Some(Ok(bare(TokenKind::Comma)));
+                    },
+
+                    // Statement separator
+                    b':' => {
+                        return Some(Ok(bare(TokenKind::Colon)));
+                    },
+
+                    // Print separator
+                    b';' => {
+                        return Some(Ok(bare(TokenKind::Semicolon)));
+                    },
+
+                    // Before a line break it's a continuation instead
+                    b'\\' => {
+                        return Some(Ok(bare(TokenKind::Backslash)));
+                    },
+
+                    // Comment to the end of the line
+                    // The line break itself is left alone so it's still an EOL
+                    b'\'' if self.config.comments.apostrophe => {
+                        mode = comment_mode(peeked);
+                    },
+
+                    // Block comment, these nest, or division
+                    b'/' => {
+                        match peeked {
+                            Some(&b'*') if self.config.comments.block => {
+                                mode = Mode::BlockCommentDelimiter(1);
+                            },
+                            _ => {
+                                return Some(Ok(bare(TokenKind::Slash)));
+                            }
+                        }
+                    },
+
+                    // Skip spaces and tabs
+                    b' ' | b'\t' => {
+                        let run = self.run_length(|b| b == b' ' || b == b'\t');
+                        self.take(run, None);
+                    },
+
+                    // Vertical tab and form feed, if the config says so
+                    0x0B | 0x0C if self.config.skip_all_whitespace => {},
+
+                    b @ _ => {
+                        return Some(Err(LexError::UnexpectedByte {
+                            byte: b,
+                            span: here
+                        }));
+                    }
+
+                },
+
+                // Only entered when the LF was already peeked
+                Mode::Newline => match byte {
+                    b'\n' => {
+                        // Emit EOL, we're good
+                        return Some(Ok(bare(TokenKind::EOL)));
+                    }
+                    _ => unreachable!()
+                },
+
+                // We've already peeked at byte if we're here
+                // That means we know we have something!
+                // Push *current* (not peeked!) byte
+                // The rest of a multi-byte letter
+                Mode::Utf8(remaining, length) => {
+                    buf.push(byte);
+
+                    if remaining > 1 {
+                        match peeked {
+                            Some(&peek_byte) if utf8_continuation(peek_byte) => {
+                                mode = Mode::Utf8(remaining - 1, length);
+                                continue;
+                            },
+                            _ => return Some(Err(LexError::InvalidUtf8 {
+                                span: self.char_span(length - remaining + 1)
+                            }))
+                        }
+                    }
+
+                    // The whole character is in, check it's a letter
+                    let span = Span {
+                        line: self.line,
+                        column: self.column - length,
+                        byte_offset: self.position - length,
+                        length
+                    };
+                    let first = buf.len() == length;
+                    match str::from_utf8(&buf[buf.len() - length..]) {
+                        Ok(text) => {
+                            let c = text.chars().next().unwrap();
+
+                            // A byte order mark at the very start is skipped
+                            // Columns start after it, like in an editor
+                            if c == '\u{FEFF}' && span.byte_offset == 0 {
+                                self.column = 1;
+                                mode = Mode::None;
+                                continue;
+                            }
+
+                            if !(c.is_alphabetic() || (!first && c.is_numeric())) {
+                                return Some(Err(LexError::UnexpectedChar {
+                                    c,
+                                    span
+                                }));
+                            }
+                        },
+                        Err(_) => return Some(Err(LexError::InvalidUtf8 {
+                            span
+                        }))
+                    }
+
+                    match peeked {
+                        Some(&b'_') |
+                        Some(&(b'0'..=b'9')) |
+                        Some(&(b'A'..=b'Z')) |
+                        Some(&(b'a'..=b'z')) |
+                        Some(&(0xC2..=0xF4)) => {
+                            mode = Mode::Text;
+                        },
+                        _ => {
+                            emit_token_text!(buf, peeked, mode, self);
+                        }
+                    }
+                },
+
+                Mode::Text => {
+                    if byte >= 0x80 && encoding != Encoding::Utf8 {
+                        match decode_byte(byte, encoding) {
+                            Some(letter) if letter.is_alphanumeric() => {
+                                push_char(buf, letter);
+                            },
+                            Some(other) => return Some(Err(LexError::UnexpectedChar {
+                                c: other,
+                                span: here
+                            })),
+                            None => return Some(Err(LexError::UnmappedByte {
+                                byte,
+                                encoding,
+                                span: here
+                            }))
+                        }
+                    }
+                    else {
+                        buf.push(byte);
+                    }
+
+                    // Multi-byte letters are finished in Mode::Utf8
+                    if byte >= 0x80 && encoding == Encoding::Utf8 {
+                        mode = match utf8_mode(byte, peeked) {
+                            Some(utf8) => utf8,
+                            None => return Some(Err(LexError::InvalidUtf8 {
+                                span: here
+                            }))
+                        };
+                        continue;
+                    }
+
+                    match peeked {
+                        Some(&peek_byte) => match peek_byte {
+                                // NOTE: THIS IS NOT THE SAME PATTERN AS
+                                // THE PREVIOUS LETTER PATTERN!!
+                                b'_' |
+                                b'0'..=b'9' |
+                                b'A'..=b'Z' |
+                                b'a'..=b'z' |
+                                0xC2..=0xF4 => {
+                                    // Carry on
+                                    self.take_run(buf, ascii_identifier);
+                                },
+                                0x80..=0xFF if encoding != Encoding::Utf8 => {},
+
+                                // REM comments out the rest of the line
+                                _ if is_rem(buf, peeked, self.after_backslash, &self.config) => {
+                                    mode = comment_mode(peeked);
+                                },
+
+                                // Valid varname ends
+                                _ => {
+                                    // Emit text token
+                                    emit_token_text!(buf, peeked, mode, self);
+                                }
+                        },
+
+                        // Nothing after the REM, nothing to emit
+                        None if is_rem(buf, None, self.after_backslash, &self.config) => {
+                            mode = Mode::None;
+                        },
+
+                        None => {
+                            // Well, let's go back to normal mode?
+                            // Also emit text token
+                            emit_token_text!(buf, peeked, mode, self);
+                        }
+                    }
+                },
+
+                Mode::Sigil => {
+                    let name = Identifier::new(buf, self.config.case_insensitive,
+                                               &self.interner);
+                    return Some(Ok(match byte {
+                        b'$' => (TokenKind::StringIdent, Payload::Identifier(name)),
+                        _ => (TokenKind::FloatIdent, Payload::Identifier(name))
+                    }));
+                },
+
+                Mode::Label => {
+                    return Some(Ok((TokenKind::Label, Payload::Identifier(Identifier::new(
+                        buf, self.config.case_insensitive, &self.interner)))));
+                },
+
+                Mode::Number => {
+                    skip_digit_separator!(byte, peeked, 10, self);
+
+                    // Push current byte
+                    buf.push(byte);
+                    match peeked {
+                        Some(&peek_byte) => match peek_byte {
+                                b'0'..=b'9' | b'_' => {
+                                    // Carry on...
+                                    self.take_run(buf, |b| b.is_ascii_digit());
+                                },
+
+                                // Decimal point found
+                                b'.' => {
+                                    // Change modes
+                                    mode = Mode::Decimal;
+                                },
+
+                                b'e' | b'E' => {
+                                    mode = Mode::ExponentMark;
+                                },
+
+                                // Valid number ends
+                                _ => {
+                                    // Emit number token
+                                    emit_token_number!(buf, self.config.integer_width, self.token_span());
+                                }
+                        },
+
+                        None => {
+                            // Also emit number token
+                            emit_token_number!(buf, self.config.integer_width, self.token_span());
+                        }
+                    }
+                },
+
+                Mode::Decimal => {
+                    skip_digit_separator!(byte, peeked, 10, self);
+
+                    buf.push(byte);
+                    match peeked {
+                        Some(&peek_byte) => match peek_byte {
+                            b'0'..=b'9' | b'_' => {
+                                // Yes, good
+                                self.take_run(buf, |b| b.is_ascii_digit());
+                            },
+
+                            // The second point goes with the error, it can't
+                            // start anything
+                            b'.' => {
+                                self.take(1, None);
+                                return Some(Err(LexError::MultipleDecimalPoints {
+                                    span: self.char_span(1)
+                                }));
+                            },
+
+                            b'e' | b'E' => {
+                                mode = Mode::ExponentMark;
+                            },
+
+                            _ => {
+                                // Emit number token
+                                emit_token_number!(buf, self.config.integer_width, self.token_span());
+                            }
+                        },
+                        
+                        None => {
+                            // Also emit number token
+                            emit_token_number!(buf, self.config.integer_width, self.token_span());
+                        }
+                    }
+                },
+
+                // The current byte is the e of 1e9
+                // An exponent needs digits, optionally with a sign first
+                Mode::ExponentMark => {
+                    buf.push(byte);
+                    match peeked {
+                        Some(&b'+') | Some(&b'-') => {
+                            mode = Mode::ExponentSign;
+                        },
+                        Some(&(b'0'..=b'9')) => {
+                            mode = Mode::Exponent;
+                        },
+                        _ => {
+                            return Some(Err(LexError::InvalidNumber {
+                                span: self.token_span()
+                            }));
+                        }
+                    }
+                },
+
+                Mode::ExponentSign => {
+                    buf.push(byte);
+                    match peeked {
+                        Some(&(b'0'..=b'9')) => {
+                            mode = Mode::Exponent;
+                        },
+                        _ => {
+                            return Some(Err(LexError::InvalidNumber {
+                                span: self.token_span()
+                            }));
+                        }
+                    }
+                },
+
+                Mode::Exponent => {
+                    skip_digit_separator!(byte, peeked, 10, self);
+
+                    buf.push(byte);
+                    match peeked {
+                        Some(&(b'0'..=b'9')) | Some(&b'_') => {
+                            // Carry on
+                            self.take_run(buf, |b| b.is_ascii_digit());
+                        },
+                        _ => {
+                            emit_token_number!(buf, self.config.integer_width, self.token_span());
+                        }
+                    }
+                },
+
+                Mode::RadixPrefix(radix) => {
+                    // There has to be at least one digit after the prefix
+                    match peeked {
+                        Some(&peek_byte) if radix_digit(peek_byte, radix) => {
+                            mode = Mode::Radix(radix);
+                        },
+                        _ => {
+                            return Some(Err(LexError::InvalidNumber {
+                                span: self.token_span()
+                            }));
+                        }
+                    }
+                },
+
+                Mode::Radix(radix) => {
+                    skip_digit_separator!(byte, peeked, radix, self);
+
+                    buf.push(byte);
+                    match peeked {
+                        Some(&b'_') => {
+                            // Separator, checked next round
+                        },
+                        Some(&peek_byte) if radix_digit(peek_byte, radix) => {
+                            // Carry on
+                            self.take_run(buf, |b| radix_digit(b, radix));
+                        },
+                        _ => {
+                            emit_token_radix!(buf, radix, self.config.integer_width,
+                                              self.token_span());
+                        }
+                    }
+                },
+
+                // The current byte is the second byte of an operator
+                Mode::Operator(first) => {
+                    return Some(Ok(bare(match (first, byte) {
+                        (b'<', b'=') => TokenKind::LessEquals,
+                        (b'>', b'=') => TokenKind::GreaterEquals,
+                        (b'<', b'>') => TokenKind::NotEquals,
+                        _ => unreachable!()
+                    })));
+                },
+
+                // The current byte is the line break after a continuation
+                Mode::Continuation => {
+                    match (byte, peeked) {
+                        (b'\r', Some(&b'\n')) => {
+                            // Leave the LF for the next round
+                        },
+                        (b'\r', _) if !self.config.lone_cr_is_newline => {
+                            return Some(Err(LexError::CarriageReturnWithoutLineFeed {
+                                byte: peeked.cloned(),
+                                span: here
+                            }));
+                        },
+                        _ => {
+                            mode = Mode::None;
+                        }
+                    }
+                },
+
+                Mode::Comment => {
+                    match peeked {
+                        None | Some(&b'\r') | Some(&b'\n') => {
+                            mode = Mode::None;
+                        },
+                        _ => {
+                            let rest = &window(&self.buffer, &self.source)[self.head..self.tail];
+                            let run = memchr2(b'\r', b'\n', rest).unwrap_or(rest.len());
+                            if run > 1 {
+                                self.take(run - 1, None);
+                            }
+                        }
+                    }
+                },
+
+                Mode::BlockComment(depth) => {
+                    if byte == b'\n' {
+                        comment_newline = true;
+                    }
+
+                    match (byte, peeked) {
+                        (b'*', Some(&b'/')) => {
+                            mode = Mode::BlockCommentDelimiter(depth - 1);
+                        },
+                        (b'/', Some(&b'*')) => {
+                            mode = Mode::BlockCommentDelimiter(depth + 1);
+                        },
+                        _ => {}
+                    }
+                },
+
+                Mode::BlockCommentDelimiter(depth) => {
+                    if depth == 0 && comment_newline {
+                        return Some(Ok(bare(TokenKind::EOL)));
+                    }
+
+                    mode = if depth == 0 {
+                        Mode::None
+                    }
+                    else {
+                        Mode::BlockComment(depth)
+                    };
+                },
+
+                Mode::String => {
+                    // The current byte might be the closing "
+                    match byte {
+                        b'"' => {
+                            emit_token_string!(buf, encoding, self.start, self.borrow_strings);
+                        },
+
+                        c @ _ => {
+                            // Nope! Push it.
+                            buf.push(c);
+
+                            // Leave the newline for the next token
+                            match peeked {
+                                None |
+                                Some(&b'\r') |
+                                Some(&b'\n') => {
+                                    return Some(Err(LexError::UnterminatedString {
+                                        span: self.token_span()
+                                    }));
+                                },
+                                _ => {
+                                    let rest = window(&self.buffer, &self.source);
+                                    let rest = &rest[self.head..self.tail];
+                                    let run = memchr3(b'"', b'\r', b'\n', rest)
+                                              .unwrap_or(rest.len());
+                                    if run > 1 {
+                                        self.take(run - 1, Some(buf));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+                            
+        }
+    }
+}
+
+impl<'a> TokenIterator<&'a [u8]> {
+    pub fn from_bytes(bytes: &'a [u8]) -> TokenIterator<&'a [u8]> {
+        TokenIterator::new(bytes)
+    }
+
+    // Not FromStr, the tokens borrow from the string
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(source: &'a str) -> TokenIterator<&'a [u8]> {
+        TokenIterator::new(source.as_bytes())
+    }
+}
+
+// For tools that get their script piped in
+// Stdin is locked for every read, which is one lock per chunk here
+#[cfg(feature = "std")]
+impl TokenIterator<io::Stdin> {
+    pub fn from_stdin() -> TokenIterator<io::Stdin> {
+        TokenIterator::new(io::stdin())
+    }
+
+    pub fn from_stdin_with_config(config: LexerConfig) -> TokenIterator<io::Stdin> {
+        TokenIterator::new_with_config(io::stdin(), config)
+    }
+}
+
+impl<R: Source> Iterator for TokenIterator<R> {
+    type Item = Result<Token<'static>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        #[cfg(feature = "std")]
+        let started = if self.config.statistics { Some(Instant::now()) } else { None };
+
+        let token = self.next_token();
+
+        #[cfg(feature = "std")]
+        {
+            if let Some(started) = started {
+                self.statistics.time += started.elapsed();
+            }
+        }
+
+        match token {
+            Some(Ok(ref token)) => {
+                self.statistics.tokens += 1;
+                if self.config.statistics {
+                    *self.statistics.kinds.entry(token.0).or_insert(0) += 1;
+                }
+            },
+            Some(Err(_)) => self.statistics.errors += 1,
+            None => {}
+        }
+
+        match token {
+            Some(Ok(token)) => {
+                self.line_start = token.0 == TokenKind::EOL;
+                self.after_backslash = token.0 == TokenKind::Backslash;
+                Some(Ok(Token {
+                    kind: token.0,
+                    span: self.token_span(),
+                    payload: token.1,
+                    trivia: self.take_trivia()
+                }))
+            },
+            Some(Err(e)) => Some(Err(e)),
+            None => None
+        }
+    }
+}
+
+// Lexes a buffer that's all in memory already, without copying strings
+// Their payloads point into the source, so the tokens can't outlive it
+pub struct BorrowedTokens<'a> {
+    tokens: TokenIterator<&'a [u8]>,
+    source: &'a [u8]
+}
+
+impl<'a> BorrowedTokens<'a> {
+    pub fn new(source: &'a [u8]) -> BorrowedTokens<'a> {
+        BorrowedTokens::new_with_config(source, LexerConfig::default())
+    }
+
+    pub fn new_with_config(source: &'a [u8], config: LexerConfig) -> BorrowedTokens<'a> {
+        let mut tokens = TokenIterator::new_with_config(source, config);
+        // Only UTF-8 is the same in the source as it is in the payload
+        tokens.borrow_strings = tokens.config.encoding == Encoding::Utf8;
+        BorrowedTokens { tokens, source }
+    }
+
+    pub fn config(&self) -> &LexerConfig {
+        self.tokens.config()
+    }
+
+    pub fn interner(&self) -> Interner {
+        self.tokens.interner()
+    }
+
+    pub fn with_interner(self, interner: Interner) -> BorrowedTokens<'a> {
+        BorrowedTokens {
+            tokens: self.tokens.with_interner(interner),
+            .. self
+        }
+    }
+
+    pub fn statistics(&self) -> &Statistics {
+        self.tokens.statistics()
+    }
+
+    pub fn trailing_trivia(&mut self) -> Vec<u8> {
+        self.tokens.trailing_trivia()
+    }
+}
+
+impl<'a> Iterator for BorrowedTokens<'a> {
+    type Item = Result<Token<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut token: Token<'a> = match self.tokens.next()? {
+            Ok(token) => token,
+            Err(e) => return Some(Err(e))
+        };
+
+        // Whatever is between the quotes
+        if let Payload::String(Cow::Borrowed(_)) = token.payload {
+            let span = token.span;
+            let contents = span.byte_offset + 1..span.byte_offset + span.length - 1;
+            token.payload = Payload::String(Cow::Borrowed(&self.source[contents]));
+        }
+
+        Some(Ok(token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use std::collections::HashSet;
+    #[cfg(not(feature = "std"))]
+    use alloc::string::ToString;
+    #[cfg(feature = "serde")]
+    use serde_json;
+
+    fn string_payload<'a, 'b>(token: &'a Token<'b>) -> &'a Cow<'b, [u8]> {
+        match token.payload {
+            Payload::String(ref bytes) => bytes,
+            _ => panic!("{:?} isn't a string", token)
+        }
+    }
+
+    #[test]
+    fn borrowed_strings_point_into_the_source() {
+        let source = b"Print \"hello\" + \"\"\n";
+        let tokens: Vec<_> = BorrowedTokens::new(source).map(|token| token.unwrap()).collect();
+        let strings: Vec<_> = tokens.iter().filter(|token| token.kind == TokenKind::String).collect();
+
+        assert_eq!(strings.len(), 2);
+        match *string_payload(strings[0]) {
+            Cow::Borrowed(bytes) => {
+                assert_eq!(bytes, b"hello");
+                assert_eq!(bytes.as_ptr(), source[7..].as_ptr());
+            },
+            Cow::Owned(_) => panic!("the string was copied")
+        }
+        assert_eq!(&**string_payload(strings[1]), b"");
+    }
+
+    #[test]
+    fn borrowed_tokens_are_the_same_as_owned_ones() {
+        let source = b"a$ = \"x\" + Str(1.5)\r\nb: Goto b ' done\n";
+        let borrowed: Vec<_> = BorrowedTokens::new(source).map(|token| token.unwrap()).collect();
+        let owned: Vec<_> = TokenIterator::new(&source[..]).map(|token| token.unwrap()).collect();
+        assert_eq!(borrowed, owned);
+    }
+
+    #[test]
+    fn other_encodings_still_copy() {
+        let config = LexerConfig { encoding: Encoding::Latin1, .. LexerConfig::default() };
+        let mut tokens = BorrowedTokens::new_with_config(b"\"caf\xe9\"", config);
+        let token = tokens.next().unwrap().unwrap();
+        match *string_payload(&token) {
+            Cow::Owned(ref bytes) => assert_eq!(&bytes[..], "caf\u{e9}".as_bytes()),
+            Cow::Borrowed(_) => panic!("Latin-1 can't be borrowed as UTF-8")
+        }
+    }
+
+    #[test]
+    fn into_owned_outlives_the_source() {
+        let token = {
+            let source = b"\"abc\"".to_vec();
+            let token = BorrowedTokens::new(&source).next().unwrap().unwrap();
+            token.into_owned()
+        };
+        assert_eq!(&**string_payload(&token), b"abc");
+    }
+
+    fn lex(source: &str) -> Vec<Result<Token<'static>, LexError>> {
+        TokenIterator::from_str(source).collect()
+    }
+
+    #[test]
+    fn bad_bytes_are_errors_and_lexing_carries_on() {
+        let tokens = lex("a @ b");
+        assert_eq!(tokens.len(), 3);
+        match tokens[1] {
+            Err(LexError::UnexpectedByte { byte: b'@', span }) => assert_eq!(span.column, 3),
+            ref other => panic!("{:?} isn't an unexpected @", other)
+        }
+        assert_eq!(tokens[2].as_ref().unwrap().kind, TokenKind::Text);
+    }
+
+    #[test]
+    fn errors_say_where_they_are() {
+        let error = lex("x = 1\ny = \"open").pop().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "2:5: String literal is missing its closing quote");
+        assert_eq!(error.message(), "String literal is missing its closing quote");
+    }
+
+    #[test]
+    fn tokens_know_where_they_are() {
+        let spans: Vec<_> = lex("Print x\n  y = 10").into_iter()
+                            .map(|token| token.unwrap().span).collect();
+        let at = |line, column, byte_offset, length| Span { line, column, byte_offset, length };
+        assert_eq!(spans, vec![at(1, 1, 0, 5), at(1, 7, 6, 1), at(1, 8, 7, 1),
+                               at(2, 3, 10, 1), at(2, 5, 12, 1), at(2, 7, 14, 2)]);
+    }
+
+    // What the tokens are, none of them may be errors
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        lex(source).into_iter().map(|token| token.unwrap().kind).collect()
+    }
+
+    // Fails once it's given out what it has, after being interrupted first
+    #[cfg(feature = "std")]
+    struct Flaky {
+        bytes: &'static [u8],
+        interrupted: bool
+    }
+
+    #[cfg(feature = "std")]
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            if self.bytes.is_empty() {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.bytes.read(buf)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn any_reader_will_do() {
+        let tokens: Vec<_> = TokenIterator::new(io::Cursor::new(b"x = 1\n".to_vec()))
+                             .map(|token| token.unwrap().kind).collect();
+        assert_eq!(tokens, kinds("x = 1\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn read_errors_come_out_once() {
+        let mut tokens = TokenIterator::new(Flaky { bytes: b"x", interrupted: false });
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenKind::Text);
+        match tokens.next() {
+            Some(Err(LexError::Io { kind: io::ErrorKind::BrokenPipe, span })) => {
+                assert_eq!(span.byte_offset, 1);
+            },
+            other => panic!("{:?} isn't a read error", other)
+        }
+        assert!(tokens.next().is_none());
+    }
+
+    #[test]
+    fn strings_and_bytes_lex_the_same() {
+        let from_str: Vec<_> = TokenIterator::from_str("a$ = \"b\"").map(Result::unwrap).collect();
+        let from_bytes: Vec<_> = TokenIterator::from_bytes(b"a$ = \"b\"").map(Result::unwrap).collect();
+        assert_eq!(from_str, from_bytes);
+    }
+
+    #[test]
+    fn bytes_dont_have_to_be_utf8() {
+        let tokens: Vec<_> = TokenIterator::from_bytes(b"a \xff").collect();
+        match tokens[1] {
+            Err(LexError::UnexpectedByte { byte: 0xFF, span }) => assert_eq!(span.byte_offset, 2),
+            ref other => panic!("{:?} isn't an unexpected byte", other)
+        }
+    }
+
+    // Gives out a byte per read
+    #[cfg(feature = "std")]
+    struct Trickle<'a>(&'a [u8]);
+
+    #[cfg(feature = "std")]
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let length = buf.len().min(self.0.len()).min(1);
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn short_reads_lex_the_same() {
+        let source = b"If a <> 1.5e3 Then Print \"x\" ' done\r\ngrid(1) = &HFF\n";
+        let trickled: Vec<_> = TokenIterator::new(Trickle(source)).map(Result::unwrap).collect();
+        let whole: Vec<_> = TokenIterator::from_bytes(source).map(Result::unwrap).collect();
+        assert_eq!(trickled, whole);
+    }
+
+    #[test]
+    fn tokens_longer_than_the_buffer() {
+        let name = "a".repeat(CHUNK * 2 + 3);
+        let source = format!("{} = \"{}\"", name, name);
+        let tokens: Vec<_> = lex(&source).into_iter().map(Result::unwrap).collect();
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens[0].span.length, name.len());
+        assert_eq!(tokens[2].payload, Payload::String(Cow::Owned(name.into_bytes())));
+    }
+
+    fn payloads(source: &str) -> Vec<Payload<'static>> {
+        lex(source).into_iter().map(|token| token.unwrap().payload).collect()
+    }
+
+    fn string(text: &str) -> Payload<'static> {
+        Payload::String(Cow::Owned(text.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(payloads("\"hello, world\" \"it's /* not */ a comment\""),
+                   vec![string("hello, world"), string("it's /* not */ a comment")]);
+        assert_eq!(lex("\"\"")[0].as_ref().unwrap().span.length, 2);
+    }
+
+    #[test]
+    fn unterminated_string_leaves_the_line_break() {
+        let tokens = lex("\"open\nx");
+        match tokens[0] {
+            Err(LexError::UnterminatedString { span }) => assert_eq!(span.length, 5),
+            ref other => panic!("{:?} isn't an unterminated string", other)
+        }
+        let rest: Vec<_> = tokens[1..].iter().map(|token| token.as_ref().unwrap().kind).collect();
+        assert_eq!(rest, vec![TokenKind::EOL, TokenKind::Text]);
+    }
+
+    #[test]
+    fn second_decimal_point_is_one_error() {
+        let tokens = lex("1.2.3");
+        assert_eq!(tokens.len(), 2);
+        match tokens[0] {
+            Err(LexError::MultipleDecimalPoints { span }) => assert_eq!(span.byte_offset, 3),
+            ref other => panic!("{:?} isn't a second decimal point", other)
+        }
+        assert_eq!(tokens[1].as_ref().unwrap().payload, Payload::Integer(3));
+    }
+
+    #[test]
+    fn hexadecimal_literals() {
+        assert_eq!(payloads("&HFF 0x1f &hff"),
+                   vec![Payload::Integer(255), Payload::Integer(31), Payload::Integer(255)]);
+        // All 64 bits set is -1, like it would be in memory
+        assert_eq!(payloads("&HFFFFFFFFFFFFFFFF"), vec![Payload::Integer(-1)]);
+        match lex("&H")[0] {
+            Err(LexError::InvalidNumber { span }) => assert_eq!(span.length, 2),
+            ref other => panic!("{:?} isn't an invalid number", other)
+        }
+    }
+
+    #[test]
+    fn binary_literals() {
+        assert_eq!(payloads("&B101 0b11"), vec![Payload::Integer(5), Payload::Integer(3)]);
+        match lex("&B2")[0] {
+            Err(LexError::InvalidNumber { span }) => assert_eq!(span.length, 2),
+            ref other => panic!("{:?} isn't an invalid number", other)
+        }
+    }
+
+    #[test]
+    fn scientific_notation() {
+        assert_eq!(payloads("1e3 1.5E-2 2e+1"),
+                   vec![Payload::Number(1000.0), Payload::Number(0.015), Payload::Number(20.0)]);
+        assert_eq!(kinds("1e3"), vec![TokenKind::Number]);
+        match lex("2e")[0] {
+            Err(LexError::InvalidNumber { span }) => assert_eq!(span.length, 2),
+            ref other => panic!("{:?} isn't an invalid number", other)
+        }
+    }
+
+    fn lex_with(source: &str, config: LexerConfig) -> Vec<Result<Token<'static>, LexError>> {
+        TokenIterator::new_with_config(source.as_bytes(), config).collect()
+    }
+
+    #[test]
+    fn apostrophe_comments() {
+        assert_eq!(kinds("x ' a comment\ny"), vec![TokenKind::Text, TokenKind::EOL, TokenKind::Text]);
+        assert_eq!(kinds("' only a comment"), vec![]);
+
+        let comments = CommentStyles { apostrophe: false, .. CommentStyles::default() };
+        match lex_with("'", LexerConfig::new().comments(comments))[0] {
+            Err(LexError::UnexpectedByte { byte: b'\'', .. }) => {},
+            ref other => panic!("{:?} isn't an unexpected '", other)
+        }
+    }
+
+    #[test]
+    fn rem_comments() {
+        assert_eq!(kinds("REM hi\nx = 1 rem more"),
+                   vec![TokenKind::EOL, TokenKind::Text, TokenKind::Equals, TokenKind::Integer]);
+        // Only REM as a word of its own
+        assert_eq!(kinds("Remark = 1"), vec![TokenKind::Text, TokenKind::Equals, TokenKind::Integer]);
+        // and not as a name with a sigil, a label or a field
+        assert_eq!(kinds("REM hello\nrem$ = \"x\" : rem# = 1\nrem:\np\\rem = 2 rem gone"),
+                   vec![TokenKind::EOL, TokenKind::StringIdent, TokenKind::Equals, TokenKind::String,
+                        TokenKind::Colon, TokenKind::FloatIdent, TokenKind::Equals, TokenKind::Integer,
+                        TokenKind::EOL, TokenKind::Label, TokenKind::EOL, TokenKind::Text,
+                        TokenKind::Backslash, TokenKind::Text, TokenKind::Equals, TokenKind::Integer]);
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        let tokens: Vec<_> = lex("x /* a /* b */ c */ y").into_iter().map(Result::unwrap).collect();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].span.column, 21);
+    }
+
+    #[test]
+    fn block_comment_over_lines_ends_the_first() {
+        let tokens: Vec<_> = lex("x /* a\nb */ y").into_iter().map(Result::unwrap).collect();
+        let kinds: Vec<_> = tokens.iter().map(|token| token.kind).collect();
+        assert_eq!(kinds, vec![TokenKind::Text, TokenKind::EOL, TokenKind::Text]);
+        assert_eq!((tokens[2].span.line, tokens[2].span.column), (2, 6));
+
+        match lex("/* open")[0] {
+            Err(LexError::UnterminatedComment { span }) => assert_eq!(span.length, 7),
+            ref other => panic!("{:?} isn't an unterminated comment", other)
+        }
+    }
+
+    // The kinds of everything but the names in between
+    fn operators(source: &str) -> Vec<TokenKind> {
+        kinds(source).into_iter().filter(|&kind| kind != TokenKind::Text).collect()
+    }
+
+    #[test]
+    fn arithmetic_operators() {
+        assert_eq!(operators("a+b-c*d/e"),
+                   vec![TokenKind::Plus, TokenKind::Minus, TokenKind::Star, TokenKind::Slash]);
+    }
+
+    #[test]
+    fn comparison_operators() {
+        assert_eq!(operators("a<b>c<=d>=e=f"),
+                   vec![TokenKind::Less, TokenKind::Greater, TokenKind::LessEquals,
+                        TokenKind::GreaterEquals, TokenKind::Equals]);
+        let spans: Vec<_> = lex("a<=b").into_iter().map(|token| token.unwrap().span.length).collect();
+        assert_eq!(spans, vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn not_equals() {
+        assert_eq!(operators("a<>b"), vec![TokenKind::NotEquals]);
+        // Only written together
+        assert_eq!(operators("a < > b"), vec![TokenKind::Less, TokenKind::Greater]);
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(operators("a^b ^ 2"), vec![TokenKind::Caret, TokenKind::Caret, TokenKind::Integer]);
+    }
+
+    #[test]
+    fn modulo() {
+        assert_eq!(operators("a Mod b % c MOD d"),
+                   vec![TokenKind::Keyword(Keyword::Mod), TokenKind::Percent,
+                        TokenKind::Keyword(Keyword::Mod)]);
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(kinds("If x Then EndIf"),
+                   vec![TokenKind::Keyword(Keyword::If), TokenKind::Text,
+                        TokenKind::Keyword(Keyword::Then), TokenKind::Keyword(Keyword::EndIf)]);
+        // Only whole words
+        assert_eq!(kinds("Iffy Printer"), vec![TokenKind::Text, TokenKind::Text]);
+
+        let keywords = KeywordSet::all().without(Keyword::Type);
+        let tokens = lex_with("Type Print", LexerConfig::new().keywords(keywords));
+        let kinds: Vec<_> = tokens.into_iter().map(|token| token.unwrap().kind).collect();
+        assert_eq!(kinds, vec![TokenKind::Text, TokenKind::Keyword(Keyword::Print)]);
+    }
+
+    #[test]
+    fn case_insensitive_names_and_keywords() {
+        let mut tokens = TokenIterator::from_str("PRINT Foo foo");
+        let interner = tokens.interner();
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenKind::Keyword(Keyword::Print));
+        let first = tokens.next().unwrap().unwrap().identifier().unwrap();
+        let second = tokens.next().unwrap().unwrap().identifier().unwrap();
+        assert_eq!(first.name, second.name);
+        assert_ne!(first.spelling, second.spelling);
+        assert_eq!(&*interner.resolve(first.name), b"foo");
+        assert_eq!(&*interner.resolve(first.spelling), b"Foo");
+    }
+
+    #[test]
+    fn case_sensitive_only_has_the_canonical_keywords() {
+        let config = LexerConfig::new().case_insensitive(false);
+        let tokens: Vec<_> = lex_with("PRINT Print Foo foo", config).into_iter()
+                             .map(Result::unwrap).collect();
+        assert_eq!(tokens[0].kind, TokenKind::Text);
+        assert_eq!(tokens[1].kind, TokenKind::Keyword(Keyword::Print));
+        assert_ne!(tokens[2].identifier().unwrap().name, tokens[3].identifier().unwrap().name);
+    }
+
+    #[test]
+    fn colon_separates_statements() {
+        assert_eq!(operators("x = 1: y = 2"),
+                   vec![TokenKind::Equals, TokenKind::Integer, TokenKind::Colon, TokenKind::Equals,
+                        TokenKind::Integer]);
+    }
+
+    #[test]
+    fn semicolon() {
+        assert_eq!(kinds("Print a;b;"),
+                   vec![TokenKind::Keyword(Keyword::Print), TokenKind::Text, TokenKind::Semicolon,
+                        TokenKind::Text, TokenKind::Semicolon]);
+    }
+
+    #[test]
+    fn square_brackets() {
+        assert_eq!(operators("a[1][b]"),
+                   vec![TokenKind::LBracket, TokenKind::Integer, TokenKind::RBracket,
+                        TokenKind::LBracket, TokenKind::RBracket]);
+    }
+
+    #[test]
+    fn every_line_ends_in_an_eol() {
+        let tokens: Vec<_> = lex("a\n\nb\n").into_iter().map(Result::unwrap).collect();
+        let eols: Vec<_> = tokens.iter().filter(|token| token.kind == TokenKind::EOL)
+                           .map(|token| (token.span.line, token.span.column)).collect();
+        assert_eq!(eols, vec![(1, 2), (2, 1), (3, 2)]);
+        // The last line doesn't need one
+        assert_eq!(kinds("a"), vec![TokenKind::Text]);
+    }
+
+    #[test]
+    fn lf_and_crlf_end_lines() {
+        let lines: Vec<_> = lex("a\nb\r\nc").into_iter().map(|token| token.unwrap())
+                            .filter(|token| token.kind == TokenKind::Text)
+                            .map(|token| token.span.line).collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn lone_cr_is_an_error_unless_allowed() {
+        match lex("b\rc")[1] {
+            Err(LexError::CarriageReturnWithoutLineFeed { byte: Some(b'c'), span }) => {
+                assert_eq!(span.byte_offset, 1);
+            },
+            ref other => panic!("{:?} isn't a lone CR", other)
+        }
+
+        let tokens = lex_with("b\rc", LexerConfig::new().lone_cr_is_newline(true));
+        let tokens: Vec<_> = tokens.into_iter().map(Result::unwrap).collect();
+        assert_eq!(tokens[1].kind, TokenKind::EOL);
+        assert_eq!((tokens[2].span.line, tokens[2].span.column), (2, 1));
+    }
+
+    #[test]
+    fn spaces_and_tabs_are_skipped() {
+        assert_eq!(kinds(" \t a \t\t= 1 "),
+                   vec![TokenKind::Text, TokenKind::Equals, TokenKind::Integer]);
+
+        // Vertical tabs and form feeds only if asked to
+        match lex("a\x0bb")[1] {
+            Err(LexError::UnexpectedByte { byte: 0x0B, .. }) => {},
+            ref other => panic!("{:?} isn't an unexpected vertical tab", other)
+        }
+        let tokens = lex_with("a\x0b\x0cb", LexerConfig::new().skip_all_whitespace(true));
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn utf8_identifiers() {
+        let mut tokens = TokenIterator::from_str("Äpple = äpple → 1");
+        let interner = tokens.interner();
+        let first = tokens.next().unwrap().unwrap();
+        assert_eq!(first.kind, TokenKind::Text);
+        // Columns count bytes
+        assert_eq!(first.span.length, 6);
+        assert_eq!(String::from_utf8_lossy(&interner.resolve(first.identifier().unwrap().name)),
+                   "äpple");
+
+        tokens.next();
+        let second = tokens.next().unwrap().unwrap();
+        assert_eq!(second.identifier().unwrap().name, first.identifier().unwrap().name);
+        match tokens.next() {
+            Some(Err(LexError::UnexpectedChar { c: '→', span })) => assert_eq!(span.length, 3),
+            other => panic!("{:?} isn't an unexpected arrow", other)
+        }
+    }
+
+    #[test]
+    fn bom_is_skipped() {
+        let token = lex("\u{feff}Print").remove(0).unwrap();
+        assert_eq!(token.kind, TokenKind::Keyword(Keyword::Print));
+        assert_eq!((token.span.column, token.span.byte_offset), (1, 3));
+        // Anywhere else it's just a character
+        match lex("x \u{feff}")[1] {
+            Err(LexError::UnexpectedChar { c: '\u{feff}', .. }) => {},
+            ref other => panic!("{:?} isn't an unexpected BOM", other)
+        }
+    }
+
+    #[test]
+    fn shebang_line_is_skipped() {
+        assert_eq!(kinds("#!/usr/bin/cb\nPrint"),
+                   vec![TokenKind::EOL, TokenKind::Keyword(Keyword::Print)]);
+        // Only on the first line
+        let tokens = lex("x\n#!y");
+        assert_eq!(tokens[2].as_ref().unwrap().kind, TokenKind::Hash);
+        match tokens[3] {
+            Err(LexError::UnexpectedByte { byte: b'!', .. }) => {},
+            ref other => panic!("{:?} isn't an unexpected !", other)
+        }
+    }
+
+    #[test]
+    fn line_continuation() {
+        let tokens: Vec<_> = lex("x = 1 _\r\n  + 2").into_iter().map(Result::unwrap).collect();
+        let lexed: Vec<_> = tokens.iter().map(|token| token.kind).collect();
+        assert_eq!(lexed, vec![TokenKind::Text, TokenKind::Equals, TokenKind::Integer, TokenKind::Plus,
+                               TokenKind::Integer]);
+        assert_eq!((tokens[3].span.line, tokens[3].span.column), (2, 3));
+        // Only at the end of a line
+        assert_eq!(kinds("x _ y"), vec![TokenKind::Text; 3]);
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(payloads("1_000 &HFF_FF 1_0.2_5"),
+                   vec![Payload::Integer(1000), Payload::Integer(0xFFFF), Payload::Number(10.25)]);
+        // Only between digits
+        for source in &["1__0", "1_"] {
+            match lex(source)[0] {
+                Err(LexError::InvalidNumber { .. }) => {},
+                ref other => panic!("{:?} isn't an invalid number", other)
+            }
+        }
+        // The rest of the number goes with the error
+        let tokens = lex("1__0_0 + &HF__F");
+        match tokens[0] {
+            Err(LexError::InvalidNumber { span }) => assert_eq!(span.length, 6),
+            ref other => panic!("{:?} isn't an invalid number", other)
+        }
+        assert_eq!(tokens[1].as_ref().map(|token| token.kind), Ok(TokenKind::Plus));
+        match tokens[2] {
+            Err(LexError::InvalidNumber { span }) => assert_eq!(span.length, 6),
+            ref other => panic!("{:?} isn't an invalid number", other)
+        }
+        assert_eq!(tokens.len(), 3);
+    }
+
+    #[test]
+    fn integer_width() {
+        let config = LexerConfig::new().integer_width(IntegerWidth::Bits32);
+        let tokens = lex_with("2147483647 2147483648 &HFFFFFFFF", config);
+        assert_eq!(tokens[0].as_ref().unwrap().payload, Payload::Integer(i32::MAX as i64));
+        match tokens[1] {
+            Err(LexError::NumberOverflow { ref digits, .. }) => assert_eq!(digits, b"2147483648"),
+            ref other => panic!("{:?} isn't an overflow", other)
+        }
+        // A bit pattern of all 32 bits is negative
+        assert_eq!(tokens[2].as_ref().unwrap().payload, Payload::Integer(-1));
+
+        assert_eq!(payloads("2147483648"), vec![Payload::Integer(2147483648)]);
+    }
+
+    #[test]
+    fn overflow_is_an_error_and_lexing_carries_on() {
+        let tokens = lex("99999999999999999999 + 1e999");
+        match tokens[0] {
+            Err(LexError::NumberOverflow { span, .. }) => assert_eq!(span.length, 20),
+            ref other => panic!("{:?} isn't an overflow", other)
+        }
+        assert_eq!(tokens[1].as_ref().unwrap().kind, TokenKind::Plus);
+        match tokens[2] {
+            Err(ref e @ LexError::NumberOverflow { .. }) => {
+                assert_eq!(e.message(), "Number 1e999 is too large");
+            },
+            ref other => panic!("{:?} isn't an overflow", other)
+        }
+    }
+
+    #[test]
+    fn sigils_make_typed_identifiers() {
+        assert_eq!(kinds("a$ b# c $ d #x"),
+                   vec![TokenKind::StringIdent, TokenKind::FloatIdent, TokenKind::Text,
+                        TokenKind::Dollar, TokenKind::Text, TokenKind::Hash, TokenKind::Text]);
+
+        let mut tokens = TokenIterator::from_str("name$");
+        let interner = tokens.interner();
+        let token = tokens.next().unwrap().unwrap();
+        // The sigil is in the span and the kind, not the name
+        assert_eq!(token.span.length, 5);
+        assert_eq!(&*interner.resolve(token.identifier().unwrap().name), b"name");
+    }
+
+    #[test]
+    fn labels_start_lines() {
+        let tokens: Vec<_> = lex("top:\n  inner: x: y").into_iter().map(Result::unwrap).collect();
+        let lexed: Vec<_> = tokens.iter().map(|token| token.kind).collect();
+        assert_eq!(lexed, vec![TokenKind::Label, TokenKind::EOL, TokenKind::Label, TokenKind::Text,
+                               TokenKind::Colon, TokenKind::Text]);
+        // With the colon
+        assert_eq!(tokens[0].span.length, 4);
+    }
+
+    #[test]
+    fn question_mark_is_print() {
+        let token = lex("? \"hi\"").remove(0).unwrap();
+        assert_eq!(token.kind, TokenKind::Keyword(Keyword::Print));
+        assert_eq!(token.span.length, 1);
+    }
+
+    fn lex_bytes(source: &[u8], encoding: Encoding) -> Vec<Result<Token<'static>, LexError>> {
+        TokenIterator::new_with_config(source, LexerConfig::new().encoding(encoding)).collect()
+    }
+
+    #[test]
+    fn strings_are_decoded_to_utf8() {
+        let latin = lex_bytes(b"\"caf\xE9\"", Encoding::Latin1);
+        assert_eq!(latin[0].as_ref().unwrap().payload, string("café"));
+
+        let windows = lex_bytes(b"\"\x80 \x93quoted\x94\"", Encoding::Windows1252);
+        assert_eq!(windows[0].as_ref().unwrap().payload, string("€ \u{201C}quoted\u{201D}"));
+
+        // Already UTF-8 so nothing changes
+        let utf8 = lex_bytes("\"café\"".as_bytes(), Encoding::Utf8);
+        assert_eq!(utf8[0].as_ref().unwrap().payload, string("café"));
+    }
+
+    #[test]
+    fn bytes_the_encoding_doesnt_have() {
+        match lex_bytes(b"\"a\x81\"", Encoding::Windows1252)[0] {
+            Err(LexError::UnmappedByte { byte, encoding, span }) => {
+                assert_eq!((byte, encoding), (0x81, Encoding::Windows1252));
+                assert_eq!((span.column, span.byte_offset), (3, 2));
+            },
+            ref other => panic!("{:?}", other)
+        }
+
+        // Latin-1 has all of them
+        assert!(lex_bytes(b"\"a\x81\"", Encoding::Latin1)[0].is_ok());
+
+        match lex_bytes(b"\"ab\xC3\"", Encoding::Utf8)[0] {
+            Err(LexError::InvalidUtf8 { span }) => assert_eq!(span.byte_offset, 3),
+            ref other => panic!("{:?}", other)
+        }
+    }
+
+    #[test]
+    fn identifiers_are_interned() {
+        let mut tokens = TokenIterator::from_str("Score = score + SCORE");
+        let interner = tokens.interner();
+        let names: Vec<_> = tokens.by_ref().map(Result::unwrap)
+            .filter_map(|token| token.identifier()).collect();
+        assert_eq!(names.len(), 3);
+        assert!(names.iter().all(|identifier| identifier.name == names[0].name));
+        assert_eq!(interner.resolve(names[0].name), b"score");
+        assert_eq!(interner.resolve(names[2].spelling), b"SCORE");
+
+        // Lexing another file with the same interner gives the same symbols
+        let more: Vec<_> = TokenIterator::from_str("score").with_interner(interner)
+            .map(Result::unwrap).collect();
+        assert_eq!(more[0].identifier().unwrap().name, names[0].name);
+    }
+
+    fn round_trip(source: &str) -> Vec<u8> {
+        let config = LexerConfig::new().lossless(true);
+        let mut tokens = TokenIterator::new_with_config(source.as_bytes(), config);
+        let mut written = Vec::new();
+        for token in tokens.by_ref() {
+            let trivia = token.unwrap().trivia.unwrap();
+            written.extend(trivia.leading);
+            written.extend(trivia.text);
+        }
+        written.extend(tokens.trailing_trivia());
+        written
+    }
+
+    #[test]
+    fn lossless_gives_back_the_input() {
+        let sources = [
+            "x = 1_000 ' count\r\n\tPrint \"hi\"  // done\n",
+            "If a Then _\n  b = &hFF /* block\n comment */ : c# = 1.50\n\n",
+            "   ",
+        ];
+        for source in sources.iter() {
+            assert_eq!(String::from_utf8(round_trip(source)).unwrap(), *source);
+        }
+    }
+
+    #[test]
+    fn trivia_is_split_around_the_token() {
+        let config = LexerConfig::new().lossless(true);
+        let token = TokenIterator::new_with_config(&b"  ' hi\n"[..], config).next().unwrap().unwrap();
+        let trivia = token.trivia.unwrap();
+        assert_eq!(token.kind, TokenKind::EOL);
+        assert_eq!((&trivia.leading[..], &trivia.text[..]), (&b"  ' hi"[..], &b"\n"[..]));
+
+        // Not asked for
+        assert_eq!(lex("x")[0].as_ref().unwrap().trivia, None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn tokens_compare_and_hash() {
+        let tokens: Vec<_> = lex("1.5 \"a\" 1.5 7 + 7").into_iter().map(Result::unwrap).collect();
+        // Same payload but somewhere else
+        assert!(tokens[0] != tokens[2]);
+        assert_eq!(tokens[0].payload, tokens[2].payload);
+        assert_eq!(tokens[0].clone(), tokens[0]);
+
+        let payloads: HashSet<_> = tokens.iter().map(|token| token.payload.clone()).collect();
+        assert_eq!(payloads.len(), 4);
+        let kinds: HashSet<_> = tokens.iter().map(|token| token.kind).collect();
+        assert_eq!(kinds.len(), 4);
+    }
+
+    #[test]
+    fn tokens_display_as_written() {
+        let shown: Vec<_> = lex("x \"a b\" 2.5 42 <> Then").into_iter()
+            .map(|token| token.unwrap().to_string()).collect();
+        assert_eq!(shown, vec!["identifier", "\"a b\"", "2.5", "42", "<>", "Then"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn tokens_go_through_json() {
+        let tokens: Vec<_> = lex("x$ = \"hi\" + 2.5 : y = 1")
+            .into_iter().map(Result::unwrap).collect();
+        let json = serde_json::to_string(&tokens).unwrap();
+        let back: Vec<Token<'static>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, tokens);
+
+        let span: serde_json::Value = serde_json::to_value(tokens[2].span).unwrap();
+        assert_eq!(span, serde_json::json!({"line": 1, "column": 6, "byte_offset": 5, "length": 4}));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn named_tokens_have_their_names() {
+        let mut it = TokenIterator::from_str("Foo$ = 1");
+        let tokens: Vec<_> = it.by_ref().map(Result::unwrap).collect();
+        let interner = it.interner();
+        let json = serde_json::to_value(tokens[0].named(&interner)).unwrap();
+        assert_eq!(json["payload"],
+                   serde_json::json!({"Identifier": {"name": "foo", "spelling": "Foo"}}));
+        assert_eq!(json["kind"], serde_json::to_value(&tokens[0]).unwrap()["kind"]);
+        // The rest are the same either way
+        assert_eq!(serde_json::to_value(tokens[2].named(&interner)).unwrap(),
+                   serde_json::to_value(&tokens[2]).unwrap());
+    }
+
+    #[test]
+    fn keywords_left_out_are_identifiers() {
+        let config = LexerConfig::new().keywords(KeywordSet::all().without(Keyword::Data));
+        let lexed: Vec<_> = lex_with("Data Print", config).into_iter()
+            .map(|token| token.unwrap().kind).collect();
+        assert_eq!(lexed, vec![TokenKind::Text, TokenKind::Keyword(Keyword::Print)]);
+
+        let none = KeywordSet::empty().with(Keyword::Print);
+        assert!(none.contains(Keyword::Print) && !none.contains(Keyword::If));
+    }
+
+    #[test]
+    fn comment_styles_can_be_turned_off() {
+        let comments = CommentStyles { apostrophe: false, ..CommentStyles::default() };
+        let lexed = lex_with("x ' no\n", LexerConfig::new().comments(comments));
+        match lexed[1] {
+            Err(LexError::UnexpectedByte { byte: b'\'', span }) => assert_eq!(span.column, 3),
+            ref other => panic!("{:?}", other)
+        }
+
+        let comments = CommentStyles { rem: false, ..CommentStyles::default() };
+        let lexed: Vec<_> = lex_with("Rem x", LexerConfig::new().comments(comments)).into_iter()
+            .map(|token| token.unwrap().kind).collect();
+        assert_eq!(lexed, vec![TokenKind::Text, TokenKind::Text]);
+    }
+
+    #[test]
+    fn other_whitespace_is_skipped_if_asked() {
+        assert!(lex("a\x0Bb").iter().any(Result::is_err));
+        let lexed = lex_with("a\x0B\x0Cb", LexerConfig::new().skip_all_whitespace(true));
+        assert_eq!(lexed.len(), 2);
+        assert!(lexed.iter().all(Result::is_ok));
+    }
+
+    // Hands over a byte at a time, and is a Source without being a Read
+    struct Dribble<'a>(&'a [u8]);
+
+    impl<'a> Source for Dribble<'a> {
+        fn read_into(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+            match self.0.split_first() {
+                Some((&first, rest)) if !buf.is_empty() => {
+                    buf[0] = first;
+                    self.0 = rest;
+                    Ok(1)
+                },
+                _ => Ok(0)
+            }
+        }
+    }
+
+    #[test]
+    fn sources_dont_need_std() {
+        let lexed: Vec<_> = TokenIterator::new(Dribble(b"Print \"hi\" + 12\n"))
+            .map(|token| token.unwrap().kind).collect();
+        assert_eq!(lexed, kinds("Print \"hi\" + 12\n"));
+    }
+
+    #[test]
+    fn runs_lex_the_same_a_byte_at_a_time() {
+        let mut source = String::new();
+        for i in 0..500 {
+            source.push_str(&format!("identifier_number_{} =    {}{} + 0.{} ' {}\n",
+                                     i, i, "0".repeat(i % 15), i, "comment ".repeat(i % 7)));
+            source.push_str(&format!("text$ = \"{}\" : h = &h{:X}\n", "string ".repeat(i % 11), i));
+        }
+
+        // Chunks of the buffer end in the middle of runs all over the place
+        let chunked: Vec<_> = TokenIterator::new(source.as_bytes()).collect();
+        let dribbled: Vec<_> = TokenIterator::new(Dribble(source.as_bytes())).collect();
+        assert_eq!(chunked.len(), 500 * 14);
+        assert!(chunked == dribbled);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut tokens = TokenIterator::from_str("first = \"half a string");
+        let first = tokens.next().unwrap().unwrap();
+
+        tokens.reset(&b"#!shebang\nsecond + first"[..]);
+        let lexed: Vec<_> = tokens.by_ref().map(Result::unwrap).collect();
+        // The shebang is on the first line again
+        assert_eq!(lexed[0].kind, TokenKind::EOL);
+        assert_eq!(lexed[1].span, Span { line: 2, column: 1, byte_offset: 10, length: 6 });
+        // Still the same names
+        assert_eq!(lexed[3].payload, first.payload);
+
+        tokens.reset(&b"1"[..]);
+        assert_eq!(tokens.next().unwrap().unwrap().payload, Payload::Integer(1));
+        assert!(tokens.next().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn stdin_keeps_the_config() {
+        // Nothing gets read until the first token is asked for
+        let tokens = TokenIterator::from_stdin_with_config(LexerConfig::new().case_insensitive(false));
+        assert!(!tokens.config().case_insensitive);
+        assert!(TokenIterator::from_stdin().config().case_insensitive);
+    }
+
+    #[test]
+    fn statistics_add_up() {
+        let mut tokens = TokenIterator::from_str("x = y @ 1\n");
+        tokens.by_ref().count();
+        // Without the config only the cheap counts
+        assert_eq!((tokens.statistics().bytes, tokens.statistics().tokens), (10, 5));
+        assert_eq!(tokens.statistics().errors, 1);
+        assert!(tokens.statistics().kinds.is_empty());
+
+        let config = LexerConfig::new().statistics(true);
+        let mut tokens = TokenIterator::new_with_config(&b"a b\n"[..], config);
+        tokens.by_ref().count();
+        tokens.reset(&b"c"[..]);
+        tokens.by_ref().count();
+        let statistics = tokens.statistics();
+        assert_eq!((statistics.bytes, statistics.tokens), (5, 4));
+        assert_eq!(statistics.kinds[&TokenKind::Text], 3);
+        assert_eq!(statistics.kinds[&TokenKind::EOL], 1);
+    }
+
+
+    // Has to be lexed over its contents, reading it isn't allowed
+    struct InMemory<'a>(&'a [u8]);
+
+    impl<'a> Source for InMemory<'a> {
+        fn read_into(&mut self, _: &mut [u8]) -> Result<usize, ReadError> {
+            panic!("read from a source that's in memory")
+        }
+
+        fn contents(&self) -> Option<&[u8]> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn sources_in_memory_arent_read() {
+        let source = "x$ = \"a string\" ' comment\n/* block\ncomment */ y = &HFF\n".repeat(2000);
+        let lexed: Vec<_> = TokenIterator::new(InMemory(source.as_bytes())).collect();
+        assert!(lexed == TokenIterator::new(source.as_bytes()).collect::<Vec<_>>());
+        assert_eq!(lexed.len(), 2000 * 9);
+
+        let config = LexerConfig::new().lossless(true);
+        let mut tokens = TokenIterator::new_with_config(InMemory(b"a = 1 ' c\n  "), config);
+        let mut written = Vec::new();
+        for token in tokens.by_ref() {
+            let trivia = token.unwrap().trivia.unwrap();
+            written.extend(trivia.leading);
+            written.extend(trivia.text);
+        }
+        written.extend(tokens.trailing_trivia());
+        assert_eq!(written, b"a = 1 ' c\n  ");
+
+        tokens.reset(InMemory(b"End"));
+        assert_eq!(tokens.next().unwrap().unwrap().kind, TokenKind::Keyword(Keyword::End));
+        assert!(tokens.next().is_none());
     }
 }
@@ -0,0 +1,421 @@
+use std::mem;
+
+use ast::{Arena, ArrayDecl, CaseValue, ExprId, ExprKind, Function, Name, PrintItem, Program, StmtId,
+          StmtKind, TypeDecl, Variable};
+
+// Goes through a tree without changing it
+// Every method walks the node's children by default, so an implementation
+// only overrides what it cares about and calls the walk_ function to keep
+// going inside it. Statements and expressions come as their ids, for
+// looking them up in the arena or keeping something about them by id:
+//
+//   struct Labels(Vec<String>);
+//
+//   impl Visitor for Labels {
+//       fn visit_label(&mut self, label: &Name) {
+//           self.0.push(label.name.clone());
+//       }
+//   }
+pub trait Visitor {
+    fn visit_program(&mut self, program: &Program) {
+        walk_program(self, program)
+    }
+
+    // The statements of a program, Function, loop or branch
+    fn visit_block(&mut self, arena: &Arena, statements: &[StmtId]) {
+        walk_block(self, arena, statements)
+    }
+
+    fn visit_stmt(&mut self, arena: &Arena, stmt: StmtId) {
+        walk_stmt(self, arena, stmt)
+    }
+
+    fn visit_expr(&mut self, arena: &Arena, expr: ExprId) {
+        walk_expr(self, arena, expr)
+    }
+
+    fn visit_function(&mut self, arena: &Arena, function: &Function) {
+        walk_function(self, arena, function)
+    }
+
+    fn visit_type(&mut self, _declared: &TypeDecl) {}
+
+    // Every variable that's used or declared, not the fields after a \
+    fn visit_variable(&mut self, _variable: &Variable) {}
+
+    // Labels after Goto, Gosub and Restore and the ones they go to
+    fn visit_label(&mut self, _label: &Name) {}
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+    visitor.visit_block(&program.arena, &program.statements);
+}
+
+pub fn walk_block<V: Visitor + ?Sized>(visitor: &mut V, arena: &Arena, statements: &[StmtId]) {
+    for &stmt in statements {
+        visitor.visit_stmt(arena, stmt);
+    }
+}
+
+fn walk_arrays<V: Visitor + ?Sized>(visitor: &mut V, arena: &Arena, arrays: &[ArrayDecl]) {
+    for array in arrays {
+        visitor.visit_variable(&array.variable);
+        for &dimension in &array.dimensions {
+            visitor.visit_expr(arena, dimension);
+        }
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, arena: &Arena, stmt: StmtId) {
+    match arena[stmt].kind {
+        StmtKind::Assign { target, value } => {
+            visitor.visit_expr(arena, target);
+            visitor.visit_expr(arena, value);
+        },
+        StmtKind::Call { ref arguments, .. } => for &argument in arguments {
+            visitor.visit_expr(arena, argument);
+        },
+        StmtKind::Print(ref items) => for item in items {
+            if let PrintItem::Value(value) = *item {
+                visitor.visit_expr(arena, value);
+            }
+        },
+        StmtKind::If { condition, ref then_branch, ref else_ifs, ref else_branch, .. } => {
+            visitor.visit_expr(arena, condition);
+            visitor.visit_block(arena, then_branch);
+            for else_if in else_ifs {
+                visitor.visit_expr(arena, else_if.condition);
+                visitor.visit_block(arena, &else_if.body);
+            }
+            if let Some(ref else_branch) = *else_branch {
+                visitor.visit_block(arena, else_branch);
+            }
+        },
+        StmtKind::For { ref variable, from, to, step, ref body } => {
+            visitor.visit_variable(variable);
+            visitor.visit_expr(arena, from);
+            visitor.visit_expr(arena, to);
+            if let Some(step) = step {
+                visitor.visit_expr(arena, step);
+            }
+            visitor.visit_block(arena, body);
+        },
+        StmtKind::While { condition, ref body } => {
+            visitor.visit_expr(arena, condition);
+            visitor.visit_block(arena, body);
+        },
+        StmtKind::Repeat { ref body, until } => {
+            visitor.visit_block(arena, body);
+            if let Some(until) = until {
+                visitor.visit_expr(arena, until);
+            }
+        },
+        StmtKind::Select { value, ref cases, ref default } => {
+            visitor.visit_expr(arena, value);
+            for case in cases {
+                for value in &case.values {
+                    match *value {
+                        CaseValue::Value(value) => visitor.visit_expr(arena, value),
+                        CaseValue::Range(from, to) => {
+                            visitor.visit_expr(arena, from);
+                            visitor.visit_expr(arena, to);
+                        }
+                    }
+                }
+                visitor.visit_block(arena, &case.body);
+            }
+            if let Some(ref default) = *default {
+                visitor.visit_block(arena, default);
+            }
+        },
+        StmtKind::Dim(ref arrays) | StmtKind::ReDim(ref arrays) => walk_arrays(visitor, arena, arrays),
+        StmtKind::Function(ref function) => visitor.visit_function(arena, function),
+        StmtKind::Type(ref declared) => visitor.visit_type(declared),
+        StmtKind::Data(ref values) => for &value in values {
+            visitor.visit_expr(arena, value);
+        },
+        StmtKind::Read(ref variables) |
+        StmtKind::Declare { ref variables, .. } => for variable in variables {
+            visitor.visit_variable(variable);
+        },
+        StmtKind::Const { ref name, value } => {
+            visitor.visit_variable(name);
+            visitor.visit_expr(arena, value);
+        },
+        StmtKind::Restore(Some(ref label)) | StmtKind::Label(ref label) |
+        StmtKind::Goto(ref label) | StmtKind::Gosub(ref label) => visitor.visit_label(label),
+        StmtKind::Return(Some(value)) => visitor.visit_expr(arena, value),
+        StmtKind::Include(_) | StmtKind::Restore(None) | StmtKind::Return(None) | StmtKind::Exit(_) |
+        StmtKind::End => {}
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, arena: &Arena, expr: ExprId) {
+    match arena[expr].kind {
+        ExprKind::Integer(_) | ExprKind::Float(_) | ExprKind::String(_) => {},
+        ExprKind::Variable(ref variable) => visitor.visit_variable(variable),
+        ExprKind::Member { object, .. } => visitor.visit_expr(arena, object),
+        ExprKind::Binary { left, right, .. } => {
+            visitor.visit_expr(arena, left);
+            visitor.visit_expr(arena, right);
+        },
+        ExprKind::Unary { operand, .. } => visitor.visit_expr(arena, operand),
+        ExprKind::Call { ref arguments, .. } => for &argument in arguments {
+            visitor.visit_expr(arena, argument);
+        },
+        ExprKind::Index { ref array, ref indices } => {
+            visitor.visit_variable(array);
+            for &index in indices {
+                visitor.visit_expr(arena, index);
+            }
+        }
+    }
+}
+
+pub fn walk_function<V: Visitor + ?Sized>(visitor: &mut V, arena: &Arena, function: &Function) {
+    for parameter in &function.parameters {
+        visitor.visit_variable(parameter);
+    }
+    visitor.visit_block(arena, &function.body);
+}
+
+// Visitor for changing the tree in place
+// Blocks are the Vecs themselves, so statements can be added or taken out
+// While a node's children are being visited it's an End or a 0 in the
+// arena, like for Fold, and it's put back after
+pub trait VisitorMut {
+    fn visit_program_mut(&mut self, program: &mut Program) {
+        walk_program_mut(self, program)
+    }
+
+    fn visit_block_mut(&mut self, arena: &mut Arena, statements: &mut Vec<StmtId>) {
+        walk_block_mut(self, arena, statements)
+    }
+
+    fn visit_stmt_mut(&mut self, arena: &mut Arena, stmt: StmtId) {
+        walk_stmt_mut(self, arena, stmt)
+    }
+
+    fn visit_expr_mut(&mut self, arena: &mut Arena, expr: ExprId) {
+        walk_expr_mut(self, arena, expr)
+    }
+
+    fn visit_function_mut(&mut self, arena: &mut Arena, function: &mut Function) {
+        walk_function_mut(self, arena, function)
+    }
+
+    fn visit_type_mut(&mut self, _declared: &mut TypeDecl) {}
+
+    fn visit_variable_mut(&mut self, _variable: &mut Variable) {}
+
+    fn visit_label_mut(&mut self, _label: &mut Name) {}
+}
+
+pub fn walk_program_mut<V: VisitorMut + ?Sized>(visitor: &mut V, program: &mut Program) {
+    visitor.visit_block_mut(&mut program.arena, &mut program.statements);
+}
+
+pub fn walk_block_mut<V: VisitorMut + ?Sized>(visitor: &mut V, arena: &mut Arena,
+                                              statements: &mut Vec<StmtId>) {
+    for stmt in statements {
+        visitor.visit_stmt_mut(arena, *stmt);
+    }
+}
+
+fn walk_arrays_mut<V: VisitorMut + ?Sized>(visitor: &mut V, arena: &mut Arena,
+                                           arrays: &mut [ArrayDecl]) {
+    for array in arrays {
+        visitor.visit_variable_mut(&mut array.variable);
+        for &dimension in &array.dimensions {
+            visitor.visit_expr_mut(arena, dimension);
+        }
+    }
+}
+
+pub fn walk_stmt_mut<V: VisitorMut + ?Sized>(visitor: &mut V, arena: &mut Arena, stmt: StmtId) {
+    let mut kind = mem::replace(&mut arena[stmt].kind, StmtKind::End);
+    match kind {
+        StmtKind::Assign { target, value } => {
+            visitor.visit_expr_mut(arena, target);
+            visitor.visit_expr_mut(arena, value);
+        },
+        StmtKind::Call { ref arguments, .. } => for &argument in arguments {
+            visitor.visit_expr_mut(arena, argument);
+        },
+        StmtKind::Print(ref mut items) => for item in items {
+            if let PrintItem::Value(value) = *item {
+                visitor.visit_expr_mut(arena, value);
+            }
+        },
+        StmtKind::If { condition, ref mut then_branch, ref mut else_ifs, ref mut else_branch,
+                       .. } => {
+            visitor.visit_expr_mut(arena, condition);
+            visitor.visit_block_mut(arena, then_branch);
+            for else_if in else_ifs {
+                visitor.visit_expr_mut(arena, else_if.condition);
+                visitor.visit_block_mut(arena, &mut else_if.body);
+            }
+            if let Some(ref mut else_branch) = *else_branch {
+                visitor.visit_block_mut(arena, else_branch);
+            }
+        },
+        StmtKind::For { ref mut variable, from, to, step, ref mut body } => {
+            visitor.visit_variable_mut(variable);
+            visitor.visit_expr_mut(arena, from);
+            visitor.visit_expr_mut(arena, to);
+            if let Some(step) = step {
+                visitor.visit_expr_mut(arena, step);
+            }
+            visitor.visit_block_mut(arena, body);
+        },
+        StmtKind::While { condition, ref mut body } => {
+            visitor.visit_expr_mut(arena, condition);
+            visitor.visit_block_mut(arena, body);
+        },
+        StmtKind::Repeat { ref mut body, until } => {
+            visitor.visit_block_mut(arena, body);
+            if let Some(until) = until {
+                visitor.visit_expr_mut(arena, until);
+            }
+        },
+        StmtKind::Select { value, ref mut cases, ref mut default } => {
+            visitor.visit_expr_mut(arena, value);
+            for case in cases {
+                for value in &case.values {
+                    match *value {
+                        CaseValue::Value(value) => visitor.visit_expr_mut(arena, value),
+                        CaseValue::Range(from, to) => {
+                            visitor.visit_expr_mut(arena, from);
+                            visitor.visit_expr_mut(arena, to);
+                        }
+                    }
+                }
+                visitor.visit_block_mut(arena, &mut case.body);
+            }
+            if let Some(ref mut default) = *default {
+                visitor.visit_block_mut(arena, default);
+            }
+        },
+        StmtKind::Dim(ref mut arrays) | StmtKind::ReDim(ref mut arrays) => {
+            walk_arrays_mut(visitor, arena, arrays)
+        },
+        StmtKind::Function(ref mut function) => visitor.visit_function_mut(arena, function),
+        StmtKind::Type(ref mut declared) => visitor.visit_type_mut(declared),
+        StmtKind::Data(ref values) => for &value in values {
+            visitor.visit_expr_mut(arena, value);
+        },
+        StmtKind::Read(ref mut variables) | StmtKind::Declare { ref mut variables, .. } => {
+            for variable in variables {
+                visitor.visit_variable_mut(variable);
+            }
+        },
+        StmtKind::Const { ref mut name, value } => {
+            visitor.visit_variable_mut(name);
+            visitor.visit_expr_mut(arena, value);
+        },
+        StmtKind::Restore(Some(ref mut label)) | StmtKind::Label(ref mut label) |
+        StmtKind::Goto(ref mut label) |
+        StmtKind::Gosub(ref mut label) => visitor.visit_label_mut(label),
+        StmtKind::Return(Some(value)) => visitor.visit_expr_mut(arena, value),
+        StmtKind::Include(_) | StmtKind::Restore(None) | StmtKind::Return(None) | StmtKind::Exit(_) |
+        StmtKind::End => {}
+    }
+    arena[stmt].kind = kind;
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, arena: &mut Arena, expr: ExprId) {
+    let mut kind = mem::replace(&mut arena[expr].kind, ExprKind::Integer(0));
+    match kind {
+        ExprKind::Integer(_) | ExprKind::Float(_) | ExprKind::String(_) => {},
+        ExprKind::Variable(ref mut variable) => visitor.visit_variable_mut(variable),
+        ExprKind::Member { object, .. } => visitor.visit_expr_mut(arena, object),
+        ExprKind::Binary { left, right, .. } => {
+            visitor.visit_expr_mut(arena, left);
+            visitor.visit_expr_mut(arena, right);
+        },
+        ExprKind::Unary { operand, .. } => visitor.visit_expr_mut(arena, operand),
+        ExprKind::Call { ref arguments, .. } => for &argument in arguments {
+            visitor.visit_expr_mut(arena, argument);
+        },
+        ExprKind::Index { ref mut array, ref indices } => {
+            visitor.visit_variable_mut(array);
+            for &index in indices {
+                visitor.visit_expr_mut(arena, index);
+            }
+        }
+    }
+    arena[expr].kind = kind;
+}
+
+pub fn walk_function_mut<V: VisitorMut + ?Sized>(visitor: &mut V, arena: &mut Arena,
+                                                 function: &mut Function) {
+    for parameter in &mut function.parameters {
+        visitor.visit_variable_mut(parameter);
+    }
+    visitor.visit_block_mut(arena, &mut function.body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_str;
+    use pretty::to_source;
+
+    #[derive(Default)]
+    struct Names {
+        variables: Vec<String>,
+        labels: Vec<String>,
+        exprs: usize
+    }
+
+    impl Visitor for Names {
+        fn visit_expr(&mut self, arena: &Arena, expr: ExprId) {
+            self.exprs += 1;
+            walk_expr(self, arena, expr)
+        }
+
+        fn visit_variable(&mut self, variable: &Variable) {
+            self.variables.push(variable.name.spelling.clone());
+        }
+
+        fn visit_label(&mut self, label: &Name) {
+            self.labels.push(label.spelling.clone());
+        }
+    }
+
+    #[test]
+    fn every_variable_and_label_in_order() {
+        let program = parse_str("top:\nDim a(n)\nFunction f(p)\nFor i = 1 To p\nRead b$\nNext\n\
+                                 EndFunction\nIf a(1) > q Then Goto top\nx = o\\y").unwrap();
+        let mut names = Names::default();
+        names.visit_program(&program);
+        assert_eq!(names.variables, vec!["a", "n", "p", "i", "p", "b", "a", "q", "x", "o"]);
+        assert_eq!(names.labels, vec!["top", "top"]);
+        // n, 1, p, a(1) and the 1 in it, q, a(1) > q, x and o\y and the o in it
+        assert_eq!(names.exprs, 10);
+    }
+
+    // Renames one variable and takes out every End
+    struct Rename;
+
+    impl VisitorMut for Rename {
+        fn visit_block_mut(&mut self, arena: &mut Arena, statements: &mut Vec<StmtId>) {
+            statements.retain(|&stmt| arena[stmt].kind != StmtKind::End);
+            walk_block_mut(self, arena, statements)
+        }
+
+        fn visit_variable_mut(&mut self, variable: &mut Variable) {
+            if variable.name.name == "old" {
+                variable.name.spelling = "new".to_string();
+                variable.name.name = "new".to_string();
+            }
+        }
+    }
+
+    #[test]
+    fn changing_the_tree_in_place() {
+        let mut program = parse_str("old = 1\nEnd\nWhile Old < 10\nold = old + 1\nEnd\nWend").unwrap();
+        Rename.visit_program_mut(&mut program);
+        assert_eq!(to_source(&program).unwrap(), "new = 1\nWhile new < 10\n    new = new + 1\nWend\n");
+    }
+}